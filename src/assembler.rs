@@ -0,0 +1,379 @@
+//! Building [`Code`] out of instructions and labels, instead of bytes.
+
+use std::collections::BTreeMap;
+
+use crate::{Code, OpCode, Constant, ConstantId, CodeId};
+
+/// Returned when assembled code can not be finished, see [`Assembler::finish`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssembleError {
+    /// A jump names a label that was never placed.
+    UnresolvedLabel(String),
+    /// A label was placed more than once.
+    DuplicateLabel(String),
+}
+
+impl std::fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssembleError::UnresolvedLabel(label) => write!(f, "label `{}` is never placed", label),
+            AssembleError::DuplicateLabel(label)  => write!(f, "label `{}` is placed twice", label),
+        }
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// Builds code one instruction at a time.
+/// Jumps name a label, which may be placed before or after the jump;
+/// the offsets to labels are filled in by [`Assembler::finish`].
+///
+/// ```
+/// let code = Assembler::new()
+///     .push(2)
+///     .push(3)
+///     .add_u64()
+///     .halt()
+///     .finish()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Assembler {
+    bytes: Vec<u8>,
+    // label -> offset it was placed at.
+    labels: BTreeMap<String, usize>,
+    // offset of a jump's immediate, the label it jumps to, and whether the jump is relative.
+    patches: Vec<(usize, String, bool)>,
+    duplicate: Option<String>,
+    constants: Vec<Constant>,
+}
+
+impl Assembler {
+    /// Starts assembling empty code.
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    /// Returns the offset the next instruction will be placed at.
+    pub fn offset(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Adds an instruction without immediates.
+    pub fn op(mut self, op: OpCode) -> Assembler {
+        self.bytes.push(op as u8);
+        self
+    }
+
+    /// Adds an instruction followed by an immediate natural.
+    pub fn op_u64(mut self, op: OpCode, immediate: u64) -> Assembler {
+        self.bytes.push(op as u8);
+        self.bytes.extend_from_slice(&immediate.to_le_bytes());
+        self
+    }
+
+    /// Places a label at the offset of the next instruction.
+    pub fn label(mut self, name: &str) -> Assembler {
+        if self.labels.insert(name.to_string(), self.bytes.len()).is_some() {
+            self.duplicate.get_or_insert_with(|| name.to_string());
+        }
+        self
+    }
+
+    /// Adds a jump to a label, such as [`OpCode::JumpIfZero`].
+    pub fn jump_op(mut self, op: OpCode, label: &str) -> Assembler {
+        self.bytes.push(op as u8);
+        self.patches.push((self.bytes.len(), label.to_string(), true));
+        self.bytes.extend_from_slice(&[0; 8]);
+        self
+    }
+
+    /// Adds a [`OpCode::Call`] of the subroutine at a label, passing it `args` arguments.
+    pub fn call(mut self, label: &str, args: u64) -> Assembler {
+        self.bytes.push(OpCode::Call as u8);
+        self.patches.push((self.bytes.len(), label.to_string(), false));
+        self.bytes.extend_from_slice(&[0; 8]);
+        self.bytes.extend_from_slice(&args.to_le_bytes());
+        self
+    }
+
+    /// Adds a [`OpCode::InstallHandler`] of the handler at a label, for an effect.
+    pub fn install_handler(mut self, effect: u64, label: &str) -> Assembler {
+        self.bytes.push(OpCode::InstallHandler as u8);
+        self.bytes.extend_from_slice(&effect.to_le_bytes());
+        self.patches.push((self.bytes.len(), label.to_string(), false));
+        self.bytes.extend_from_slice(&[0; 8]);
+        self
+    }
+
+    /// Adds a [`OpCode::Perform`] of an effect.
+    pub fn perform(self, effect: u64) -> Assembler {
+        self.op_u64(OpCode::Perform, effect)
+    }
+
+    /// Adds a [`OpCode::Resume`].
+    pub fn resume(self) -> Assembler {
+        self.op(OpCode::Resume)
+    }
+
+    /// Adds a [`OpCode::Return`], keeping `results` results.
+    pub fn ret(self, results: u64) -> Assembler {
+        self.op_u64(OpCode::Return, results)
+    }
+
+    /// Adds a [`OpCode::Jump`] to a label.
+    pub fn jump(self, label: &str) -> Assembler {
+        self.jump_op(OpCode::Jump, label)
+    }
+
+    /// Adds a [`OpCode::JumpIfZero`] to a label.
+    pub fn jump_if_zero(self, label: &str) -> Assembler {
+        self.jump_op(OpCode::JumpIfZero, label)
+    }
+
+    /// Adds a [`OpCode::JumpIfNonZero`] to a label.
+    pub fn jump_if_non_zero(self, label: &str) -> Assembler {
+        self.jump_op(OpCode::JumpIfNonZero, label)
+    }
+
+    /// Adds a [`OpCode::Push`] of a natural.
+    pub fn push(self, value: u64) -> Assembler {
+        self.op_u64(OpCode::Push, value)
+    }
+
+    /// Adds a [`OpCode::Push`] of the bits of a float.
+    pub fn push_f64(self, value: f64) -> Assembler {
+        self.push(value.to_bits())
+    }
+
+    /// Adds a [`OpCode::AddU64`].
+    pub fn add_u64(self) -> Assembler {
+        self.op(OpCode::AddU64)
+    }
+
+    /// Adds a [`OpCode::SubU64`].
+    pub fn sub_u64(self) -> Assembler {
+        self.op(OpCode::SubU64)
+    }
+
+    /// Adds a [`OpCode::MulU64`].
+    pub fn mul_u64(self) -> Assembler {
+        self.op(OpCode::MulU64)
+    }
+
+    /// Adds a [`OpCode::AddU64Checked`].
+    pub fn add_u64_checked(self) -> Assembler {
+        self.op(OpCode::AddU64Checked)
+    }
+
+    /// Adds a [`OpCode::SubU64Checked`].
+    pub fn sub_u64_checked(self) -> Assembler {
+        self.op(OpCode::SubU64Checked)
+    }
+
+    /// Adds a [`OpCode::MulU64Checked`].
+    pub fn mul_u64_checked(self) -> Assembler {
+        self.op(OpCode::MulU64Checked)
+    }
+
+    /// Adds a [`OpCode::AddF64`].
+    pub fn add_f64(self) -> Assembler {
+        self.op(OpCode::AddF64)
+    }
+
+    /// Adds a [`OpCode::SubF64`].
+    pub fn sub_f64(self) -> Assembler {
+        self.op(OpCode::SubF64)
+    }
+
+    /// Adds a [`OpCode::MulF64`].
+    pub fn mul_f64(self) -> Assembler {
+        self.op(OpCode::MulF64)
+    }
+
+    /// Adds a [`OpCode::DivF64`].
+    pub fn div_f64(self) -> Assembler {
+        self.op(OpCode::DivF64)
+    }
+
+    /// Adds a [`OpCode::I2F`].
+    pub fn i2f(self) -> Assembler {
+        self.op(OpCode::I2F)
+    }
+
+    /// Adds a [`OpCode::F2I`].
+    pub fn f2i(self) -> Assembler {
+        self.op(OpCode::F2I)
+    }
+
+    /// Adds a [`OpCode::Eq`].
+    pub fn eq(self) -> Assembler {
+        self.op(OpCode::Eq)
+    }
+
+    /// Adds a [`OpCode::Lt`].
+    pub fn lt(self) -> Assembler {
+        self.op(OpCode::Lt)
+    }
+
+    /// Adds a [`OpCode::Gt`].
+    pub fn gt(self) -> Assembler {
+        self.op(OpCode::Gt)
+    }
+
+    /// Adds a constant to the pool of the code.
+    /// Ids count up from zero, in the order constants are added, see [`Code::add_constant`].
+    pub fn constant(mut self, constant: Constant) -> Assembler {
+        self.constants.push(constant);
+        self
+    }
+
+    /// Adds a [`OpCode::LoadConst`] of a constant.
+    pub fn load_const(self, id: ConstantId) -> Assembler {
+        self.op_u64(OpCode::LoadConst, id.0 as u64)
+    }
+
+    /// Adds a [`OpCode::Alloc`].
+    pub fn alloc(self) -> Assembler {
+        self.op(OpCode::Alloc)
+    }
+
+    /// Adds a [`OpCode::AllocShared`].
+    pub fn alloc_shared(self) -> Assembler {
+        self.op(OpCode::AllocShared)
+    }
+
+    /// Adds a [`OpCode::Share`].
+    pub fn share(self) -> Assembler {
+        self.op(OpCode::Share)
+    }
+
+    /// Adds a [`OpCode::Realloc`].
+    pub fn realloc(self) -> Assembler {
+        self.op(OpCode::Realloc)
+    }
+
+    /// Adds a [`OpCode::Free`].
+    pub fn free(self) -> Assembler {
+        self.op(OpCode::Free)
+    }
+
+    /// Adds a [`OpCode::Load`].
+    pub fn load(self) -> Assembler {
+        self.op(OpCode::Load)
+    }
+
+    /// Adds a [`OpCode::Store`].
+    pub fn store(self) -> Assembler {
+        self.op(OpCode::Store)
+    }
+
+    /// Adds a [`OpCode::Spawn`] of a fiber running some code.
+    pub fn spawn(self, code: CodeId) -> Assembler {
+        self.op_u64(OpCode::Spawn, code.0 as u64)
+    }
+
+    /// Adds a [`OpCode::Send`].
+    pub fn send(self) -> Assembler {
+        self.op(OpCode::Send)
+    }
+
+    /// Adds a [`OpCode::Receive`].
+    pub fn receive(self) -> Assembler {
+        self.op(OpCode::Receive)
+    }
+
+    /// Adds a [`OpCode::Pop`].
+    pub fn pop(self) -> Assembler {
+        self.op(OpCode::Pop)
+    }
+
+    /// Adds a [`OpCode::Dup`].
+    pub fn dup(self) -> Assembler {
+        self.op(OpCode::Dup)
+    }
+
+    /// Adds a [`OpCode::Swap`].
+    pub fn swap(self) -> Assembler {
+        self.op(OpCode::Swap)
+    }
+
+    /// Adds a [`OpCode::Over`].
+    pub fn over(self) -> Assembler {
+        self.op(OpCode::Over)
+    }
+
+    /// Adds a [`OpCode::Rot`].
+    pub fn rot(self) -> Assembler {
+        self.op(OpCode::Rot)
+    }
+
+    /// Adds a [`OpCode::Halt`].
+    pub fn halt(self) -> Assembler {
+        self.op(OpCode::Halt)
+    }
+
+    /// Fills in the offset of every label jumped to, and returns the code.
+    pub fn finish(mut self) -> Result<Code, AssembleError> {
+        if let Some(label) = self.duplicate {
+            return Err(AssembleError::DuplicateLabel(label));
+        }
+        for (at, label, relative) in self.patches.iter() {
+            let target = *self.labels.get(label)
+                .ok_or_else(|| AssembleError::UnresolvedLabel(label.clone()))?;
+            // jumps are relative to the end of the jump, calls are not
+            let offset = match relative {
+                true  => target as i64 - (*at + 8) as i64,
+                false => target as i64,
+            };
+            self.bytes[*at..(*at + 8)].copy_from_slice(&offset.to_le_bytes());
+        }
+        let mut code = Code::new(self.bytes);
+        for constant in self.constants {
+            code.add_constant(constant);
+        }
+        return Ok(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assemble_loop() {
+        // counts up forever, skipping over a halt
+        let code = Assembler::new()
+            .push(0)
+            .jump("start")
+            .halt()
+            .label("start")
+            .push(1)
+            .add_u64()
+            .jump("start")
+            .finish()
+            .unwrap();
+
+        let push = OpCode::Push as u8;
+        let jump = OpCode::Jump as u8;
+        let expected = vec![
+            push, 0, 0, 0, 0, 0, 0, 0, 0,
+            jump, 1, 0, 0, 0, 0, 0, 0, 0,
+            OpCode::Halt as u8,
+            push, 1, 0, 0, 0, 0, 0, 0, 0,
+            OpCode::AddU64 as u8,
+            jump, 0xed, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        ];
+        assert_eq!(code.bytes(), &expected[..]);
+    }
+
+    #[test]
+    fn bad_labels() {
+        let error = Assembler::new().jump("nowhere").finish().unwrap_err();
+        assert_eq!(error, AssembleError::UnresolvedLabel("nowhere".to_string()));
+        assert_eq!(error.to_string(), "label `nowhere` is never placed");
+
+        let error = Assembler::new().label("a").halt().label("a").finish().unwrap_err();
+        assert_eq!(error, AssembleError::DuplicateLabel("a".to_string()));
+    }
+}
@@ -1,213 +1,567 @@
-use crate::Pointer;
+use crate::{Constant, ConstantId};
 
-#[derive(Debug, Clone, Copy)]
+/// A single instruction of the virtual machine, encoded as one byte.
+/// Any immediate operands follow the opcode byte in the code.
+/// Naturals are popped in reverse order, so `a b SubU64` leaves `a - b`.
+///
+/// Arithmetic wraps around on overflow by default, the same in debug and release builds.
+/// The checked variants trap instead, see [`crate::StepResult::Overflow`].
+///
+/// Values are not tagged with their type, the opcode says how to read them:
+/// the `F64` opcodes read values as the bits of an IEEE 754 double, see [`f64::from_bits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(u8)]
 pub enum OpCode {
-    // Unsigned 64-bit natural numbers
-    NatAdd,
-    NatSub,
-    NatMul,
-    NatDiv,
-    NatShl,
-    NatShr,
-    NatBitAnd,
-    NatBitOr,
-    NatBitXor,
-    NatEq,
-    NatToFloat,
-
-    // Signed 64-bit integers
-    IntAdd,
-    IntSub,
-    IntMul,
-    IntDiv,
-    IntEq,
-    IntToFloat,
-
-    // Signed 64-bit floating point numbers
-    // NaNs are not allowed
-    FloatAdd,
-    FloatSub,
-    FloatMul,
-    FloatDiv,
-    FloatPartialEq,
-
-    // Boolean
-    BoolAnd,
-    BoolOr,
-    BoolXor,
-    BoolNot,
-    BoolEq,
-
-    // Vaporization
-    AllocSingle, // R1 _    -> owned pointer
-    AllocPair,   // R1 R2   -> owned pointer
-    AllocMany,   // SIZE _  -> owned pointer
-    Borrow,      // PTR _   -> borrowed pointer
-    ReadSingle,  // PTR IDX      -> R3
-    ReadMany,    // PTR IDX SIZE -> onto stack
-    WriteOwned,  // owned_pointer idx val
-    Write,       // PTR SIZE -> R3 owned pointer / (loc of copying code on stack)
-
-    // Pointer
-    PointerIsOwned,    // PTR _ -> bool
-    PointerIsBorrowed, // PTR _ -> bool
-
-    // Between Stack and register
-    PushSingle, // R1 _ -> onto stack
-    PushPair,   // R1 R2 -> onto stack
-    PushTriple, // R1 R2 R3 -> onto stack
-    PopSingle,  // R1
-    PopPair,    // R1 R2
-    PopTriple,  // R1 R2 R3
-
-    // Stack
-    DelSingle,  // R1
-    DelPair,    // R1 R2
-    DelTriple,  // R1 R2 R3
-    StackConst, // Idx
-
-    // Register
-    RegConst, // Idx _ -> R3
-    RegSwap,  // R1 _ -> R3
-
-    // Control Flow
-    JumpTrue,   // loc R1 -> _
-    JumpFalse,  // loc R1 -> _
-    JumpBranch, // loc1 R1 loc2
-    Call,       // code num_args num_captures
-    Return,     // R1 _ -> _
-    ReturnMany, // R1 num_ret -> _
-
-    // Higher Order
-    ClosureSingle, // loc R2           -> owned pointer to closure
-    Closure,       // loc num_captures -> owned pointer to closure
-
-    // Fibers and Handlers
-    Fiber,       // code num_captures -> owned pointer to fiber
-    FiberCall,   // arg fiber -> R3
-    FiberYield,  // arg _ -> R3
-    HandlerAdd,  // effect fiber -> _
-    HandlerCall, // effect -> R3
+    /// Pops two naturals and pushes their sum.
+    AddU64,
+    /// Pops two naturals and pushes their difference.
+    SubU64,
+    /// Pops two naturals and pushes their product.
+    MulU64,
+    /// Pushes the natural that follows, as 8 little-endian bytes.
+    Push,
+    /// Pops a value and discards it.
+    Pop,
+    /// Pushes a copy of the top value.
+    Dup,
+    /// Stops running.
+    Halt,
+    /// Jumps by the signed offset that follows, as 8 little-endian bytes.
+    /// The offset is from the end of the jump, so an offset of zero does nothing.
+    Jump,
+    /// Like [`OpCode::AddU64`], but traps on overflow.
+    AddU64Checked,
+    /// Like [`OpCode::SubU64`], but traps on overflow.
+    SubU64Checked,
+    /// Like [`OpCode::MulU64`], but traps on overflow.
+    MulU64Checked,
+    /// Pops two naturals and pushes `1` if they are equal, `0` otherwise.
+    Eq,
+    /// Pops two naturals and pushes `1` if the first is less than the second, `0` otherwise.
+    Lt,
+    /// Pops two naturals and pushes `1` if the first is greater than the second, `0` otherwise.
+    Gt,
+    /// Pops a natural, and jumps like [`OpCode::Jump`] if it is zero.
+    JumpIfZero,
+    /// Pops a natural, and jumps like [`OpCode::Jump`] if it is not zero.
+    JumpIfNonZero,
+    /// Calls the subroutine at the offset that follows, passing it some arguments.
+    /// The offset is from the start of the code, and the number of arguments follows it,
+    /// both as 8 little-endian bytes. The arguments are the top values of the stack,
+    /// and become the first locals of a new frame, see [`crate::Stack::push_frame`].
+    Call,
+    /// Returns from the current subroutine, keeping some results.
+    /// The number of results follows, as 8 little-endian bytes.
+    /// The results are the top values of the frame, and replace its other values.
+    Return,
+    /// Loads the constant whose id follows, as 8 little-endian bytes, see [`Code::constant`].
+    /// A natural is pushed, and bytes are copied into a fresh allocation,
+    /// whose owned pointer is pushed, see [`crate::Constant::to_slots`].
+    LoadConst,
+    /// Pops a size, and pushes an owned pointer to a fresh allocation of that many slots,
+    /// which all read as zero.
+    Alloc,
+    /// Pops a pointer and the size of its allocation, and frees it,
+    /// or gives up its share of the allocation if it is shared, see [`OpCode::Share`].
+    Free,
+    /// Pops a pointer and the index of a slot in its allocation, and pushes the slot.
+    /// The stack is untyped, so pointers may be forged; the pointer is checked
+    /// to be the start of a live allocation, and the slot to be inside it,
+    /// see [`crate::StepResult::BadPointer`] and [`crate::StepResult::OutOfBounds`].
+    Load,
+    /// Pops a pointer, the index of a slot in its allocation, and a value,
+    /// and writes the value to the slot in place.
+    /// The pointer and the slot are checked like they are for a [`OpCode::Load`].
+    Store,
+    /// Spawns a fiber running the code whose id follows, as 8 little-endian bytes,
+    /// and pushes the id of the new fiber, see [`crate::Worker::spawn`].
+    Spawn,
+    /// Pops a state, and installs a handler for the effect whose id follows,
+    /// at the offset from the start of the code that follows it, both as 8 little-endian bytes.
+    /// The handler is uninstalled once the current frame returns.
+    InstallHandler,
+    /// Pops an operation, then its argument, and performs the effect whose id follows,
+    /// as 8 little-endian bytes. The innermost handler for the effect that is not already
+    /// running is run in a new frame, whose first locals are its state, the argument,
+    /// and the operation.
+    Perform,
+    /// Pops a result, then a new state for the running handler,
+    /// and continues after the effect it is handling, with the result pushed.
+    Resume,
+    /// Pops a value, then the id of a fiber, and sends the value to the fiber,
+    /// see [`crate::Fiber::deliver`].
+    Send,
+    /// Pushes the oldest message sent to the fiber,
+    /// or parks the fiber until there is one, see [`crate::StepResult::Parked`].
+    Receive,
+    /// Exchanges the top two values, so `a b Swap` leaves `b a`.
+    Swap,
+    /// Pushes a copy of the value below the top, so `a b Over` leaves `a b a`.
+    Over,
+    /// Rotates the third value to the top, so `a b c Rot` leaves `b c a`.
+    Rot,
+    /// Like [`OpCode::Alloc`], but allocates in the heap shared by every fiber on the worker,
+    /// so the pointer can be sent to other fibers, see [`crate::Worker::with_shared_heap`].
+    AllocShared,
+    /// Pops an owned pointer, and pushes it twice, as two owners of the allocation.
+    /// Nothing is copied, so a `Store` through either owner is seen through both.
+    Share,
+    /// Pops two floats and pushes their sum.
+    AddF64,
+    /// Pops two floats and pushes their difference.
+    SubF64,
+    /// Pops two floats and pushes their product.
+    MulF64,
+    /// Pops two floats and pushes their quotient.
+    /// Like all float arithmetic this never traps, it follows IEEE 754:
+    /// dividing by zero gives an infinity, or NaN if the dividend is zero or NaN.
+    DivF64,
+    /// Pops a natural and pushes the nearest float.
+    I2F,
+    /// Pops a float and pushes it as a natural, rounded towards zero.
+    /// Floats out of range saturate, to zero or [`u64::MAX`], and NaN becomes zero.
+    F2I,
+    /// Pops a pointer, the size of its allocation, and a new size,
+    /// and resizes the allocation, pushing the pointer to it, see [`crate::Heap::realloc`].
+    /// Slots past the old size read as zero. The pointer must be the only owner.
+    /// The allocation may move, so any other copy of the old pointer on the stack dangles;
+    /// with generation checks on, a later use of one traps as a [`crate::StepResult::BadPointer`],
+    /// see [`crate::Heap::with_generation_checks`].
+    Realloc,
 }
 
-#[derive(Debug, Clone)]
-pub struct Instr {
-    op_code: OpCode,
-    r0: u8,
-    r1: u8,
-    out: u8,
+/// Every opcode, indexed by its byte.
+pub(crate) const OPCODES: &[OpCode] = &[
+    OpCode::AddU64,
+    OpCode::SubU64,
+    OpCode::MulU64,
+    OpCode::Push,
+    OpCode::Pop,
+    OpCode::Dup,
+    OpCode::Halt,
+    OpCode::Jump,
+    OpCode::AddU64Checked,
+    OpCode::SubU64Checked,
+    OpCode::MulU64Checked,
+    OpCode::Eq,
+    OpCode::Lt,
+    OpCode::Gt,
+    OpCode::JumpIfZero,
+    OpCode::JumpIfNonZero,
+    OpCode::Call,
+    OpCode::Return,
+    OpCode::LoadConst,
+    OpCode::Alloc,
+    OpCode::Free,
+    OpCode::Load,
+    OpCode::Store,
+    OpCode::Spawn,
+    OpCode::InstallHandler,
+    OpCode::Perform,
+    OpCode::Resume,
+    OpCode::Send,
+    OpCode::Receive,
+    OpCode::Swap,
+    OpCode::Over,
+    OpCode::Rot,
+    OpCode::AllocShared,
+    OpCode::Share,
+    OpCode::AddF64,
+    OpCode::SubF64,
+    OpCode::MulF64,
+    OpCode::DivF64,
+    OpCode::I2F,
+    OpCode::F2I,
+    OpCode::Realloc,
+];
+
+impl OpCode {
+    /// Decodes an opcode from its byte,
+    /// or returns `None` if the byte is not an opcode.
+    pub fn from_u8(byte: u8) -> Option<OpCode> {
+        OPCODES.get(byte as usize).copied()
+    }
+
+    /// Returns the name of the opcode, as written by [`Code::disassemble`].
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            OpCode::AddU64        => "add_u64",
+            OpCode::SubU64        => "sub_u64",
+            OpCode::MulU64        => "mul_u64",
+            OpCode::Push          => "push",
+            OpCode::Pop           => "pop",
+            OpCode::Dup           => "dup",
+            OpCode::Halt          => "halt",
+            OpCode::Jump          => "jump",
+            OpCode::AddU64Checked => "add_u64_checked",
+            OpCode::SubU64Checked => "sub_u64_checked",
+            OpCode::MulU64Checked => "mul_u64_checked",
+            OpCode::Eq            => "eq",
+            OpCode::Lt            => "lt",
+            OpCode::Gt            => "gt",
+            OpCode::JumpIfZero    => "jump_if_zero",
+            OpCode::JumpIfNonZero => "jump_if_non_zero",
+            OpCode::Call          => "call",
+            OpCode::Return        => "return",
+            OpCode::LoadConst     => "load_const",
+            OpCode::Alloc         => "alloc",
+            OpCode::Free          => "free",
+            OpCode::Load          => "load",
+            OpCode::Store         => "store",
+            OpCode::Spawn         => "spawn",
+            OpCode::InstallHandler => "install_handler",
+            OpCode::Perform       => "perform",
+            OpCode::Resume        => "resume",
+            OpCode::Send          => "send",
+            OpCode::Receive       => "receive",
+            OpCode::Swap          => "swap",
+            OpCode::Over          => "over",
+            OpCode::Rot           => "rot",
+            OpCode::AllocShared   => "alloc_shared",
+            OpCode::Share         => "share",
+            OpCode::AddF64        => "add_f64",
+            OpCode::SubF64        => "sub_f64",
+            OpCode::MulF64        => "mul_f64",
+            OpCode::DivF64        => "div_f64",
+            OpCode::I2F           => "i2f",
+            OpCode::F2I           => "f2i",
+            OpCode::Realloc       => "realloc",
+        }
+    }
+
+    /// Returns the number of 8 byte immediates that follow the opcode.
+    pub fn immediates(self) -> usize {
+        match self {
+            OpCode::Push | OpCode::Jump | OpCode::JumpIfZero | OpCode::JumpIfNonZero => 1,
+            OpCode::Return | OpCode::LoadConst | OpCode::Spawn | OpCode::Perform => 1,
+            OpCode::Call | OpCode::InstallHandler => 2,
+            _ => 0,
+        }
+    }
+
+    /// Returns whether the immediates of the opcode are signed.
+    pub fn is_signed(self) -> bool {
+        matches!(self, OpCode::Jump | OpCode::JumpIfZero | OpCode::JumpIfNonZero)
+    }
+}
+
+/// Returned when code is malformed, see [`Code::validate`].
+/// Each names the offset of the instruction at fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The byte is not an opcode.
+    IllegalInstruction { offset: usize, byte: u8 },
+    /// The code ends before the immediates of the instruction do.
+    Truncated { offset: usize },
+    /// A jump, call, or handler lands outside the code, or inside an instruction.
+    BadTarget { offset: usize },
 }
 
-impl Instr {
-    pub fn exec(&self, regs: &mut [u64]) -> () {
-        let r0  = self.r0 as usize;
-        let r1  = self.r1 as usize;
-        let out = self.out as usize;
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::IllegalInstruction { offset, byte } => write!(f, "illegal instruction {:#04x} at {}", byte, offset),
+            VerifyError::Truncated { offset }                => write!(f, "instruction at {} is cut short", offset),
+            VerifyError::BadTarget { offset }                => write!(f, "instruction at {} does not land on an instruction", offset),
+        }
+    }
+}
 
-        use std::mem::transmute as tm;
-        use OpCode::*;
+impl std::error::Error for VerifyError {}
 
-        // SAFETY: arguments gauranteed to be bitwise representation of ints: size(i64) == size(u64)
-        let int_binop = |f: fn(i64, i64) -> i64, a0: u64, a1: u64| unsafe {
-            let b0: i64 = tm(a0);
-            let b1: i64 = tm(a1);
-            tm(f(b0, b1))
-        };
+/// A sequence of encoded instructions, and the pool of constants they load.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Code {
+    bytes: Vec<u8>,
+    constants: Vec<Constant>,
+}
+
+impl Code {
+    /// Wraps some already encoded instructions, with no constants.
+    pub fn new(bytes: Vec<u8>) -> Code {
+        Code { bytes, constants: vec![] }
+    }
+
+    /// Adds a constant to the pool, returning its id.
+    /// Ids count up from zero, in the order constants are added.
+    pub fn add_constant(&mut self, constant: Constant) -> ConstantId {
+        self.constants.push(constant);
+        ConstantId(self.constants.len() - 1)
+    }
+
+    /// Returns a constant in the pool, or `None` if there is no constant with that id.
+    pub fn constant(&self, id: ConstantId) -> Option<&Constant> {
+        self.constants.get(id.0)
+    }
+
+    /// Returns the encoded instructions.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Decodes the opcode at the instruction pointer, moving the instruction pointer past it.
+    /// Past the end of the code, or at a byte that is not an opcode,
+    /// returns [`OpCode::Halt`] without moving, so bad code stops instead of running on.
+    pub fn prefetch(&self, ip: &mut usize) -> OpCode {
+        match self.bytes.get(*ip).and_then(|byte| OpCode::from_u8(*byte)) {
+            Some(op) => {
+                *ip += 1;
+                op
+            },
+            None => OpCode::Halt,
+        }
+    }
+
+    /// Reads an immediate natural at the instruction pointer, as 8 little-endian bytes,
+    /// moving the instruction pointer past it.
+    /// Returns `None` without moving if the code ends first.
+    pub fn read_u64(&self, ip: &mut usize) -> Option<u64> {
+        let bytes = self.bytes.get(*ip..)?.get(..8)?;
+        *ip += 8;
+        Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Like [`Code::read_u64`], for a signed immediate.
+    pub fn read_i64(&self, ip: &mut usize) -> Option<i64> {
+        self.read_u64(ip).map(|immediate| immediate as i64)
+    }
+
+    /// Decodes every instruction, returning the offset of each and its text,
+    /// such as `push 7`. Bytes that do not decode to an instruction,
+    /// including the rest of an instruction cut short, are written as `.db 0xNN`.
+    pub fn disassemble(&self) -> Vec<(usize, String)> {
+        let mut lines = vec![];
+        let mut ip = 0;
+        while ip < self.bytes.len() {
+            let start = ip;
+            let op = match OpCode::from_u8(self.bytes[ip]) {
+                Some(op) => op,
+                None => {
+                    lines.push((start, format!(".db {:#04x}", self.bytes[ip])));
+                    ip += 1;
+                    continue;
+                },
+            };
 
-        // SAFETY: arguments gauranteed to be bitwise representation of floats: size(f64) == size(u64)
-        let float_binop = |f: fn(f64, f64) -> f64, a0: u64, a1: u64| unsafe {
-            let b0: f64 = tm(a0);
-            let b1: f64 = tm(a1);
-            tm(f(b0, b1))
+            ip += 1;
+            let mut line = op.mnemonic().to_string();
+            for _ in 0..op.immediates() {
+                match self.read_u64(&mut ip) {
+                    Some(immediate) if op.is_signed() => line.push_str(&format!(" {}", immediate as i64)),
+                    Some(immediate) => line.push_str(&format!(" {}", immediate)),
+                    None => {
+                        ip = start;
+                        break;
+                    },
+                }
+            }
+
+            // cut short, so every byte left is data
+            if ip == start {
+                for (offset, byte) in self.bytes.iter().enumerate().skip(start) {
+                    lines.push((offset, format!(".db {:#04x}", byte)));
+                }
+                break;
+            }
+            lines.push((start, line));
+        }
+        return lines;
+    }
+
+    /// Checks that the code decodes, so it can be rejected before it runs instead of trapping.
+    /// Every byte must be part of an instruction, every instruction must have all its immediates,
+    /// and every jump, call, and handler must land on the start of an instruction,
+    /// or the very end of the code.
+    /// Returns the error of the first instruction at fault.
+    pub fn validate(&self) -> Result<(), VerifyError> {
+        let len = self.bytes.len();
+        // offset -> whether an instruction starts there.
+        let mut starts = vec![false; len + 1];
+        // offset of each instruction with a target, and the target.
+        let mut targets = vec![];
+        let mut decoded = Ok(());
+
+        let mut ip = 0;
+        while ip < len {
+            let offset = ip;
+            let op = match OpCode::from_u8(self.bytes[ip]) {
+                Some(op) => op,
+                None => {
+                    decoded = Err(VerifyError::IllegalInstruction { offset, byte: self.bytes[ip] });
+                    break;
+                },
+            };
+            ip += 1;
+            let immediates: Option<Vec<u64>> = (0..op.immediates()).map(|_| self.read_u64(&mut ip)).collect();
+            let Some(immediates) = immediates else {
+                decoded = Err(VerifyError::Truncated { offset });
+                break;
+            };
+            starts[offset] = true;
+
+            // jumps are relative to the end of the jump, calls and handlers are not
+            let target = match op {
+                OpCode::Jump | OpCode::JumpIfZero | OpCode::JumpIfNonZero => Some(ip.checked_add_signed(immediates[0] as i64 as isize)),
+                OpCode::Call => Some(usize::try_from(immediates[0]).ok()),
+                OpCode::InstallHandler => Some(usize::try_from(immediates[1]).ok()),
+                _ => None,
+            };
+            if let Some(target) = target {
+                targets.push((offset, target));
+            }
+        }
+        starts[len] = true;
+
+        // past a decoding error, it is not known where instructions start
+        let known = match decoded {
+            Err(VerifyError::IllegalInstruction { offset, .. }) | Err(VerifyError::Truncated { offset }) => offset,
+            _ => len,
         };
+        for (offset, target) in targets {
+            let bad = match target {
+                Some(target) => target > len || (target < known && !starts[target]),
+                None => true,
+            };
+            if bad { return Err(VerifyError::BadTarget { offset }); }
+        }
+        return decoded;
+    }
+}
+
+impl std::fmt::Display for Code {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (offset, line) in self.disassemble() {
+            writeln!(f, "{:>4}  {}", offset, line)?;
+        }
+        Ok(())
+    }
+}
 
-        match self.op_code {
-            NatAdd => { regs[out] = regs[r0] + regs[r1] },
-            NatSub => { regs[out] = regs[r0] - regs[r1] },
-            NatMul => { regs[out] = regs[r0] * regs[r1] },
-            NatDiv => { regs[out] = regs[r0] / regs[r1] },
-            NatShl => { regs[out] = regs[r0] << regs[r1] },
-            NatShr => { regs[out] = regs[r0] >> regs[r1] },
-            NatBitAnd => { regs[out] = regs[r0] & regs[r1] },
-            NatBitOr  => { regs[out] = regs[r0] | regs[r1] },
-            NatBitXor => { regs[out] = regs[r0] ^ regs[r1] },
-            NatEq     => { todo!("booleans!"); },
-
-            // Signed 64-bit integers
-            IntAdd => { regs[out] = int_binop(|a, b| { a + b }, regs[r0], regs[r1]) },
-            IntSub => { regs[out] = int_binop(|a, b| { a - b }, regs[r0], regs[r1]) },
-            IntMul => { regs[out] = int_binop(|a, b| { a * b }, regs[r0], regs[r1]) },
-            IntDiv => { regs[out] = int_binop(|a, b| { a / b }, regs[r0], regs[r1]) },
-            IntEq  => { todo!("booleans!") },
-
-            // Signed 64-bit floating point numbers
-            // NaNs are not allowed
-            FloatAdd => { regs[out] = float_binop(|a, b| { a + b }, regs[r0], regs[r1]) },
-            FloatSub => { regs[out] = float_binop(|a, b| { a - b }, regs[r0], regs[r1]) },
-            FloatMul => { regs[out] = float_binop(|a, b| { a * b }, regs[r0], regs[r1]) },
-            FloatDiv => { regs[out] = float_binop(|a, b| { a / b }, regs[r0], regs[r1]) },
-            FloatPartialEq => { todo!("booleans!") },
-
-            // Boolean
-            | BoolAnd
-            | BoolOr
-            | BoolXor
-            | BoolNot
-            | BoolEq => { todo!("booleans!") },
-
-            _ => todo!("not yep implemented"),
-
-            // // Vaporization
-            // AllocSingle, // R1 _    -> owned pointer
-            // AllocPair,   // R1 R2   -> owned pointer
-            // AllocMany,   // SIZE _  -> owned pointer
-            // Borrow,      // PTR _   -> borrowed pointer
-            // ReadSingle,  // PTR IDX      -> R3
-            // ReadMany,    // PTR IDX SIZE -> onto stack
-            // WriteSingle, // PTR IDX VAL  -> copy on write
-            // WriteMany,   // PTR IDX SIZE -> from stack
-            //
-            // // Pointer
-            PointerIsOwned => { },
-            // PointerIsBorrowed, // PTR _ -> bool
-            //
-            // // Between Stack and register
-            // PushSingle, // R1 _ -> onto stack
-            // PushPair,   // R1 R2 -> onto stack
-            // PushTriple, // R1 R2 R3 -> onto stack
-            // PopSingle,  // R1
-            // PopPair,    // R1 R2
-            // PopTriple,  // R1 R2 R3
-            //
-            // // Stack
-            // DelSingle,  // R1
-            // DelPair,    // R1 R2
-            // DelTriple,  // R1 R2 R3
-            // StackConst, // Idx
-            //
-            // // Register
-            // RegConst, // Idx _ -> R3
-            // RegSwap,  // R1 _ -> R3
-            //
-            // // Control Flow
-            // JumpTrue,   // loc R1 -> _
-            // JumpFalse,  // loc R1 -> _
-            // Call,       // code num_args num_captures
-            // Return,     // R1 _ -> _
-            // ReturnMany, // R1 num_ret -> _
-            //
-            // // Higher Order
-            // ClosureSingle, // loc R2           -> owned pointer to closure
-            // Closure,       // loc num_captures -> owned pointer to closure
-            //
-            // // Fibers and Handlers
-            // Fiber,       // code num_captures -> owned pointer to fiber
-            // FiberCall,   // arg fiber -> R3
-            // FiberYield,  // arg _ -> R3
-            // HandlerAdd,  // effect fiber -> _
-            // HandlerCall, // effect -> R3
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes_round_trip() {
+        for (byte, op) in OPCODES.iter().enumerate() {
+            assert_eq!(*op as u8 as usize, byte);
+            assert_eq!(OpCode::from_u8(byte as u8), Some(*op));
         }
-        todo!()
+        assert_eq!(OpCode::from_u8(OPCODES.len() as u8), None);
+        assert_eq!(OpCode::from_u8(u8::MAX), None);
+    }
+
+    #[test]
+    fn decode_stream() {
+        let mut bytes = vec![OpCode::Push as u8];
+        bytes.extend_from_slice(&0x0123_4567_89ab_cdef_u64.to_le_bytes());
+        bytes.extend_from_slice(&[OpCode::AddU64 as u8, OpCode::Halt as u8]);
+        let code = Code::new(bytes);
+
+        let mut ip = 0;
+        assert_eq!(code.prefetch(&mut ip), OpCode::Push);
+        assert_eq!(code.read_u64(&mut ip), Some(0x0123_4567_89ab_cdef));
+        assert_eq!(ip, 9);
+        assert_eq!(code.prefetch(&mut ip), OpCode::AddU64);
+        assert_eq!(code.prefetch(&mut ip), OpCode::Halt);
+        assert_eq!(ip, 11);
+
+        // past the end
+        assert_eq!(code.prefetch(&mut ip), OpCode::Halt);
+        assert_eq!(code.read_u64(&mut ip), None);
+        assert_eq!(ip, 11);
+        let mut ip = 100;
+        assert_eq!(code.read_u64(&mut ip), None);
+
+        // a truncated immediate, and a byte that is not an opcode
+        let code = Code::new(vec![0xff, 1, 2, 3]);
+        let mut ip = 0;
+        assert_eq!(code.prefetch(&mut ip), OpCode::Halt);
+        assert_eq!(ip, 0);
+        ip = 1;
+        assert_eq!(code.read_u64(&mut ip), None);
+        assert_eq!(ip, 1);
+    }
+
+    #[test]
+    fn disassemble_program() {
+        let code = crate::Assembler::new()
+            .push(7)
+            .label("top")
+            .dup()
+            .mul_u64()
+            .jump("top")
+            .halt()
+            .finish()
+            .unwrap();
+        let lines: Vec<_> = code.disassemble();
+        assert_eq!(lines, vec![
+            (0, "push 7".to_string()),
+            (9, "dup".to_string()),
+            (10, "mul_u64".to_string()),
+            (11, "jump -11".to_string()),
+            (20, "halt".to_string()),
+        ]);
+        assert_eq!(code.to_string(), "   0  push 7\n   9  dup\n  10  mul_u64\n  11  jump -11\n  20  halt\n");
+    }
+
+    #[test]
+    fn validate_programs() {
+        let code = crate::Assembler::new()
+            .push(3)
+            .label("loop")
+            .push(1).sub_u64()
+            .dup().jump_if_non_zero("loop")
+            .call("end", 0)
+            .label("end")
+            .finish()
+            .unwrap();
+        assert_eq!(code.validate(), Ok(()));
+        assert_eq!(Code::new(vec![]).validate(), Ok(()));
+
+        let mut bytes = vec![OpCode::Dup as u8, OpCode::Push as u8, 1, 2, 3];
+        assert_eq!(Code::new(bytes.clone()).validate(), Err(VerifyError::Truncated { offset: 1 }));
+        bytes[1] = 0xff;
+        let error = Code::new(bytes).validate().unwrap_err();
+        assert_eq!(error, VerifyError::IllegalInstruction { offset: 1, byte: 0xff });
+        assert_eq!(error.to_string(), "illegal instruction 0xff at 1");
+    }
+
+    #[test]
+    fn validate_jump_targets() {
+        // a jump back into the immediate of the push
+        let mut bytes = vec![OpCode::Push as u8, 0, 0, 0, 0, 0, 0, 0, 0, OpCode::Jump as u8];
+        bytes.extend_from_slice(&(-14_i64).to_le_bytes());
+        assert_eq!(Code::new(bytes.clone()).validate(), Err(VerifyError::BadTarget { offset: 9 }));
+
+        // landing on an instruction, or the very end, is fine, but not past it
+        bytes[10..].copy_from_slice(&(-18_i64).to_le_bytes());
+        assert_eq!(Code::new(bytes.clone()).validate(), Ok(()));
+        bytes[10..].copy_from_slice(&0_i64.to_le_bytes());
+        assert_eq!(Code::new(bytes.clone()).validate(), Ok(()));
+        bytes[10..].copy_from_slice(&1_i64.to_le_bytes());
+        assert_eq!(Code::new(bytes.clone()).validate(), Err(VerifyError::BadTarget { offset: 9 }));
+        bytes[10..].copy_from_slice(&(-100_i64).to_le_bytes());
+        assert_eq!(Code::new(bytes).validate(), Err(VerifyError::BadTarget { offset: 9 }));
+
+        // calls are absolute
+        let mut bytes = vec![OpCode::Call as u8];
+        bytes.extend_from_slice(&3_u64.to_le_bytes());
+        bytes.extend_from_slice(&0_u64.to_le_bytes());
+        assert_eq!(Code::new(bytes).validate(), Err(VerifyError::BadTarget { offset: 0 }));
+
+        // a bad jump before a decoding error is found first
+        let mut bytes = vec![OpCode::Jump as u8];
+        bytes.extend_from_slice(&(-5_i64).to_le_bytes());
+        bytes.push(0xff);
+        assert_eq!(Code::new(bytes).validate(), Err(VerifyError::BadTarget { offset: 0 }));
+    }
+
+    #[test]
+    fn disassemble_bad_bytes() {
+        let code = Code::new(vec![OpCode::Pop as u8, 0xff, OpCode::Push as u8, 1, 2]);
+        let lines: Vec<_> = code.disassemble().into_iter().map(|(_offset, line)| line).collect();
+        assert_eq!(lines, vec!["pop", ".db 0xff", ".db 0x03", ".db 0x01", ".db 0x02"]);
+        assert_eq!(code.disassemble()[4].0, 4);
     }
 }
@@ -0,0 +1,68 @@
+//! Values known before code runs, which code loads by id, see [`crate::OpCode::LoadConst`].
+
+use crate::Slot;
+
+/// Identifies a constant in the constant pool of some code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConstantId(pub usize);
+
+/// A value in a constant pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Constant {
+    /// A natural, loaded straight onto the stack.
+    U64(u64),
+    /// Some bytes, loaded onto the heap, see [`Constant::to_slots`].
+    Bytes(Vec<u8>),
+}
+
+impl Constant {
+    /// Returns the slots a constant is stored in on the heap,
+    /// or `None` if it is loaded onto the stack instead.
+    /// Bytes are stored as their length, followed by the bytes packed 8 to a slot,
+    /// little-endian, with the last slot padded with zeros.
+    pub fn to_slots(&self) -> Option<Vec<Slot>> {
+        match self {
+            Constant::U64(_) => None,
+            Constant::Bytes(bytes) => {
+                let mut slots = Vec::with_capacity(1 + bytes.len().div_ceil(8));
+                // SAFETY: the bits are naturals.
+                slots.push(unsafe { Slot::from_bits(bytes.len() as u64) });
+                for chunk in bytes.chunks(8) {
+                    let mut padded = [0; 8];
+                    padded[..chunk.len()].copy_from_slice(chunk);
+                    // SAFETY: the bits are naturals.
+                    slots.push(unsafe { Slot::from_bits(u64::from_le_bytes(padded)) });
+                }
+                Some(slots)
+            },
+        }
+    }
+}
+
+/// Reads back bytes stored as by [`Constant::to_slots`].
+///
+/// # Safety
+/// Caller must ensure the slots hold bytes stored that way.
+pub unsafe fn bytes_from_slots(slots: &[Slot]) -> Vec<u8> {
+    let len = slots[0].to_u64() as usize;
+    let mut bytes: Vec<u8> = slots[1..].iter().flat_map(|slot| slot.to_u64().to_le_bytes()).collect();
+    bytes.truncate(len);
+    return bytes;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_round_trip() {
+        for len in [0, 1, 7, 8, 9, 20] {
+            let bytes: Vec<u8> = (0..len).map(|byte| byte as u8 + 1).collect();
+            let slots = Constant::Bytes(bytes.clone()).to_slots().unwrap();
+            assert_eq!(slots.len(), 1 + (len as usize).div_ceil(8));
+            // SAFETY: the slots were stored from bytes
+            assert_eq!(unsafe { bytes_from_slots(&slots) }, bytes);
+        }
+        assert!(Constant::U64(3).to_slots().is_none());
+    }
+}
@@ -1,15 +1,548 @@
-use std::collections::BTreeMap;
-use crate::{Stack, Heap};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
 
-pub struct HandlerId(usize);
+use crate::{Stack, Heap, Heaps, Code, CodeId, StepResult, VmConfig, step_with};
 
+/// Identifies an effect handler installed on a fiber.
+/// Handlers installed later have larger ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HandlerId(pub usize);
+
+/// Handles an effect performed by a fiber, see [`crate::OpCode::Perform`].
+/// A handler keeps a state, which it is given when an effect is performed,
+/// and which it replaces when it resumes, see [`crate::OpCode::Resume`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Handler {
-    fiber: Fiber,
+    effect: u64,
+    // offset of the code that handles the effect.
+    target: usize,
+    state:  u64,
+    // the number of frames when the handler was installed;
+    // the handler is uninstalled once the frame it was installed in returns.
+    depth:  usize,
+}
+
+impl Handler {
+    /// Returns the effect the handler handles.
+    pub fn effect(&self) -> u64 {
+        self.effect
+    }
+
+    /// Returns the state of the handler.
+    pub fn state(&self) -> u64 {
+        self.state
+    }
 }
 
+/// Where to resume after a handler is done, see [`Fiber::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Resumption {
+    handler: HandlerId,
+    // the number of frames while the handler runs.
+    depth:   usize,
+}
+
+/// Identifies a fiber running on a worker, see [`crate::Worker::spawn`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FiberId(pub usize);
+
+/// A lightweight thread of execution, running some code with its own stack and heap.
+/// Fibers are scheduled cooperatively by a [`crate::Worker`].
+#[derive(Debug)]
 pub struct Fiber {
+    code:   CodeId,
+    ip:     usize,
+    stack:  Stack,
+    // shared with forks of the fiber until it is next stepped, see `Fiber::fork`.
+    heap:   Arc<Heap>,
+    parent: Option<FiberId>,
     handlers: BTreeMap<HandlerId, Handler>,
-    stack:    Stack,
-    heap:     Heap,
-    parent:   FiberId,
+    next_handler: usize,
+    // the handlers that are running, innermost last.
+    resumptions: Vec<Resumption>,
+    // messages sent to the fiber, oldest first.
+    mailbox: VecDeque<u64>,
+    parked:  bool,
+    // instructions left to run before the fiber must be refueled.
+    gas:     u64,
+}
+
+/// The whole state of a fiber at some point, see [`Fiber::snapshot`].
+#[derive(Debug, Clone)]
+pub struct FiberSnapshot {
+    code:   CodeId,
+    ip:     usize,
+    stack:  Stack,
+    // the heap, as by `Heap::snapshot`.
+    heap:   Vec<u8>,
+    parent: Option<FiberId>,
+    handlers: BTreeMap<HandlerId, Handler>,
+    next_handler: usize,
+    resumptions: Vec<Resumption>,
+    mailbox: VecDeque<u64>,
+    parked:  bool,
+    gas:     u64,
+}
+
+impl FiberSnapshot {
+    /// Returns the offset of the next instruction the fiber ran when the snapshot was taken.
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+}
+
+impl Fiber {
+    /// Constructs a fiber that starts at the beginning of some code,
+    /// with an empty stack limited by a configuration, and an empty heap.
+    pub fn new(code: CodeId, parent: Option<FiberId>, config: VmConfig) -> Fiber {
+        Fiber {
+            code,
+            ip: 0,
+            stack: Stack::with_config(config),
+            heap: Arc::new(Heap::new()),
+            parent,
+            handlers: BTreeMap::new(),
+            next_handler: 0,
+            resumptions: vec![],
+            mailbox: VecDeque::new(),
+            parked: false,
+            gas: config.gas(),
+        }
+    }
+
+    /// Runs the next instruction of the fiber, see [`step_with`],
+    /// with the heap shared by its worker, if there is one.
+    /// The code must be the code the fiber was constructed with.
+    ///
+    /// Effects and messages are handled by the fiber, so this never returns
+    /// [`StepResult::InstallHandler`], [`StepResult::Perform`], [`StepResult::Resume`],
+    /// or [`StepResult::Receive`]. If there is no message to receive, the fiber is parked.
+    /// A fiber that halts with values on its stack gives the top one as its result,
+    /// see [`StepResult::Halted`].
+    ///
+    /// Each instruction costs one gas, including one that performs an effect,
+    /// however much the fiber does to handle it.
+    /// A heap still shared with a fork is copied first, see [`Fiber::fork`].
+    pub fn step(&mut self, code: &Code, shared: Option<&mut Heap>) -> StepResult {
+        if self.gas == 0 { return StepResult::OutOfGas; }
+        self.gas -= 1;
+
+        if Arc::get_mut(&mut self.heap).is_none() {
+            self.heap = Arc::new(self.heap.fork());
+        }
+        let mut heaps = Heaps { local: Arc::get_mut(&mut self.heap).unwrap(), shared };
+        let result = match step_with(&mut self.ip, &mut self.stack, &mut heaps, code) {
+            StepResult::InstallHandler { effect, target } => self.install_handler(effect, target),
+            StepResult::Perform(effect) => self.perform(effect),
+            StepResult::Resume => self.resume(),
+            StepResult::Receive => self.receive(),
+            StepResult::Halt => match self.stack.peek() {
+                Ok(value) => StepResult::Halted(value),
+                Err(_) => StepResult::Halt,
+            },
+            result => result,
+        };
+        self.uninstall_returned();
+        return result;
+    }
+
+    /// Pops the initial state of a new handler, and installs it in the current frame.
+    fn install_handler(&mut self, effect: u64, target: usize) -> StepResult {
+        let state = match self.stack.pop() {
+            Ok(state) => state,
+            Err(_) => return StepResult::StackUnderflow,
+        };
+        let id = HandlerId(self.next_handler);
+        self.next_handler += 1;
+        self.handlers.insert(id, Handler { effect, target, state, depth: self.stack.depth() });
+        return StepResult::Continue;
+    }
+
+    /// Pops an operation and its argument, and runs the innermost handler for an effect
+    /// that is not already running, in a new frame.
+    fn perform(&mut self, effect: u64) -> StepResult {
+        let running = |id: &HandlerId| self.resumptions.iter().any(|resumption| resumption.handler == *id);
+        let (id, handler) = match self.handlers.iter().rev()
+            .find(|(id, handler)| handler.effect == effect && !running(id))
+        {
+            Some((id, handler)) => (*id, *handler),
+            None => return StepResult::UnhandledEffect(effect),
+        };
+
+        let (operation, argument) = match (self.stack.pop(), self.stack.pop()) {
+            (Ok(operation), Ok(argument)) => (operation, argument),
+            _ => return StepResult::StackUnderflow,
+        };
+        // the two values just popped make room for the state
+        for value in [handler.state, argument, operation] {
+            if self.stack.push(value).is_err() { return StepResult::StackOverflow; }
+        }
+        match self.stack.push_frame(self.ip, 3) {
+            Ok(()) => (),
+            Err(_) => return StepResult::StackOverflow,
+        }
+
+        self.resumptions.push(Resumption { handler: id, depth: self.stack.depth() });
+        self.ip = handler.target;
+        return StepResult::Continue;
+    }
+
+    /// Pops a result and the new state of the running handler,
+    /// and continues after the effect the handler is handling, with the result pushed.
+    fn resume(&mut self) -> StepResult {
+        match self.resumptions.last() {
+            Some(resumption) if resumption.depth == self.stack.depth() => (),
+            _ => return StepResult::NoResumption,
+        }
+        let (result, state) = match (self.stack.pop(), self.stack.pop()) {
+            (Ok(result), Ok(state)) => (result, state),
+            _ => return StepResult::StackUnderflow,
+        };
+
+        let resumption = self.resumptions.pop().unwrap();
+        let frame = self.stack.return_frame(0).unwrap();
+        // a value was just popped, so there is room for it
+        self.stack.push(result).unwrap();
+        self.ip = frame.return_ip();
+        if let Some(handler) = self.handlers.get_mut(&resumption.handler) {
+            handler.state = state;
+        }
+        return StepResult::Continue;
+    }
+
+    /// Pushes the oldest message, or parks the fiber at the `Receive` if there are none.
+    fn receive(&mut self) -> StepResult {
+        let message = match self.mailbox.front() {
+            Some(message) => *message,
+            None => {
+                self.ip -= 1;
+                self.parked = true;
+                return StepResult::Parked;
+            },
+        };
+        if self.stack.push(message).is_err() {
+            return StepResult::StackOverflow;
+        }
+        self.mailbox.pop_front();
+        return StepResult::Continue;
+    }
+
+    /// Adds a message to the end of the mailbox of the fiber, unparking it.
+    pub fn deliver(&mut self, message: u64) {
+        self.mailbox.push_back(message);
+        self.parked = false;
+    }
+
+    /// Returns whether the fiber is waiting for a message, see [`StepResult::Parked`].
+    pub fn is_parked(&self) -> bool {
+        self.parked
+    }
+
+    /// Returns the gas the fiber has left.
+    pub fn gas(&self) -> u64 {
+        self.gas
+    }
+
+    /// Adds to the gas of the fiber, up to as much as there can be.
+    pub fn refuel(&mut self, gas: u64) {
+        self.gas = self.gas.saturating_add(gas);
+    }
+
+    /// Saves the whole state of the fiber, so it can be rewound to it, see [`Fiber::restore`].
+    /// The heap is saved as by [`Heap::snapshot`].
+    pub fn snapshot(&self) -> FiberSnapshot {
+        FiberSnapshot {
+            code: self.code,
+            ip: self.ip,
+            stack: self.stack.clone(),
+            heap: self.heap.snapshot(),
+            parent: self.parent,
+            handlers: self.handlers.clone(),
+            next_handler: self.next_handler,
+            resumptions: self.resumptions.clone(),
+            mailbox: self.mailbox.clone(),
+            parked: self.parked,
+            gas: self.gas,
+        }
+    }
+
+    /// Rewinds the fiber to a snapshot, replacing all of its state,
+    /// so it runs on exactly as it did from when the snapshot was taken.
+    pub fn restore(&mut self, snapshot: &FiberSnapshot) {
+        let heap = Heap::restore(&snapshot.heap).expect("a fiber snapshot holds a valid heap snapshot");
+        *self = Fiber {
+            code: snapshot.code,
+            ip: snapshot.ip,
+            stack: snapshot.stack.clone(),
+            heap: Arc::new(heap),
+            parent: snapshot.parent,
+            handlers: snapshot.handlers.clone(),
+            next_handler: snapshot.next_handler,
+            resumptions: snapshot.resumptions.clone(),
+            mailbox: snapshot.mailbox.clone(),
+            parked: snapshot.parked,
+            gas: snapshot.gas,
+        };
+    }
+
+    /// Returns a copy of the fiber, with its own stack, as a child of some fiber.
+    /// The copy runs on from where the fiber is, and nothing either writes is seen by the other.
+    /// The heap is not copied, but shared by the two until one of them is stepped,
+    /// which copies it for that one, see [`Heap::fork`]. So forking is cheap,
+    /// and a fork that is never run never copies the heap at all.
+    pub fn fork(&self, parent: FiberId) -> Fiber {
+        Fiber {
+            code: self.code,
+            ip: self.ip,
+            stack: self.stack.clone(),
+            heap: Arc::clone(&self.heap),
+            parent: Some(parent),
+            handlers: self.handlers.clone(),
+            next_handler: self.next_handler,
+            resumptions: self.resumptions.clone(),
+            mailbox: self.mailbox.clone(),
+            parked: self.parked,
+            gas: self.gas,
+        }
+    }
+
+    /// Returns whether two fibers still share one heap, see [`Fiber::fork`].
+    pub fn shares_heap_with(&self, other: &Fiber) -> bool {
+        Arc::ptr_eq(&self.heap, &other.heap)
+    }
+
+    /// Returns whether the fiber can run, which it can unless it is parked or out of gas.
+    pub fn is_runnable(&self) -> bool {
+        !self.parked && self.gas > 0
+    }
+
+    /// Uninstalls handlers installed in frames that have returned,
+    /// and forgets handlers that returned without resuming.
+    fn uninstall_returned(&mut self) {
+        let depth = self.stack.depth();
+        while self.handlers.last_key_value().is_some_and(|(_id, handler)| handler.depth > depth) {
+            self.handlers.pop_last();
+        }
+        while self.resumptions.last().is_some_and(|resumption| resumption.depth > depth) {
+            self.resumptions.pop();
+        }
+    }
+
+    /// Returns an installed handler,
+    /// or `None` if the frame it was installed in has returned.
+    pub fn handler(&self, id: HandlerId) -> Option<&Handler> {
+        self.handlers.get(&id)
+    }
+
+    /// Returns the code the fiber runs.
+    pub fn code(&self) -> CodeId {
+        self.code
+    }
+
+    /// Returns the offset of the next instruction the fiber will run.
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Returns the stack of the fiber.
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    /// Returns the stack of the fiber, mutably.
+    pub fn stack_mut(&mut self) -> &mut Stack {
+        &mut self.stack
+    }
+
+    /// Returns the heap of the fiber.
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Returns the fiber that spawned this one, if any.
+    pub fn parent(&self) -> Option<FiberId> {
+        self.parent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Assembler;
+
+    const STATE: u64 = 1;
+    const GET: u64 = 0;
+    const PUT: u64 = 1;
+
+    /// Runs a fiber until it stops, returning how it stopped.
+    fn run(fiber: &mut Fiber, code: &Code) -> StepResult {
+        loop {
+            match fiber.step(code, None) {
+                StepResult::Continue => continue,
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn state_effect_threads_state() {
+        let code = Assembler::new()
+            .push(10).install_handler(STATE, "state")
+            // x = get(); put(x + 5)
+            .push(0).push(GET).perform(STATE)
+            .push(5).add_u64()
+            .push(PUT).perform(STATE).pop()
+            // x = get(); put(x * 2)
+            .push(0).push(GET).perform(STATE)
+            .push(2).mul_u64()
+            .push(PUT).perform(STATE).pop()
+            .push(0).push(GET).perform(STATE)
+            .halt()
+            // the handler gets its state, the argument, and the operation
+            .label("state")
+            .jump_if_non_zero("put")
+            // get resumes with the state, leaving it as it was
+            .pop().dup().resume()
+            // put resumes with zero, replacing the state with the argument
+            .label("put")
+            .push(0).resume()
+            .finish().unwrap();
+
+        let mut fiber = Fiber::new(CodeId(0), None, VmConfig::default());
+        assert_eq!(run(&mut fiber, &code), StepResult::Halted(30));
+        assert_eq!(fiber.stack().as_slice(), &[30]);
+        assert_eq!(fiber.stack().depth(), 0);
+        let handler = fiber.handler(HandlerId(0)).unwrap();
+        assert_eq!(handler.effect(), STATE);
+        assert_eq!(handler.state(), 30);
+    }
+
+    #[test]
+    fn handlers_are_scoped_to_frames() {
+        // the handler is installed in a subroutine, so is gone once it returns
+        let code = Assembler::new()
+            .call("install", 0)
+            .push(0).push(GET).perform(STATE)
+            .halt()
+            .label("install")
+            .push(10).install_handler(STATE, "state")
+            .ret(0)
+            .label("state")
+            .pop().pop().resume()
+            .finish().unwrap();
+        let mut fiber = Fiber::new(CodeId(0), None, VmConfig::default());
+        assert_eq!(run(&mut fiber, &code), StepResult::UnhandledEffect(STATE));
+        assert_eq!(fiber.handler(HandlerId(0)), None);
+    }
+
+    #[test]
+    fn handlers_do_not_handle_themselves() {
+        // performing an effect inside its own handler goes to the handler outside it
+        let code = Assembler::new()
+            .push(1).install_handler(STATE, "outer")
+            .push(2).install_handler(STATE, "inner")
+            .push(0).push(GET).perform(STATE)
+            .halt()
+            .label("inner")
+            .pop().pop()
+            .push(0).push(GET).perform(STATE)
+            .push(100).add_u64()
+            .resume()
+            .label("outer")
+            .pop().pop().dup().resume()
+            .finish().unwrap();
+        let mut fiber = Fiber::new(CodeId(0), None, VmConfig::default());
+        assert_eq!(run(&mut fiber, &code), StepResult::Halted(101));
+        // the inner handler resumed with its state replaced by the outer state
+        assert_eq!(fiber.stack().as_slice(), &[101]);
+        assert_eq!(fiber.handler(HandlerId(1)).unwrap().state(), 2);
+    }
+
+    #[test]
+    fn resume_outside_a_handler_traps() {
+        let code = Assembler::new().push(0).push(0).resume().finish().unwrap();
+        let mut fiber = Fiber::new(CodeId(0), None, VmConfig::default());
+        assert_eq!(run(&mut fiber, &code), StepResult::NoResumption);
+    }
+
+    #[test]
+    fn restoring_a_snapshot_rewinds() {
+        // stores the state of a handler to the heap as it counts it up
+        let code = Assembler::new()
+            .push(0).install_handler(STATE, "state")
+            .push(1).alloc()
+            .push(4)
+            .label("loop")
+            .over().push(0)
+            .push(0).push(GET).perform(STATE)
+            .push(1).add_u64()
+            .dup().push(PUT).perform(STATE).pop()
+            .store()
+            .push(1).sub_u64()
+            .dup().jump_if_non_zero("loop")
+            .pop().push(0).load()
+            .halt()
+            .label("state")
+            .jump_if_non_zero("put")
+            .pop().dup().resume()
+            .label("put")
+            .push(0).resume()
+            .finish().unwrap();
+
+        // every instruction run from now on, and the state the fiber stops in
+        let trace = |fiber: &mut Fiber| {
+            let mut steps = vec![];
+            loop {
+                let result = fiber.step(&code, None);
+                steps.push((fiber.ip(), fiber.stack().as_slice().to_vec(), result));
+                if result != StepResult::Continue { break; }
+            }
+            (steps, fiber.heap().snapshot())
+        };
+
+        let mut fiber = Fiber::new(CodeId(0), None, VmConfig::default());
+        for _ in 0..40 {
+            assert_eq!(fiber.step(&code, None), StepResult::Continue);
+        }
+        let snapshot = fiber.snapshot();
+        let stack = fiber.stack().clone();
+        let state = fiber.handler(HandlerId(0)).unwrap().state();
+        let first = trace(&mut fiber);
+        assert_eq!(first.0.last().unwrap().2, StepResult::Halted(4));
+        assert_eq!(fiber.handler(HandlerId(0)).unwrap().state(), 4);
+        assert_ne!(state, 4);
+
+        // nothing from after the snapshot is left over, and it runs on the same
+        fiber.restore(&snapshot);
+        assert_eq!(fiber.ip(), snapshot.ip());
+        assert_eq!(fiber.stack(), &stack);
+        assert_eq!(fiber.handler(HandlerId(0)).unwrap().state(), state);
+        assert_eq!(trace(&mut fiber), first);
+        fiber.restore(&snapshot);
+        assert_eq!(trace(&mut fiber), first);
+    }
+
+    #[test]
+    fn forks_share_their_heap_until_stepped() {
+        // writes to a fresh block, then overwrites it, and reads it back
+        let code = Assembler::new()
+            .push(1).alloc()
+            .dup().push(0).push(10).store()
+            .dup().push(0).push(20).store()
+            .push(0).load()
+            .finish().unwrap();
+        let mut fiber = Fiber::new(CodeId(0), None, VmConfig::default());
+        for _ in 0..6 {
+            assert_eq!(fiber.step(&code, None), StepResult::Continue);
+        }
+
+        let mut fork = fiber.fork(FiberId(0));
+        assert!(fork.shares_heap_with(&fiber));
+        assert_eq!(fork.parent(), Some(FiberId(0)));
+        let before = fiber.heap().snapshot();
+        assert_eq!(run(&mut fork, &code), StepResult::Halted(20));
+        assert!(!fork.shares_heap_with(&fiber));
+        assert_eq!(fiber.heap().snapshot(), before);
+        assert_eq!(run(&mut fiber, &code), StepResult::Halted(20));
+    }
 }
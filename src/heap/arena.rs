@@ -0,0 +1,146 @@
+//! Bump allocation of many small blocks that are freed all at once.
+
+use std::collections::BTreeMap;
+
+use super::{Heap, Pointer, Backing};
+use super::pointer::PointerIdx;
+
+/// The size of the first region of an arena, in slots.
+const FIRST_REGION: usize = 64;
+
+/// Names an arena in a heap, see [`Heap::begin_arena`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ArenaId(usize);
+
+/// The regions reserved by an arena.
+#[derive(Debug, Default, Clone)]
+pub(super) struct Arena {
+    // start and size of each region, the last one is being bumped into.
+    // each region is a live allocation in the heap.
+    regions: Vec<(PointerIdx, usize)>,
+    // the number of slots used in the last region.
+    used: usize,
+}
+
+impl Arena {
+    /// Returns whether a live allocation is one of the regions of the arena.
+    pub(super) fn owns(&self, start: PointerIdx) -> bool {
+        self.regions.iter().any(|(region, _slots)| *region == start)
+    }
+
+    /// Moves regions along with the allocations they live in, see [`Heap::compact`].
+    pub(super) fn relocate(&mut self, moved: &BTreeMap<PointerIdx, PointerIdx>) {
+        for (start, _slots) in self.regions.iter_mut() {
+            if let Some(new_start) = moved.get(start) {
+                *start = *new_start;
+            }
+        }
+    }
+}
+
+impl<B: Backing> Heap<B> {
+    /// Starts a new arena, which hands out slots from a few large regions.
+    /// Allocations in an arena can not be freed on their own,
+    /// instead all of them are freed at once by [`Heap::drop_arena`].
+    pub fn begin_arena(&mut self) -> ArenaId {
+        let id = ArenaId(self.next_arena);
+        self.next_arena += 1;
+        self.arenas.insert(id, Arena::default());
+        return id;
+    }
+
+    /// Allocates some slots in an arena by bumping a pointer.
+    /// When the current region is full, a new region at least twice as large is reserved.
+    /// The pointer is not a live allocation of its own, see [`Heap::size_of`].
+    ///
+    /// # Safety
+    /// The slots are not initialized, like [`Heap::alloc`].
+    ///
+    /// # Panics
+    /// If the arena was dropped.
+    pub unsafe fn alloc_in(&mut self, arena: ArenaId, slots: usize) -> Pointer {
+        let current = self.arenas.get(&arena).expect("allocation in an arena that was dropped");
+        let region = current.regions.last().copied();
+
+        let (start, used) = match region {
+            Some((start, size)) if current.used + slots <= size => (start, current.used),
+            _ => {
+                let size = region.map_or(FIRST_REGION, |(_start, size)| size * 2).max(slots);
+                let start = self.alloc(size).to_idx();
+                self.arenas.get_mut(&arena).unwrap().regions.push((start, size));
+                (start, 0)
+            },
+        };
+
+        self.arenas.get_mut(&arena).unwrap().used = used + slots;
+        return Pointer::new(start + used);
+    }
+
+    /// Frees every allocation in an arena at once, by freeing its regions.
+    /// Any pointers into the arena must no longer be used.
+    ///
+    /// # Panics
+    /// If the arena was already dropped.
+    pub fn drop_arena(&mut self, arena: ArenaId) {
+        let arena = self.arenas.remove(&arena).expect("arena was already dropped");
+        let regions: Vec<_> = arena.regions.iter()
+            .map(|(start, slots)| (self.tag_generation(Pointer::new(*start)), *slots))
+            .collect();
+        self.free_many(&regions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arena_bumps_and_drops() {
+        let mut heap = Heap::new();
+        let before = heap.calloc(5);
+        let arena = heap.begin_arena();
+
+        // SAFETY: data is never read
+        let pointers: Vec<_> = (0..200).map(|_| unsafe { heap.alloc_in(arena, 1) }).collect();
+        assert_eq!(pointers[0].idx(), 5);
+        assert!(pointers.windows(2).take(63).all(|pair| pair[1].idx() == pair[0].idx() + 1));
+        // regions of 64, 128, and 256 slots
+        assert_eq!(heap.free.live.len(), 1 + 3);
+
+        let after = heap.calloc(2);
+        heap.drop_arena(arena);
+
+        let free: Vec<_> = heap.free.iter_free().map(|(p, s)| (p.idx(), s)).collect();
+        assert_eq!(free, vec![(5, 64 + 128 + 256)]);
+        heap.free(after, 2);
+        assert!(heap.free.iter_free().next().is_none());
+        assert_eq!(heap.stats().total_slots, 5);
+        assert_eq!(heap.size_of(before), Some(5));
+    }
+
+    #[test]
+    fn arena_fits_large_allocations() {
+        let mut heap = Heap::new();
+        let arena = heap.begin_arena();
+        // SAFETY: data is never read
+        let small = unsafe { heap.alloc_in(arena, 3) };
+        let large = unsafe { heap.alloc_in(arena, 500) };
+        let next = unsafe { heap.alloc_in(arena, 3) };
+        assert_eq!(small.idx(), 0);
+        assert_eq!(large.idx(), FIRST_REGION as u64);
+        assert_eq!(next.idx(), FIRST_REGION as u64 + 500);
+
+        heap.drop_arena(arena);
+        assert_eq!(heap.stats().total_slots, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "dropped")]
+    fn arena_use_after_drop() {
+        let mut heap = Heap::new();
+        let arena = heap.begin_arena();
+        heap.drop_arena(arena);
+        // SAFETY: data is never read
+        unsafe { heap.alloc_in(arena, 1) };
+    }
+}
@@ -0,0 +1,152 @@
+//! Where a heap keeps its slots, see [`Heap::with_backing`].
+
+use std::ops::DerefMut;
+
+use crate::Slot;
+use super::AllocError;
+#[cfg(doc)]
+use super::Heap;
+
+/// Storage for the slots of a heap, which grows and shrinks at the end.
+/// The slots in use, and their count, are read through the slice it derefs to.
+pub trait Backing: DerefMut<Target = [Slot]> {
+    /// Returns the most slots this backing can ever hold, if there is such a limit.
+    /// A heap never grows past this, see [`Heap::with_max_capacity`].
+    fn max_len(&self) -> Option<usize>;
+
+    /// Adds some zeroed slots at the end,
+    /// or returns an error leaving the backing as it was if they do not fit.
+    fn grow(&mut self, additional: usize) -> Result<(), AllocError>;
+
+    /// Drops every slot past `len`. Does nothing if there are already fewer slots.
+    fn truncate(&mut self, len: usize);
+}
+
+/// Slots on the global heap, which are only limited by memory.
+impl Backing for Vec<Slot> {
+    fn max_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn grow(&mut self, additional: usize) -> Result<(), AllocError> {
+        // SAFETY: the heap does not hand out grown slots as pointers
+        self.extend((0..additional).map(|_| unsafe { Slot::zero() }));
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        Vec::truncate(self, len);
+    }
+}
+
+/// A fixed region of memory, such as a static buffer on a system without an allocator.
+/// The heap grows into the region from the start, and can not grow past its end.
+#[cfg(feature = "fixed_backing")]
+#[derive(Debug)]
+pub struct FixedBacking<'a> {
+    region: &'a mut [Slot],
+    // the number of slots in use, from the start of the region.
+    len: usize,
+}
+
+#[cfg(feature = "fixed_backing")]
+impl<'a> FixedBacking<'a> {
+    /// Uses a region of memory as the slots of a heap, starting empty.
+    /// Whatever the region held is never read, slots are zeroed as the heap grows into them.
+    pub fn new(region: &'a mut [u64]) -> FixedBacking<'a> {
+        // SAFETY: a slot is a transparent wrapper of a `u64`, and any bits are a valid slot
+        let region = unsafe { std::slice::from_raw_parts_mut(region.as_mut_ptr() as *mut Slot, region.len()) };
+        return FixedBacking { region, len: 0 };
+    }
+}
+
+#[cfg(feature = "fixed_backing")]
+impl std::ops::Deref for FixedBacking<'_> {
+    type Target = [Slot];
+
+    fn deref(&self) -> &[Slot] {
+        &self.region[..self.len]
+    }
+}
+
+#[cfg(feature = "fixed_backing")]
+impl DerefMut for FixedBacking<'_> {
+    fn deref_mut(&mut self) -> &mut [Slot] {
+        &mut self.region[..self.len]
+    }
+}
+
+#[cfg(feature = "fixed_backing")]
+impl Backing for FixedBacking<'_> {
+    fn max_len(&self) -> Option<usize> {
+        Some(self.region.len())
+    }
+
+    fn grow(&mut self, additional: usize) -> Result<(), AllocError> {
+        let available = self.region.len() - self.len;
+        if additional > available {
+            return Err(AllocError::OutOfMemory { requested: additional, available });
+        }
+        for slot in self.region[self.len..(self.len + additional)].iter_mut() {
+            // SAFETY: the heap does not hand out grown slots as pointers
+            *slot = unsafe { Slot::zero() };
+        }
+        self.len += additional;
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+}
+
+#[cfg(all(test, feature = "fixed_backing"))]
+mod tests {
+    use super::*;
+    use crate::heap::Heap;
+
+    // only uses the region given, so it would run the same without an allocator
+    #[test]
+    fn fixed_backing_runs_out_gracefully() {
+        let mut region = [0xffff_u64; 16];
+        let mut heap = Heap::with_backing(FixedBacking::new(&mut region));
+        let a = heap.calloc(6);
+        let b = heap.calloc(8);
+        assert_eq!(heap.capacity(), 14);
+        // SAFETY: calloc zeroes
+        assert_eq!(unsafe { heap.read_slot(b, 7).to_u64() }, 0);
+
+        // growing the tail past the end of the region fails instead of panicking
+        // SAFETY: data is never read
+        let error = unsafe { heap.try_alloc(3) }.unwrap_err();
+        assert_eq!(error, AllocError::OutOfMemory { requested: 3, available: 2 });
+        assert_eq!(heap.free.audit(), Ok(()));
+        assert_eq!(heap.capacity(), 14);
+
+        // but freed slots are reused, and the tail fills up exactly
+        heap.free(a, 6);
+        assert_eq!(heap.calloc(4).idx(), 0);
+        assert_eq!(heap.calloc(2).idx(), 4);
+        let c = heap.calloc(2);
+        assert_eq!(c.idx(), 14);
+        assert_eq!(heap.capacity(), 16);
+        assert!(unsafe { heap.try_alloc(1) }.is_err());
+
+        // the heap shrinks back as the tail is freed
+        heap.free(b, 8);
+        heap.free(c, 2);
+        assert_eq!(heap.capacity(), 6);
+        drop(heap);
+        assert_eq!(region[0], 0);
+        assert_eq!(region[15], 0);
+    }
+
+    #[test]
+    fn max_capacity_is_capped_by_the_region() {
+        let mut region = [0; 4];
+        let mut heap = Heap::with_backing(FixedBacking::new(&mut region)).with_max_capacity(100);
+        // SAFETY: data is never read
+        let error = unsafe { heap.try_alloc(5) }.unwrap_err();
+        assert_eq!(error, AllocError::OutOfMemory { requested: 5, available: 4 });
+    }
+}
@@ -0,0 +1,223 @@
+//! A heap that can be shared between threads, split into shards that are locked separately.
+//!
+//! Each shard owns a fixed range of addresses: the shard index is stored in the
+//! high bits of the slot index of every pointer handed out,
+//! so a pointer can be routed back to its shard without a global lookup.
+
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Heap, Pointer, AuditError, HeapError};
+
+/// The number of low bits of a slot index that address a slot within a shard.
+const SHARD_SHIFT: u32 = 40;
+/// The most shards a heap can be split into, limited by the bits left in a pointer.
+pub const MAX_SHARDS: usize = 1 << (48 - SHARD_SHIFT);
+
+/// A heap split into shards, each behind its own lock.
+/// Allocations are spread between shards round-robin, skipping shards that are busy,
+/// so threads allocating at the same time rarely wait on each other.
+#[derive(Debug)]
+pub struct ConcurrentHeap {
+    shards: Vec<Mutex<Heap>>,
+    // the shard the next allocation tries first.
+    next: AtomicUsize,
+}
+
+impl ConcurrentHeap {
+    /// Creates a heap with a number of shards, each configured as by [`Heap::new`].
+    pub fn new(shards: usize) -> ConcurrentHeap {
+        return ConcurrentHeap::from_shards((0..shards).map(|_| Heap::new()).collect());
+    }
+
+    /// Creates a heap from empty heaps used as shards,
+    /// to configure each shard with the builder methods on [`Heap`].
+    ///
+    /// # Panics
+    /// If there are no shards, more than [`MAX_SHARDS`], or one of them is not empty.
+    pub fn from_shards(shards: Vec<Heap>) -> ConcurrentHeap {
+        assert!(!shards.is_empty(), "a concurrent heap needs at least one shard");
+        assert!(shards.len() <= MAX_SHARDS, "a concurrent heap has at most {} shards", MAX_SHARDS);
+        assert!(shards.iter().all(|shard| shard.capacity() == 0), "shards must start empty");
+
+        let shards = shards.into_iter()
+            .map(|shard| {
+                // every slot of a shard must be addressable below the shard bits
+                let max_capacity = shard.max_capacity.map_or(1 << SHARD_SHIFT, |max| max.min(1 << SHARD_SHIFT));
+                Mutex::new(shard.with_max_capacity(max_capacity))
+            })
+            .collect();
+        return ConcurrentHeap { shards, next: AtomicUsize::new(0) };
+    }
+
+    /// Returns the number of shards.
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the shard a pointer was allocated in.
+    ///
+    /// # Panics
+    /// If the pointer was not allocated by this heap.
+    pub fn shard_of(&self, pointer: Pointer) -> usize {
+        let shard = (pointer.idx() >> SHARD_SHIFT) as usize;
+        assert!(shard < self.shards.len(), "pointer does not belong to this heap");
+        return shard;
+    }
+
+    /// Locks a shard, see [`ConcurrentHeap::with_heap`].
+    fn lock(&self, shard: usize) -> MutexGuard<'_, Heap> {
+        self.shards[shard].lock().expect("a thread panicked while holding a shard")
+    }
+
+    /// Locks the first shard that is not busy, starting from the next one in turn.
+    /// If every shard is busy, waits for the one whose turn it is.
+    fn lock_any(&self) -> (usize, MutexGuard<'_, Heap>) {
+        let first = self.next.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        for offset in 0..self.shards.len() {
+            let shard = (first + offset) % self.shards.len();
+            match self.shards[shard].try_lock() {
+                Ok(heap) => return (shard, heap),
+                Err(TryLockError::WouldBlock) => continue,
+                Err(TryLockError::Poisoned(_)) => panic!("a thread panicked while holding a shard"),
+            }
+        }
+        return (first, self.lock(first));
+    }
+
+    /// Tags a pointer from a shard with the shard it came from.
+    fn to_global(shard: usize, pointer: Pointer) -> Pointer {
+        pointer.offset(shard << SHARD_SHIFT)
+    }
+
+    /// Strips the shard from a pointer, giving the pointer within its shard.
+    fn to_local(shard: usize, pointer: Pointer) -> Pointer {
+        pointer.sub((shard as u64) << SHARD_SHIFT)
+    }
+
+    /// Allocates some slots in whichever shard is free, see [`Heap::alloc`].
+    ///
+    /// # Safety
+    /// The slots are not initialized, like [`Heap::alloc`].
+    pub unsafe fn alloc(&self, slots: usize) -> Pointer {
+        let (shard, mut heap) = self.lock_any();
+        return ConcurrentHeap::to_global(shard, heap.alloc(slots));
+    }
+
+    /// Allocates some zeroed slots in whichever shard is free, see [`Heap::calloc`].
+    pub fn calloc(&self, slots: usize) -> Pointer {
+        let (shard, mut heap) = self.lock_any();
+        return ConcurrentHeap::to_global(shard, heap.calloc(slots));
+    }
+
+    /// Frees an allocation in the shard it was allocated in, see [`Heap::free`].
+    pub fn free(&self, pointer: Pointer, slots: usize) {
+        if let Err(error) = self.try_free(pointer, slots) {
+            panic!("{}", error);
+        }
+    }
+
+    /// Frees an allocation in the shard it was allocated in, see [`Heap::try_free`].
+    pub fn try_free(&self, pointer: Pointer, slots: usize) -> Result<(), HeapError> {
+        let shard = self.shard_of(pointer);
+        return self.lock(shard).try_free(ConcurrentHeap::to_local(shard, pointer), slots);
+    }
+
+    /// Locks the shard a pointer was allocated in, and calls a function with it
+    /// and the pointer within that shard, to read or write the allocation.
+    /// Pointers within a shard do not carry the shard,
+    /// so they must not be used with this heap, or with another shard.
+    pub fn with_heap<R>(&self, pointer: Pointer, f: impl FnOnce(&mut Heap, Pointer) -> R) -> R {
+        let shard = self.shard_of(pointer);
+        return f(&mut self.lock(shard), ConcurrentHeap::to_local(shard, pointer));
+    }
+
+    /// Returns the size of the live allocation a pointer points to, see [`Heap::size_of`].
+    pub fn size_of(&self, pointer: Pointer) -> Option<usize> {
+        self.with_heap(pointer, |heap, pointer| heap.size_of(pointer))
+    }
+
+    /// Returns the number of slots in live allocations, summed over all shards.
+    pub fn used(&self) -> usize {
+        (0..self.shards.len()).map(|shard| self.lock(shard).used()).sum()
+    }
+
+    /// Checks the free list of every shard, one at a time, see [`RangeSet::audit`].
+    ///
+    /// [`RangeSet::audit`]: super::RangeSet::audit
+    pub fn audit(&self) -> Result<(), AuditError> {
+        for shard in 0..self.shards.len() {
+            self.lock(shard).free.audit()?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Slot;
+    use crate::heap::tests::random_alloc_size;
+
+    #[test]
+    fn pointers_know_their_shard() {
+        let heap = ConcurrentHeap::new(3);
+        let pointers: Vec<_> = (0..6).map(|_| heap.calloc(2)).collect();
+        let shards: Vec<_> = pointers.iter().map(|pointer| heap.shard_of(*pointer)).collect();
+        assert_eq!(shards, vec![0, 1, 2, 0, 1, 2]);
+        assert!(pointers.iter().all(|pointer| pointer.is_owned()));
+        assert_eq!(pointers[1].idx(), 1 << SHARD_SHIFT);
+        assert_eq!(pointers[4].idx(), (1 << SHARD_SHIFT) + 2);
+
+        heap.free(pointers[4], 2);
+        assert_eq!(heap.size_of(pointers[4]), None);
+        assert_eq!(heap.size_of(pointers[1]), Some(2));
+        assert_eq!(heap.used(), 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not belong")]
+    fn foreign_pointers_are_rejected() {
+        let heap = ConcurrentHeap::new(2);
+        heap.free(Pointer::tagged(5 << SHARD_SHIFT, true), 1);
+    }
+
+    #[test]
+    fn threads_hammer_shards() {
+        const THREADS: u64 = 8;
+        let heap = ConcurrentHeap::new(4);
+
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let heap = &heap;
+                scope.spawn(move || {
+                    let mut rng = attorand::Rng::new_with_seed(0x5eed + thread);
+                    let mut live = vec![];
+                    for i in 0..500 {
+                        let size = random_alloc_size(&mut rng);
+                        let pointer = heap.calloc(size);
+                        let tag = (thread << 32) | i;
+                        // SAFETY: only ever read back as a natural
+                        heap.with_heap(pointer, |heap, pointer| heap.write_slot(pointer, 0, unsafe { Slot::from_bits(tag) }));
+                        live.push((pointer, size, tag));
+
+                        if rng.next_bool() {
+                            let index = rng.next_u64_max((live.len() - 1) as u64) as usize;
+                            let (pointer, size, tag) = live.swap_remove(index);
+                            // SAFETY: written as a natural
+                            let found = heap.with_heap(pointer, |heap, pointer| unsafe { heap.read_slot(pointer, 0).to_u64() });
+                            assert_eq!(found, tag, "allocation was clobbered");
+                            heap.free(pointer, size);
+                        }
+                    }
+                    for (pointer, size, _tag) in live {
+                        heap.free(pointer, size);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(heap.audit(), Ok(()));
+        assert_eq!(heap.used(), 0);
+    }
+}
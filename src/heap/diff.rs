@@ -0,0 +1,107 @@
+//! Comparing the live allocations of two states of the same heap.
+
+use super::{Heap, Pointer, Backing};
+
+/// The allocations that changed between two states of a heap, see [`Heap::diff`].
+/// Each list is in address order.
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct HeapDiff {
+    /// Allocations that are only live in the later heap, with their sizes.
+    pub allocated: Vec<(Pointer, usize)>,
+    /// Allocations that are only live in the earlier heap, with their sizes.
+    pub freed: Vec<(Pointer, usize)>,
+    /// Allocations live in both, but with a different size: the old size, then the new.
+    pub resized: Vec<(Pointer, usize, usize)>,
+}
+
+impl HeapDiff {
+    /// Returns whether nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.allocated.is_empty() && self.freed.is_empty() && self.resized.is_empty()
+    }
+}
+
+impl std::fmt::Debug for HeapDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "HeapDiff (no changes)");
+        }
+
+        writeln!(f, "HeapDiff {{")?;
+        for (pointer, slots) in self.allocated.iter() {
+            writeln!(f, "  + {} slots at {}", slots, pointer.idx())?;
+        }
+        for (pointer, slots) in self.freed.iter() {
+            writeln!(f, "  - {} slots at {}", slots, pointer.idx())?;
+        }
+        for (pointer, old, new) in self.resized.iter() {
+            writeln!(f, "  ~ {} -> {} slots at {}", old, new, pointer.idx())?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl<B: Backing> Heap<B> {
+    /// Lists the allocations that changed from this heap to a later state of it.
+    /// An allocation counts as the same if it starts at the same slot,
+    /// so a block freed and reallocated in place with another size shows up as resized.
+    /// To keep an earlier state around, see [`Heap::snapshot`].
+    pub fn diff(&self, later: &Heap<B>) -> HeapDiff {
+        let mut diff = HeapDiff::default();
+        let (before, after) = (&self.free.live, &later.free.live);
+
+        for (start, slots) in before.iter() {
+            match after.get(start) {
+                None => diff.freed.push((Pointer::new(*start), *slots)),
+                Some(new) if new != slots => diff.resized.push((Pointer::new(*start), *slots, *new)),
+                Some(_) => (),
+            }
+        }
+        for (start, slots) in after.iter() {
+            if !before.contains_key(start) {
+                diff.allocated.push((Pointer::new(*start), *slots));
+            }
+        }
+
+        return diff;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_lists_changes() {
+        let mut heap = Heap::new();
+        let old = heap.calloc(3);
+        let _kept = heap.calloc(2);
+        let before = Heap::restore(&heap.snapshot()).unwrap();
+        assert!(before.diff(&heap).is_empty());
+
+        let a = heap.calloc(4);
+        let b = heap.calloc(1);
+        heap.free(old, 3);
+
+        let diff = before.diff(&heap);
+        assert_eq!(diff.allocated, vec![(a, 4), (b, 1)]);
+        assert_eq!(diff.freed, vec![(old, 3)]);
+        assert!(diff.resized.is_empty());
+        assert_eq!(
+            format!("{:?}", diff),
+            "HeapDiff {\n  + 4 slots at 5\n  + 1 slots at 9\n  - 3 slots at 0\n}",
+        );
+    }
+
+    #[test]
+    fn diff_lists_resizes() {
+        let mut heap = Heap::new();
+        let pointer = heap.calloc(2);
+        let before = Heap::restore(&heap.snapshot()).unwrap();
+
+        // SAFETY: data is never read
+        let grown = unsafe { heap.realloc(pointer, 2, 6) };
+        assert_eq!(grown, pointer);
+        assert_eq!(before.diff(&heap).resized, vec![(pointer, 2, 6)]);
+    }
+}
@@ -0,0 +1,365 @@
+//! Tracing garbage collection over the live allocations of a heap.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{Heap, Pointer, Backing};
+use super::pointer::PointerIdx;
+
+/// A reference to an allocation that does not keep it alive,
+/// see [`Heap::downgrade`] and [`Heap::upgrade`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WeakPointer(u64);
+
+/// The allocations that weak pointers refer to, by the pointer handed out for each.
+/// Every allocation with weak pointers has one token, shared by all of them,
+/// which is forgotten once the allocation is freed, so a later allocation
+/// in the same place is never mistaken for it.
+#[derive(Debug, Default, Clone)]
+pub(super) struct WeakTable {
+    tokens: BTreeMap<PointerIdx, u64>,
+    targets: BTreeMap<u64, PointerIdx>,
+    next: u64,
+}
+
+impl WeakTable {
+    /// Returns the token of an allocation, giving it a new one if it has none.
+    fn token(&mut self, target: PointerIdx) -> u64 {
+        if let Some(token) = self.tokens.get(&target) { return *token; }
+        let token = self.next;
+        self.next += 1;
+        self.tokens.insert(target, token);
+        self.targets.insert(token, target);
+        return token;
+    }
+
+    /// Forgets an allocation, so its weak pointers no longer upgrade.
+    pub(super) fn remove(&mut self, target: PointerIdx) {
+        if let Some(token) = self.tokens.remove(&target) {
+            self.targets.remove(&token);
+        }
+    }
+
+    /// Follows an allocation that moved.
+    pub(super) fn moved(&mut self, from: PointerIdx, to: PointerIdx) {
+        if let Some(token) = self.tokens.remove(&from) {
+            self.tokens.insert(to, token);
+            self.targets.insert(token, to);
+        }
+    }
+
+    /// Follows many allocations that moved at once, given where each one now is.
+    pub(super) fn remap(&mut self, to: impl Fn(PointerIdx) -> PointerIdx) {
+        self.tokens = self.tokens.iter().map(|(target, token)| (to(*target), *token)).collect();
+        self.targets = self.tokens.iter().map(|(target, token)| (*token, *target)).collect();
+    }
+}
+
+impl<B: Backing> Heap<B> {
+    /// Returns the start and size of the live allocation containing a pointer, if any.
+    fn block_containing(&self, pointer: Pointer) -> Option<(PointerIdx, usize)> {
+        let idx = pointer.to_idx();
+        let (start, slots) = self.free.live.range(..=idx).next_back()?;
+        if start.to_usize() + slots > idx.to_usize() {
+            Some((*start, *slots))
+        } else {
+            None
+        }
+    }
+
+    /// Returns each slot of an allocation named by `pointer_slots`, with the pointer it holds,
+    /// skipping slots past the end of the allocation, see [`Heap::gc`].
+    /// Slots are counted from the pointer handed out, so guards are skipped.
+    fn outgoing(&self, start: PointerIdx, slots: usize, pointer_slots: &impl Fn(Pointer) -> Vec<usize>) -> Vec<(usize, Pointer)> {
+        let offset = self.guard_offset(start);
+        let object = self.tag_generation(Pointer::new(start)).offset(offset);
+        pointer_slots(object).into_iter()
+            .filter(|slot| *slot < slots - 2 * offset)
+            // SAFETY: the caller promises these slots hold pointers
+            .map(|slot| (slot, unsafe { self.data[object.idx() as usize + slot].to_borrowed_pointer() }))
+            .collect()
+    }
+
+    /// Describes the allocations reachable from the roots as a tree, for debugging,
+    /// tracing pointers the same way as [`Heap::gc`] does.
+    ///
+    /// Each allocation is a line with its start, and its header as an object,
+    /// see [`Heap::object_type`] and [`Heap::object_len`], followed by the pointers it holds,
+    /// indented under it, each after the slot it is in.
+    /// An allocation is only described the first time it is reached; after that
+    /// it is marked `(back-edge)` if it is one of the allocations it is under, so part of a cycle,
+    /// or `(seen)` if not. Pointers that don't point into a live allocation are marked `(dangling)`.
+    pub fn dump_graph(&self, roots: &[Pointer], pointer_slots: impl Fn(Pointer) -> Vec<usize>) -> String {
+        enum Step { Enter(Option<usize>, Pointer, usize), Leave(PointerIdx) }
+        let mut dump = String::new();
+        let mut described = BTreeSet::new();
+        // the allocations above the one being described
+        let mut path = BTreeSet::new();
+        let mut pending: Vec<_> = roots.iter().rev().map(|root| Step::Enter(None, *root, 0)).collect();
+
+        while let Some(step) = pending.pop() {
+            let (slot, pointer, depth) = match step {
+                Step::Enter(slot, pointer, depth) => (slot, pointer, depth),
+                Step::Leave(start) => { path.remove(&start); continue; },
+            };
+            dump.push_str(&"  ".repeat(depth));
+            if let Some(slot) = slot {
+                dump.push_str(&format!("[{}] ", slot));
+            }
+            let (start, slots) = match self.block_containing(pointer) {
+                Some(block) => block,
+                None => { dump.push_str(&format!("@{} (dangling)\n", pointer.idx())); continue; },
+            };
+            dump.push_str(&format!("@{}", start.to_usize()));
+            if path.contains(&start) { dump.push_str(" (back-edge)\n"); continue; }
+            if !described.insert(start) { dump.push_str(" (seen)\n"); continue; }
+
+            let object = self.tag_generation(Pointer::new(start)).offset(self.guard_offset(start));
+            match (self.object_type(object), self.object_len(object)) {
+                (Some(type_id), Some(len)) => dump.push_str(&format!(" type {} len {}\n", type_id, len)),
+                _ => dump.push_str(&format!(" {} slots\n", slots)),
+            }
+            path.insert(start);
+            pending.push(Step::Leave(start));
+            for (slot, pointer) in self.outgoing(start, slots, &pointer_slots).into_iter().rev() {
+                pending.push(Step::Enter(Some(slot), pointer, depth + 1));
+            }
+        }
+        return dump;
+    }
+
+    /// Returns a weak pointer to an allocation, which upgrades back to a borrowed pointer
+    /// for as long as the allocation is live, see [`Heap::upgrade`].
+    /// Weak pointers follow the allocation if it moves, as by [`Heap::realloc`] or [`Heap::compact`].
+    ///
+    /// # Panics
+    /// If the pointer is not to the start of a live allocation, see [`Heap::verify_pointer`].
+    pub fn downgrade(&mut self, pointer: Pointer) -> WeakPointer {
+        assert!(self.verify_pointer(pointer), "can not downgrade a pointer that is not to a live allocation");
+        return WeakPointer(self.weak.token(pointer.to_idx()));
+    }
+
+    /// Returns a borrowed pointer to the allocation a weak pointer refers to,
+    /// or `None` if it has since been freed, whether by [`Heap::gc`] or otherwise.
+    pub fn upgrade(&self, weak: WeakPointer) -> Option<Pointer> {
+        let target = *self.weak.targets.get(&weak.0)?;
+        return Some(self.tag_generation(Pointer::new(target)).borrow());
+    }
+
+    /// Frees every live allocation that can not be reached from the roots.
+    ///
+    /// Starting from the allocations the roots point into,
+    /// the slots of each allocation named by `pointer_slots` are read as pointers
+    /// to more reachable allocations. `pointer_slots` is given the owned pointer handed out
+    /// for each allocation, and may name slots in any order.
+    /// Each allocation is visited once, so cycles are fine.
+    /// Pointers that don't point into a live allocation are ignored,
+    /// as are slots past the end of an allocation.
+    /// Weak pointers to the freed allocations no longer upgrade.
+    /// The regions of arenas are never freed, as only [`Heap::drop_arena`] may free them.
+    pub fn gc(&mut self, roots: &[Pointer], pointer_slots: impl Fn(Pointer) -> Vec<usize>) {
+        let mut reachable = BTreeSet::new();
+        let mut pending = roots.to_vec();
+
+        while let Some(pointer) = pending.pop() {
+            let (start, slots) = match self.block_containing(pointer) {
+                Some(block) => block,
+                None => continue,
+            };
+            if !reachable.insert(start) { continue; }
+            pending.extend(self.outgoing(start, slots, &pointer_slots).into_iter().map(|(_slot, pointer)| pointer));
+        }
+
+        let garbage: Vec<_> = self.free.live.iter()
+            .filter(|(start, _slots)| !reachable.contains(*start))
+            .filter(|(start, _slots)| !self.arenas.values().any(|arena| arena.owns(**start)))
+            .map(|(start, slots)| (self.tag_generation(Pointer::new(*start)), *slots))
+            .collect();
+        self.free_many(&garbage);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Slot;
+    use super::*;
+
+    /// Allocates a node of two slots: a pointer to the next node, and a payload.
+    fn node(heap: &mut Heap, payload: u64) -> Pointer {
+        let pointer = heap.calloc(2);
+        // SAFETY: only ever read back as a natural
+        heap.write_slot(pointer, 1, unsafe { Slot::from_bits(payload) });
+        pointer
+    }
+
+    fn link(heap: &mut Heap, from: Pointer, to: Pointer) {
+        // SAFETY: only ever read back as a pointer
+        heap.write_slot(from, 0, unsafe { Slot::from_bits(to.borrow().to_bits()) });
+    }
+
+    #[test]
+    fn gc_frees_unreachable_cycle() {
+        let mut heap = Heap::new().with_generation_checks(true);
+        // a -> b <-> c, and d <-> e on its own
+        let nodes: Vec<_> = (0..5).map(|payload| node(&mut heap, payload)).collect();
+        let (a, b, c, d, e) = (nodes[0], nodes[1], nodes[2], nodes[3], nodes[4]);
+        link(&mut heap, a, b);
+        link(&mut heap, b, c);
+        link(&mut heap, c, b);
+        link(&mut heap, d, e);
+        link(&mut heap, e, d);
+        let leaf = node(&mut heap, 5);
+
+        // everything is live while every cycle has a root
+        let slots = |pointer: Pointer| if pointer == leaf { vec![] } else { vec![0] };
+        heap.gc(&[a, d, leaf], slots);
+        assert!(nodes.iter().all(|node| heap.size_of(*node) == Some(2)));
+
+        // dropping the root of the second cycle frees all of it
+        heap.gc(&[a, leaf], slots);
+        for pointer in [a, b, c, leaf] {
+            assert_eq!(heap.size_of(pointer), Some(2));
+        }
+        assert_eq!(heap.size_of(d), None);
+        assert_eq!(heap.size_of(e), None);
+        assert!(heap.free_at(d, 4));
+
+        // the survivors were left alone
+        // SAFETY: written as a natural
+        assert_eq!(unsafe { heap.read_slot(c, 1).to_u64() }, 2);
+    }
+
+    #[test]
+    fn gc_ignores_dangling_pointers() {
+        let mut heap = Heap::new();
+        let a = node(&mut heap, 0);
+        let b = node(&mut heap, 1);
+        let _end = node(&mut heap, 2);
+        link(&mut heap, a, b);
+        heap.free(b, 2);
+
+        // a still points at b, which is now free
+        heap.gc(&[a], |_pointer| vec![0, 7]);
+        assert_eq!(heap.size_of(a), Some(2));
+        assert_eq!(heap.size_of(b), None);
+        assert_eq!(heap.stats().total_slots, 2);
+    }
+
+    #[test]
+    fn weak_pointers_do_not_survive_collection() {
+        let mut heap = Heap::new();
+        let root = node(&mut heap, 0);
+        let kept = node(&mut heap, 1);
+        let lost = node(&mut heap, 2);
+        link(&mut heap, root, kept);
+        let weak_kept = heap.downgrade(kept);
+        let weak_lost = heap.downgrade(lost);
+        assert_eq!(heap.downgrade(lost), weak_lost);
+        assert_eq!(heap.upgrade(weak_lost), Some(lost.borrow()));
+
+        let slots = |pointer: Pointer| if pointer == root { vec![0] } else { vec![] };
+        heap.gc(&[root], slots);
+        assert_eq!(heap.upgrade(weak_kept), Some(kept.borrow()));
+        assert_eq!(heap.upgrade(weak_lost), None);
+
+        // a new allocation in the same place is not the old one
+        let reused = node(&mut heap, 3);
+        assert_eq!(reused.idx(), lost.idx());
+        assert_eq!(heap.upgrade(weak_lost), None);
+        assert_ne!(heap.downgrade(reused), weak_lost);
+    }
+
+    #[test]
+    fn weak_pointers_follow_moves() {
+        let mut heap = Heap::new();
+        let gap = heap.calloc(3);
+        let a = heap.calloc(2);
+        let weak = heap.downgrade(a);
+        heap.free(gap, 3);
+
+        let moved = heap.compact();
+        let a = moved[&a];
+        assert_eq!(heap.upgrade(weak), Some(a.borrow()));
+
+        let _b = heap.calloc(1);
+        // SAFETY: the allocation is 2 slots and has no other owners
+        let a = unsafe { heap.realloc(a, 2, 4) };
+        assert_eq!(heap.upgrade(weak), Some(a.borrow()));
+        heap.free(a, 4);
+        assert_eq!(heap.upgrade(weak), None);
+    }
+
+    #[test]
+    fn dump_graph_marks_back_edges() {
+        let mut heap = Heap::new();
+        // a -> b -> c -> a, a -> c, and c -> d, which is freed
+        let a = heap.alloc_object(1, 2);
+        let b = heap.alloc_object(2, 2);
+        let c = heap.alloc_object(3, 2);
+        let d = heap.alloc_object(4, 0);
+        let point = |heap: &mut Heap, from: Pointer, slot: usize, to: Pointer| {
+            // SAFETY: only ever read back as a pointer
+            heap.write_slot(from, slot, unsafe { Slot::from_bits(to.borrow().to_bits()) });
+        };
+        point(&mut heap, a, 1, b);
+        point(&mut heap, b, 1, c);
+        point(&mut heap, c, 1, a);
+        point(&mut heap, a, 2, c);
+        point(&mut heap, c, 2, d);
+        heap.free(d, 1);
+        // slots are followed in the order they are named
+        let slots = |pointer: Pointer| match pointer.idx() {
+            0 => vec![1, 2],
+            3 => vec![1],
+            _ => vec![2, 1],
+        };
+
+        let dump = heap.dump_graph(&[a], slots);
+        assert_eq!(dump, concat!(
+            "@0 type 1 len 2\n",
+            "  [1] @3 type 2 len 2\n",
+            "    [1] @6 type 3 len 2\n",
+            "      [2] @9 (dangling)\n",
+            "      [1] @0 (back-edge)\n",
+            "  [2] @6 (seen)\n",
+        ));
+        for node in [a, b, c] {
+            let line = format!("@{} type", node.idx());
+            assert_eq!(dump.matches(&line).count(), 1);
+        }
+    }
+
+    #[test]
+    fn gc_leaves_arena_regions_alone() {
+        let mut heap = Heap::new();
+        let arena = heap.begin_arena();
+        // SAFETY: data is never read
+        let _bumped = unsafe { heap.alloc_in(arena, 3) };
+        let root = node(&mut heap, 0);
+
+        // the region is unreachable, but belongs to the arena
+        heap.gc(&[root], |_pointer| vec![]);
+        let other = heap.calloc(64);
+        heap.drop_arena(arena);
+        assert_eq!(heap.size_of(other), Some(64));
+        assert_eq!(heap.size_of(root), Some(2));
+    }
+
+    #[test]
+    fn gc_traces_guarded_allocations_from_their_pointers() {
+        let mut heap = Heap::new().with_guards(true);
+        let (a, b) = (node(&mut heap, 0), node(&mut heap, 1));
+        link(&mut heap, a, b);
+        let unreachable = node(&mut heap, 2);
+
+        // slots are counted from the pointers handed out, not from the guards
+        heap.gc(&[a], |pointer| {
+            assert!(pointer == a || pointer == b);
+            vec![0, 1, 2]
+        });
+        assert_eq!(heap.size_of(a), Some(2));
+        assert_eq!(heap.size_of(b), Some(2));
+        assert_eq!(heap.size_of(unreachable), None);
+        // SAFETY: written as a natural
+        assert_eq!(unsafe { heap.read_slot(b, 1).to_u64() }, 1);
+    }
+}
@@ -1,61 +1,886 @@
-use crate::Slot;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{Slot, FromSlots, ToSlots};
 
 pub mod pointer;
 pub mod range_set;
+pub mod snapshot;
+pub mod diff;
+pub mod gc;
+pub mod arena;
+pub mod concurrent;
+pub mod backing;
+#[cfg(feature = "serde")]
+mod serialize;
+
+pub use pointer::{Pointer, Index, MAX_GENERATION};
+pub use range_set::{RangeSet, FitPolicy, AuditError, ImportError, FreeResult, DefragPlan, AllocCounters};
+pub use snapshot::SnapshotError;
+pub use diff::HeapDiff;
+pub use arena::ArenaId;
+pub use gc::WeakPointer;
+pub use concurrent::ConcurrentHeap;
+pub use backing::Backing;
+#[cfg(feature = "fixed_backing")]
+pub use backing::FixedBacking;
+use pointer::PointerIdx;
+
+/// What the guard slots around each allocation hold, see [`Heap::with_guards`].
+pub const GUARD: u64 = 0xDEADBEEFDEADBEEF;
+
+/// The most slots any heap can hold, whatever its maximum capacity:
+/// every slot needs an index that fits in a pointer,
+/// and the slots can take up at most `isize::MAX` bytes.
+const MAX_SLOTS: usize = {
+    let bytes = (isize::MAX as usize / std::mem::size_of::<Slot>()) as u64;
+    (if pointer::INDEX_SPACE < bytes { pointer::INDEX_SPACE } else { bytes }) as usize
+};
+
+/// How much to grow the heap by when an allocation does not fit.
+/// Any slots grown past what the allocation needs are left free at the end of the heap,
+/// until they are used or released, see [`Heap::shrink_to_fit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GrowthPolicy {
+    /// Grow by exactly as much as the allocation needs.
+    #[default]
+    Exact,
+    /// Grow by at least the current capacity, doubling the heap.
+    Doubling,
+    /// Grow by a multiple of a number of slots.
+    Chunked(usize),
+}
+
+impl GrowthPolicy {
+    /// Returns how many slots to grow a heap of a given capacity by,
+    /// when it needs to grow by at least `needed` slots.
+    pub fn grow_by(self, capacity: usize, needed: usize) -> usize {
+        match self {
+            GrowthPolicy::Exact            => needed,
+            GrowthPolicy::Doubling         => needed.max(capacity),
+            GrowthPolicy::Chunked(chunk)   => needed.div_ceil(chunk.max(1)) * chunk.max(1),
+        }
+    }
+}
+
+/// How much to round allocations up by, see [`Heap::with_size_classes`].
+/// Rounding wastes the slots past what was asked for,
+/// but a freed allocation is far more likely to fit the next one of its class exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SizeClassPolicy {
+    /// Allocate exactly as many slots as asked for.
+    #[default]
+    Exact,
+    /// Round up to the next power of two.
+    PowerOfTwo,
+    /// Round up to a multiple of a number of slots.
+    Quantum(usize),
+}
+
+impl SizeClassPolicy {
+    /// Returns the size of the class an allocation of some number of slots is rounded up to.
+    /// Rounding a class again leaves it as it is.
+    pub fn round(self, slots: usize) -> usize {
+        match self {
+            SizeClassPolicy::Exact            => slots,
+            SizeClassPolicy::PowerOfTwo       => if slots == 0 { 0 } else { slots.next_power_of_two() },
+            SizeClassPolicy::Quantum(quantum) => slots.div_ceil(quantum.max(1)) * quantum.max(1),
+        }
+    }
+}
+
+/// An allocation made, freed, or moved by a [`Heap`], see [`Heap::set_observer`].
+/// Sizes are as asked for, before rounding to a size class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocEvent {
+    /// An allocation was made.
+    Alloc { pointer: Pointer, slots: usize },
+    /// An allocation was freed.
+    Free { pointer: Pointer, slots: usize },
+    /// An allocation was resized, and moved if the pointers differ.
+    Realloc { old: Pointer, new: Pointer, old_slots: usize, new_slots: usize },
+}
+
+/// Called with every [`AllocEvent`] of a heap.
+/// Must be `Send`, so heaps can still be sent between threads.
+struct Observer(Box<dyn FnMut(AllocEvent) + Send>);
+
+// SAFETY: the function is only ever called through `&mut`,
+// so a shared `&Observer` can not be used to call it from two threads at once
+unsafe impl Sync for Observer {}
+
+impl std::fmt::Debug for Observer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Observer")
+    }
+}
+
+/// What a heap should do once its pressure hook has run, see [`Heap::set_pressure_hook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressureResponse {
+    /// Grow the heap as it was going to.
+    Grow,
+    /// Look for a free range again first, because the hook freed some.
+    Retry,
+}
+
+/// A function run before a heap grows past some number of slots, see [`Heap::set_pressure_hook`].
+/// Must be `Send`, so heaps can still be sent between threads.
+pub type PressureFn<B = Vec<Slot>> = Box<dyn FnMut(&mut Heap<B>) -> PressureResponse + Send>;
+
+/// A pressure hook, and the number of slots it is run before growing past.
+struct PressureHook<B>(usize, PressureFn<B>);
+
+// SAFETY: the hook is only ever run through `&mut`, see `Observer`
+unsafe impl<B> Sync for PressureHook<B> {}
+
+impl<B> std::fmt::Debug for PressureHook<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PressureHook({})", self.0)
+    }
+}
+
+/// Size and fragmentation information about a [`Heap`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeapStats {
+    /// Size of the backing allocation, in bytes.
+    pub heap_bytes: usize,
+    /// Number of slots in the backing allocation.
+    pub total_slots: usize,
+    /// Number of free ranges that could not be merged.
+    pub disjoint_free_ranges: usize,
+    /// Number of slots that are not allocated.
+    pub free_slots: usize,
+    /// `free_slots / capacity`, or `0.0` for an empty heap.
+    pub fragmentation_ratio: f64,
+    /// Number of allocated slots past what was asked for, see [`Heap::with_size_classes`].
+    pub internal_slack: usize,
+}
+
+/// Returned when the heap can not satisfy an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// Growing the heap would exceed its maximum capacity.
+    /// `available` is the number of slots the heap can still grow by.
+    OutOfMemory { requested: usize, available: usize },
+}
+
+impl std::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AllocError::OutOfMemory { requested, available } => write!(
+                f, "out of memory: requested {} slots, heap can grow by {} slots",
+                requested, available,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+/// How far an incremental compaction has got, see [`Heap::compact_step`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactState {
+    /// A map from the old owned pointer of each allocation moved so far
+    /// to its new owned pointer, as returned by [`Heap::compact`].
+    pub relocations: BTreeMap<Pointer, Pointer>,
+    // allocations starting before this have already been compacted.
+    cursor: usize,
+    done: bool,
+}
+
+impl CompactState {
+    /// Starts a compaction from the start of the heap.
+    pub fn new() -> CompactState {
+        CompactState::default()
+    }
+
+    /// Returns whether the compaction has finished.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+/// Returned when an allocation can not be grown without moving it, see [`Heap::try_grow_in_place`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrowError {
+    /// The slots after the allocation are not free, so growing would move it.
+    WouldMove,
+    /// The slots after the allocation are free, but growing into them
+    /// would exceed the maximum capacity.
+    OutOfMemory(AllocError),
+}
+
+impl std::fmt::Display for GrowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GrowError::WouldMove          => write!(f, "allocation can not grow without moving"),
+            GrowError::OutOfMemory(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for GrowError {}
+
+/// Returned when a pointer is misused, see [`Heap::with_generation_checks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    /// The pointer was already freed, or was never allocated.
+    DoubleFree,
+    /// The pointer was read from after being freed.
+    UseAfterFree,
+    /// A guard slot next to the allocation was overwritten.
+    Overrun,
+    /// A guarded allocation was freed with a size other than the one it was allocated with.
+    SizeMismatch { freed: usize, allocated: usize },
+}
 
-pub use pointer::Pointer;
-use range_set::RangeSet;
+impl std::fmt::Display for HeapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeapError::DoubleFree   => write!(f, "double free of pointer"),
+            HeapError::UseAfterFree => write!(f, "use of pointer after free"),
+            HeapError::Overrun      => write!(f, "write past the end of an allocation"),
+            HeapError::SizeMismatch { freed, allocated } => write!(
+                f, "free of {} slots from an allocation of {} slots",
+                freed, allocated,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HeapError {}
 
+/// A heap of slots, kept in a [`Backing`] that is a `Vec` by default.
 #[derive(Debug)]
-pub struct Heap {
-    data: Vec<Slot>,
+pub struct Heap<B = Vec<Slot>> {
+    data: B,
     free: RangeSet,
+    max_capacity: Option<usize>,
+    // start of allocation -> number of owned pointers to it.
+    // only shared allocations are present; a missing entry means a count of 1.
+    refs: BTreeMap<PointerIdx, usize>,
+    zero_on_free: bool,
+    // start of allocation -> current generation of allocations starting there.
+    // only present if generations are being checked.
+    generations: Option<BTreeMap<PointerIdx, u16>>,
+    // arenas that have not been dropped yet, and the id to give the next one.
+    arenas: BTreeMap<ArenaId, arena::Arena>,
+    next_arena: usize,
+    // allocations of at least this many slots are aligned to the given alignment.
+    large_align: Option<(usize, usize)>,
+    // the pointers handed out for allocations flanked by guard slots.
+    // only present if guards are on.
+    guarded: Option<BTreeSet<PointerIdx>>,
+    growth: GrowthPolicy,
+    size_classes: SizeClassPolicy,
+    // start of allocation -> slots it was rounded up by, if any.
+    // only present if allocations are rounded to size classes.
+    slack: Option<BTreeMap<PointerIdx, usize>>,
+    // the allocations weak pointers refer to.
+    weak: gc::WeakTable,
+    // told about every allocation and free, if set.
+    observer: Option<Observer>,
+    // run before growing past its threshold, if set.
+    pressure: Option<PressureHook<B>>,
+    // start of allocation -> label it was allocated with.
+    #[cfg(feature = "debug_alloc")]
+    labels: BTreeMap<PointerIdx, &'static str>,
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
+    }
 }
 
 impl Heap {
     /// Constructs new empty heap.
     pub fn new() -> Heap {
+        Heap::with_backing(vec![])
+    }
+
+    /// Returns a copy of the heap, with every allocation, pointer, and setting as it is.
+    /// The observer and pressure hook are not copied, so the copy has neither.
+    pub fn fork(&self) -> Heap {
+        Heap {
+            // SAFETY: the slots of the copy belong to the copy, like those of a restored snapshot
+            data: self.data.iter().map(|slot| unsafe { Slot::from_bits(slot.to_u64()) }).collect(),
+            free: self.free.clone(),
+            max_capacity: self.max_capacity,
+            refs: self.refs.clone(),
+            zero_on_free: self.zero_on_free,
+            generations: self.generations.clone(),
+            arenas: self.arenas.clone(),
+            next_arena: self.next_arena,
+            large_align: self.large_align,
+            guarded: self.guarded.clone(),
+            growth: self.growth,
+            size_classes: self.size_classes,
+            slack: self.slack.clone(),
+            weak: self.weak.clone(),
+            observer: None,
+            pressure: None,
+            #[cfg(feature = "debug_alloc")]
+            labels: self.labels.clone(),
+        }
+    }
+}
+
+impl<B: Backing> Heap<B> {
+    /// Constructs a new empty heap that keeps its slots in a given backing,
+    /// and that never grows past what the backing can hold.
+    ///
+    /// # Panics
+    /// If the backing is not empty.
+    pub fn with_backing(data: B) -> Heap<B> {
+        assert!(data.is_empty(), "heap backing must start empty");
         Heap {
-            data: vec![],
+            max_capacity: data.max_len(),
+            data,
             free: RangeSet::new(),
+            refs: BTreeMap::new(),
+            zero_on_free: false,
+            generations: None,
+            arenas: BTreeMap::new(),
+            next_arena: 0,
+            large_align: None,
+            guarded: None,
+            growth: GrowthPolicy::default(),
+            size_classes: SizeClassPolicy::default(),
+            slack: None,
+            weak: gc::WeakTable::default(),
+            observer: None,
+            pressure: None,
+            #[cfg(feature = "debug_alloc")]
+            labels: BTreeMap::new(),
+        }
+    }
+
+    /// Caps the number of slots the heap may grow to.
+    /// Allocations past the cap fail, see [`Heap::try_alloc`].
+    pub fn with_max_capacity(mut self, max_slots: usize) -> Heap<B> {
+        self.max_capacity = Some(self.data.max_len().map_or(max_slots, |len| len.min(max_slots)));
+        self
+    }
+
+    /// Places new allocations according to a given policy.
+    /// Should be set before anything is allocated.
+    pub fn with_policy(mut self, policy: FitPolicy) -> Heap<B> {
+        self.free.policy = policy;
+        self
+    }
+
+    /// Grows the heap by more than an allocation needs, according to a policy,
+    /// so that a run of allocations grows the backing allocation fewer times.
+    /// Growth is still capped by any maximum capacity.
+    pub fn with_growth_policy(mut self, growth: GrowthPolicy) -> Heap<B> {
+        self.growth = growth;
+        self
+    }
+
+    /// Rounds every allocation up to the size of its class, according to a policy,
+    /// so that freed allocations are reused more often, at the cost of the slots rounded up by,
+    /// see [`HeapStats::internal_slack`]. Sizes are rounded as they are freed and reallocated too,
+    /// so the size an allocation was asked for can always be given back,
+    /// but [`Heap::size_of`] returns the rounded size.
+    /// Should be set before anything is allocated.
+    pub fn with_size_classes(mut self, size_classes: SizeClassPolicy) -> Heap<B> {
+        self.size_classes = size_classes;
+        self.slack = match size_classes {
+            SizeClassPolicy::Exact => None,
+            _ => Some(BTreeMap::new()),
+        };
+        self
+    }
+
+    /// Remembers how many slots past what was asked for an allocation has.
+    fn note_slack(&mut self, start: PointerIdx, slots: usize) {
+        if let Some(slack) = &mut self.slack {
+            if slots > 0 { slack.insert(start, slots); } else { slack.remove(&start); }
+        }
+    }
+
+    /// Calls a function after every allocation, free and reallocation,
+    /// with the pointers the operation ended up with, replacing any previous observer.
+    /// A reallocation is a single [`AllocEvent::Realloc`],
+    /// even if it allocates and frees to move the data.
+    pub fn set_observer(&mut self, observer: Box<dyn FnMut(AllocEvent) + Send>) {
+        self.observer = Some(Observer(observer));
+    }
+
+    /// Calls a function before an allocation grows the heap past `threshold_slots`,
+    /// replacing any previous hook, so a runtime can collect garbage first.
+    /// The hook is given the heap, and may free allocations in it;
+    /// if it returns [`PressureResponse::Retry`], the allocation looks for a free range
+    /// once more before growing, so a hook that frees anything should return that.
+    /// The hook is not called for allocations it makes itself, nor when a reallocation grows in place.
+    pub fn set_pressure_hook(&mut self, threshold_slots: usize, hook: PressureFn<B>) {
+        self.pressure = Some(PressureHook(threshold_slots, hook));
+    }
+
+    /// Runs the pressure hook if growing by some slots would cross its threshold,
+    /// returning whether to look for a free range again.
+    fn relieve_pressure(&mut self, needed: usize) -> bool {
+        match &self.pressure {
+            Some(PressureHook(threshold, _)) if needed > 0 && self.data.len() + needed > *threshold => (),
+            _ => return false,
+        }
+        let mut hook = self.pressure.take().unwrap();
+        let len = self.data.len();
+        let response = (hook.1)(self);
+        // the hook may have set a new one
+        self.pressure.get_or_insert(hook);
+        // freeing the tail shrinks the heap, which changes how far it has to grow
+        return response == PressureResponse::Retry || self.data.len() != len;
+    }
+
+    /// Tells the observer about an event, if there is one.
+    fn observe(&mut self, event: AllocEvent) {
+        if let Some(Observer(observer)) = &mut self.observer {
+            observer(event);
+        }
+    }
+
+    /// Zeroes slots as they are freed, so freed data can not be read back.
+    /// Data left behind when [`Heap::realloc`] moves an allocation is zeroed too.
+    pub fn with_zero_on_free(mut self, zero_on_free: bool) -> Heap<B> {
+        self.zero_on_free = zero_on_free;
+        self
+    }
+
+    /// Aligns every allocation of at least `threshold` slots to a multiple of `align`,
+    /// which must be a power of two, as if by [`Heap::alloc_aligned`].
+    /// Any padding before an aligned allocation stays free.
+    pub fn with_large_align(mut self, threshold: usize, align: usize) -> Heap<B> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two, got {}", align);
+        self.large_align = Some((threshold, align));
+        self
+    }
+
+    /// Flanks each allocation with a guard slot on either side holding [`GUARD`],
+    /// so that writes just past either end of an allocation are caught
+    /// when it is freed, see [`Heap::try_free`].
+    /// Aligned allocations and arenas are not guarded.
+    /// This costs two slots per allocation, so is meant for debugging.
+    pub fn with_guards(mut self, guards: bool) -> Heap<B> {
+        self.guarded = if guards { Some(BTreeSet::new()) } else { None };
+        self
+    }
+
+    /// Returns whether a pointer is to an allocation flanked by guard slots.
+    fn is_guarded(&self, pointer: Pointer) -> bool {
+        self.guarded.as_ref().is_some_and(|guarded| guarded.contains(&pointer.to_idx()))
+    }
+
+    /// Returns how many slots into the live allocation starting at `start`
+    /// the pointer handed out for it is, so past the guard if it is guarded.
+    fn guard_offset(&self, start: PointerIdx) -> usize {
+        if self.is_guarded(Pointer::new(start + 1)) { 1 } else { 0 }
+    }
+
+    /// Returns whether both guard slots around a guarded allocation are intact,
+    /// given the pointer to the guarded allocation, not the one handed out.
+    fn guards_intact(&self, outer: Pointer, slots: usize) -> bool {
+        let start = outer.to_idx().to_usize();
+        // SAFETY: guard slots only ever hold naturals
+        [start, start + slots + 1].iter().all(|slot| unsafe { self.data[*slot].to_u64() } == GUARD)
+    }
+
+    /// Tags pointers with the generation of the allocation they were made for,
+    /// so that stale pointers can be caught, see [`Heap::try_free`] and [`Heap::try_read`].
+    /// Each time an allocation starting at a given slot is freed or moved,
+    /// the generation for that slot is bumped, wrapping after [`MAX_GENERATION`].
+    /// This costs a map lookup per operation, so is meant for debugging.
+    pub fn with_generation_checks(mut self, check: bool) -> Heap<B> {
+        self.generations = if check { Some(BTreeMap::new()) } else { None };
+        self
+    }
+
+    /// Tags a pointer to the start of an allocation with its current generation.
+    fn tag_generation(&self, pointer: Pointer) -> Pointer {
+        match &self.generations {
+            Some(generations) => {
+                let generation = generations.get(&pointer.to_idx()).copied().unwrap_or(0);
+                pointer.with_generation(generation)
+            },
+            None => pointer,
+        }
+    }
+
+    /// Bumps the generation of allocations starting at a given slot,
+    /// invalidating any pointers to the old allocation.
+    fn retire_generation(&mut self, idx: PointerIdx) {
+        if let Some(generations) = &mut self.generations {
+            let generation = generations.entry(idx).or_insert(0);
+            *generation = if *generation == MAX_GENERATION { 0 } else { *generation + 1 };
+        }
+    }
+
+    /// Returns whether a pointer points into a live allocation of the same generation.
+    /// Always true if generations are not being checked.
+    fn check_generation(&self, pointer: Pointer) -> bool {
+        let generations = match &self.generations {
+            Some(generations) => generations,
+            None => return true,
+        };
+
+        let idx = pointer.to_idx();
+        match self.free.live.range(..=idx).next_back() {
+            Some((start, slots)) if start.to_usize() + slots > idx.to_usize() => {
+                let generation = generations.get(start).copied().unwrap_or(0);
+                generation == pointer.generation()
+            },
+            _ => false,
         }
     }
 
     /// Dumps a representation of the heap to stdout.
     /// Useful for general debugging.
     pub fn draw_free(&self) {
-        print!("|");
-        let mut old = 0;
-        let mut unused = 0;
-        for (key, value) in self.free.ranges.iter() {
-            print!("{}", "_".repeat(key.to_usize() - old));
-            print!("{}", "X".repeat(*value));
-            unused += value;
-            old = key.to_usize();
-        }
-        print!("{}", "_".repeat(self.free.capacity - old));
-        println!("|");
+        println!("|{}|", self.render_ascii(64));
 
+        let stats = self.stats();
+        let unused = stats.free_slots;
         println!("==== INFO ====");
-        println!("heap size:       {} bytes", self.data.len() * 8);
-        println!("total slots:     {} slots", self.data.len());
-        println!("disjoint ranges: {} slots", self.free.ranges.len());
-        let pct = (unused as f64 / self.free.capacity as f64) * 100.0;
+        println!("heap size:       {} bytes", stats.heap_bytes);
+        println!("total slots:     {} slots", stats.total_slots);
+        println!("disjoint ranges: {} slots", stats.disjoint_free_ranges);
+        let pct = stats.fragmentation_ratio * 100.0;
         println!("fragmentation:   {} / {} = {:.2}%", unused, self.free.capacity, pct);
     }
 
+    /// Returns the number of free ranges of each size,
+    /// see [`RangeSet::size_histogram`].
+    pub fn free_histogram(&self) -> BTreeMap<usize, usize> {
+        self.free.size_histogram()
+    }
+
+    /// Draws the heap scaled to `width` columns, for eyeballing fragmentation.
+    /// Each column is drawn by how much of the slots it covers are free,
+    /// from `#` for all allocated, through `=`, `+`, and `:`, to `-` for all free.
+    /// An empty heap is drawn as all free.
+    pub fn render_ascii(&self, width: usize) -> String {
+        const GRADIENT: [char; 5] = ['#', '=', '+', ':', '-'];
+        let capacity = self.free.capacity;
+        if capacity == 0 { return "-".repeat(width); }
+
+        // column `c` covers the slots from `bound(c)` up to `bound(c + 1)`,
+        // a column narrower than a slot covers the slot it lies in.
+        let bound = |column: usize| column * capacity / width;
+        let span = |column: usize| (bound(column), bound(column + 1).max(bound(column) + 1));
+
+        let mut free = vec![0; width];
+        for (start, size) in self.free.ranges.iter() {
+            let (start, end) = (start.to_usize(), start.to_usize() + size);
+            // first column that overlaps the range
+            let mut column = (start * width / capacity).saturating_sub(1);
+            while column < width && span(column).0 < end {
+                let (from, to) = span(column);
+                free[column] += to.min(end).saturating_sub(from.max(start));
+                column += 1;
+            }
+        }
+
+        free.iter().enumerate().map(|(column, free)| {
+            let (from, to) = span(column);
+            let fraction = *free as f64 / (to - from) as f64;
+            GRADIENT[(fraction * (GRADIENT.len() - 1) as f64).round() as usize]
+        }).collect()
+    }
+
+    /// Returns the total number of slots in the heap, allocated or free.
+    pub fn capacity(&self) -> usize {
+        self.free.capacity
+    }
+
+    /// Returns the number of slots that are allocated.
+    pub fn used(&self) -> usize {
+        self.free.capacity - self.free.total_free()
+    }
+
+    /// Returns whether no slots are allocated.
+    pub fn is_empty(&self) -> bool {
+        self.used() == 0
+    }
+
+    /// Returns a snapshot of the heap's size and fragmentation.
+    /// Cheap enough to sample periodically.
+    pub fn stats(&self) -> HeapStats {
+        let free_slots = self.free.total_free();
+        let fragmentation_ratio = if self.free.capacity == 0 {
+            0.0
+        } else {
+            free_slots as f64 / self.free.capacity as f64
+        };
+
+        HeapStats {
+            heap_bytes:           self.data.len() * 8,
+            total_slots:          self.data.len(),
+            disjoint_free_ranges: self.free.ranges.len(),
+            free_slots,
+            fragmentation_ratio,
+            internal_slack:       self.slack.as_ref().map_or(0, |slack| slack.values().sum()),
+        }
+    }
+
+    /// Returns how allocations were placed since the heap was made,
+    /// or since [`Heap::reset_counters`].
+    /// Slots grown ahead of time by a [`GrowthPolicy`] are free when the allocation is placed,
+    /// so the allocation they were grown for counts as reused.
+    pub fn counters(&self) -> AllocCounters {
+        self.free.counters
+    }
+
+    /// Sets every counter back to zero, see [`Heap::counters`].
+    pub fn reset_counters(&mut self) {
+        self.free.counters = AllocCounters::default();
+    }
+
     /// Allocate a pointer of a given size.
     /// Returns the smallest first allocation that will fit the pointer.
     ///
     /// # Safety
     /// The allocated pointer will point to garbage data.
     /// This call must immediately be followed by a call to [`Heap::write`].
+    ///
+    /// # Panics
+    /// If the heap has a maximum capacity that this allocation would exceed.
     pub unsafe fn alloc(&mut self, slots: usize) -> Pointer {
-        let (pointer, extra_capacity) = self.free.mark_first(slots);
+        match self.try_alloc(slots) {
+            Ok(pointer) => pointer,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    /// Like [`Heap::alloc`], but returns an error instead of
+    /// growing the heap past its maximum capacity.
+    ///
+    /// # Safety
+    /// See [`Heap::alloc`].
+    pub unsafe fn try_alloc(&mut self, slots: usize) -> Result<Pointer, AllocError> {
+        self.try_alloc_grown(slots).map(|(pointer, _grown)| pointer)
+    }
+
+    /// Allocate a pointer of a given size, whose slots all read as zero.
+    ///
+    /// # Panics
+    /// If the heap has a maximum capacity that this allocation would exceed.
+    pub fn calloc(&mut self, slots: usize) -> Pointer {
+        match self.try_calloc(slots) {
+            Ok(pointer) => pointer,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    /// Allocates `count` allocations of `slots_each` slots, whose slots all read as zero.
+    /// The allocations are carved out of one contiguous run, in address order,
+    /// if the heap can fit one; otherwise as many as fit in the largest free range are,
+    /// and the rest are allocated one at a time.
+    /// Heaps with guards or size classes always allocate one at a time.
+    ///
+    /// # Panics
+    /// If the heap has a maximum capacity that the allocations would exceed.
+    pub fn alloc_n(&mut self, count: usize, slots_each: usize) -> Vec<Pointer> {
+        let mut pointers = Vec::with_capacity(count);
+        if count > 0 && slots_each > 0 && self.guarded.is_none() && self.slack.is_none() {
+            pointers = self.carve_run(count, slots_each);
+        }
+        while pointers.len() < count {
+            pointers.push(self.calloc(slots_each));
+        }
+        return pointers;
+    }
+
+    /// Allocates one zeroed run of up to `count` allocations of `slots_each` slots,
+    /// and splits it into them, for [`Heap::alloc_n`].
+    fn carve_run(&mut self, count: usize, slots_each: usize) -> Vec<Pointer> {
+        // the run is handed out as separate allocations, so the observer hears of each
+        let observer = self.observer.take();
+        let fits = self.free.largest_free().map_or(0, |(_start, size)| size / slots_each).min(count);
+        let run = count.checked_mul(slots_each)
+            .and_then(|slots| self.try_calloc(slots).ok())
+            .map(|run| (run, count))
+            .or_else(|| (fits > 0).then(|| (self.calloc(fits * slots_each), fits)));
+
+        let mut pointers = Vec::new();
+        if let Some((mut rest, carved)) = run {
+            for _ in 1..carved {
+                let (first, second) = self.split(rest, slots_each);
+                pointers.push(first);
+                rest = second;
+            }
+            pointers.push(rest);
+        }
+
+        self.observer = observer;
+        for pointer in pointers.iter() {
+            self.observe(AllocEvent::Alloc { pointer: *pointer, slots: slots_each });
+        }
+        return pointers;
+    }
+
+    /// Like [`Heap::calloc`], but returns an error instead of
+    /// growing the heap past its maximum capacity.
+    pub fn try_calloc(&mut self, slots: usize) -> Result<Pointer, AllocError> {
+        // SAFETY: the allocation is zeroed before it is returned
+        let (pointer, grown) = unsafe { self.try_alloc_grown(slots)? };
+
+        // freshly grown slots at the end are already zero,
+        // only the slots reused from a free range need zeroing.
+        self.zero(pointer.to_idx().to_usize(), self.size_classes.round(slots) - grown);
+        return Ok(pointer);
+    }
+
+    /// Like [`Heap::try_alloc`], but also returns how many slots
+    /// at the end of the allocation were freshly grown, and thus zeroed.
+    /// The allocation is rounded up to its size class.
+    unsafe fn try_alloc_grown(&mut self, requested: usize) -> Result<(Pointer, usize), AllocError> {
+        self.try_alloc_placed(requested, None)
+    }
+
+    /// Like [`Heap::try_alloc_grown`], but aligned to `align` if given,
+    /// or as set by [`Heap::with_large_align`] if not.
+    unsafe fn try_alloc_placed(&mut self, requested: usize, align: Option<usize>) -> Result<(Pointer, usize), AllocError> {
+        // so rounding and guards can not overflow
+        if requested > MAX_SLOTS { return Err(self.out_of_memory(requested)); }
+        let slots = self.size_classes.round(requested);
+        let align = align.unwrap_or(match self.large_align {
+            Some((threshold, align)) if slots >= threshold => align,
+            _ => 1,
+        });
+        if self.guarded.is_none() {
+            let (pointer, grown) = self.try_alloc_unguarded(slots, align, 0)?;
+            self.note_slack(pointer.to_idx(), slots - requested);
+            self.observe(AllocEvent::Alloc { pointer, slots: requested });
+            return Ok((pointer, grown));
+        }
+
+        // hand out the slots between the guards, which are what is aligned
+        let (outer, grown) = self.try_alloc_unguarded(slots + 2, align, 1)?;
+        let start = outer.to_idx().to_usize();
+        self.data[start] = Slot::from_bits(GUARD);
+        self.data[start + slots + 1] = Slot::from_bits(GUARD);
+        let pointer = outer.offset(1);
+        self.guarded.as_mut().unwrap().insert(pointer.to_idx());
+        self.note_slack(outer.to_idx(), slots - requested);
+        self.observe(AllocEvent::Alloc { pointer, slots: requested });
+        return Ok((pointer, grown.saturating_sub(1).min(slots)));
+    }
+
+    /// Like [`Heap::try_alloc_grown`], ignoring guards,
+    /// with the slot `offset` slots into the allocation at a multiple of `align`.
+    unsafe fn try_alloc_unguarded(&mut self, slots: usize, align: usize, offset: usize) -> Result<(Pointer, usize), AllocError> {
+        // check before marking, extending a tail range may grow the heap too.
+        // padding for alignment may need up to `align - 1` more slots.
+        let padded = match slots.checked_add(align - 1) {
+            Some(padded) if padded <= MAX_SLOTS => padded,
+            _ => return Err(self.out_of_memory(slots)),
+        };
+        let mut needed = self.free.extra_capacity_for(padded);
+        if self.relieve_pressure(needed) {
+            needed = self.free.extra_capacity_for(padded);
+        }
+        if needed > self.available() {
+            return Err(self.out_of_memory(slots));
+        }
+
+        // grow ahead of time, so the allocation fits in the free tail
+        if needed > 0 && self.growth != GrowthPolicy::Exact {
+            self.reserve(self.growth.grow_by(self.data.len(), needed).min(self.available()));
+        }
+
+        let (pointer, extra_capacity) = if align == 1 {
+            self.free.mark_first(slots)
+        } else {
+            // only the part of the allocation past the old end is freshly grown
+            let old_capacity = self.free.capacity;
+            let pointer = self.free.mark_first_aligned_at(slots, align, offset);
+            let start = pointer.to_idx().to_usize();
+            let grown = (start + slots).saturating_sub(old_capacity.max(start));
+            self.resize_data(self.free.capacity - grown);
+            (pointer, grown)
+        };
 
         // increase the size of the allocation if needed.
-        self.data.extend((0..extra_capacity).map(|_| unsafe { Slot::zero() }));
-        return pointer;
+        self.resize_data(self.data.len() + extra_capacity);
+        return Ok((self.tag_generation(pointer), extra_capacity));
+    }
+
+    /// Returns how many slots the heap can still grow by,
+    /// up to its maximum capacity, if it has one, and never past what any heap can hold.
+    fn available(&self) -> usize {
+        let max = self.max_capacity.map_or(MAX_SLOTS, |max| max.min(MAX_SLOTS));
+        max.saturating_sub(self.data.len())
+    }
+
+    /// Returns the error for an allocation of some slots that does not fit.
+    fn out_of_memory(&self, requested: usize) -> AllocError {
+        AllocError::OutOfMemory { requested, available: self.available() }
+    }
+
+    /// Grows the heap by some free slots up front,
+    /// so that later allocations can be placed without growing the heap.
+    /// Freeing an allocation right before the reserved slots releases them again.
+    ///
+    /// # Panics
+    /// If the heap has a maximum capacity that this would exceed.
+    pub fn reserve(&mut self, additional: usize) {
+        if let Some(max) = self.max_capacity {
+            assert!(
+                self.data.len() + additional <= max,
+                "can not reserve {} slots past the maximum capacity of {}", additional, max,
+            );
+        }
+        self.free.reserve(additional);
+        self.resize_data(self.data.len() + additional);
+    }
+
+    /// Grows or shrinks the data to a number of slots, zeroing any new slots.
+    /// Growing past what the backing can hold is caught by the capacity checks
+    /// before anything is marked, so the backing running out here is a bug.
+    fn resize_data(&mut self, len: usize) {
+        if len <= self.data.len() {
+            self.data.truncate(len);
+        } else if let Err(error) = self.data.grow(len - self.data.len()) {
+            panic!("heap grew past its backing: {}", error);
+        }
+    }
+
+    /// Releases any free slots at the end of the heap,
+    /// so the heap ends with the last live allocation.
+    /// Does nothing if the heap does not end in a free range.
+    pub fn shrink_to_fit(&mut self) {
+        let unneeded_capacity = self.free.trim_tail();
+        self.data.truncate(self.data.len() - unneeded_capacity);
+    }
+
+    /// Allocate a pointer of a given size, whose index is a multiple of `align`.
+    /// `align` must be a power of two; an alignment of `1` is the same as [`Heap::alloc`].
+    ///
+    /// # Safety
+    /// See [`Heap::alloc`].
+    ///
+    /// # Panics
+    /// If `align` is not a power of two,
+    /// or the heap has a maximum capacity that this allocation would exceed.
+    pub unsafe fn alloc_aligned(&mut self, slots: usize, align: usize) -> Pointer {
+        match self.try_alloc_aligned(slots, align) {
+            Ok(pointer) => pointer,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    /// Like [`Heap::alloc_aligned`], but returns an error instead of
+    /// growing the heap past its maximum capacity.
+    ///
+    /// # Safety
+    /// See [`Heap::alloc`].
+    ///
+    /// # Panics
+    /// If `align` is not a power of two.
+    pub unsafe fn try_alloc_aligned(&mut self, slots: usize, align: usize) -> Result<Pointer, AllocError> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two, got {}", align);
+        self.try_alloc_placed(slots, Some(align)).map(|(pointer, _grown)| pointer)
     }
 
     /// Reallocates an allocation to a larger size
@@ -71,95 +896,970 @@ impl Heap {
     ///
     /// Like when using `Heap::alloc`, this call must be immediately be followed by
     /// a call to [`Heap::write`] to fill the uninitialized portion of the new array.
+    ///
+    /// # Panics
+    /// If the heap has a maximum capacity that growing the allocation would exceed.
     pub unsafe fn realloc(&mut self, pointer: Pointer, old: usize, new: usize) -> Pointer {
+        match self.try_realloc(pointer, old, new) {
+            Ok(pointer) => pointer,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    /// Like [`Heap::realloc`], but returns an error instead of
+    /// growing the heap past its maximum capacity, leaving the allocation as it was.
+    ///
+    /// # Safety
+    /// See [`Heap::realloc`].
+    pub unsafe fn try_realloc(&mut self, pointer: Pointer, old: usize, new: usize) -> Result<Pointer, AllocError> {
+        assert!(pointer.is_owned());
+        if new > MAX_SLOTS { return Err(self.out_of_memory(new)); }
+        // moving may allocate and free, which the observer only hears of as this
+        let observer = self.observer.take();
+        let new_pointer = if self.is_guarded(pointer) {
+            self.realloc_guarded(pointer, old, new)
+        } else {
+            // sizes in the same class need nothing done
+            let class = self.size_classes.round(new);
+            self.note_slack(pointer.to_idx(), 0);
+            let new_pointer = self.realloc_unguarded(pointer, self.size_classes.round(old), class);
+            match new_pointer {
+                Ok(new_pointer) => self.note_slack(new_pointer.to_idx(), class - new),
+                Err(_) => self.note_slack(pointer.to_idx(), self.size_classes.round(old) - old),
+            }
+            new_pointer
+        };
+        self.observer = observer;
+        let new_pointer = new_pointer?;
+
+        if new_pointer.idx() != pointer.idx() {
+            self.free.counters.moves_on_realloc += 1;
+        }
+        self.observe(AllocEvent::Realloc { old: pointer, new: new_pointer, old_slots: old, new_slots: new });
+        return Ok(new_pointer);
+    }
+
+    /// Resizes an allocation like [`Heap::realloc`], but only if it can keep its start,
+    /// so any other pointers to it stay valid. The slots past the old size are garbage.
+    /// Shrinking never moves, and growing only succeeds into the free slots directly after.
+    /// Sliding back into free slots before the allocation would change its start,
+    /// so that returns [`GrowError::WouldMove`] too.
+    /// Allocations flanked by guards always move, see [`Heap::with_guards`].
+    /// On error, the allocation is left as it was.
+    ///
+    /// # Safety
+    /// See [`Heap::realloc`].
+    pub unsafe fn try_grow_in_place(&mut self, pointer: Pointer, old: usize, new: usize) -> Result<(), GrowError> {
         assert!(pointer.is_owned());
+        if new > MAX_SLOTS { return Err(GrowError::OutOfMemory(self.out_of_memory(new))); }
+        let (class_old, class_new) = (self.size_classes.round(old), self.size_classes.round(new));
+        if self.is_guarded(pointer) && old != new { return Err(GrowError::WouldMove); }
+
+        if class_new > class_old {
+            if !self.free.is_free(pointer.offset(class_old), class_new - class_old) {
+                return Err(GrowError::WouldMove);
+            }
+            let available = self.available();
+            let past_the_end = (pointer.to_idx().to_usize() + class_new).saturating_sub(self.data.len());
+            if past_the_end > available {
+                return Err(GrowError::OutOfMemory(AllocError::OutOfMemory { requested: new, available }));
+            }
+        }
+
+        let grown = self.try_realloc(pointer, old, new).map_err(GrowError::OutOfMemory)?;
+        debug_assert_eq!(grown, pointer);
+        return Ok(());
+    }
 
+    /// Like [`Heap::try_realloc`], ignoring guards and size classes.
+    unsafe fn realloc_unguarded(&mut self, pointer: Pointer, old: usize, new: usize) -> Result<Pointer, AllocError> {
         if new > old {
-            // try allocation continiously
-            let tail = pointer.add(old as u64);
-            if self.free.is_free(tail, new - old) {
-                // increase the size of the current allocation
-                self.free.mark_smaller(tail.to_idx(), new - old);
-                return pointer;
+            // try allocation continiously, unless that grows the heap too far
+            let tail = pointer.offset(old);
+            let available = self.available();
+            let past_the_end = (pointer.to_idx().to_usize() + new).saturating_sub(self.data.len());
+            if self.free.is_free(tail, new - old) && past_the_end <= available {
+                // increase the size of the current allocation,
+                // growing the heap if it runs off the end
+                let extra_capacity = self.free.grow(pointer, old, new);
+                self.resize_data(self.data.len() + extra_capacity);
+                return Ok(pointer);
+            }
+
+            // try sliding back into the free space before the allocation
+            if let Some(new_pointer) = self.free.slide_back(pointer, old, new) {
+                // the allocation only moves down, so copy low-to-high
+                let (from, to) = (pointer.to_idx().to_usize(), new_pointer.to_idx().to_usize());
+                for slot in 0..old {
+                    self.data.swap(to + slot, from + slot);
+                }
+                if self.zero_on_free && to + new < from + old {
+                    self.zero(to + new, from + old - (to + new));
+                }
+                self.data.truncate(self.free.capacity);
+                if let Some(refs) = self.refs.remove(&pointer.to_idx()) {
+                    self.refs.insert(new_pointer.to_idx(), refs);
+                }
+                self.move_label(pointer.to_idx(), new_pointer.to_idx());
+                self.weak.moved(pointer.to_idx(), new_pointer.to_idx());
+                self.retire_generation(pointer.to_idx());
+                return Ok(self.tag_generation(new_pointer));
             }
 
-            // TODO: free before reallocation might open up space before?
             // reallocate new larger allocation, copy over data.
-            let new_pointer = self.alloc(new);
+            let new_pointer = self.try_alloc(new)?;
             for slot in 0..old {
                 self.data.swap(
                     new_pointer.to_idx().to_usize() + slot,
                     pointer.to_idx().to_usize() + slot,
                 );
             }
-            // and free old small allocation
+            // and free old small allocation, keeping any other owners
+            let refs = self.refs.remove(&pointer.to_idx());
+            self.move_label(pointer.to_idx(), new_pointer.to_idx());
+            self.weak.moved(pointer.to_idx(), new_pointer.to_idx());
             self.free(pointer, old);
-            return new_pointer;
+            if let Some(refs) = refs {
+                self.refs.insert(new_pointer.to_idx(), refs);
+            }
+            return Ok(new_pointer);
         } else if old > new {
             // free back half of allocation
-            self.free(pointer.add(new as u64), old - new);
+            self.release_unchecked(pointer.offset(new), old - new);
         }
 
         // they're equal, so do nothing
-        return pointer;
+        return Ok(pointer);
     }
 
-    // Reads a single slot relative to a pointer.
-    pub fn read_slot(&self, pointer: Pointer, slot: usize) -> &Slot {
-        &self.data[pointer.to_idx().to_usize() + slot]
+    /// Like [`Heap::try_realloc`], for an allocation flanked by guard slots.
+    /// Always moves the allocation, so the guards are checked when freeing the old one.
+    unsafe fn realloc_guarded(&mut self, pointer: Pointer, old: usize, new: usize) -> Result<Pointer, AllocError> {
+        if old == new { return Ok(pointer); }
+
+        let new_pointer = self.try_alloc(new)?;
+        let (from, to) = (pointer.to_idx().to_usize(), new_pointer.to_idx().to_usize());
+        for slot in 0..old.min(new) {
+            self.data.swap(to + slot, from + slot);
+        }
+        let refs = self.refs.remove(&pointer.to_idx());
+        self.move_label(pointer.sub(1).to_idx(), new_pointer.sub(1).to_idx());
+        self.weak.moved(pointer.to_idx(), new_pointer.to_idx());
+        self.free(pointer, old);
+        if let Some(refs) = refs {
+            self.refs.insert(new_pointer.to_idx(), refs);
+        }
+        return Ok(new_pointer);
     }
 
-    // Reads a range of data.
-    pub fn read(&self, pointer: Pointer, slots: usize) -> &[Slot] {
-        let start = pointer.to_idx().to_usize() as usize;
-        &self.data[start..(start + slots)]
+    /// Allocates like [`Heap::calloc`], remembering a label for the allocation,
+    /// such as where it was made, see [`Heap::live_allocations`].
+    /// Labels are only kept if the `debug_alloc` feature is on.
+    pub fn alloc_labeled(&mut self, slots: usize, label: &'static str) -> Pointer {
+        let pointer = self.calloc(slots);
+        #[cfg(feature = "debug_alloc")]
+        {
+            let start = if self.is_guarded(pointer) { pointer.sub(1) } else { pointer };
+            self.labels.insert(start.to_idx(), label);
+        }
+        #[cfg(not(feature = "debug_alloc"))]
+        let _ = label;
+        return pointer;
     }
 
-    // TODO: figure out apis for reading and writing
+    /// Allocates an object of `payload_slots` zeroed slots, after a one slot header
+    /// holding the type id in its high 32 bits and the number of payload slots in its low 32.
+    /// Returns the pointer to the header, see [`Heap::object_type`] and [`Heap::object_len`].
+    ///
+    /// # Panics
+    /// If the payload is too long for the header, or the heap can not grow to fit the object.
+    pub fn alloc_object(&mut self, type_id: u32, payload_slots: usize) -> Pointer {
+        let len = u32::try_from(payload_slots).expect("object payload is too long for its header");
+        let pointer = self.calloc(payload_slots + 1);
+        // SAFETY: the header is a natural
+        self.data[pointer.to_idx().to_usize()] = unsafe { Slot::from_bits(((type_id as u64) << 32) | len as u64) };
+        return pointer;
+    }
 
-    pub fn write_slot(&self, pointer: Pointer, slots: usize)
+    /// Reads the header of an object made by [`Heap::alloc_object`],
+    /// or returns `None` if the pointer is not the start of a live allocation.
+    /// The header of an allocation made any other way is whatever is in its first slot.
+    fn object_header(&self, pointer: Pointer) -> Option<u64> {
+        if !self.verify_pointer(pointer) || self.size_of(pointer) == Some(0) { return None; }
+        // SAFETY: the header is a natural
+        return Some(unsafe { self.read_slot(pointer, 0).to_u64() });
+    }
 
-    pub fn write(
-        &mut self,
-        pointer: Pointer,
-        item: &mut [Slot],
-    ) -> Option<???> {
-        // can't write to a pointer we don't own! make a copy first.
-        if !pointer.is_owned() { return None; }
+    /// Returns the type id of an object, see [`Heap::alloc_object`],
+    /// or `None` if the pointer is not the start of a live allocation.
+    pub fn object_type(&self, pointer: Pointer) -> Option<u32> {
+        self.object_header(pointer).map(|header| (header >> 32) as u32)
+    }
 
+    /// Returns the number of payload slots of an object, after its header, see [`Heap::alloc_object`],
+    /// or `None` if the pointer is not the start of a live allocation.
+    pub fn object_len(&self, pointer: Pointer) -> Option<usize> {
+        self.object_header(pointer).map(|header| header as u32 as usize)
     }
 
-    pub fn free(&mut self, pointer: Pointer, slots: usize) {
-        assert!(pointer.is_owned());
-        let unneeded_capacity = self.free.free(pointer, slots);
-        self.data.truncate(self.data.len() - unneeded_capacity);
+    /// Iterates over the live allocations in ascending address order,
+    /// yielding an owned pointer to each allocation and its size,
+    /// as it was allocated, see [`Heap::size_of`].
+    /// The inverse of [`RangeSet::iter_free`].
+    pub fn iter_live(&self) -> impl Iterator<Item = (Pointer, usize)> + '_ {
+        self.free.live.iter().map(|(start, slots)| {
+            let pointer = self.tag_generation(Pointer::new(*start));
+            if self.is_guarded(pointer.offset(1)) {
+                (pointer.offset(1), slots - 2)
+            } else {
+                (pointer, *slots)
+            }
+        })
     }
-}
 
+    /// Returns the live allocation containing a slot, as a borrowed pointer to its start,
+    /// and the offset of the slot within it, or `None` if the slot is free or past the heap.
+    /// Any word that resolves can be taken as keeping the allocation alive,
+    /// which is what a conservative scan needs. Guard slots belong to no allocation.
+    pub fn resolve_interior(&self, raw_idx: u64) -> Option<(Pointer, usize)> {
+        let idx = PointerIdx::new(usize::try_from(raw_idx).ok()?);
+        let (start, slots) = self.free.live.range(..=idx).next_back()?;
+        let offset = idx.to_usize() - start.to_usize();
+        if offset >= *slots { return None; }
 
-#[cfg(test)]
-pub mod tests {
-    use std::collections::BTreeMap;
-    use super::*;
+        let pointer = self.tag_generation(Pointer::new(*start)).borrow();
+        if self.is_guarded(pointer.offset(1)) {
+            if offset == 0 || offset == slots - 1 { return None; }
+            return Some((pointer.offset(1), offset - 1));
+        }
+        return Some((pointer, offset));
+    }
+
+    /// Lists every live allocation in address order, with its size and label,
+    /// see [`Heap::alloc_labeled`]. Allocations without a label,
+    /// or all of them if the `debug_alloc` feature is off, are labelled `"unlabeled"`.
+    pub fn live_allocations(&self) -> Vec<(Pointer, usize, &'static str)> {
+        self.iter_live().map(|(pointer, slots)| {
+            #[cfg(feature = "debug_alloc")]
+            let label = {
+                let start = if self.is_guarded(pointer) { pointer.sub(1) } else { pointer };
+                self.labels.get(&start.to_idx()).copied().unwrap_or("unlabeled")
+            };
+            #[cfg(not(feature = "debug_alloc"))]
+            let label = "unlabeled";
+            (pointer, slots, label)
+        }).collect()
+    }
+
+    /// Moves the label of an allocation that moved, if it has one.
+    fn move_label(&mut self, from: PointerIdx, to: PointerIdx) {
+        #[cfg(feature = "debug_alloc")]
+        if let Some(label) = self.labels.remove(&from) {
+            self.labels.insert(to, label);
+        }
+        #[cfg(not(feature = "debug_alloc"))]
+        let _ = (from, to);
+    }
+
+    /// Like [`Heap::realloc`], but looks up the old size of the allocation.
+    ///
+    /// # Safety
+    /// See [`Heap::realloc`].
+    pub unsafe fn realloc_tracked(&mut self, pointer: Pointer, new: usize) -> Pointer {
+        let old = self.size_of(pointer).expect("realloc of pointer that is not a live allocation");
+        self.realloc(pointer, old, new)
+    }
+
+    /// Splits a live allocation into two adjacent live allocations,
+    /// the first holding the slots before `at_slot`, and the second the rest.
+    /// No data is copied, and both halves keep the owners of the allocation.
+    /// The halves are freed on their own, and can be joined again by [`Heap::merge`].
+    ///
+    /// # Panics
+    /// If the pointer is not the start of a live allocation,
+    /// if `at_slot` does not leave at least one slot in each half,
+    /// or if the heap has guards, see [`Heap::with_guards`].
+    pub fn split(&mut self, pointer: Pointer, at_slot: usize) -> (Pointer, Pointer) {
+        assert!(self.guarded.is_none(), "can not split allocations in a heap with guards");
+        assert!(self.slack.is_none(), "can not split allocations in a heap with size classes");
+        let slots = match self.free.size_of(pointer) {
+            Some(slots) if self.check_generation(pointer) => slots,
+            _ => panic!("split of pointer that is not a live allocation"),
+        };
+        assert!(0 < at_slot && at_slot < slots, "split must leave slots in both halves");
+
+        let start = pointer.to_idx();
+        let second = start + at_slot;
+        self.free.live.insert(start, at_slot);
+        self.free.live.insert(second, slots - at_slot);
+        if let Some(refs) = self.refs.get(&start).copied() {
+            self.refs.insert(second, refs);
+        }
+        #[cfg(feature = "debug_alloc")]
+        if let Some(label) = self.labels.get(&start).copied() {
+            self.labels.insert(second, label);
+        }
+
+        let second = self.tag_generation(Pointer::new(second)).with_owned(pointer.is_owned());
+        return (pointer, second);
+    }
+
+    /// Frees `slots` slots from the middle of a live allocation, starting at `at_slot`,
+    /// leaving two live allocations on either side of the freed gap, like [`Heap::split`].
+    /// Returns the pointer to the second allocation, which keeps the owners of the first.
+    ///
+    /// # Panics
+    /// If the pointer is not the start of a live allocation,
+    /// if the gap does not leave at least one slot on either side,
+    /// or if the heap has guards or size classes.
+    pub fn free_subrange(&mut self, pointer: Pointer, at_slot: usize, slots: usize) -> Pointer {
+        assert!(self.guarded.is_none(), "can not free part of an allocation in a heap with guards");
+        assert!(self.slack.is_none(), "can not free part of an allocation in a heap with size classes");
+        let size = match self.free.size_of(pointer) {
+            Some(size) if self.check_generation(pointer) => size,
+            _ => panic!("free of part of pointer that is not a live allocation"),
+        };
+        let end = at_slot.checked_add(slots).filter(|end| 0 < at_slot && 0 < slots && *end < size);
+        let end = end.expect("freed part must be inside the allocation, leaving slots on either side");
+
+        let start = pointer.to_idx();
+        let (gap, second) = (start + at_slot, start + end);
+        if let Some(refs) = self.refs.get(&start).copied() {
+            self.refs.insert(second, refs);
+        }
+        #[cfg(feature = "debug_alloc")]
+        if let Some(label) = self.labels.get(&start).copied() {
+            self.labels.insert(second, label);
+        }
+        if self.zero_on_free {
+            self.zero(gap.to_usize(), slots);
+        }
+        // the range set keeps what is left on either side of the gap live
+        let gap = Pointer::new(gap);
+        self.free.free_within(gap, slots);
+        self.observe(AllocEvent::Free { pointer: gap, slots });
+
+        return self.tag_generation(Pointer::new(second)).with_owned(pointer.is_owned());
+    }
+
+    /// Joins two live allocations into one, if `b` starts exactly where `a` ends,
+    /// and both have the same number of owners, see [`Heap::split`].
+    /// No data is copied. Returns the joined allocation, which starts at `a`,
+    /// or `None` if the allocations can not be joined, leaving both as they were.
+    /// Any other pointers to `b` must no longer be used.
+    ///
+    /// # Panics
+    /// If the heap has guards, see [`Heap::with_guards`].
+    pub fn merge(&mut self, a: Pointer, b: Pointer) -> Option<Pointer> {
+        assert!(self.guarded.is_none(), "can not merge allocations in a heap with guards");
+        assert!(self.slack.is_none(), "can not merge allocations in a heap with size classes");
+        if !self.check_generation(a) || !self.check_generation(b) { return None; }
+        let first = self.free.size_of(a)?;
+        let second = self.free.size_of(b)?;
+        if a.offset(first).idx() != b.idx() { return None; }
+        if self.ref_count(a) != self.ref_count(b) { return None; }
+
+        self.free.live.remove(&b.to_idx());
+        self.free.live.insert(a.to_idx(), first + second);
+        self.refs.remove(&b.to_idx());
+        self.weak.remove(b.to_idx());
+        #[cfg(feature = "debug_alloc")]
+        self.labels.remove(&b.to_idx());
+        self.retire_generation(b.to_idx());
+        return Some(a);
+    }
+
+    /// Returns whether a range of slots is entirely free.
+    /// Unlike [`RangeSet::is_free`], slots past the end of the heap are never free.
+    pub fn free_at(&self, pointer: Pointer, slots: usize) -> bool {
+        match self.free_span_at(pointer) {
+            Some((start, size)) => start.distance(pointer) + slots <= size,
+            None => false,
+        }
+    }
+
+    /// Returns the whole free range containing a given slot,
+    /// or `None` if the slot is allocated or past the end of the heap.
+    ///
+    /// ```
+    /// let mut heap = Heap::new();
+    /// let _ = unsafe { heap.alloc(2) };
+    /// let b = unsafe { heap.alloc(4) };
+    /// let _ = unsafe { heap.alloc(1) };
+    /// heap.free(b, 4);
+    /// let at = |idx| Pointer::tagged(idx, false);
+    ///
+    /// // inside the free range
+    /// let (start, size) = heap.free_span_at(at(3)).unwrap();
+    /// assert_eq!((start.idx(), size), (2, 4));
+    /// // at either edge of the free range
+    /// assert_eq!(heap.free_span_at(at(2)).unwrap().0.idx(), 2);
+    /// assert_eq!(heap.free_span_at(at(5)).unwrap().0.idx(), 2);
+    /// // just outside the free range
+    /// assert_eq!(heap.free_span_at(at(1)), None);
+    /// assert_eq!(heap.free_span_at(at(6)), None);
+    /// ```
+    pub fn free_span_at(&self, pointer: Pointer) -> Option<(Pointer, usize)> {
+        self.free.free_span_at(pointer)
+    }
+
+    /// Returns the size of a live allocation,
+    /// or `None` if the pointer isn't the start of one.
+    pub fn size_of(&self, pointer: Pointer) -> Option<usize> {
+        if self.is_guarded(pointer) {
+            return self.free.size_of(pointer.sub(1)).map(|slots| slots - 2);
+        }
+        self.free.size_of(pointer)
+    }
+
+    /// Returns whether a pointer is the start of a live allocation,
+    /// and, if generations are being checked, was made for that allocation.
+    /// Unlike [`Heap::free_at`], which asks whether slots are free,
+    /// this is whether the pointer can be read, written, or freed.
+    /// The tag is not checked, so a borrowed pointer into a live allocation is fine.
+    pub fn verify_pointer(&self, pointer: Pointer) -> bool {
+        self.size_of(pointer).is_some() && self.check_generation(pointer)
+    }
+
+    // Reads a single slot relative to a pointer.
+    pub fn read_slot(&self, pointer: Pointer, slot: usize) -> &Slot {
+        &self.data[pointer.to_idx().to_usize() + slot]
+    }
+
+    // Writes a single slot relative to a pointer, in place.
+    // Like `read_mut`, this never copies on write.
+    pub fn write_slot(&mut self, pointer: Pointer, slot: usize, value: Slot) {
+        let limit = self.size_of(pointer).unwrap_or(self.data.len());
+        debug_assert!(slot < limit, "slot {} is out of bounds for a block of {} slots", slot, limit);
+        self.data[pointer.to_idx().to_usize() + slot] = value;
+    }
+
+    /// Like [`Heap::read`], but returns an error if the pointer is stale,
+    /// see [`Heap::with_generation_checks`].
+    pub fn try_read(&self, pointer: Pointer, slots: usize) -> Result<&[Slot], HeapError> {
+        if !self.check_generation(pointer) {
+            return Err(HeapError::UseAfterFree);
+        }
+        Ok(self.read(pointer, slots))
+    }
+
+    // Reads a range of data.
+    pub fn read(&self, pointer: Pointer, slots: usize) -> &[Slot] {
+        let start = pointer.to_idx().to_usize();
+        &self.data[start..(start + slots)]
+    }
+
+    /// Borrows a range of data mutably, so a block can be written in place.
+    /// Unlike [`Heap::write`], this never copies on write,
+    /// so writes are seen through every pointer sharing the block.
+    pub fn read_mut(&mut self, pointer: Pointer, slots: usize) -> &mut [Slot] {
+        let start = pointer.to_idx().to_usize();
+        assert!(
+            start + slots <= self.data.len(),
+            "read of {} slots at {} runs past the end of the heap ({} slots), is the size stale?",
+            slots, start, self.data.len(),
+        );
+        &mut self.data[start..(start + slots)]
+    }
+
+    /// Slides all live allocations towards the start of the heap, removing any gaps.
+    /// The free space left over past the last allocation is released.
+    ///
+    /// Returns a map from the old owned pointer of each allocation that moved
+    /// to its new owned pointer, as handed out, so just past the guard of guarded allocations;
+    /// allocations that did not move are not included.
+    /// Any pointers to moved allocations must be updated by the caller,
+    /// including pointers into arena regions, see [`Heap::alloc_in`].
+    pub fn compact(&mut self) -> BTreeMap<Pointer, Pointer> {
+        let mut moves = Vec::new();
+        let mut live = BTreeMap::new();
+        let mut refs = BTreeMap::new();
+        let mut starts = BTreeMap::new();
+        let mut guarded = self.guarded.as_ref().map(|_| BTreeSet::new());
+        let mut slack = self.slack.as_ref().map(|_| BTreeMap::new());
+        #[cfg(feature = "debug_alloc")]
+        let mut labels = BTreeMap::new();
+        let mut next = 0;
+
+        // allocations are visited low-to-high, and only ever move down,
+        // so a move never clobbers data that has yet to be moved.
+        for (start, slots) in self.free.live.iter() {
+            let (start, slots) = (*start, *slots);
+            let new_start = PointerIdx::new(next);
+
+            if start != new_start {
+                for slot in 0..slots {
+                    self.data.swap(next + slot, start.to_usize() + slot);
+                }
+                let offset = self.guard_offset(start);
+                let old_pointer = self.tag_generation(Pointer::new(start)).offset(offset);
+                moves.push((old_pointer, start, new_start, offset));
+            }
+
+            if let Some(count) = self.refs.get(&start) {
+                refs.insert(new_start, *count);
+            }
+            starts.insert(start, new_start);
+            if let Some(guarded) = &mut guarded {
+                if self.is_guarded(Pointer::new(start + 1)) {
+                    guarded.insert(new_start + 1);
+                }
+            }
+            if let (Some(slack), Some(slots)) = (&mut slack, self.slack.as_ref().and_then(|old| old.get(&start))) {
+                slack.insert(new_start, *slots);
+            }
+            #[cfg(feature = "debug_alloc")]
+            if let Some(label) = self.labels.get(&start) {
+                labels.insert(new_start, *label);
+            }
+            live.insert(new_start, slots);
+            next += slots;
+        }
+
+        // everything past the last allocation is free, so drop it
+        let empty = RangeSet::with_policy(self.free.policy).with_small_sizes(self.free.small.len());
+        self.free = RangeSet { live, counters: self.free.counters, ..empty };
+        self.free.capacity = next;
+        self.refs = refs;
+        self.guarded = guarded;
+        // a weak pointer may be to just past a guard, so it moves by as much as its allocation
+        self.weak.remap(|target| {
+            let (old, new) = starts.range(..=target).next_back().unwrap();
+            *new + (target.to_usize() - old.to_usize())
+        });
+        self.slack = slack;
+        #[cfg(feature = "debug_alloc")]
+        { self.labels = labels; }
+        self.data.truncate(next);
+
+        // arena regions are allocations too, so they move with them
+        if !self.arenas.is_empty() {
+            let moved = moves.iter()
+                .map(|(_old_pointer, start, new_start, _offset)| (*start, *new_start))
+                .collect();
+            for arena in self.arenas.values_mut() {
+                arena.relocate(&moved);
+            }
+        }
+
+        // old pointers to moved allocations are now stale
+        for (_old_pointer, start, _new_start, _offset) in moves.iter() {
+            self.retire_generation(*start);
+        }
+        return moves.into_iter()
+            .map(|(old_pointer, _start, new_start, offset)| {
+                (old_pointer, self.tag_generation(Pointer::new(new_start)).offset(offset))
+            })
+            .collect();
+    }
+
+    /// Compacts the heap like [`Heap::compact`], but a little at a time,
+    /// so compaction can be interleaved with other work.
+    /// Each call moves allocations, lowest first, until the next one would take the slots
+    /// moved by this call past `budget_slots`, and returns whether compaction has finished.
+    /// An allocation larger than the budget is moved on its own, so every call makes progress.
+    /// Allocations that do not need to move are passed over without using up the budget.
+    ///
+    /// The allocations moved so far are added to [`CompactState::relocations`],
+    /// and pointers to them must be updated by the caller before they are used again.
+    /// Allocations freed between calls below where the compaction has got to
+    /// leave gaps that are not closed until the next compaction.
+    pub fn compact_step(&mut self, state: &mut CompactState, budget_slots: usize) -> bool {
+        if state.done { return true; }
+        let mut moved = 0;
+
+        while let Some((start, slots)) = self.free.live.range(PointerIdx::new(state.cursor)..).next() {
+            let (start, slots) = (*start, *slots);
+            let gap_before = self.free.ranges.range(..start).next_back()
+                .is_some_and(|(before, size)| before.end(*size) == Some(start.to_usize()));
+            if gap_before {
+                if moved > 0 && moved + slots > budget_slots { return false; }
+                let new_start = self.move_down(start, slots, &mut state.relocations);
+                state.cursor = new_start.to_usize() + slots;
+                moved += slots;
+            } else {
+                state.cursor = start.to_usize() + slots;
+            }
+            // an allocation of no slots does not move the cursor on, so step past it
+            if slots == 0 { state.cursor += 1; }
+        }
+
+        // everything past the last allocation is free, so drop it
+        self.free.trim_tail();
+        self.data.truncate(self.free.capacity);
+        state.done = true;
+        return true;
+    }
+
+    /// Slides a live allocation down into the free range right before it, for [`Heap::compact_step`],
+    /// recording the move and returning where it moved to.
+    fn move_down(&mut self, start: PointerIdx, slots: usize, relocations: &mut BTreeMap<Pointer, Pointer>) -> PointerIdx {
+        let offset = self.guard_offset(start);
+        let old_pointer = self.tag_generation(Pointer::new(start)).offset(offset);
+        let new_start = self.free.slide_back(Pointer::new(start), slots, slots).unwrap().to_idx();
+        // the allocation only moves down, so copy low-to-high
+        for slot in 0..slots {
+            self.data.swap(new_start.to_usize() + slot, start.to_usize() + slot);
+        }
+        self.data.truncate(self.free.capacity);
+
+        if let Some(count) = self.refs.remove(&start) {
+            self.refs.insert(new_start, count);
+        }
+        if let Some(guarded) = &mut self.guarded {
+            // weak pointers to a guarded allocation are to just past its guard
+            if guarded.remove(&(start + 1)) {
+                guarded.insert(new_start + 1);
+                self.weak.moved(start + 1, new_start + 1);
+            }
+        }
+        if let Some(slack) = self.slack.as_mut().and_then(|slack| slack.remove(&start)) {
+            self.note_slack(new_start, slack);
+        }
+        self.move_label(start, new_start);
+        self.weak.moved(start, new_start);
+        if !self.arenas.is_empty() {
+            let moved = BTreeMap::from([(start, new_start)]);
+            for arena in self.arenas.values_mut() {
+                arena.relocate(&moved);
+            }
+        }
+
+        // old pointers to the allocation are now stale
+        self.retire_generation(start);
+        relocations.insert(old_pointer, self.tag_generation(Pointer::new(new_start)).offset(offset));
+        return new_start;
+    }
+
+    /// Returns another owned pointer to the same allocation.
+    /// The allocation is shared until all but one owner have written to it.
+    pub fn share(&mut self, pointer: Pointer) -> Pointer {
+        self.retain(pointer);
+        return pointer;
+    }
+
+    /// Adds an owner to an allocation, see [`Heap::release`].
+    /// Allocations start out with a single owner.
+    pub fn retain(&mut self, pointer: Pointer) {
+        assert!(pointer.is_owned());
+        *self.refs.entry(pointer.to_idx()).or_insert(1) += 1;
+    }
+
+    /// Removes an owner from an allocation, freeing it if that was the last owner.
+    /// Returns whether the allocation was freed.
+    ///
+    /// # Panics
+    /// If the allocation is freed, in the same cases as [`Heap::free`].
+    pub fn release(&mut self, pointer: Pointer, slots: usize) -> bool {
+        assert!(pointer.is_owned());
+        if self.ref_count(pointer) > 1 {
+            self.drop_owner(pointer.to_idx());
+            return false;
+        }
+        self.free(pointer, slots);
+        return true;
+    }
+
+    /// Removes an owner from a shared allocation.
+    fn drop_owner(&mut self, idx: PointerIdx) {
+        match self.refs.get(&idx).copied() {
+            Some(2) => { self.refs.remove(&idx); },
+            Some(n) => { self.refs.insert(idx, n - 1); },
+            None    => (),
+        }
+    }
+
+    /// Returns the number of owned pointers to an allocation.
+    pub fn ref_count(&self, pointer: Pointer) -> usize {
+        self.refs.get(&pointer.to_idx()).copied().unwrap_or(1)
+    }
+
+    /// Returns whether writing through a pointer would modify the allocation in place.
+    /// This is the case when the pointer is owned and is the only owner.
+    pub fn is_unique(&self, pointer: Pointer) -> bool {
+        pointer.is_owned() && self.ref_count(pointer) == 1
+    }
+
+    /// Writes `item` to the start of an allocation, copying on write.
+    ///
+    /// If the pointer is the only owner of the allocation, see [`Heap::is_unique`],
+    /// the data is written in place and the same pointer is returned.
+    /// Otherwise, the allocation is shared: a fresh allocation of the same size is made,
+    /// the old contents are copied over, `item` is written to the copy,
+    /// and the new owned pointer is returned.
+    /// If an owned pointer was copied, it gives up its share of the old allocation.
+    ///
+    /// The returned pointer must be used in place of the old one from then on.
+    pub fn write(&mut self, pointer: Pointer, item: &[Slot]) -> Pointer {
+        let slots = self.size_of(pointer).expect("write to pointer that is not a live allocation");
+        assert!(
+            item.len() <= slots,
+            "write of {} slots to allocation of {} slots", item.len(), slots,
+        );
+
+        let pointer = if self.is_unique(pointer) {
+            pointer
+        } else {
+            self.copy_for_write(pointer, slots)
+        };
+
+        let start = pointer.to_idx().to_usize();
+        for (offset, slot) in item.iter().enumerate() {
+            // SAFETY: the bits are copied verbatim.
+            self.data[start + offset] = unsafe { Slot::from_bits(slot.to_u64()) };
+        }
+        return pointer;
+    }
+
+    /// Reads `count` values of some type from the start of an allocation,
+    /// each from [`FromSlots::SLOTS`] slots in a row.
+    ///
+    /// # Safety
+    /// Caller must ensure the slots hold values of that type.
+    ///
+    /// # Panics
+    /// If the pointer is not the start of a live allocation,
+    /// or the values would run past the end of it.
+    pub unsafe fn read_as<T: FromSlots>(&self, pointer: Pointer, count: usize) -> Vec<T> {
+        let slots = self.size_of(pointer).expect("read from pointer that is not a live allocation");
+        let needed = count.checked_mul(T::SLOTS).filter(|needed| *needed <= slots);
+        let Some(needed) = needed else {
+            panic!("read of {} values of {} slots from allocation of {} slots", count, T::SLOTS, slots);
+        };
+        let slots = self.read(pointer, needed);
+        (0..count).map(|index| T::from_slots(&slots[(index * T::SLOTS)..((index + 1) * T::SLOTS)])).collect()
+    }
 
-    fn random_alloc_size(rng: &mut attorand::Rng) -> usize {
+    /// Writes values of some type to the start of an allocation, copying on write like [`Heap::write`],
+    /// each to [`ToSlots::SLOTS`] slots in a row. Returns the pointer to use from then on.
+    ///
+    /// # Panics
+    /// If the pointer is not the start of a live allocation,
+    /// or the values would run past the end of it.
+    pub fn write_as<T: ToSlots>(&mut self, pointer: Pointer, values: &[T]) -> Pointer {
+        let mut slots = Vec::with_capacity(values.len() * T::SLOTS);
+        for value in values {
+            value.to_slots(&mut slots);
+        }
+        return self.write(pointer, &slots);
+    }
+
+    /// Copies a range of slots into a fresh allocation, returning its owned pointer.
+    /// The source is left untouched, so this is a deep copy of the range.
+    pub fn clone_range(&mut self, src: Pointer, slots: usize) -> Pointer {
+        // SAFETY: every slot is immediately overwritten by the copy.
+        let new_pointer = unsafe { self.alloc(slots) };
+
+        // alloc may grow the heap, so copy by index
+        let (from, to) = (src.to_idx().to_usize(), new_pointer.to_idx().to_usize());
+        for slot in 0..slots {
+            // SAFETY: the bits are copied verbatim.
+            self.data[to + slot] = unsafe { Slot::from_bits(self.data[from + slot].to_u64()) };
+        }
+        return new_pointer;
+    }
+
+    /// Copies a shared allocation into a fresh one, releasing the old share if owned.
+    fn copy_for_write(&mut self, pointer: Pointer, slots: usize) -> Pointer {
+        let new_pointer = self.clone_range(pointer, slots);
+
+        if pointer.is_owned() {
+            self.drop_owner(pointer.to_idx());
+        }
+
+        return new_pointer;
+    }
+
+    /// Frees an allocation of a given size.
+    ///
+    /// # Panics
+    /// If generations are being checked and the pointer is stale,
+    /// or if guards are on and one was overwritten or the size is wrong, see [`Heap::try_free`].
+    pub fn free(&mut self, pointer: Pointer, slots: usize) {
+        if let Err(error) = self.try_free(pointer, slots) {
+            panic!("{}", error);
+        }
+    }
+
+    /// Like [`Heap::free`], but returns an error if the pointer is stale,
+    /// see [`Heap::with_generation_checks`],
+    /// or if a guard slot next to the allocation was overwritten, see [`Heap::with_guards`].
+    /// Guarded allocations must also be freed with the size they were allocated with.
+    /// Nothing is freed if there is an error.
+    pub fn try_free(&mut self, pointer: Pointer, requested: usize) -> Result<(), HeapError> {
+        let slots = self.size_classes.round(requested);
+        if self.is_guarded(pointer) {
+            let outer = pointer.sub(1);
+            // the guards are checked where they really are, whatever size was passed
+            let allocated = match self.free.size_of(outer) {
+                Some(outer_slots) => outer_slots - 2,
+                None => return Err(HeapError::DoubleFree),
+            };
+            if !self.guards_intact(outer, allocated) {
+                return Err(HeapError::Overrun);
+            }
+            if slots != allocated {
+                return Err(HeapError::SizeMismatch { freed: requested, allocated });
+            }
+            self.check_free(outer)?;
+            self.refs.remove(&pointer.to_idx());
+            self.weak.remove(pointer.to_idx());
+            self.release_unchecked(outer, slots + 2);
+        } else {
+            self.check_free(pointer)?;
+            self.release_unchecked(pointer, slots);
+        }
+        self.observe(AllocEvent::Free { pointer, slots: requested });
+        Ok(())
+    }
+
+    /// Frees many allocations at once, given their pointers and sizes.
+    /// Allocations are freed in address order so neighbors merge as they go,
+    /// and the heap is only shrunk once at the end.
+    ///
+    /// # Panics
+    /// If generations are being checked and any pointer is stale.
+    pub fn free_many(&mut self, items: &[(Pointer, usize)]) {
+        // guards are checked one allocation at a time
+        if self.guarded.is_some() {
+            for (pointer, slots) in items.iter() {
+                self.free(*pointer, *slots);
+            }
+            return;
+        }
+
+        let mut items = items.to_vec();
+        items.sort_by_key(|(pointer, _slots)| pointer.idx());
+
+        for (pointer, slots) in items.iter() {
+            if let Err(error) = self.check_free(*pointer) {
+                panic!("{}", error);
+            }
+            let requested = *slots;
+            let slots = self.size_classes.round(requested);
+            self.forget(*pointer, slots);
+            self.free.free_within(*pointer, slots);
+            self.observe(AllocEvent::Free { pointer: *pointer, slots: requested });
+        }
+
+        // if the last allocation freed ended up in the free tail, release it
+        if let Some((last, _slots)) = items.last() {
+            if self.free.is_tail_free(*last) {
+                self.shrink_to_fit();
+            }
+        }
+    }
+
+    /// Checks that a pointer can be freed, and retires its generation if so.
+    fn check_free(&mut self, pointer: Pointer) -> Result<(), HeapError> {
+        if self.generations.is_some() {
+            if self.free.size_of(pointer).is_none() || !self.check_generation(pointer) {
+                return Err(HeapError::DoubleFree);
+            }
+            self.retire_generation(pointer.to_idx());
+        }
+        Ok(())
+    }
+
+    /// Frees a range of slots, without checking the pointer.
+    fn release_unchecked(&mut self, pointer: Pointer, slots: usize) {
+        self.forget(pointer, slots);
+        let unneeded_capacity = self.free.free(pointer, slots);
+        self.data.truncate(self.data.len() - unneeded_capacity);
+    }
+
+    /// Clears any data kept about a range of slots that is about to be freed.
+    fn forget(&mut self, pointer: Pointer, slots: usize) {
+        assert!(pointer.is_owned());
+        if self.zero_on_free {
+            self.zero(pointer.to_idx().to_usize(), slots);
+        }
+        self.refs.remove(&pointer.to_idx());
+        self.weak.remove(pointer.to_idx());
+        self.note_slack(pointer.to_idx(), 0);
+        #[cfg(feature = "debug_alloc")]
+        self.labels.remove(&pointer.to_idx());
+        // in case guards were freed along with the allocation between them
+        if let Some(guarded) = &mut self.guarded {
+            if guarded.remove(&(pointer.to_idx() + 1)) {
+                self.weak.remove(pointer.to_idx() + 1);
+            }
+        }
+    }
+
+    /// Zeroes a range of slots in the backing allocation.
+    fn zero(&mut self, start: usize, slots: usize) {
+        for slot in &mut self.data[start..(start + slots)] {
+            // SAFETY: freed slots are never read as anything.
+            *slot = unsafe { Slot::zero() };
+        }
+    }
+
+    /// Like [`Heap::free`], but looks up the size of the allocation.
+    pub fn free_tracked(&mut self, pointer: Pointer) {
+        let slots = self.size_of(pointer).expect("free of pointer that is not a live allocation");
+        self.free(pointer, slots);
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::BTreeMap;
+    use super::*;
+
+    pub fn random_alloc_size(rng: &mut attorand::Rng) -> usize {
          rng.next_byte() as usize + 1
     }
 
     const STRESS_ITER: usize = 1000;
+    const STRESS_SEED: u64 = 0x5eed;
 
-    #[test]
-    pub fn stress_test_heap() {
+    /// The seed stress tests run with.
+    /// Set `FLEX_STRESS_SEED` to re-run with the seed from a failure.
+    fn stress_seed() -> u64 {
+        match std::env::var("FLEX_STRESS_SEED") {
+            Ok(seed) => seed.parse().expect("FLEX_STRESS_SEED must be a number"),
+            Err(_) => STRESS_SEED,
+        }
+    }
+
+    /// Runs the stress workload on a fresh heap, see [`stress_heap_seeded`].
+    pub fn run_stress(seed: u64, iterations: usize) -> Heap {
         let mut heap = Heap::new();
+        stress_heap_seeded(&mut heap, seed, iterations);
+        heap
+    }
+
+    fn stress_heap(heap: &mut Heap) {
+        stress_heap_seeded(heap, stress_seed(), STRESS_ITER);
+    }
+
+    /// Randomly allocates, reallocates, and frees, auditing the heap after every step.
+    /// The same seed always runs the same steps.
+    /// Returns how many allocations reused a whole free range exactly, leaving no gap behind.
+    fn stress_heap_seeded(heap: &mut Heap, seed: u64, iterations: usize) -> usize {
+        let mut reused = 0;
         let mut pointers = BTreeMap::new();
-        let mut rng = attorand::Rng::new_default();
+        let mut rng = attorand::Rng::new_with_seed(seed);
+        let audit = |heap: &Heap, i: usize| {
+            if let Err(error) = heap.free.audit() {
+                panic!("stress test with seed {} broke at iteration {}: {}", seed, i, error);
+            }
+            assert_eq!(
+                heap.data.len(), heap.free.capacity,
+                "stress test with seed {} broke at iteration {}: data does not match capacity", seed, i,
+            );
+        };
 
-        for i in 0..STRESS_ITER {
-            if i % 100 == 0 { println!("{}", i); }
+        for i in 0..iterations {
             let size = random_alloc_size(&mut rng);
+            let (capacity, ranges) = (heap.capacity(), heap.free.ranges.len());
             // SAFETY: data is never read
             let pointer = unsafe { heap.alloc(size) };
+            if heap.capacity() == capacity && heap.free.ranges.len() < ranges { reused += 1; }
             pointers.insert(i, (pointer, size));
+            audit(heap, i);
 
             let index = rng.next_u64_max((pointers.len() - 1) as u64) as usize;
             if rng.next_bool() {
@@ -175,35 +1875,1354 @@ pub mod tests {
                     heap.free(*to_modify, *old_size);
                     pointers.remove(&index);
                 }
+                audit(heap, i);
             }
         }
 
+        for (pointer, size) in pointers.values() {
+            assert_eq!(heap.size_of(*pointer), Some(heap.size_classes.round(*size)));
+        }
+        return reused;
+    }
+
+    #[test]
+    pub fn stress_test_heap() {
+        let heap = run_stress(stress_seed(), STRESS_ITER);
         heap.draw_free();
     }
 
     #[test]
-    pub fn stress_test_native() {
-        let mut rng = attorand::Rng::new_default();
-        let mut pointers = BTreeMap::new();
+    pub fn stress_is_deterministic() {
+        let snapshot = run_stress(7, STRESS_ITER / 4).snapshot();
+        assert_eq!(run_stress(7, STRESS_ITER / 4).snapshot(), snapshot);
+        assert_ne!(run_stress(8, STRESS_ITER / 4).snapshot(), snapshot);
+    }
 
-        for i in 0..STRESS_ITER {
-            let size = random_alloc_size(&mut rng);
-            let pointer = vec![0; size];
-            pointers.insert(i, (pointer, size));
+    #[test]
+    pub fn compare_fit_policies() {
+        let [first, best, worst] = [FitPolicy::FirstFit, FitPolicy::BestFit, FitPolicy::WorstFit].map(|policy| {
+            let mut heap = Heap::new().with_policy(policy);
+            stress_heap_seeded(&mut heap, STRESS_SEED, STRESS_ITER);
+            let stats = heap.stats();
+            assert_eq!(stats.total_slots, heap.free.capacity);
+            assert!(stats.fragmentation_ratio < 1.0);
+            stats
+        });
+        assert!(best.fragmentation_ratio < first.fragmentation_ratio);
+        assert!(first.fragmentation_ratio < worst.fragmentation_ratio);
+        assert!(best.total_slots <= first.total_slots);
+    }
 
-            let index = rng.next_u64_max((pointers.len() - 1) as u64) as usize;
-            if rng.next_bool() {
-                let (index, _) = pointers.iter().nth(index).unwrap();
-                let index = *index;
+    #[test]
+    pub fn size_classes_trade_slack_for_reuse() {
+        let mut exact = Heap::new();
+        let exact_reused = stress_heap_seeded(&mut exact, STRESS_SEED, STRESS_ITER);
+        let mut classes = Heap::new().with_size_classes(SizeClassPolicy::PowerOfTwo);
+        let classes_reused = stress_heap_seeded(&mut classes, STRESS_SEED, STRESS_ITER);
 
-                if rng.next_bool() {
-                    let new_size = random_alloc_size(&mut rng);
-                    let pointer = vec![0; new_size];
-                    pointers.insert(index, (pointer, new_size));
-                } else {
-                    pointers.remove(&index);
-                }
+        let (exact, classes) = (exact.stats(), classes.stats());
+        assert!(classes_reused > exact_reused);
+        assert!(classes.disjoint_free_ranges <= exact.disjoint_free_ranges);
+        assert!(classes.fragmentation_ratio < exact.fragmentation_ratio);
+        assert_eq!(exact.internal_slack, 0);
+        assert!(classes.internal_slack > 0);
+    }
+
+    #[test]
+    pub fn size_classes_round_allocations() {
+        assert_eq!(SizeClassPolicy::PowerOfTwo.round(5), 8);
+        assert_eq!(SizeClassPolicy::PowerOfTwo.round(8), 8);
+        assert_eq!(SizeClassPolicy::Quantum(4).round(5), 8);
+        assert_eq!(SizeClassPolicy::Exact.round(5), 5);
+
+        let mut heap = Heap::new().with_size_classes(SizeClassPolicy::PowerOfTwo);
+        let a = heap.calloc(5);
+        let b = heap.calloc(3);
+        assert_eq!(heap.size_of(a), Some(8));
+        assert_eq!(heap.stats().internal_slack, 3 + 1);
+
+        // growing within the class stays in place, and the freed block fits the next of its class
+        // SAFETY: data is never read
+        let a = unsafe { heap.realloc(a, 5, 7) };
+        assert_eq!(a.idx(), 0);
+        assert_eq!(heap.stats().internal_slack, 1 + 1);
+        heap.free(a, 7);
+        let c = heap.calloc(6);
+        assert_eq!(c.idx(), 0);
+        assert_eq!(heap.capacity(), 12);
+
+        heap.free(b, 3);
+        heap.free(c, 6);
+        assert!(heap.is_empty());
+        assert_eq!(heap.stats().internal_slack, 0);
+    }
+
+    #[test]
+    pub fn fit_policies_pick_expected_range() {
+        // leave free ranges of 4, 3, and 6 slots, in that order
+        let picks = [FitPolicy::FirstFit, FitPolicy::BestFit, FitPolicy::WorstFit].map(|policy| {
+            let mut heap = Heap::new().with_policy(policy);
+            // SAFETY: data is never read
+            let pointers: Vec<_> = [4, 1, 3, 1, 6, 1].iter()
+                .map(|size| (unsafe { heap.alloc(*size) }, *size))
+                .collect();
+            for (pointer, size) in [pointers[0], pointers[2], pointers[4]] {
+                heap.free(pointer, size);
             }
+            unsafe { heap.alloc(3) }.idx()
+        });
+        assert_eq!(picks, [0, 5, 9]);
+    }
+
+    #[test]
+    pub fn size_of_tracks_allocations() {
+        let mut heap = Heap::new();
+        let sizes = [1, 7, 3, 12, 5];
+        // SAFETY: data is never read
+        let pointers: Vec<_> = sizes.iter().map(|s| unsafe { heap.alloc(*s) }).collect();
+
+        for (pointer, size) in pointers.iter().zip(sizes.iter()) {
+            assert_eq!(heap.size_of(*pointer), Some(*size));
         }
+
+        // interior pointers are not the start of an allocation
+        assert_eq!(heap.size_of(pointers[1].offset(1)), None);
+
+        // SAFETY: data is never read
+        let grown = unsafe { heap.realloc(pointers[4], 5, 9) };
+        assert_eq!(heap.size_of(grown), Some(9));
+        let shrunk = unsafe { heap.realloc(pointers[3], 12, 4) };
+        assert_eq!(heap.size_of(shrunk), Some(4));
+
+        heap.free(pointers[0], 1);
+        assert_eq!(heap.size_of(pointers[0]), None);
+    }
+
+    #[test]
+    pub fn free_tracked_matches_explicit_free() {
+        let mut explicit = Heap::new();
+        let mut tracked = Heap::new();
+        // SAFETY: data is never read
+        let a = unsafe { [explicit.alloc(3), explicit.alloc(4), explicit.alloc(5)] };
+        let b = unsafe { [tracked.alloc(3), tracked.alloc(4), tracked.alloc(5)] };
+
+        explicit.free(a[2], 5);
+        tracked.free_tracked(b[2]);
+        assert_eq!(explicit.stats(), tracked.stats());
+        assert_eq!(tracked.free.capacity, 7);
+
+        explicit.free(a[0], 3);
+        tracked.free_tracked(b[0]);
+        assert_eq!(explicit.stats(), tracked.stats());
+
+        // SAFETY: data is never read
+        let a = unsafe { explicit.realloc(a[1], 4, 9) };
+        let b = unsafe { tracked.realloc_tracked(b[1], 9) };
+        assert_eq!(explicit.size_of(a), tracked.size_of(b));
+        assert_eq!(explicit.stats(), tracked.stats());
+    }
+
+    #[test]
+    pub fn capped_heap_runs_out_of_memory() {
+        let mut heap = Heap::new().with_max_capacity(10);
+        // SAFETY: data is never read
+        let a = unsafe { heap.try_alloc(4) }.unwrap();
+        let _ = unsafe { heap.try_alloc(4) }.unwrap();
+        assert_eq!(
+            unsafe { heap.try_alloc(3) }.unwrap_err(),
+            AllocError::OutOfMemory { requested: 3, available: 2 },
+        );
+
+        // freed gaps can still be reused.
+        heap.free(a, 4);
+        assert!(unsafe { heap.try_alloc(3) }.is_ok());
+        assert_eq!(heap.stats().total_slots, 8);
+    }
+
+    #[test]
+    pub fn capped_heap_gates_tail_extension() {
+        let mut heap = Heap::new().with_max_capacity(10);
+        // SAFETY: data is never read
+        let _ = unsafe { heap.alloc(6) };
+        // leave a free range at the tail of the heap
+        heap.reserve(2);
+
+        // extending the tail range by 3 would go past the cap
+        assert_eq!(
+            unsafe { heap.try_alloc(5) }.unwrap_err(),
+            AllocError::OutOfMemory { requested: 5, available: 2 },
+        );
+        // but extending it by 2 fits exactly
+        let pointer = unsafe { heap.try_alloc(4) }.unwrap();
+        assert_eq!(pointer.to_idx().to_usize(), 6);
+        assert_eq!(heap.stats().total_slots, 10);
+    }
+
+    pub fn slots(values: &[u64]) -> Vec<Slot> {
+        // SAFETY: only ever read back as naturals
+        values.iter().map(|v| unsafe { Slot::from_bits(*v) }).collect()
+    }
+
+    pub fn read_u64s(heap: &Heap, pointer: Pointer, slots: usize) -> Vec<u64> {
+        // SAFETY: only ever written as naturals
+        heap.read(pointer, slots).iter().map(|s| unsafe { s.to_u64() }).collect()
+    }
+
+    #[test]
+    pub fn write_unique_in_place() {
+        let mut heap = Heap::new();
+        // SAFETY: immediately written
+        let pointer = unsafe { heap.alloc(3) };
+        let pointer = heap.write(pointer, &slots(&[1, 2, 3]));
+        let written = heap.write(pointer, &slots(&[4, 5]));
+
+        assert_eq!(written.to_idx(), pointer.to_idx());
+        assert_eq!(read_u64s(&heap, written, 3), vec![4, 5, 3]);
+        assert_eq!(heap.stats().total_slots, 3);
+    }
+
+    #[test]
+    pub fn write_shared_copies() {
+        let mut heap = Heap::new();
+        // SAFETY: immediately written
+        let original = unsafe { heap.alloc(3) };
+        let original = heap.write(original, &slots(&[1, 2, 3]));
+
+        // writing through a borrow copies, leaving the owner be
+        let copy = heap.write(original.borrow(), &slots(&[7]));
+        assert!(copy.is_owned());
+        assert_ne!(copy.to_idx(), original.to_idx());
+        assert_eq!(read_u64s(&heap, copy, 3), vec![7, 2, 3]);
+        assert_eq!(read_u64s(&heap, original, 3), vec![1, 2, 3]);
+
+        // writing through a shared owner copies and gives up its share
+        let other = heap.share(original);
+        assert_eq!(heap.ref_count(original), 2);
+        let other = heap.write(other, &slots(&[8, 9]));
+        assert_ne!(other.to_idx(), original.to_idx());
+        assert_eq!(heap.ref_count(original), 1);
+        assert_eq!(read_u64s(&heap, other, 3), vec![8, 9, 3]);
+        assert_eq!(read_u64s(&heap, original, 3), vec![1, 2, 3]);
+
+        // now the last owner, so writes go in place
+        assert_eq!(heap.write(original, &slots(&[0])).to_idx(), original.to_idx());
+    }
+
+    #[test]
+    pub fn tagged_pointers_index_same_slot() {
+        let mut heap = Heap::new();
+        // SAFETY: immediately written
+        let _ = unsafe { heap.alloc(2) };
+        let pointer = unsafe { heap.alloc(3) };
+        let pointer = heap.write(pointer, &slots(&[1, 2, 3]));
+
+        let borrowed = pointer.with_owned(false);
+        let retagged = Pointer::tagged(pointer.idx(), true);
+        assert_eq!(pointer.idx(), 2);
+        for slot in 0..3 {
+            let expected = unsafe { heap.read_slot(pointer, slot).to_u64() };
+            assert_eq!(unsafe { heap.read_slot(borrowed, slot).to_u64() }, expected);
+            assert_eq!(unsafe { heap.read_slot(retagged, slot).to_u64() }, expected);
+        }
+        assert_eq!(heap.size_of(borrowed), Some(3));
+    }
+
+    #[test]
+    pub fn alloc_aligned_is_aligned() {
+        let mut heap = Heap::new();
+        let mut pointers = vec![];
+        let mut rng = attorand::Rng::new_default();
+
+        for _ in 0..STRESS_ITER {
+            let size = random_alloc_size(&mut rng);
+            let align = 1 << rng.next_u64_max(6);
+            // SAFETY: data is never read
+            let pointer = unsafe { heap.alloc_aligned(size, align) };
+            assert_eq!(pointer.idx() % align as u64, 0);
+            assert_eq!(heap.size_of(pointer), Some(size));
+            pointers.push((pointer, size));
+
+            if rng.next_bool() {
+                let index = rng.next_u64_max((pointers.len() - 1) as u64) as usize;
+                let (pointer, size) = pointers.swap_remove(index);
+                heap.free(pointer, size);
+            }
+            assert_eq!(heap.data.len(), heap.free.capacity);
+        }
+
+        // padding is returned to the heap, not leaked
+        let live: usize = pointers.iter().map(|(_, size)| size).sum();
+        assert_eq!(live + heap.stats().free_slots, heap.free.capacity);
+    }
+
+    #[test]
+    pub fn alloc_aligned_by_one_is_alloc() {
+        let mut aligned = Heap::new();
+        let mut plain = Heap::new();
+        for size in [3, 1, 4, 1, 5] {
+            // SAFETY: data is never read
+            let a = unsafe { aligned.alloc_aligned(size, 1) };
+            let b = unsafe { plain.alloc(size) };
+            assert_eq!(a.idx(), b.idx());
+        }
+        assert_eq!(aligned.stats(), plain.stats());
+    }
+
+    #[test]
+    pub fn alloc_aligned_follows_heap_settings() {
+        // a capped heap errors instead of growing past its maximum
+        let mut heap = Heap::new().with_max_capacity(8);
+        let _first = heap.calloc(1);
+        // SAFETY: data is never read
+        assert!(unsafe { heap.try_alloc_aligned(4, 16) }.is_err());
+        assert_eq!(heap.capacity(), 1);
+        let pointer = unsafe { heap.try_alloc_aligned(4, 4) }.unwrap();
+        assert_eq!(pointer.idx(), 4);
+        assert_eq!(heap.capacity(), 8);
+
+        // the pointer between the guards is the one that is aligned
+        let mut heap = Heap::new().with_guards(true);
+        let _first = heap.calloc(1);
+        let pointer = unsafe { heap.alloc_aligned(2, 8) };
+        assert_eq!(pointer.idx(), 8);
+        assert!(heap.is_guarded(pointer));
+        assert!(heap.try_free(pointer, 2).is_ok());
+
+        // and growth follows the growth policy
+        let mut heap = Heap::new().with_growth_policy(GrowthPolicy::Chunked(32));
+        let pointer = unsafe { heap.alloc_aligned(3, 4) };
+        assert_eq!(pointer.idx(), 0);
+        assert_eq!(heap.capacity(), 32);
+    }
+
+    #[test]
+    #[should_panic(expected = "alignment must be a power of two")]
+    pub fn alloc_aligned_rejects_non_power_of_two() {
+        let mut heap = Heap::new();
+        // SAFETY: data is never read
+        unsafe { heap.alloc_aligned(4, 3) };
+    }
+
+    #[test]
+    pub fn compact_removes_gaps() {
+        let mut heap = Heap::new();
+        let mut blocks = vec![];
+        for (i, size) in [3, 5, 2, 7, 1, 4, 6].iter().enumerate() {
+            // SAFETY: immediately written
+            let pointer = unsafe { heap.alloc(*size) };
+            let values: Vec<u64> = (0..*size).map(|s| (i * 100 + s) as u64).collect();
+            let pointer = heap.write(pointer, &slots(&values));
+            blocks.push((pointer, values));
+        }
+
+        // free every other block, leaving holes
+        let (freed, kept): (Vec<_>, Vec<_>) = blocks.into_iter()
+            .enumerate()
+            .partition(|(i, _)| i % 2 == 1);
+        for (_, (pointer, values)) in freed.iter() {
+            heap.free(*pointer, values.len());
+        }
+        assert_eq!(heap.stats().disjoint_free_ranges, 3);
+
+        let relocations = heap.compact();
+        let stats = heap.stats();
+        assert_eq!(stats.disjoint_free_ranges, 0);
+        assert_eq!(stats.free_slots, 0);
+        assert_eq!(stats.total_slots, 3 + 2 + 1 + 6);
+
+        // the first block never moves, the rest do
+        assert_eq!(relocations.len(), kept.len() - 1);
+        for (_, (pointer, values)) in kept.iter() {
+            let pointer = relocations.get(pointer).copied().unwrap_or(*pointer);
+            assert_eq!(heap.size_of(pointer), Some(values.len()));
+            assert_eq!(&read_u64s(&heap, pointer, values.len()), values);
+        }
+    }
+
+    #[test]
+    pub fn realloc_slides_back() {
+        let mut heap = Heap::new();
+        // SAFETY: immediately written
+        let free = unsafe { heap.alloc(2) };
+        let pointer = unsafe { heap.alloc(3) };
+        let pointer = heap.write(pointer, &slots(&[1, 2, 3]));
+        let _used = unsafe { heap.alloc(4) };
+        heap.free(free, 2);
+
+        // [free 2][alloc 3][used 4] -> [alloc 5][used 4]
+        let moved = unsafe { heap.realloc(pointer, 3, 5) };
+        assert!(moved.idx() < pointer.idx());
+        assert_eq!(moved.idx(), 0);
+        assert_eq!(heap.size_of(moved), Some(5));
+        assert_eq!(heap.size_of(pointer), None);
+        assert_eq!(read_u64s(&heap, moved, 3), vec![1, 2, 3]);
+
+        let stats = heap.stats();
+        assert_eq!(stats.total_slots, 9);
+        assert_eq!(stats.free_slots, 0);
+    }
+
+    #[test]
+    pub fn realloc_grows_tail_in_place() {
+        let mut heap = Heap::new();
+        // SAFETY: immediately written
+        let _ = unsafe { heap.alloc(4) };
+        let pointer = unsafe { heap.alloc(3) };
+        let pointer = heap.write(pointer, &slots(&[1, 2, 3]));
+
+        let grown = unsafe { heap.realloc(pointer, 3, 8) };
+        assert_eq!(grown.idx(), pointer.idx());
+        assert_eq!(heap.size_of(grown), Some(8));
+        assert_eq!(heap.stats().total_slots, 12);
+        assert_eq!(read_u64s(&heap, grown, 3), vec![1, 2, 3]);
+
+        // a partially free tail range is extended by just the shortfall
+        heap.reserve(2);
+        let grown = unsafe { heap.realloc(grown, 8, 13) };
+        assert_eq!(grown.idx(), pointer.idx());
+        assert_eq!(heap.stats().total_slots, 17);
+        assert_eq!(heap.stats().free_slots, 0);
+    }
+
+    #[test]
+    pub fn zero_on_free_wipes_freed_slots() {
+        for zero_on_free in [false, true] {
+            let mut heap = Heap::new().with_zero_on_free(zero_on_free);
+            // SAFETY: immediately written
+            let secret = unsafe { heap.alloc(3) };
+            let secret = heap.write(secret, &slots(&[0xAA, 0xBB, 0xCC]));
+            let _ = unsafe { heap.alloc(1) };
+            heap.free(secret, 3);
+
+            // SAFETY: reading the stale data is the point
+            let reused = unsafe { heap.alloc(3) };
+            assert_eq!(reused.idx(), secret.idx());
+            let expected = if zero_on_free { vec![0, 0, 0] } else { vec![0xAA, 0xBB, 0xCC] };
+            assert_eq!(read_u64s(&heap, reused, 3), expected);
+        }
+    }
+
+    #[test]
+    pub fn zero_on_free_wipes_moved_slots() {
+        let mut heap = Heap::new().with_zero_on_free(true);
+        // SAFETY: immediately written
+        let secret = unsafe { heap.alloc(2) };
+        let secret = heap.write(secret, &slots(&[0xAA, 0xBB]));
+        let _ = unsafe { heap.alloc(1) };
+
+        // no room to grow in place, so the allocation moves to the end
+        let moved = unsafe { heap.realloc(secret, 2, 4) };
+        assert_ne!(moved.idx(), secret.idx());
+        assert_eq!(read_u64s(&heap, moved, 2), vec![0xAA, 0xBB]);
+        let reused = unsafe { heap.alloc(2) };
+        assert_eq!(reused.idx(), secret.idx());
+        assert_eq!(read_u64s(&heap, reused, 2), vec![0, 0]);
+    }
+
+    #[test]
+    pub fn calloc_reads_zero() {
+        let mut heap = Heap::new();
+        // SAFETY: immediately written
+        let stale = unsafe { heap.alloc(3) };
+        let stale = heap.write(stale, &slots(&[1, 2, 3]));
+        let _ = unsafe { heap.alloc(1) };
+        heap.free(stale, 3);
+
+        let reused = heap.calloc(3);
+        assert_eq!(reused.idx(), stale.idx());
+        assert_eq!(read_u64s(&heap, reused, 3), vec![0, 0, 0]);
+
+        // freshly grown slots are zero too
+        let fresh = heap.calloc(5);
+        assert_eq!(fresh.idx(), 4);
+        assert_eq!(read_u64s(&heap, fresh, 5), vec![0; 5]);
+    }
+
+    #[test]
+    pub fn generation_checks_catch_double_free() {
+        let mut heap = Heap::new().with_generation_checks(true);
+        // SAFETY: data is never read
+        let a = unsafe { heap.alloc(3) };
+        let _ = unsafe { heap.alloc(1) };
+        assert_eq!(heap.try_free(a, 3), Ok(()));
+        assert_eq!(heap.try_free(a, 3), Err(HeapError::DoubleFree));
+
+        // the same slot reused by a new allocation
+        let b = unsafe { heap.alloc(3) };
+        assert_eq!(b.idx(), a.idx());
+        assert_ne!(b.generation(), a.generation());
+        assert_eq!(heap.try_free(a, 3), Err(HeapError::DoubleFree));
+        assert_eq!(heap.try_free(b, 3), Ok(()));
+    }
+
+    #[test]
+    pub fn generation_checks_catch_use_after_free() {
+        let mut heap = Heap::new().with_generation_checks(true);
+        // SAFETY: immediately written
+        let a = unsafe { heap.alloc(2) };
+        let a = heap.write(a, &slots(&[1, 2]));
+        let _ = unsafe { heap.alloc(1) };
+        assert!(heap.try_read(a, 2).is_ok());
+        assert!(heap.try_read(a.borrow().offset(1), 1).is_ok());
+
+        heap.free(a, 2);
+        assert_eq!(heap.try_read(a, 2).unwrap_err(), HeapError::UseAfterFree);
+
+        let b = heap.calloc(2);
+        assert_eq!(b.idx(), a.idx());
+        assert_eq!(heap.try_read(a, 2).unwrap_err(), HeapError::UseAfterFree);
+        assert!(heap.try_read(b, 2).is_ok());
+    }
+
+    #[test]
+    pub fn generation_checks_survive_stress() {
+        let mut heap = Heap::new().with_generation_checks(true);
+        stress_heap(&mut heap);
+    }
+
+    #[test]
+    pub fn reserve_merges_with_free_tail() {
+        let mut heap = Heap::new();
+        // SAFETY: data is never read
+        let a = unsafe { heap.alloc(2) };
+        let _ = unsafe { heap.alloc(3) };
+        heap.free(a, 2);
+        heap.reserve(4);
+        let before = heap.stats();
+        assert_eq!(before.disjoint_free_ranges, 2);
+        assert_eq!(before.total_slots, 9);
+
+        heap.reserve(6);
+        let after = heap.stats();
+        assert_eq!(after.disjoint_free_ranges, before.disjoint_free_ranges);
+        assert_eq!(after.free_slots, 2 + 4 + 6);
+        assert_eq!(after.total_slots, 15);
+
+        // reserved slots are used before growing
+        let b = unsafe { heap.alloc(10) };
+        assert_eq!(b.idx(), 5);
+        assert_eq!(heap.stats().total_slots, 15);
+    }
+
+    #[test]
+    pub fn shrink_to_fit_releases_free_tail() {
+        let mut heap = Heap::new();
+        // SAFETY: data is never read
+        let _ = unsafe { heap.alloc(2) };
+        let middle = unsafe { heap.alloc(3) };
+        let last = unsafe { heap.alloc(2) };
+        let tail = unsafe { heap.alloc(4) };
+        heap.free(middle, 3);
+        heap.reserve(5);
+        heap.free(tail, 4);
+        assert_eq!(heap.data.len(), 7);
+
+        heap.reserve(5);
+        heap.shrink_to_fit();
+        assert_eq!(heap.data.len(), last.idx() as usize + 2);
+        let free: Vec<_> = heap.free.iter_free().map(|(p, s)| (p.idx(), s)).collect();
+        assert_eq!(free, vec![(2, 3)]);
+
+        // nothing left to shrink
+        heap.shrink_to_fit();
+        assert_eq!(heap.data.len(), 7);
+    }
+
+    #[test]
+    pub fn free_many_merges_adjacent() {
+        let mut heap = Heap::new();
+        // SAFETY: data is never read
+        let _ = unsafe { heap.alloc(1) };
+        let blocks: Vec<_> = [2, 3, 4].iter().map(|s| (unsafe { heap.alloc(*s) }, *s)).collect();
+        let _ = unsafe { heap.alloc(1) };
+
+        // out of order on purpose
+        heap.free_many(&[blocks[2], blocks[0], blocks[1]]);
+        let free: Vec<_> = heap.free.iter_free().map(|(p, s)| (p.idx(), s)).collect();
+        assert_eq!(free, vec![(1, 9)]);
+        assert_eq!(heap.stats().total_slots, 11);
+    }
+
+    #[test]
+    pub fn free_many_shrinks_tail_once() {
+        let mut heap = Heap::new();
+        // SAFETY: data is never read
+        let _ = unsafe { heap.alloc(1) };
+        let blocks: Vec<_> = [2, 3, 4].iter().map(|s| (unsafe { heap.alloc(*s) }, *s)).collect();
+
+        heap.free_many(&blocks);
+        assert_eq!(heap.stats().total_slots, 1);
+        assert_eq!(heap.stats().disjoint_free_ranges, 0);
+    }
+
+    #[test]
+    pub fn clone_range_is_independent() {
+        let mut heap = Heap::new();
+        // SAFETY: immediately written
+        let original = unsafe { heap.alloc(4) };
+        let original = heap.write(original, &slots(&[1, 2, 3, 4]));
+
+        let clone = heap.clone_range(original, 4);
+        assert_ne!(clone.idx(), original.idx());
+        assert_eq!(heap.size_of(clone), Some(4));
+
+        let original = heap.write(original, &slots(&[9, 9]));
+        assert_eq!(read_u64s(&heap, original, 4), vec![9, 9, 3, 4]);
+        assert_eq!(read_u64s(&heap, clone, 4), vec![1, 2, 3, 4]);
+
+        // a sub-range can be cloned too
+        let part = heap.clone_range(original.offset(2), 2);
+        assert_eq!(read_u64s(&heap, part, 2), vec![3, 4]);
+    }
+
+    /// Mirrors the example on [`Heap::free_span_at`].
+    #[test]
+    pub fn free_span_queries() {
+        let mut heap = Heap::new();
+        // SAFETY: data is never read
+        let a = unsafe { heap.alloc(2) };
+        let b = unsafe { heap.alloc(4) };
+        let _ = unsafe { heap.alloc(1) };
+        heap.free(b, 4);
+
+        let (start, size) = heap.free_span_at(b.offset(1)).unwrap();
+        assert_eq!((start.idx(), size), (2, 4));
+        assert_eq!(heap.free_span_at(b).unwrap().0.idx(), 2);
+        assert_eq!(heap.free_span_at(b.offset(3)).unwrap().0.idx(), 2);
+        assert_eq!(heap.free_span_at(a.offset(1)), None);
+        assert_eq!(heap.free_span_at(b.offset(4)), None);
+
+        assert!(heap.free_at(b, 4));
+        assert!(heap.free_at(b.offset(1), 3));
+        assert!(!heap.free_at(b, 5));
+        assert!(!heap.free_at(a, 1));
+        assert!(!heap.free_at(b.offset(5), 1));
+    }
+
+    #[test]
+    pub fn read_mut_writes_in_place() {
+        let mut heap = Heap::new();
+        let pointer = heap.calloc(4);
+        let values = slots(&[0, 10, 20, 30]);
+        for (slot, value) in heap.read_mut(pointer, 4).iter_mut().zip(values) {
+            *slot = value;
+        }
+        assert_eq!(read_u64s(&heap, pointer, 4), vec![0, 10, 20, 30]);
+    }
+
+    #[test]
+    #[should_panic(expected = "is the size stale?")]
+    pub fn read_mut_past_the_end() {
+        let mut heap = Heap::new();
+        let pointer = heap.calloc(4);
+        heap.read_mut(pointer, 5);
+    }
+
+    #[test]
+    pub fn typed_reads_round_trip() {
+        let mut heap = Heap::new();
+        let floats = vec![1.5, -0.25, f64::INFINITY, 1e-300];
+        let pointer = heap.calloc(5);
+        let pointer = heap.write_as(pointer, &floats);
+        // SAFETY: written as floats
+        assert_eq!(unsafe { heap.read_as::<f64>(pointer, 4) }, floats);
+        // SAFETY: any bits are a natural
+        assert_eq!(unsafe { heap.read_as::<u64>(pointer, 1) }, vec![1.5_f64.to_bits()]);
+
+        let pairs = [[1, 2], [3, 4]];
+        let pointer = heap.write_as(pointer, &pairs);
+        // SAFETY: written as naturals
+        assert_eq!(unsafe { heap.read_as::<[u64; 2]>(pointer, 2) }, pairs.to_vec());
+        let pointer = heap.write_as(pointer, &[-7_i64]);
+        // SAFETY: written as integers
+        assert_eq!(unsafe { heap.read_as::<i64>(pointer, 1) }, vec![-7]);
+    }
+
+    #[test]
+    #[should_panic(expected = "read of 3 values of 2 slots from allocation of 5 slots")]
+    pub fn typed_reads_past_the_block() {
+        let mut heap = Heap::new();
+        let pointer = heap.calloc(5);
+        let _other = heap.calloc(3);
+        // SAFETY: zeroed slots are naturals
+        let _ = unsafe { heap.read_as::<[u64; 2]>(pointer, 3) };
+    }
+
+    #[test]
+    pub fn write_slot_reads_back() {
+        let mut heap = Heap::new();
+        let pointer = heap.calloc(3);
+        for (slot, value) in slots(&[7, 8, 9]).into_iter().enumerate().rev() {
+            heap.write_slot(pointer, slot, value);
+        }
+        for (slot, value) in [7, 8, 9].iter().enumerate() {
+            // SAFETY: only ever written as naturals
+            assert_eq!(unsafe { heap.read_slot(pointer, slot).to_u64() }, *value);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "out of bounds")]
+    pub fn write_slot_past_the_block() {
+        let mut heap = Heap::new();
+        let pointer = heap.calloc(3);
+        let _other = heap.calloc(3);
+        heap.write_slot(pointer, 3, slots(&[1]).pop().unwrap());
+    }
+
+    #[test]
+    pub fn render_ascii_extremes() {
+        let mut heap = Heap::new();
+        heap.reserve(100);
+        assert_eq!(heap.render_ascii(16), "-".repeat(16));
+        assert_eq!(heap.render_ascii(300), "-".repeat(300));
+
+        let _pointer = heap.calloc(100);
+        assert_eq!(heap.render_ascii(16), "#".repeat(16));
+        assert_eq!(heap.render_ascii(300), "#".repeat(300));
+    }
+
+    #[test]
+    pub fn render_ascii_gradient() {
+        let mut heap = Heap::new();
+        let pointers: Vec<_> = [4, 1, 3, 4].iter().map(|size| heap.calloc(*size)).collect();
+        heap.free(pointers[0], 4);
+        heap.free(pointers[2], 3);
+        // columns of four slots: all free, one in four allocated, all allocated
+        assert_eq!(heap.render_ascii(3), "-:#");
+    }
+
+    #[test]
+    pub fn retain_release_balance() {
+        let mut heap = Heap::new();
+        let pointer = heap.calloc(3);
+        let _end = heap.calloc(1);
+        assert_eq!(heap.ref_count(pointer), 1);
+
+        for _ in 0..3 { heap.retain(pointer); }
+        assert_eq!(heap.ref_count(pointer), 4);
+        for expected in [3, 2, 1] {
+            assert!(!heap.release(pointer, 3));
+            assert_eq!(heap.ref_count(pointer), expected);
+            assert_eq!(heap.size_of(pointer), Some(3));
+        }
+        assert!(heap.refs.is_empty());
+    }
+
+    #[test]
+    pub fn release_frees_at_zero() {
+        let mut heap = Heap::new();
+        let pointer = heap.calloc(3);
+        let _end = heap.calloc(1);
+        heap.retain(pointer);
+
+        assert!(!heap.release(pointer, 3));
+        assert!(heap.release(pointer, 3));
+        assert_eq!(heap.size_of(pointer), None);
+        assert!(heap.free_at(pointer, 3));
+    }
+
+    #[test]
+    pub fn large_align_applies_above_threshold() {
+        let mut heap = Heap::new().with_large_align(32, 64);
+        let small = heap.calloc(3);
+        let large = heap.calloc(40);
+        let small_after = heap.calloc(2);
+        assert_eq!(small.idx(), 0);
+        assert_eq!(large.idx(), 64);
+        // the padding went back to the free list, and small blocks are not aligned
+        assert_eq!(small_after.idx(), 3);
+        assert_eq!(heap.free.iter_free().next().map(|(p, s)| (p.idx(), s)), Some((5, 59)));
+        assert_eq!(heap.stats().total_slots, 104);
+
+        // a reused range is zeroed by calloc, a fresh one is grown
+        heap.write(large, &slots(&[1; 40]));
+        heap.free(large, 40);
+        let again = heap.calloc(40);
+        assert_eq!(again.idx(), 64);
+        assert_eq!(read_u64s(&heap, again, 40), vec![0; 40]);
+    }
+
+    #[test]
+    pub fn used_and_free_make_capacity() {
+        let heap = Heap::new();
+        assert!(heap.is_empty());
+        assert_eq!(heap.capacity(), 0);
+
+        let mut heap = run_stress(38, STRESS_ITER / 4);
+        heap.reserve(10);
+        assert!(!heap.is_empty());
+        assert_eq!(heap.used() + heap.stats().free_slots, heap.capacity());
+        assert_eq!(heap.capacity(), heap.stats().total_slots);
+
+        let live: usize = heap.free.live.values().sum();
+        assert_eq!(heap.used(), live);
+    }
+
+    #[test]
+    pub fn guards_catch_overrun() {
+        let mut heap = Heap::new().with_guards(true);
+        let a = heap.calloc(3);
+        let b = heap.calloc(2);
+        assert_eq!(a.idx(), 1);
+        assert_eq!(heap.size_of(a), Some(3));
+
+        // one past the end of `a` is its trailing guard
+        heap.read_mut(a, 4)[3] = slots(&[7]).pop().unwrap();
+        assert_eq!(heap.try_free(a, 3), Err(HeapError::Overrun));
+        assert_eq!(heap.size_of(a), Some(3));
+
+        // an intact allocation frees cleanly
+        heap.try_free(b, 2).unwrap();
+        assert_eq!(heap.size_of(b), None);
+    }
+
+    #[test]
+    pub fn guards_catch_wrong_sizes() {
+        let mut heap = Heap::new().with_guards(true);
+        let a = heap.calloc(3);
+        let b = heap.calloc(2);
+
+        // neither too few nor too many slots are freed
+        assert_eq!(heap.try_free(a, 2), Err(HeapError::SizeMismatch { freed: 2, allocated: 3 }));
+        assert_eq!(heap.try_free(a, 5), Err(HeapError::SizeMismatch { freed: 5, allocated: 3 }));
+        assert_eq!(heap.size_of(a), Some(3));
+        assert_eq!(heap.size_of(b), Some(2));
+
+        // the guards are checked at the real size, even if the size passed is wrong
+        heap.read_mut(a, 4)[3] = slots(&[7]).pop().unwrap();
+        assert_eq!(heap.try_free(a, 1), Err(HeapError::Overrun));
+        heap.try_free(b, 2).unwrap();
+    }
+
+    #[test]
+    pub fn guards_survive_stress() {
+        let mut heap = Heap::new().with_guards(true).with_generation_checks(true);
+        stress_heap(&mut heap);
+        for (start, slots) in heap.free.live.iter() {
+            assert!(heap.guards_intact(Pointer::new(*start), slots - 2));
+        }
+    }
+
+    /// Returns how many times allocating one slot at a time grows the heap.
+    fn count_growths(mut heap: Heap, allocations: usize) -> usize {
+        let mut growths = 0;
+        for _ in 0..allocations {
+            let before = heap.data.len();
+            heap.calloc(1);
+            if heap.data.len() != before { growths += 1; }
+        }
+        growths
+    }
+
+    #[test]
+    pub fn growth_policies_grow_less() {
+        assert_eq!(count_growths(Heap::new(), 1000), 1000);
+        assert_eq!(count_growths(Heap::new().with_growth_policy(GrowthPolicy::Doubling), 1000), 11);
+        assert_eq!(count_growths(Heap::new().with_growth_policy(GrowthPolicy::Chunked(64)), 1000), 16);
+
+        // the extra is free and can be reclaimed
+        let mut heap = Heap::new().with_growth_policy(GrowthPolicy::Doubling);
+        for _ in 0..5 { heap.calloc(1); }
+        assert_eq!(heap.capacity(), 8);
+        assert_eq!(heap.used(), 5);
+        heap.shrink_to_fit();
+        assert_eq!(heap.capacity(), 5);
+    }
+
+    #[test]
+    pub fn growth_respects_max_capacity() {
+        let mut heap = Heap::new()
+            .with_growth_policy(GrowthPolicy::Chunked(100))
+            .with_max_capacity(10);
+        heap.calloc(4);
+        assert_eq!(heap.capacity(), 10);
+        heap.calloc(6);
+        // SAFETY: data is never read
+        assert!(unsafe { heap.try_alloc(1) }.is_err());
+    }
+
+    #[test]
+    pub fn live_allocations_have_labels() {
+        let label = |label| if cfg!(feature = "debug_alloc") { label } else { "unlabeled" };
+        let mut heap = Heap::new();
+        let a = heap.alloc_labeled(2, "a");
+        let b = heap.alloc_labeled(3, "b");
+        let c = heap.calloc(1);
+        let d = heap.alloc_labeled(4, "d");
+        heap.free(b, 3);
+
+        // labels follow allocations that move
+        // SAFETY: data is never read
+        let a = unsafe { heap.realloc(a, 2, 5) };
+        assert_eq!(a.idx(), 0);
+        let d = unsafe { heap.realloc(d, 4, 8) };
+
+        assert_eq!(heap.live_allocations(), vec![
+            (a, 5, label("a")),
+            (c, 1, "unlabeled"),
+            (d, 8, label("d")),
+        ]);
+    }
+
+    #[test]
+    pub fn iter_live_yields_survivors() {
+        let mut heap = Heap::new().with_generation_checks(true);
+        let pointers: Vec<_> = [2, 7, 1, 3, 5].iter().map(|size| (heap.calloc(*size), *size)).collect();
+        heap.free(pointers[1].0, 7);
+        heap.free(pointers[4].0, 5);
+        // reuses part of the freed range, with a new generation
+        let reused = heap.calloc(4);
+        assert_eq!(reused.idx(), 2);
+
+        let live: Vec<_> = heap.iter_live().collect();
+        assert_eq!(live, vec![pointers[0], (reused, 4), pointers[2], pointers[3]]);
+        assert!(live.iter().all(|(pointer, slots)| heap.size_of(*pointer) == Some(*slots)));
+
+        let mut guarded = Heap::new().with_guards(true);
+        let a = guarded.calloc(3);
+        let b = guarded.calloc(1);
+        assert_eq!(guarded.iter_live().collect::<Vec<_>>(), vec![(a, 3), (b, 1)]);
+    }
+
+    #[test]
+    pub fn split_updates_both_sizes() {
+        let mut heap = Heap::new();
+        let pointer = heap.calloc(6);
+        heap.write(pointer, &slots(&[0, 1, 2, 3, 4, 5]));
+        let (a, b) = heap.split(pointer, 2);
+        assert_eq!((a.idx(), b.idx()), (0, 2));
+        assert_eq!(heap.size_of(a), Some(2));
+        assert_eq!(heap.size_of(b), Some(4));
+        assert_eq!(read_u64s(&heap, b, 4), vec![2, 3, 4, 5]);
+        assert_eq!(heap.used(), 6);
+
+        // the halves are freed on their own
+        heap.free(a, 2);
+        assert!(heap.free_at(a, 2));
+        assert_eq!(read_u64s(&heap, b, 4), vec![2, 3, 4, 5]);
+        assert_eq!(heap.free.audit(), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "both halves")]
+    pub fn split_at_end_panics() {
+        let mut heap = Heap::new();
+        let pointer = heap.calloc(3);
+        heap.split(pointer, 3);
+    }
+
+    #[test]
+    pub fn merge_needs_adjacent_allocations() {
+        let mut heap = Heap::new().with_generation_checks(true);
+        let a = heap.calloc(2);
+        let b = heap.calloc(3);
+        let c = heap.calloc(1);
+        assert_eq!(heap.merge(a, c), None);
+        assert_eq!(heap.merge(b, a), None);
+        assert_eq!(heap.size_of(a), Some(2));
+
+        let merged = heap.merge(a, b).unwrap();
+        assert_eq!(merged, a);
+        assert_eq!(heap.size_of(a), Some(5));
+        assert_eq!(heap.size_of(b), None);
+        assert_eq!(heap.free.audit(), Ok(()));
+
+        // split and merge undo each other
+        let (first, second) = heap.split(merged, 4);
+        assert_eq!(heap.merge(first, second), Some(a));
+        assert_eq!(heap.live_allocations().len(), 2);
+    }
+
+    #[test]
+    pub fn merge_keeps_owners_apart() {
+        let mut heap = Heap::new();
+        let a = heap.calloc(2);
+        let b = heap.calloc(2);
+        heap.retain(b);
+        assert_eq!(heap.merge(a, b), None);
+        heap.retain(a);
+        assert_eq!(heap.merge(a, b), Some(a));
+        assert_eq!(heap.ref_count(a), 2);
+    }
+
+    #[test]
+    pub fn stats_empty_heap() {
+        let heap = Heap::new();
+        assert_eq!(heap.stats(), HeapStats {
+            heap_bytes:           0,
+            total_slots:          0,
+            disjoint_free_ranges: 0,
+            free_slots:           0,
+            fragmentation_ratio:  0.0,
+            internal_slack:       0,
+        });
+    }
+
+    #[test]
+    pub fn stats_fragmented_heap() {
+        let mut heap = Heap::new();
+        // SAFETY: data is never read
+        let pointers: Vec<_> = (0..4).map(|_| unsafe { heap.alloc(2) }).collect();
+        heap.free(pointers[1], 2);
+
+        assert_eq!(heap.stats(), HeapStats {
+            heap_bytes:           8 * 8,
+            total_slots:          8,
+            disjoint_free_ranges: 1,
+            free_slots:           2,
+            fragmentation_ratio:  0.25,
+            internal_slack:       0,
+        });
+    }
+
+    #[test]
+    pub fn stress_test_native() {
+        let mut rng = attorand::Rng::new_default();
+        let mut pointers = BTreeMap::new();
+
+        for i in 0..STRESS_ITER {
+            let size = random_alloc_size(&mut rng);
+            let pointer = vec![0; size];
+            pointers.insert(i, (pointer, size));
+
+            let index = rng.next_u64_max((pointers.len() - 1) as u64) as usize;
+            if rng.next_bool() {
+                let (index, _) = pointers.iter().nth(index).unwrap();
+                let index = *index;
+
+                if rng.next_bool() {
+                    let new_size = random_alloc_size(&mut rng);
+                    let pointer = vec![0; new_size];
+                    pointers.insert(index, (pointer, new_size));
+                } else {
+                    pointers.remove(&index);
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn observer_sees_every_event() {
+        use std::sync::{Arc, Mutex};
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut heap = Heap::new();
+        let recorded = events.clone();
+        heap.set_observer(Box::new(move |event| recorded.lock().unwrap().push(event)));
+
+        let a = heap.calloc(2);
+        let b = heap.calloc(1);
+        // b is in the way, so growing a moves it
+        let moved = unsafe { heap.realloc(a, 2, 4) };
+        let shrunk = unsafe { heap.realloc(moved, 4, 3) };
+        heap.free(b, 1);
+        heap.free(shrunk, 3);
+
+        assert_ne!(moved.idx(), a.idx());
+        assert_eq!(shrunk, moved);
+        assert_eq!(*events.lock().unwrap(), vec![
+            AllocEvent::Alloc   { pointer: a, slots: 2 },
+            AllocEvent::Alloc   { pointer: b, slots: 1 },
+            AllocEvent::Realloc { old: a, new: moved, old_slots: 2, new_slots: 4 },
+            AllocEvent::Realloc { old: moved, new: moved, old_slots: 4, new_slots: 3 },
+            AllocEvent::Free    { pointer: b, slots: 1 },
+            AllocEvent::Free    { pointer: moved, slots: 3 },
+        ]);
+    }
+
+    #[test]
+    pub fn verify_pointer_only_accepts_live_starts() {
+        let mut heap = Heap::new().with_generation_checks(true);
+        let a = heap.calloc(3);
+        let b = heap.calloc(2);
+        assert!(heap.verify_pointer(a));
+        assert!(heap.verify_pointer(b.borrow()));
+
+        // interior and out of range pointers
+        assert!(!heap.verify_pointer(a.offset(1)));
+        assert!(!heap.verify_pointer(b.offset(2)));
+        assert!(!heap.verify_pointer(Pointer::tagged(1000, true)));
+
+        // freed pointers, even once the slots are reused
+        heap.free(a, 3);
+        assert!(!heap.verify_pointer(a));
+        let reused = heap.calloc(3);
+        assert_eq!(reused.idx(), a.idx());
+        assert!(heap.verify_pointer(reused));
+        assert!(!heap.verify_pointer(a));
+    }
+
+    #[test]
+    pub fn counters_follow_placement() {
+        let mut heap = Heap::new();
+        let a = heap.calloc(4);
+        let b = heap.calloc(2);
+        heap.free(a, 4);
+        // fits in the gap a left
+        let c = heap.calloc(3);
+        // b is in the way, and the gap after c is too small, so c moves past b
+        let moved = unsafe { heap.realloc(c, 3, 6) };
+        // the reserved slots at the tail are too few, so the tail is extended
+        heap.reserve(2);
+        let d = heap.calloc(5);
+        // shrinking never moves
+        let _ = unsafe { heap.realloc(d, 5, 3) };
+
+        assert_ne!(moved.idx(), c.idx());
+        assert_eq!(heap.counters(), AllocCounters {
+            reused_allocs:    1,
+            grown_allocs:     3,
+            tail_extends:     1,
+            moves_on_realloc: 1,
+        });
+
+        heap.reset_counters();
+        assert_eq!(heap.counters(), AllocCounters::default());
+        heap.free(b, 2);
+        let _ = heap.calloc(2);
+        assert_eq!(heap.counters().reused_allocs, 1);
+    }
+
+    #[test]
+    pub fn pressure_hook_avoids_growth() {
+        use std::sync::{Arc, Mutex};
+
+        let mut heap = Heap::new();
+        let garbage = heap.calloc(4);
+        let _live = heap.calloc(4);
+        let calls = Arc::new(Mutex::new(0));
+
+        // the garbage is only freed once the heap is about to grow past 8 slots
+        let counted = calls.clone();
+        let mut garbage = Some(garbage);
+        heap.set_pressure_hook(8, Box::new(move |heap| {
+            *counted.lock().unwrap() += 1;
+            match garbage.take() {
+                Some(garbage) => { heap.free(garbage, 4); PressureResponse::Retry },
+                None => PressureResponse::Grow,
+            }
+        }));
+
+        let reused = heap.calloc(4);
+        assert_eq!(reused.idx(), 0);
+        assert_eq!(heap.capacity(), 8);
+        assert_eq!(heap.counters().reused_allocs, 1);
+
+        // with nothing left to free, the heap grows
+        let grown = heap.calloc(2);
+        assert_eq!(grown.idx(), 8);
+        assert_eq!(*calls.lock().unwrap(), 2);
+
+        // allocations that fit never call the hook
+        heap.free(grown, 2);
+        let _ = heap.calloc(0);
+        assert_eq!(*calls.lock().unwrap(), 2);
+    }
+
+    #[test]
+    pub fn resolve_interior_finds_the_allocation() {
+        let mut heap = Heap::new();
+        let a = heap.calloc(3);
+        let b = heap.calloc(4);
+        let c = heap.calloc(1);
+        heap.free(b, 4);
+
+        assert_eq!(heap.resolve_interior(0), Some((a.borrow(), 0)));
+        assert_eq!(heap.resolve_interior(1), Some((a.borrow(), 1)));
+        assert_eq!(heap.resolve_interior(2), Some((a.borrow(), 2)));
+        assert_eq!(heap.resolve_interior(7), Some((c.borrow(), 0)));
+        // free space, and past the heap
+        assert_eq!(heap.resolve_interior(3), None);
+        assert_eq!(heap.resolve_interior(6), None);
+        assert_eq!(heap.resolve_interior(8), None);
+        assert_eq!(heap.resolve_interior(u64::MAX), None);
+
+        // guards are skipped
+        let mut heap = Heap::new().with_guards(true);
+        let a = heap.calloc(2);
+        assert_eq!(heap.resolve_interior(0), None);
+        assert_eq!(heap.resolve_interior(1), Some((a.borrow(), 0)));
+        assert_eq!(heap.resolve_interior(2), Some((a.borrow(), 1)));
+        assert_eq!(heap.resolve_interior(3), None);
+    }
+
+    #[test]
+    pub fn alloc_n_is_contiguous() {
+        let mut heap = Heap::new();
+        let pointers = heap.alloc_n(5, 3);
+        assert_eq!(pointers.len(), 5);
+        for (index, pointer) in pointers.iter().enumerate() {
+            assert_eq!(pointer.idx(), index as u64 * 3);
+            assert_eq!(heap.size_of(*pointer), Some(3));
+        }
+        assert_eq!(heap.capacity(), 15);
+        heap.free(pointers[2], 3);
+        heap.free.audit().unwrap();
+
+        // only two fit in the largest free range of a full heap, the rest are placed alone
+        let mut heap = Heap::new().with_max_capacity(8);
+        let gap = heap.calloc(4);
+        let _live = heap.calloc(1);
+        heap.free(gap, 4);
+        let pointers = heap.alloc_n(3, 2);
+        let starts: Vec<_> = pointers.iter().map(|pointer| pointer.idx()).collect();
+        assert_eq!(starts, vec![0, 2, 5]);
+        assert!(heap.alloc_n(0, 4).is_empty());
+    }
+
+    #[test]
+    pub fn grow_in_place_never_moves() {
+        let mut heap = Heap::new();
+        let a = heap.calloc(2);
+        let b = heap.calloc(2);
+        let c = heap.calloc(2);
+        heap.free(a, 2);
+
+        // b is hemmed in by c, and sliding back into a's slots would move it
+        assert_eq!(unsafe { heap.try_grow_in_place(b, 2, 3) }, Err(GrowError::WouldMove));
+        assert_eq!(heap.size_of(b), Some(2));
+        assert_eq!(heap.free.iter_free().next().map(|(p, s)| (p.idx(), s)), Some((0, 2)));
+
+        // c is at the tail, so it grows past the end
+        unsafe { heap.try_grow_in_place(c, 2, 5) }.unwrap();
+        assert_eq!(heap.size_of(c), Some(5));
+        unsafe { heap.try_grow_in_place(c, 5, 1) }.unwrap();
+        assert_eq!(heap.capacity(), 5);
+
+        // free slots after, but past the maximum capacity
+        let mut heap = Heap::new().with_max_capacity(4);
+        let a = heap.calloc(2);
+        let error = unsafe { heap.try_grow_in_place(a, 2, 6) }.unwrap_err();
+        assert!(matches!(error, GrowError::OutOfMemory(_)));
+        assert_eq!(heap.size_of(a), Some(2));
+    }
+
+    #[test]
+    pub fn free_subrange_punches_a_hole() {
+        let mut heap = Heap::new();
+        let block = heap.calloc(10);
+        let second = heap.free_subrange(block, 3, 4);
+
+        assert_eq!(second.idx(), 7);
+        assert_eq!(heap.size_of(block), Some(3));
+        assert_eq!(heap.size_of(second), Some(3));
+        let live: Vec<_> = heap.iter_live().map(|(p, s)| (p.idx(), s)).collect();
+        assert_eq!(live, vec![(0, 3), (7, 3)]);
+        let free: Vec<_> = heap.free.iter_free().map(|(p, s)| (p.idx(), s)).collect();
+        assert_eq!(free, vec![(3, 4)]);
+        heap.free.audit().unwrap();
+
+        // the gap merges once a neighbor is freed
+        heap.free(block, 3);
+        let free: Vec<_> = heap.free.iter_free().map(|(p, s)| (p.idx(), s)).collect();
+        assert_eq!(free, vec![(0, 7)]);
+        heap.free.audit().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "leaving slots on either side")]
+    pub fn free_subrange_must_leave_both_sides() {
+        let mut heap = Heap::new();
+        let block = heap.calloc(4);
+        heap.free_subrange(block, 1, 3);
+    }
+
+    #[test]
+    pub fn object_headers_describe_objects() {
+        let mut heap = Heap::new();
+        let object = heap.alloc_object(0xbeef, 3);
+        let empty = heap.alloc_object(u32::MAX, 0);
+
+        assert_eq!(heap.size_of(object), Some(4));
+        assert_eq!(heap.object_type(object), Some(0xbeef));
+        assert_eq!(heap.object_len(object), Some(3));
+        assert_eq!(unsafe { heap.read_slot(object, 0).to_u64() }, 0xbeef << 32 | 3);
+        assert!(heap.read(object, 4)[1..].iter().all(|slot| unsafe { slot.to_u64() } == 0));
+        assert_eq!((heap.object_type(empty), heap.object_len(empty)), (Some(u32::MAX), Some(0)));
+
+        // the payload does not disturb the header
+        heap.write_slot(object, 1, unsafe { Slot::from_bits(7) });
+        assert_eq!(heap.object_len(object), Some(3));
+        heap.free(object, 4);
+        assert_eq!(heap.object_type(object), None);
+    }
+
+    #[test]
+    pub fn compact_step_matches_compact() {
+        let fragmented = || {
+            let mut heap = Heap::new().with_generation_checks(true);
+            let mut blocks = vec![];
+            for (i, size) in [3, 5, 2, 7, 1, 4, 6, 2].iter().enumerate() {
+                let values: Vec<u64> = (0..*size).map(|s| (i * 100 + s) as u64).collect();
+                let pointer = heap.calloc(*size);
+                blocks.push((heap.write(pointer, &slots(&values)), *size));
+            }
+            for (pointer, size) in blocks.iter().skip(1).step_by(3) {
+                heap.free(*pointer, *size);
+            }
+            heap
+        };
+        let mut full = fragmented();
+        let relocations = full.compact();
+
+        let mut heap = fragmented();
+        let mut state = CompactState::new();
+        let mut steps = 0;
+        loop {
+            let before = state.relocations.clone();
+            let done = heap.compact_step(&mut state, 3);
+            let moved: Vec<usize> = state.relocations.iter()
+                .filter(|(old, _new)| !before.contains_key(old))
+                .map(|(_old, new)| heap.size_of(*new).unwrap())
+                .collect();
+            // each step only moves as much as its budget, or one larger allocation
+            assert!(moved.iter().sum::<usize>() <= 3 || moved.len() == 1);
+            heap.free.audit().unwrap();
+            steps += 1;
+            if done { break; }
+        }
+        assert!(steps > 2);
+        assert!(state.is_done());
+        assert!(heap.compact_step(&mut state, 3));
+
+        assert_eq!(state.relocations, relocations);
+        assert_eq!(heap.iter_live().collect::<Vec<_>>(), full.iter_live().collect::<Vec<_>>());
+        assert_eq!(heap.stats(), full.stats());
+        for (pointer, size) in full.iter_live() {
+            assert_eq!(read_u64s(&heap, pointer, size), read_u64s(&full, pointer, size));
+        }
+        // stale pointers are stale either way
+        for old in relocations.keys() {
+            assert!(!heap.verify_pointer(*old));
+        }
+    }
+
+    #[test]
+    pub fn compact_relocates_guarded_pointers() {
+        let fragmented = || {
+            let mut heap = Heap::new().with_guards(true).with_generation_checks(true);
+            let gap = heap.calloc(3);
+            let pointer = heap.calloc(2);
+            let pointer = heap.write(pointer, &slots(&[7, 8]));
+            heap.free(gap, 3);
+            (heap, pointer)
+        };
+
+        let (mut heap, pointer) = fragmented();
+        let relocations = heap.compact();
+        let (mut stepped, _pointer) = fragmented();
+        let mut state = CompactState::new();
+        while !stepped.compact_step(&mut state, 1) {}
+        assert_eq!(state.relocations, relocations);
+
+        // both sides of the map are the pointers between the guards
+        let moved = relocations[&pointer];
+        assert_eq!(moved.idx(), 1);
+        assert!(heap.verify_pointer(moved));
+        assert_eq!(heap.size_of(moved), Some(2));
+        assert_eq!(read_u64s(&heap, moved, 2), vec![7, 8]);
+        assert!(heap.try_free(moved, 2).is_ok());
     }
 }
@@ -1,83 +1,315 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::ops::Add;
+
 /// A tagged copy-on-write pointer to some data in a managed heap.
-#[derive(Debug, Clone, Copy)]
+/// The top bit tags whether the pointer owns the data it points to,
+/// the next bit whether it points into the heap shared by a worker instead of a fiber's own,
+/// the next 14 bits are the generation of the allocation pointed to,
+/// and the low 48 bits are the index of the slot pointed to.
+///
+/// Heaps ignore the shared bit, it only tells which heap a pointer is into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Pointer(u64);
 
-const OWNED:   u64 = 0x8000000000000000;
-const POINTER: u64 = 0x7fffffffffffffff;
+const OWNED:      u64 = 0x8000000000000000;
+const SHARED:     u64 = 0x4000000000000000;
+const GENERATION: u64 = 0x3fff000000000000;
+const POINTER:    u64 = 0x0000ffffffffffff;
+
+/// The number of slots a pointer can index.
+pub(super) const INDEX_SPACE: u64 = POINTER + 1;
+
+const GENERATION_SHIFT: u32 = 48;
+/// The largest generation a pointer can carry before wrapping back to zero.
+pub const MAX_GENERATION: u16 = (GENERATION >> GENERATION_SHIFT) as u16;
 
 impl Pointer {
     /// Create an owned pointer from an index.
-    pub(super) fn new(idx: PointerIdx) -> Pointer {
+    pub(super) fn new<I: Index>(idx: PointerIdx<I>) -> Pointer {
         let idx = idx.to_u64();
         assert!(idx <= POINTER);
         Pointer(OWNED | (POINTER & idx))
     }
 
-    /// Pointer arithmetic.
-    /// Maintains ownership.
-    pub(super) fn add(&self, slots: u64) -> Pointer {
-        let new_index: u64 = self.to_idx().to_u64() + slots;
-        assert!(new_index <= POINTER);
-        Pointer((self.0 & OWNED) | new_index)
+    /// Create a pointer from an index, with the given ownership.
+    pub fn tagged(idx: u64, owned: bool) -> Pointer {
+        assert!(idx <= POINTER);
+        Pointer(idx).with_owned(owned)
+    }
+
+    /// Returns the same pointer with the given ownership.
+    pub fn with_owned(self, owned: bool) -> Pointer {
+        if owned {
+            Pointer(OWNED | self.0)
+        } else {
+            self.borrow()
+        }
+    }
+
+    /// Returns the same pointer, tagged as pointing into a shared heap or not.
+    pub fn with_shared(self, shared: bool) -> Pointer {
+        if shared {
+            Pointer(SHARED | self.0)
+        } else {
+            Pointer(self.0 & !SHARED)
+        }
+    }
+
+    /// Check whether a pointer points into a shared heap, see [`Pointer::with_shared`].
+    pub fn is_shared(self) -> bool {
+        self.0 & SHARED == SHARED
+    }
+
+    /// Returns the index of the slot pointed to, without the tag.
+    pub fn idx(self) -> u64 {
+        self.0 & POINTER
+    }
+
+    /// Returns the generation of the allocation the pointer was made for.
+    /// Only meaningful for heaps that check generations.
+    pub fn generation(self) -> u16 {
+        ((self.0 & GENERATION) >> GENERATION_SHIFT) as u16
+    }
+
+    /// Returns the same pointer, tagged with a given generation.
+    pub fn with_generation(self, generation: u16) -> Pointer {
+        assert!(generation <= MAX_GENERATION);
+        let generation = (generation as u64) << GENERATION_SHIFT;
+        Pointer((self.0 & !GENERATION) | generation)
+    }
+
+    /// Returns a pointer some slots past this one.
+    /// Keeps the whole tag: ownership, the shared bit, and generation,
+    /// so the result is checked like the pointer it was made from.
+    ///
+    /// # Panics
+    /// If the index would not fit in a pointer.
+    pub fn offset(self, slots: usize) -> Pointer {
+        let new_index = self.idx().checked_add(slots as u64)
+            .filter(|idx| *idx <= POINTER)
+            .expect("pointer arithmetic overflowed");
+        Pointer((self.0 & !POINTER) | new_index)
+    }
+
+    /// Returns the number of slots from this pointer forward to another.
+    /// Only the indices are compared, the tags of either pointer are ignored.
+    ///
+    /// # Panics
+    /// If the other pointer comes before this one.
+    pub fn distance(self, other: Pointer) -> usize {
+        let slots = other.idx().checked_sub(self.idx()).expect("pointer distance is negative");
+        slots as usize
+    }
+
+    /// Pointer arithmetic, backwards.
+    /// Maintains ownership and generation.
+    pub(super) fn sub(self, slots: u64) -> Pointer {
+        let new_index: u64 = self.idx().checked_sub(slots).expect("pointer arithmetic underflowed");
+        Pointer((self.0 & !POINTER) | new_index)
     }
 
     /// Return the internal index of the pointer.
-    pub(super) fn to_idx(&self) -> PointerIdx {
-        PointerIdx(self.0 & POINTER)
+    pub(super) fn to_idx(self) -> PointerIdx {
+        PointerIdx(self.idx())
     }
 
     /// Check whether a reference is borrowing the data it points to.
-    pub fn is_borrowed(&self) -> bool {
+    pub fn is_borrowed(self) -> bool {
         self.0 & OWNED == 0
     }
 
     /// Check whether a reference owns the data it points to.
-    pub fn is_owned(&self) -> bool {
+    pub fn is_owned(self) -> bool {
         self.0 & OWNED == OWNED
     }
 
     /// Demote an owned pointer to a borrowed pointer.
-    pub fn borrow(&self) -> Pointer {
-        Pointer(self.0 & POINTER)
+    /// Maintains generation.
+    pub fn borrow(self) -> Pointer {
+        Pointer(self.0 & !OWNED)
     }
 
+    /// Reinterpret raw bits as a pointer.
+    ///
+    /// # Safety
+    /// Caller must ensure the bits were produced by [`Pointer::to_bits`].
     pub unsafe fn from_bits(bits: u64) -> Pointer {
         Pointer(bits)
     }
 
+    /// Returns the raw bits of the pointer, tag included.
+    ///
+    /// # Safety
+    /// The bits must not be used to forge a pointer with different ownership.
     pub unsafe fn to_bits(self) -> u64 {
         self.0
     }
 }
 
+/// An unsigned integer type used to store slot indices in the maps.
+/// Smaller types make for smaller map keys, at the cost of a smaller heap.
+pub trait Index: Copy + Ord + Hash + Debug + Add<Output = Self> {
+    /// The largest index of this type, or `usize::MAX` if that is smaller.
+    const MAX: usize;
+    /// Converts from a `usize`, panicking if it does not fit.
+    fn from_usize(idx: usize) -> Self;
+    /// Converts to a `usize`.
+    fn to_usize(self) -> usize;
+}
+
+macro_rules! impl_index {
+    ($($int:ty),*) => {$(
+        impl Index for $int {
+            const MAX: usize = if <$int>::MAX as u128 > usize::MAX as u128 {
+                usize::MAX
+            } else {
+                <$int>::MAX as usize
+            };
+
+            fn from_usize(idx: usize) -> $int {
+                <$int>::try_from(idx).expect("index does not fit in the index type")
+            }
+
+            fn to_usize(self) -> usize {
+                self as usize
+            }
+        }
+    )*};
+}
+
+impl_index!(u16, u32, u64, usize);
+
 /// Used as a key in the maps.
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash)]
-pub(super) struct PointerIdx(u64);
+pub(super) struct PointerIdx<I = u64>(I);
 
-impl PointerIdx {
-    pub(super) fn new(idx: u64) -> PointerIdx {
-        PointerIdx(idx)
+impl<I: Index> PointerIdx<I> {
+    pub(super) fn new(idx: usize) -> PointerIdx<I> {
+        PointerIdx(I::from_usize(idx))
     }
 
     pub(super) fn to_usize(self) -> usize {
-        self.0 as usize
+        self.0.to_usize()
     }
 
     pub(super) fn to_u64(self) -> u64 {
-        self.0
+        self.to_usize() as u64
+    }
+
+    /// Returns the index just past a range of slots starting here,
+    /// or `None` if it does not fit in a `usize`.
+    pub(super) fn end(self, slots: usize) -> Option<usize> {
+        self.to_usize().checked_add(slots)
     }
 }
 
-impl From<Pointer> for PointerIdx {
-    fn from(pointer: Pointer) -> PointerIdx {
-        pointer.to_idx()
+impl<I: Index> From<Pointer> for PointerIdx<I> {
+    fn from(pointer: Pointer) -> PointerIdx<I> {
+        PointerIdx::new(pointer.idx() as usize)
     }
 }
 
-impl std::ops::Add<u64> for PointerIdx {
-    type Output = PointerIdx;
+impl<I: Index> Add<usize> for PointerIdx<I> {
+    type Output = PointerIdx<I>;
+
+    fn add(self, other: usize) -> PointerIdx<I> {
+        PointerIdx::new(self.end(other).expect("index arithmetic overflowed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_round_trip() {
+        for idx in [0, 1, 42, POINTER] {
+            let owned = Pointer::tagged(idx, true);
+            let borrowed = Pointer::tagged(idx, false);
+            assert!(owned.is_owned() && !owned.is_borrowed());
+            assert!(borrowed.is_borrowed() && !borrowed.is_owned());
+            assert_eq!(owned.idx(), idx);
+            assert_eq!(borrowed.idx(), idx);
+
+            assert!(owned.with_owned(false).is_borrowed());
+            assert!(borrowed.with_owned(true).is_owned());
+            assert_eq!(owned.with_owned(false).idx(), idx);
+            assert_eq!(borrowed.with_owned(true).idx(), idx);
+            assert_eq!(owned.borrow().idx(), idx);
+        }
+    }
+
+    #[test]
+    fn offset_keeps_tag() {
+        let owned = Pointer::tagged(7, true).offset(3);
+        assert!(owned.is_owned());
+        assert_eq!(owned.idx(), 10);
+        let borrowed = Pointer::tagged(7, false).offset(3);
+        assert!(borrowed.is_borrowed());
+        assert_eq!(borrowed.idx(), 10);
+
+        let tagged = Pointer::tagged(7, true).with_shared(true).with_generation(5);
+        let moved = tagged.offset(4);
+        assert!(moved.is_owned() && moved.is_shared());
+        assert_eq!(moved.generation(), 5);
+        assert_eq!(moved.offset(0), moved);
+    }
+
+    #[test]
+    fn distance_ignores_tags() {
+        let start = Pointer::tagged(7, true).with_generation(2);
+        assert_eq!(start.distance(start), 0);
+        assert_eq!(start.distance(start.offset(9)), 9);
+        let other = Pointer::tagged(12, false).with_shared(true).with_generation(3);
+        assert_eq!(start.distance(other), 5);
+        assert_eq!(start.distance(start.offset(6).sub(6)), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "pointer distance is negative")]
+    fn distance_backwards() {
+        Pointer::tagged(7, true).distance(Pointer::tagged(6, true));
+    }
+
+    #[test]
+    #[should_panic(expected = "pointer arithmetic overflowed")]
+    fn offset_out_of_range() {
+        Pointer::tagged(POINTER, true).offset(1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn tagged_index_out_of_range() {
+        Pointer::tagged(POINTER + 1, true);
+    }
+
+    #[test]
+    fn generation_round_trip() {
+        let pointer = Pointer::tagged(42, true);
+        assert_eq!(pointer.generation(), 0);
+        for generation in [1, 7, MAX_GENERATION] {
+            let tagged = pointer.with_generation(generation);
+            assert_eq!(tagged.generation(), generation);
+            assert_eq!(tagged.idx(), 42);
+            assert!(tagged.is_owned());
+            assert_eq!(tagged.borrow().generation(), generation);
+            assert_eq!(tagged.offset(3).generation(), generation);
+            assert_eq!(tagged.borrow().with_owned(true), tagged);
+        }
+    }
 
-    fn add(self, other: u64) -> PointerIdx {
-        PointerIdx(self.0 + other)
+    #[test]
+    fn shared_tag_is_independent() {
+        let pointer = Pointer::tagged(42, true).with_generation(MAX_GENERATION);
+        assert!(!pointer.is_shared());
+        let shared = pointer.with_shared(true);
+        assert!(shared.is_shared());
+        assert!(shared.is_owned());
+        assert_eq!(shared.idx(), 42);
+        assert_eq!(shared.generation(), MAX_GENERATION);
+        assert!(shared.borrow().is_shared());
+        assert!(shared.with_generation(3).is_shared());
+        assert_eq!(shared.with_shared(false), pointer);
     }
 }
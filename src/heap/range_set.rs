@@ -1,94 +1,753 @@
 use std::collections::{BTreeMap, BTreeSet};
 
-use crate::heap::pointer::{Pointer, PointerIdx};
+use crate::heap::pointer::{Index, Pointer, PointerIdx};
+use crate::heap::AllocError;
 
 // Needs to do a few simple things:
 // Returns all ranges greater than or equal to a given size
 // when a range is added, merges neighboring ranges together
 // when a range is removed, splits neighboring ranges
 
+/// The number of small range sizes kept in buckets by default.
+const SMALL_SIZES: usize = 8;
+
+/// Which free range to place a new allocation in, when several fit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FitPolicy {
+    /// The lowest-address range that fits.
+    FirstFit,
+    /// The smallest range that fits.
+    /// Small ranges are taken most recently freed first,
+    /// larger ranges lowest-address first.
+    #[default]
+    BestFit,
+    /// The largest range, if it fits.
+    WorstFit,
+}
+
+/// What freeing a range did, see [`RangeSet::free_merged`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeResult {
+    /// The start and size of the free range the freed slots were merged into.
+    pub range: (Pointer, usize),
+    /// Capacity the heap can be shrunk by, because the merged range was at the tail.
+    /// If non-zero, the merged range is no longer part of the heap.
+    pub reclaimed_tail: usize,
+}
+
+/// What compacting the ranges would do, see [`RangeSet::defragmentation_plan`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DefragPlan {
+    /// Number of live allocations that would move down.
+    pub moves: usize,
+    /// Number of slots that would be copied, summed over the allocations that move.
+    pub copied_slots: usize,
+    /// Size of the single free range that would be left at the tail.
+    pub free_tail: usize,
+}
+
+/// How allocations were placed, counted since the counters were last reset,
+/// see [`crate::Heap::counters`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AllocCounters {
+    /// Allocations that fit in a free range without growing the capacity.
+    pub reused_allocs: usize,
+    /// Allocations placed past the end, growing the capacity by their whole size.
+    pub grown_allocs: usize,
+    /// Allocations that started in the free range at the tail,
+    /// growing the capacity by only what did not fit.
+    pub tail_extends: usize,
+    /// Reallocations that had to move the allocation.
+    pub moves_on_realloc: usize,
+}
+
+/// An invariant of a [`RangeSet`] that does not hold, see [`RangeSet::audit`].
+/// Ranges are named by the index of their first slot, which may be too large for a pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditError {
+    /// A size class in the size map has no ranges in it.
+    EmptySizeClass { size: usize },
+    /// A size class or bucket holds a range that is not free with that size,
+    /// or a size class holds a range that belongs in a bucket.
+    StaleSizeClass { start: usize, size: usize },
+    /// A free range is missing from its size class or bucket, or is in it twice.
+    Unclassified { start: usize, size: usize },
+    /// Two free ranges are back-to-back, so should have been merged.
+    Unmerged { first: usize, second: usize },
+    /// Two ranges overlap, free or live.
+    Overlapping { first: usize, second: usize },
+    /// A range runs past the end of the heap.
+    PastCapacity { start: usize, size: usize },
+}
+
+impl std::fmt::Display for AuditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuditError::EmptySizeClass { size } => write!(
+                f, "size class {} is empty", size,
+            ),
+            AuditError::StaleSizeClass { start, size } => write!(
+                f, "size class {} holds range at {}, which is not a free range of that size",
+                size, start,
+            ),
+            AuditError::Unclassified { start, size } => write!(
+                f, "free range of {} slots at {} is not in its size class exactly once",
+                size, start,
+            ),
+            AuditError::Unmerged { first, second } => write!(
+                f, "free ranges at {} and {} are back-to-back",
+                first, second,
+            ),
+            AuditError::Overlapping { first, second } => write!(
+                f, "ranges at {} and {} overlap",
+                first, second,
+            ),
+            AuditError::PastCapacity { start, size } => write!(
+                f, "range of {} slots at {} runs past the end of the heap",
+                size, start,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+/// Why live allocations could not be imported, see [`RangeSet::from_live`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportError {
+    /// Two live allocations overlap, or start at the same slot.
+    Overlapping { first: Pointer, second: Pointer },
+    /// A live allocation runs past the capacity.
+    PastCapacity { start: Pointer, size: usize },
+    /// The capacity is too large for the index type of the range set.
+    CapacityTooLarge { capacity: usize },
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Overlapping { first, second } => write!(
+                f, "live allocations at {} and {} overlap",
+                first.idx(), second.idx(),
+            ),
+            ImportError::PastCapacity { start, size } => write!(
+                f, "live allocation of {} slots at {} runs past the capacity",
+                size, start.idx(),
+            ),
+            ImportError::CapacityTooLarge { capacity } => write!(
+                f, "capacity of {} slots does not fit in the index type",
+                capacity,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
 /// Keeps track of unallocated ranges of slots.
 /// When a pointer is freed, it's range is merged with other ranges.
-/// We use a pair of BTreeMaps to keep this snappy under the hood.
-#[derive(Debug)]
-pub(super) struct RangeSet {
+/// We use a pair of BTreeMaps to keep this snappy under the hood,
+/// and a third to remember the size of each live allocation.
+/// Free ranges of only a few slots are kept in per-size buckets instead,
+/// so that the common case of a tiny allocation does not need to search.
+/// Indices are stored as `I`, which defaults to `u64`;
+/// a smaller index type halves the size of the map keys, but limits the capacity.
+#[derive(Debug, Clone)]
+pub struct RangeSet<I: Index = u64> {
     pub(super) capacity: usize,
     // slots before, length of range
-    pub(super) ranges: BTreeMap<PointerIdx, usize>,
+    pub(super) ranges: BTreeMap<PointerIdx<I>, usize>,
     // length -> start of range
     // if an entry size is present in the map, the pointer set must be non-empty.
-    pub(super) free: BTreeMap<usize, BTreeSet<PointerIdx>>,
+    // ranges small enough for a bucket are not in this map.
+    pub(super) free: BTreeMap<usize, BTreeSet<PointerIdx<I>>>,
+    // length - 1 -> starts of ranges, for lengths up to the number of buckets.
+    // order within a bucket is arbitrary, the last pushed is taken first.
+    pub(super) small: Vec<Vec<PointerIdx<I>>>,
+    // start of allocation -> length of allocation
+    // live allocations never overlap each other or a free range.
+    pub(super) live: BTreeMap<PointerIdx<I>, usize>,
+    pub(super) policy: FitPolicy,
+    pub(super) counters: AllocCounters,
 }
 
-impl RangeSet {
+impl<I: Index> RangeSet<I> {
     /// Create a new RangeSet with no capacity
-    pub fn new() -> RangeSet {
+    pub fn new() -> RangeSet<I> {
         RangeSet {
             capacity: 0,
             ranges:   BTreeMap::new(),
             free:     BTreeMap::new(),
+            small:    vec![Vec::new(); SMALL_SIZES],
+            live:     BTreeMap::new(),
+            policy:   FitPolicy::default(),
+            counters: AllocCounters::default(),
+        }
+    }
+
+    /// Keeps free ranges of up to `sizes` slots in per-size buckets,
+    /// which makes placing allocations of those sizes constant time.
+    /// Defaults to 8; zero keeps every free range in the size map.
+    pub fn with_small_sizes(mut self, sizes: usize) -> RangeSet<I> {
+        let ranges = std::mem::take(&mut self.ranges);
+        self.free.clear();
+        self.small = vec![Vec::new(); sizes];
+        for (pointer, slots) in ranges {
+            self.insert_free(pointer, slots);
         }
+        self
+    }
+
+    /// Create a new RangeSet with no capacity that places allocations by a given policy.
+    pub fn with_policy(policy: FitPolicy) -> RangeSet<I> {
+        RangeSet { policy, ..RangeSet::new() }
     }
 
     /// Adds some capacity to the heap.
     pub fn add_free_capacity(&mut self, slots: usize) {
-        self.free(Pointer::new(PointerIdx::new(self.capacity as u64)), slots);
-        self.capacity += slots;
+        self.release_within(PointerIdx::new(self.capacity), slots);
+        self.add_capacity(slots);
+    }
+
+    /// Returns the most slots the range set can hold,
+    /// one past the largest index of its index type.
+    pub fn max_capacity(&self) -> usize {
+        I::MAX.saturating_add(1)
+    }
+
+    /// Grows the capacity by some slots.
+    ///
+    /// # Panics
+    /// If the capacity would grow past [`RangeSet::max_capacity`].
+    fn add_capacity(&mut self, slots: usize) {
+        let capacity = self.capacity.checked_add(slots).filter(|capacity| *capacity <= self.max_capacity());
+        self.capacity = capacity.unwrap_or_else(|| panic!("range set can not grow past {} slots", self.max_capacity()));
+    }
+
+    /// Adds some free capacity to the end of the heap in one go.
+    /// If the heap already ends in a free range, the new capacity is merged into it.
+    pub fn reserve(&mut self, additional: usize) {
+        if additional == 0 { return; }
+        self.add_free_capacity(additional);
+    }
+
+    /// Removes the free range at the end of the heap, if there is one,
+    /// returning the number of slots the capacity was reduced by.
+    pub fn trim_tail(&mut self) -> usize {
+        let (tail, size) = match self.ranges.iter().next_back() {
+            Some((tail, size)) => (*tail, *size),
+            None => return 0,
+        };
+        if !self.ends_at_capacity(tail, size) { return 0; }
+
+        self.mark(tail);
+        self.capacity -= size;
+        return size;
+    }
+
+    /// Merges every pair of back-to-back free ranges into one, in address order,
+    /// returning the number of merges made.
+    /// Freeing always merges neighbors, so this only finds work in a free list
+    /// that was damaged or built by hand; afterwards no [`AuditError::Unmerged`] remains.
+    pub fn coalesce_all(&mut self) -> usize {
+        let ranges: Vec<_> = self.ranges.iter().map(|(start, size)| (*start, *size)).collect();
+        let mut merges = 0;
+        let mut run: Option<(PointerIdx<I>, usize)> = None;
+        for (start, size) in ranges {
+            run = match run {
+                Some((first, slots)) if first.end(slots) == Some(start.to_usize()) => {
+                    // the run is re-filed once it ends, under its combined size
+                    if self.ranges.contains_key(&first) { self.mark(first); }
+                    self.mark(start);
+                    merges += 1;
+                    Some((first, slots + size))
+                },
+                _ => {
+                    self.refile(run);
+                    Some((start, size))
+                },
+            };
+        }
+        self.refile(run);
+        return merges;
+    }
+
+    /// Files a run of merged ranges found by [`RangeSet::coalesce_all`] as one free range,
+    /// unless it is still filed because nothing was merged into it.
+    fn refile(&mut self, run: Option<(PointerIdx<I>, usize)>) {
+        if let Some((start, slots)) = run {
+            if !self.ranges.contains_key(&start) { self.insert_free(start, slots); }
+        }
+    }
+
+    /// Returns how allocations placed by [`RangeSet::mark_first`] or [`RangeSet::mark_fit`] were placed.
+    /// Only [`AllocCounters::moves_on_realloc`] is left to the heap.
+    pub fn counters(&self) -> AllocCounters {
+        self.counters
     }
 
     /// Create a new rangeset with the capacity of a pre-allocated heap.
-    pub fn new_with_free_capacity(slots: usize) -> RangeSet {
+    pub fn new_with_free_capacity(slots: usize) -> RangeSet<I> {
         let mut empty = RangeSet::new();
         empty.add_free_capacity(slots);
         empty
     }
 
+    /// Rebuilds a range set from the starts and sizes of its free ranges and live allocations.
+    /// Returns `None` if they don't make up a consistent range set,
+    /// see [`RangeSet::is_consistent`].
+    pub(super) fn from_ranges(
+        capacity: usize,
+        free: &[(usize, usize)],
+        live: &[(usize, usize)],
+    ) -> Option<RangeSet<I>> {
+        let mut ranges = RangeSet { capacity, ..RangeSet::new() };
+        for (start, slots) in free.iter() {
+            let start = PointerIdx::new(*start);
+            if *slots == 0 || ranges.ranges.contains_key(&start) { return None; }
+            ranges.insert_free(start, *slots);
+        }
+        for (start, slots) in live.iter() {
+            if ranges.live.insert(PointerIdx::new(*start), *slots).is_some() { return None; }
+        }
+        ranges.is_consistent().then_some(ranges)
+    }
+
+    /// Builds a range set from the starts and sizes of its live allocations, in any order,
+    /// and the capacity of the heap they are in. Every slot not in a live allocation is free.
+    /// Returns an error if the allocations overlap or run past the capacity.
+    pub fn from_live(capacity: usize, live: &[(Pointer, usize)]) -> Result<RangeSet<I>, ImportError> {
+        let mut ranges = RangeSet { capacity, ..RangeSet::new() };
+        if capacity > ranges.max_capacity() { return Err(ImportError::CapacityTooLarge { capacity }); }
+        let mut sorted = live.to_vec();
+        sorted.sort_by_key(|(pointer, _slots)| pointer.idx());
+
+        // the last allocation, and where it ends
+        let mut previous: Option<Pointer> = None;
+        let mut end = 0;
+        for (pointer, slots) in sorted {
+            let start = pointer.idx() as usize;
+            // an allocation of no slots may start at the capacity, which may not be an index
+            if start.checked_add(slots).is_none_or(|end| end > capacity) || start > I::MAX {
+                return Err(ImportError::PastCapacity { start: pointer, size: slots });
+            }
+            if let Some(first) = previous {
+                // allocations of no slots overlap anything else starting where they do
+                if start < end || first.idx() == pointer.idx() {
+                    return Err(ImportError::Overlapping { first, second: pointer });
+                }
+            }
+            if start > end {
+                ranges.insert_free(PointerIdx::new(end), start - end);
+            }
+            ranges.live.insert(PointerIdx::new(start), slots);
+            end = start + slots;
+            previous = Some(pointer);
+        }
+        if capacity > end {
+            ranges.insert_free(PointerIdx::new(end), capacity - end);
+        }
+        return Ok(ranges);
+    }
+
+    /// Iterates over the free ranges in ascending address order,
+    /// yielding the start of each range and its size.
+    ///
+    /// ```
+    /// let mut ranges: RangeSet = RangeSet::new_with_free_capacity(10);
+    /// let (a, _) = ranges.mark_first(2);
+    /// let (_, _) = ranges.mark_first(3);
+    /// let (c, _) = ranges.mark_first(1);
+    /// let (_, _) = ranges.mark_first(4);
+    /// ranges.free(a, 2);
+    /// ranges.free(c, 1);
+    ///
+    /// let free: Vec<_> = ranges.iter_free().map(|(p, s)| (p.idx(), s)).collect();
+    /// assert_eq!(free, vec![(0, 2), (5, 1)]);
+    /// let by_size: Vec<_> = ranges.iter_free_by_size().map(|(s, p)| (s, p.idx())).collect();
+    /// assert_eq!(by_size, vec![(1, 5), (2, 0)]);
+    /// ```
+    pub fn iter_free(&self) -> impl Iterator<Item = (Pointer, usize)> + '_ {
+        self.ranges.iter().map(|(pointer, size)| (Pointer::new(*pointer), *size))
+    }
+
+    /// Iterates over the free ranges in ascending size order,
+    /// yielding the size of each range and its start.
+    /// Ranges of the same size are yielded in ascending address order.
+    /// See [`RangeSet::iter_free`] for an example.
+    pub fn iter_free_by_size(&self) -> impl Iterator<Item = (usize, Pointer)> + '_ {
+        let small = self.small.iter().enumerate().flat_map(|(index, bucket)| {
+            let mut pointers = bucket.clone();
+            pointers.sort();
+            pointers.into_iter().map(move |pointer| (index + 1, Pointer::new(pointer)))
+        });
+        let large = self.free.iter().flat_map(|(size, pointers)| {
+            pointers.iter().map(move |pointer| (*size, Pointer::new(*pointer)))
+        });
+        small.chain(large)
+    }
+
+    /// Iterates over the free ranges of at least a given size, in ascending size order,
+    /// yielding the start of each range and its size.
+    /// Ranges of the same size are yielded in ascending address order.
+    /// Only the buckets and size classes that fit are visited.
+    pub fn free_ranges_at_least(&self, size: usize) -> impl Iterator<Item = (Pointer, usize)> + '_ {
+        let skipped = size.saturating_sub(1);
+        let small = self.small.iter().enumerate().skip(skipped).flat_map(|(index, bucket)| {
+            let mut pointers = bucket.clone();
+            pointers.sort();
+            pointers.into_iter().map(move |pointer| (Pointer::new(pointer), index + 1))
+        });
+        let large = self.free.range(size..).flat_map(|(size, pointers)| {
+            pointers.iter().map(move |pointer| (Pointer::new(*pointer), *size))
+        });
+        small.chain(large)
+    }
+
+    /// Returns the largest free range, if there are any, in logarithmic time.
+    /// If several ranges share the largest size, the lowest-address one is returned,
+    /// or the most recently freed one if they are small enough for a bucket.
+    pub fn largest_free(&self) -> Option<(Pointer, usize)> {
+        self.largest().map(|(pointer, size)| (Pointer::new(pointer), size))
+    }
+
+    /// Returns the largest free range, see [`RangeSet::largest_free`].
+    fn largest(&self) -> Option<(PointerIdx<I>, usize)> {
+        if let Some((size, pointers)) = self.free.iter().next_back() {
+            return Some((*pointers.first().unwrap(), *size));
+        }
+
+        // buckets are taken from the back, so the last is the one that would be placed in
+        self.small.iter().enumerate().rev()
+            .find_map(|(index, bucket)| Some((*bucket.last()?, index + 1)))
+    }
+
+    /// Iterates over the starts of the free ranges that fit an allocation, smallest first.
+    fn fits(&self, slots: usize) -> impl Iterator<Item = PointerIdx<I>> + '_ {
+        let small = self.small.iter().skip(slots.saturating_sub(1))
+            .flat_map(|bucket| bucket.iter().rev().copied());
+        let large = self.free.range(slots..)
+            .flat_map(|(_size, pointers)| pointers.iter().copied());
+        small.chain(large)
+    }
+
+    /// Describes the free ranges as a Graphviz DOT graph.
+    /// Each free range is a node labelled with its start and size,
+    /// chained to the next range in address order.
+    /// Each size class is a node with dashed edges to the ranges of that size.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph RangeSet {\n    rankdir=LR;\n");
+        dot.push_str("    node [shape=record];\n");
+
+        let mut previous = None;
+        for (pointer, size) in self.iter_free() {
+            let start = pointer.idx();
+            dot.push_str(&format!("    r{} [label=\"{} | {} slots\"];\n", start, start, size));
+            if let Some(previous) = previous {
+                dot.push_str(&format!("    r{} -> r{};\n", previous, start));
+            }
+            previous = Some(start);
+        }
+
+        let mut class = None;
+        for (size, pointer) in self.iter_free_by_size() {
+            if class != Some(size) {
+                dot.push_str(&format!("    s{} [label=\"size {}\", shape=ellipse];\n", size, size));
+                class = Some(size);
+            }
+            dot.push_str(&format!("    s{} -> r{} [style=dashed];\n", size, pointer.idx()));
+        }
+
+        dot.push_str("}\n");
+        return dot;
+    }
+
+    /// Returns the number of free ranges of each size, for sizes with at least one range.
+    pub fn size_histogram(&self) -> BTreeMap<usize, usize> {
+        let small = self.small.iter().enumerate()
+            .filter(|(_index, bucket)| !bucket.is_empty())
+            .map(|(index, bucket)| (index + 1, bucket.len()));
+        let large = self.free.iter().map(|(size, pointers)| (*size, pointers.len()));
+        small.chain(large).collect()
+    }
+
+    /// Returns the total number of free slots.
+    pub fn total_free(&self) -> usize {
+        self.ranges.values().sum()
+    }
+
+    /// Works out what sliding every live allocation down to close the gaps would do,
+    /// as [`crate::Heap::compact`] does, from the ranges alone, without moving anything.
+    /// Useful for deciding whether compacting is worth the copying.
+    pub fn defragmentation_plan(&self) -> DefragPlan {
+        let mut plan = DefragPlan::default();
+        let mut next = 0;
+        for (start, slots) in self.live.iter() {
+            if start.to_usize() != next {
+                plan.moves += 1;
+                plan.copied_slots += slots;
+            }
+            next += slots;
+        }
+        plan.free_tail = self.capacity - next;
+        return plan;
+    }
+
     /// Returns a pointer and the size to increase the allocation by.
     /// The backing allocation size must be increased according to the returned size.
     /// Do not call `add_free_capacity` with the returned size of this method,
     /// because the allocation is used, not free.
+    ///
+    /// # Panics
+    /// If the capacity would grow past [`RangeSet::max_capacity`].
     pub fn mark_first(&mut self, slots: usize) -> (Pointer, usize) {
-        // try filling the smallest earliest gap possible.
-        if let Some((_size, potential)) = self.free.range(slots..).next() {
-            let pointer = *potential.iter().next().unwrap();
+        let (pointer, extra_capacity) = self.find_first(slots);
+        self.live.insert(PointerIdx::<I>::from(pointer), slots);
+        return (pointer, extra_capacity);
+    }
+
+    /// Like [`RangeSet::mark_first`], but returns an error leaving the range set as it was
+    /// if the capacity would have to grow past [`RangeSet::max_capacity`].
+    pub fn try_mark_first(&mut self, slots: usize) -> Result<(Pointer, usize), AllocError> {
+        let available = self.max_capacity().saturating_sub(self.capacity);
+        if self.extra_capacity_for(slots) > available {
+            return Err(AllocError::OutOfMemory { requested: slots, available });
+        }
+        return Ok(self.mark_first(slots));
+    }
+
+    /// Like [`RangeSet::mark_first`], but only places the allocation in a free range
+    /// that already fits it, and returns `None` instead of growing the capacity.
+    /// For callers that grow whatever backs the range set themselves,
+    /// see [`RangeSet::add_free_capacity`].
+    /// Not to be confused with [`RangeSet::try_mark_first`], which may still grow.
+    pub fn mark_fit(&mut self, slots: usize) -> Option<Pointer> {
+        let pointer = self.find_fit(slots)?;
+        self.mark_smaller(pointer, slots);
+        self.counters.reused_allocs += 1;
+        self.live.insert(pointer, slots);
+        return Some(Pointer::new(pointer));
+    }
+
+    /// Returns the number of slots the range set covers, free and live.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns how much [`RangeSet::mark_first`] would increase the capacity by
+    /// to fit an allocation of a given size, without marking anything.
+    pub fn extra_capacity_for(&self, slots: usize) -> usize {
+        // an existing gap fits, no need to grow
+        if self.fits(slots).next().is_some() {
+            return 0;
+        }
+
+        // a tail range only needs to be extended
+        if let Some((tail, size)) = self.ranges.iter().next_back() {
+            if self.ends_at_capacity(*tail, *size) {
+                return slots - size;
+            }
+        }
+
+        return slots;
+    }
+
+    /// Finds and marks the first range that fits, without tracking it as live.
+    fn find_first(&mut self, slots: usize) -> (Pointer, usize) {
+        // try filling a gap, as picked by the policy.
+        if let Some(pointer) = self.find_fit(slots) {
             self.mark_smaller(pointer, slots);
+            self.counters.reused_allocs += 1;
             return (Pointer::new(pointer), 0);
         }
 
         // if the last range is a tail range, try extending it
-        if let Some((tail, size)) = self.ranges.iter().rev().next() {
+        if let Some((tail, size)) = self.ranges.iter().next_back() {
             // copy to please the borrow checker gods
             let (tail, size) = (*tail, *size);
             // this free range goes right up to the end
-            if tail.to_usize() + size == self.capacity {
+            if self.ends_at_capacity(tail, size) {
                 self.mark(tail);
                 let remaining = slots - size;
-                self.capacity += remaining;
+                self.add_capacity(remaining);
+                self.counters.tail_extends += 1;
                 return (Pointer::new(tail), remaining)
             }
         }
 
-        let pointer = Pointer::new(PointerIdx::new(self.capacity as u64));
-        self.capacity += slots;
+        let pointer = Pointer::new(PointerIdx::<I>::new(self.capacity));
+        self.add_capacity(slots);
+        self.counters.grown_allocs += 1;
         return (pointer, slots);
     }
 
+    /// Returns the free range an allocation should be placed in, according to the policy.
+    fn find_fit(&self, slots: usize) -> Option<PointerIdx<I>> {
+        match self.policy {
+            FitPolicy::FirstFit => self.ranges.iter()
+                .find(|(_pointer, size)| **size >= slots)
+                .map(|(pointer, _size)| *pointer),
+            FitPolicy::BestFit => self.fits(slots).next(),
+            FitPolicy::WorstFit => self.largest()
+                .filter(|(_pointer, size)| *size >= slots)
+                .map(|(pointer, _size)| pointer),
+        }
+    }
+
+    /// Like [`RangeSet::mark_first`], but the returned pointer's index
+    /// is a multiple of `align`, which must be a power of two.
+    /// Any padding before the aligned pointer is returned to the free list.
+    /// The capacity may grow,
+    /// so the backing allocation must be resized to match it afterwards.
+    pub fn mark_first_aligned(&mut self, slots: usize, align: usize) -> Pointer {
+        self.mark_first_aligned_at(slots, align, 0)
+    }
+
+    /// Like [`RangeSet::mark_first_aligned`], but it is the index `offset` slots
+    /// into the range that is a multiple of `align`, rather than its start.
+    pub(super) fn mark_first_aligned_at(&mut self, slots: usize, align: usize, offset: usize) -> Pointer {
+        assert!(align.is_power_of_two(), "alignment must be a power of two, got {}", align);
+        let align_up = |idx: usize| {
+            let aligned = idx.checked_add(offset + align - 1).expect("aligned index overflowed") & !(align - 1);
+            aligned - offset
+        };
+
+        // try carving an aligned range out of the smallest gap possible.
+        let mut found = None;
+        for pointer in self.fits(slots) {
+            let aligned = align_up(pointer.to_usize());
+            if aligned.checked_add(slots).is_some_and(|end| end <= pointer.to_usize() + self.ranges[&pointer]) {
+                found = Some((pointer, aligned));
+                break;
+            }
+        }
+
+        let aligned = if let Some((pointer, aligned)) = found {
+            let size = self.mark(pointer);
+            let end = aligned + slots;
+            let range_end = pointer.to_usize() + size;
+            if end < range_end {
+                self.release_within(PointerIdx::new(end), range_end - end);
+            }
+            if pointer.to_usize() < aligned {
+                self.release_within(pointer, aligned - pointer.to_usize());
+            }
+            aligned
+        } else {
+            // otherwise grow the heap, starting in the tail range if there is one
+            let start = match self.ranges.iter().next_back() {
+                Some((tail, size)) if self.ends_at_capacity(*tail, *size) => {
+                    let tail = *tail;
+                    self.mark(tail);
+                    tail.to_usize()
+                },
+                _ => self.capacity,
+            };
+            let aligned = align_up(start);
+            // the tail range would have fit otherwise, so this always grows
+            let end = aligned.checked_add(slots).expect("aligned allocation overflowed");
+            self.add_capacity(end - self.capacity);
+            if start < aligned {
+                self.release_within(PointerIdx::new(start), aligned - start);
+            }
+            aligned
+        };
+
+        let pointer = PointerIdx::new(aligned);
+        self.live.insert(pointer, slots);
+        return Pointer::new(pointer);
+    }
+
     /// Mark a pointer for use reserving a certain number of slots,
     /// returns the extra free space to the heap.
-    pub fn mark_smaller(&mut self, pointer: PointerIdx, slots: usize) {
+    pub(super) fn mark_smaller(&mut self, pointer: PointerIdx<I>, slots: usize) {
         // grab the full allocation
         let size = self.mark(pointer);
         if size == slots { return; }
 
         // let go of the end; may cause minor fragmentation
         assert!(slots < size);
-        self.free(Pointer::new(pointer.into()).add(slots as u64), size - slots);
+        self.release_within(pointer + slots, size - slots);
+    }
+
+    /// Grows a live allocation of size `old` in place to size `new`.
+    /// The slots directly after the allocation must be free, see [`RangeSet::is_free`].
+    /// Returns the size to increase the backing allocation by,
+    /// which is non-zero when growing past the end of the heap.
+    pub fn grow(&mut self, pointer: Pointer, old: usize, new: usize) -> usize {
+        assert!(new > old);
+        let start = PointerIdx::<I>::from(pointer);
+        // an allocation at the very end has no index after it
+        let tail = start.end(old).expect("allocation runs past the end of the index space");
+        let tail = (tail < self.capacity).then(|| PointerIdx::new(tail));
+        let needed = new - old;
+        let free = tail.and_then(|tail| self.ranges.get(&tail).copied()).unwrap_or(0);
+
+        if free >= needed {
+            self.live.insert(start, new);
+            self.mark_smaller(tail.unwrap(), needed);
+            return 0;
+        }
+
+        // the rest of the heap is not enough, so grow it by the shortfall
+        assert_eq!(start.end(old + free), Some(self.capacity));
+        let shortfall = needed - free;
+        self.add_capacity(shortfall);
+        self.live.insert(start, new);
+        if free > 0 { self.mark(tail.unwrap()); }
+        return shortfall;
+    }
+
+    /// Grows a live allocation of size `old` to size `new` by moving its start
+    /// back into the free range directly before it, if that range is large enough.
+    /// Returns the new start of the allocation; the data must be moved there.
+    /// The capacity may shrink if the allocation was at the tail.
+    pub fn slide_back(&mut self, pointer: Pointer, old: usize, new: usize) -> Option<Pointer> {
+        let pointer: PointerIdx<I> = pointer.into();
+        let (before, size) = self.ranges.range(..pointer).next_back()
+            .map(|(before, size)| (*before, *size))?;
+        if before.end(size) != Some(pointer.to_usize()) || size + old < new {
+            return None;
+        }
+
+        self.mark(before);
+        self.live.remove(&pointer);
+        self.live.insert(before, new);
+
+        // give back whatever is left over at the end of the old allocation
+        let end = before.to_usize() + new;
+        let old_end = pointer.to_usize() + old;
+        if end < old_end {
+            self.release(PointerIdx::new(end), old_end - end);
+        }
+        return Some(Pointer::new(before));
+    }
+
+    /// Returns the size of the live allocation starting at a pointer, if any.
+    pub fn size_of(&self, pointer: Pointer) -> Option<usize> {
+        self.live.get(&PointerIdx::<I>::from(pointer)).copied()
     }
 
     /// Mark a pointer for use, returns the size of the full allocation
-    fn mark(&mut self, pointer: PointerIdx) -> usize {
+    ///
+    /// Small ranges are taken from the back of their bucket when placing an allocation,
+    /// which is constant time; any other small range, such as one being merged
+    /// or reallocated into, is searched for from the back, in time linear in the bucket.
+    fn mark(&mut self, pointer: PointerIdx<I>) -> usize {
         // remove it from the ranges set, getting the size of the pointer
         let size = self.ranges.remove(&pointer).unwrap();
+        // small ranges are found in their bucket instead
+        if let Some(bucket) = self.bucket(size) {
+            if bucket.last() == Some(&pointer) {
+                bucket.pop();
+            } else {
+                let index = bucket.iter().rposition(|p| *p == pointer).unwrap();
+                bucket.swap_remove(index);
+            }
+            return size;
+        }
         // remove it from the free set, by inverse looking up by size
         assert!(self.free.get_mut(&size).unwrap().remove(&pointer));
         // if the pointer was the last of a given size, remove the entry from the map
@@ -99,46 +758,232 @@ impl RangeSet {
         return size;
     }
 
-    // TODO: tail allocations.
     /// Returns whether a pointer of a given size is free at a given point.
     /// Used to determine whether reallocation in place is possible.
+    /// A range running past the capacity counts as free
+    /// if everything from the pointer up to the capacity is free,
+    /// because the rest can be had by growing the heap.
     pub fn is_free(&self, pointer: Pointer, slots: usize) -> bool {
-        let pointer: PointerIdx = pointer.to_idx();
+        let pointer: PointerIdx<I> = pointer.into();
 
         // get the first pointer before or at the one specified.
-        if let Some((p, free_range)) = self.ranges.range(..=pointer).rev().next() {
+        if let Some((p, free_range)) = self.ranges.range(..=pointer).next_back() {
             // check that the free range covers the range of the pointer in question
             let p_end = p.to_usize() + free_range;
-            let pointer_end = pointer.to_usize() + slots;
+            let pointer_end = match pointer.end(slots) {
+                Some(pointer_end) => pointer_end,
+                None => return false,
+            };
 
             // for a pointer to be free it must be in the range!
             if p_end >= pointer_end {
                 return true;
             }
 
-            // TODO: tail allocations; need to increase the allocation size.
-            // || self.free.capacity == p_end
-            //     && self.free.capacity < pointer_end
+            // a tail range can be extended to cover the rest
+            if p_end == self.capacity && pointer.to_usize() <= p_end {
+                return true;
+            }
         }
 
-        false
+        // starting right at the end, all of it can be grown into
+        pointer.to_usize() == self.capacity
+    }
+
+    /// Returns the free range containing a given slot, if the slot is free.
+    pub fn free_span_at(&self, pointer: Pointer) -> Option<(Pointer, usize)> {
+        let pointer: PointerIdx<I> = pointer.into();
+
+        // get the first pointer before or at the one specified.
+        let (start, size) = self.ranges.range(..=pointer).next_back()?;
+        if start.to_usize() + size > pointer.to_usize() {
+            Some((Pointer::new(*start), *size))
+        } else {
+            None
+        }
     }
 
     /// Returns capacity that the heap can be shrunk by if freeing a tail allocation
-    pub fn free(&mut self, pointer: Pointer, mut slots: usize) -> usize {
-        let mut pointer: PointerIdx = pointer.into();
+    pub fn free(&mut self, pointer: Pointer, slots: usize) -> usize {
+        self.free_merged(pointer, slots).reclaimed_tail
+    }
 
+    /// Like [`RangeSet::free`], but also returns the free range the freed slots were merged into.
+    pub fn free_merged(&mut self, pointer: Pointer, slots: usize) -> FreeResult {
+        let pointer: PointerIdx<I> = pointer.into();
+        debug_assert!(pointer.end(slots).is_some_and(|end| end <= self.capacity), "freed range runs past the capacity");
+        self.untrack(pointer, slots);
+        self.release_merged(pointer, slots)
+    }
+
+    /// Like [`RangeSet::free`], but never shrinks the heap,
+    /// even if the range is at the tail. See [`RangeSet::trim_tail`].
+    pub fn free_within(&mut self, pointer: Pointer, slots: usize) {
+        let pointer: PointerIdx<I> = pointer.into();
+        debug_assert!(pointer.end(slots).is_some_and(|end| end <= self.capacity), "freed range runs past the capacity");
+        self.untrack(pointer, slots);
+        self.release_within(pointer, slots);
+    }
+
+    /// Checks that the maps agree with each other:
+    /// every free range is in exactly one size class or bucket of its size,
+    /// no size class is empty, no two free ranges are back-to-back,
+    /// and no range, free or live, overlaps another or runs past the capacity.
+    /// Cheap enough to call after every operation in tests.
+    pub fn audit(&self) -> Result<(), AuditError> {
+        for (size, pointers) in self.free.iter() {
+            if pointers.is_empty() { return Err(AuditError::EmptySizeClass { size: *size }); }
+            for pointer in pointers.iter() {
+                if *size <= self.small.len() || self.ranges.get(pointer) != Some(size) {
+                    return Err(AuditError::StaleSizeClass { start: pointer.to_usize(), size: *size });
+                }
+            }
+        }
+        for (index, bucket) in self.small.iter().enumerate() {
+            for pointer in bucket.iter() {
+                if self.ranges.get(pointer) != Some(&(index + 1)) {
+                    return Err(AuditError::StaleSizeClass { start: pointer.to_usize(), size: index + 1 });
+                }
+            }
+        }
+
+        let mut previous: Option<(PointerIdx<I>, usize)> = None;
+        for (start, size) in self.ranges.iter() {
+            let classified = match self.small.get(size.wrapping_sub(1)) {
+                Some(bucket) => bucket.iter().filter(|p| *p == start).count() == 1,
+                None => self.free.get(size).is_some_and(|pointers| pointers.contains(start)),
+            };
+            if !classified {
+                return Err(AuditError::Unclassified { start: start.to_usize(), size: *size });
+            }
+
+            // neighboring free ranges must have been merged
+            if let Some((before, before_size)) = previous {
+                if before.end(before_size) == Some(start.to_usize()) {
+                    return Err(AuditError::Unmerged { first: before.to_usize(), second: start.to_usize() });
+                }
+            }
+            previous = Some((*start, *size));
+        }
+
+        // walk every range in address order, free and live alike
+        let mut spans: Vec<_> = self.ranges.iter().chain(self.live.iter())
+            .map(|(start, size)| (start.to_usize(), *size))
+            .collect();
+        spans.sort();
+        let mut previous: Option<(usize, usize)> = None;
+        for (start, size) in spans {
+            if let Some((before, before_size)) = previous {
+                if start < before + before_size {
+                    return Err(AuditError::Overlapping { first: before, second: start });
+                }
+            }
+            if start.checked_add(size).is_none_or(|end| end > self.capacity) {
+                return Err(AuditError::PastCapacity { start, size });
+            }
+            previous = Some((start, size));
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the range set passes [`RangeSet::audit`],
+    /// for use on a range set that was loaded from outside rather than built up by allocating.
+    pub fn is_consistent(&self) -> bool {
+        self.audit().is_ok()
+    }
+
+    /// Returns whether a range of slots ends exactly at the capacity,
+    /// so freeing it would shrink the heap, and growing it would extend the heap.
+    /// A range running past the capacity is not a tail.
+    pub fn is_tail(&self, pointer: Pointer, slots: usize) -> bool {
+        self.ends_at_capacity(pointer.into(), slots)
+    }
+
+    /// Like [`RangeSet::is_tail`], for an index.
+    fn ends_at_capacity(&self, start: PointerIdx<I>, slots: usize) -> bool {
+        start.end(slots) == Some(self.capacity)
+    }
+
+    /// Returns whether a pointer lies in a free range that reaches the end of the heap.
+    pub fn is_tail_free(&self, pointer: Pointer) -> bool {
+        let pointer: PointerIdx<I> = pointer.into();
+        match self.ranges.range(..=pointer).next_back() {
+            Some((tail, size)) => self.ends_at_capacity(*tail, *size)
+                && pointer.to_usize() < self.capacity,
+            None => false,
+        }
+    }
+
+    /// Removes a range from the live allocation containing it.
+    /// Whatever is left of the allocation on either side of the range stays live.
+    fn untrack(&mut self, pointer: PointerIdx<I>, slots: usize) {
+        let (start, size) = match self.live.range(..=pointer).next_back() {
+            Some((start, size)) => (*start, *size),
+            None => return,
+        };
+
+        // the range must lie within the allocation
+        let end = start.to_usize() + size;
+        let pointer_end = match pointer.end(slots) {
+            Some(pointer_end) if pointer_end <= end => pointer_end,
+            _ => return,
+        };
+
+        self.live.remove(&start);
+        if start < pointer {
+            self.live.insert(start, pointer.to_usize() - start.to_usize());
+        }
+        if pointer_end < end {
+            self.live.insert(pointer + slots, end - pointer_end);
+        }
+    }
+
+    /// Returns a range to the free list, merging it with its neighbors.
+    /// Returns capacity that the heap can be shrunk by if freeing a tail range.
+    fn release(&mut self, pointer: PointerIdx<I>, slots: usize) -> usize {
+        self.release_merged(pointer, slots).reclaimed_tail
+    }
+
+    /// Like [`RangeSet::release`], but returns the merged range too.
+    fn release_merged(&mut self, pointer: PointerIdx<I>, slots: usize) -> FreeResult {
+        let (pointer, slots) = self.coalesce(pointer, slots);
+        let range = (Pointer::new(pointer), slots);
+
+        // if this is a tail free, reduce the size of the heap
+        if self.ends_at_capacity(pointer, slots) {
+            self.capacity -= slots;
+            return FreeResult { range, reclaimed_tail: slots };
+        }
+
+        // not a tail free, there still may be an allocation after this one
+        // return 0 to keep slots
+        self.insert_free(pointer, slots);
+        return FreeResult { range, reclaimed_tail: 0 };
+    }
+
+    /// Returns a range to the free list, merging it with its neighbors,
+    /// but never shrinking the heap, even if the range is at the tail.
+    /// Used to give back the unused part of a range that was split.
+    fn release_within(&mut self, pointer: PointerIdx<I>, slots: usize) {
+        let (pointer, slots) = self.coalesce(pointer, slots);
+        self.insert_free(pointer, slots);
+    }
+
+    /// Merges a range with any free ranges directly before or after it,
+    /// returning the combined range. The combined range is not yet free.
+    fn coalesce(&mut self, mut pointer: PointerIdx<I>, mut slots: usize) -> (PointerIdx<I>, usize) {
         // merge it with any other nearby ranges
         // start with the range before
-        if let Some((pointer_before, size)) = self.ranges.range(..pointer).rev().next() {
+        if let Some((pointer_before, size)) = self.ranges.range(..pointer).next_back() {
             let (pointer_before, size) = (*pointer_before, *size);
 
             // if the free ranges are back-to-back, we merge them by extending the old range
-            if pointer_before + size as u64 == pointer {
+            if pointer_before.end(size) == Some(pointer.to_usize()) {
                 // use the new combined pointer
                 self.mark(pointer_before);
                 pointer = pointer_before;
-                slots = size + slots;
+                slots += size;
             }
         }
 
@@ -147,32 +992,516 @@ impl RangeSet {
         // so `pointer..` is technically exclusive
         if let Some((pointer_after, size)) = self.ranges.range(pointer..).next() {
             let (pointer_after, size) = (*pointer_after, *size);
-            if pointer + size as u64 == pointer_after {
+            if pointer.end(slots) == Some(pointer_after.to_usize()) {
                 // extend the pointer to be longer
                 self.mark(pointer_after);
                 slots += size;
             }
         }
 
-        // if this is a tail free, reduce the size of the heap
-        if pointer.to_usize() + slots== self.capacity {
-            self.capacity -= slots;
-            return slots;
-        }
+        return (pointer, slots);
+    }
 
-        // add the pointer with its new size in the free map
+    /// Returns the bucket for free ranges of a given size, if the size is small.
+    fn bucket(&mut self, slots: usize) -> Option<&mut Vec<PointerIdx<I>>> {
+        if slots == 0 { return None; }
+        self.small.get_mut(slots - 1)
+    }
+
+    /// Adds a range to the free list as is, without merging.
+    /// A range that was merged into a bigger one moves out of its bucket with it,
+    /// because merging always goes through [`RangeSet::mark`] first.
+    fn insert_free(&mut self, pointer: PointerIdx<I>, slots: usize) {
+        // add the pointer with its new size in the free map, or its bucket
         // add the pointer with its new size to the ranges map
-        if let Some(s) = self.free.get_mut(&slots) {
+        if let Some(bucket) = self.bucket(slots) {
+            bucket.push(pointer);
+        } else if let Some(s) = self.free.get_mut(&slots) {
             s.insert(pointer);
         } else {
             let mut pointers = BTreeSet::new();
             pointers.insert(pointer);
             self.free.insert(slots, pointers);
         }
-
-        // not a tail free, there still may be an allocation after this one
-        // return 0 to keep slots
         assert!(self.ranges.insert(pointer, slots).is_none());
-        return 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mirrors the example on [`RangeSet::iter_free`].
+    #[test]
+    fn iter_free_ranges() {
+        let mut ranges: RangeSet = RangeSet::new_with_free_capacity(10);
+        let (a, _) = ranges.mark_first(2);
+        let (_, _) = ranges.mark_first(3);
+        let (c, _) = ranges.mark_first(1);
+        let (_, _) = ranges.mark_first(4);
+        ranges.free(a, 2);
+        ranges.free(c, 1);
+
+        let free: Vec<_> = ranges.iter_free().map(|(p, s)| (p.idx(), s)).collect();
+        assert_eq!(free, vec![(0, 2), (5, 1)]);
+        let by_size: Vec<_> = ranges.iter_free_by_size().map(|(s, p)| (s, p.idx())).collect();
+        assert_eq!(by_size, vec![(1, 5), (2, 0)]);
+    }
+
+    #[test]
+    fn largest_and_total_free() {
+        let mut ranges: RangeSet = RangeSet::new();
+        assert_eq!(ranges.largest_free(), None);
+        assert_eq!(ranges.total_free(), 0);
+
+        let pointers: Vec<_> = [3, 1, 5, 1, 5, 1, 2, 1].iter()
+            .map(|size| (ranges.mark_first(*size).0, *size))
+            .collect();
+        for index in [0, 2, 4, 6] {
+            let (pointer, size) = pointers[index];
+            ranges.free(pointer, size);
+        }
+
+        // both ranges of 5 slots are small enough for a bucket, so the last freed one
+        let (pointer, size) = ranges.largest_free().unwrap();
+        assert_eq!((pointer.idx(), size), (10, 5));
+        assert_eq!(ranges.total_free(), 3 + 5 + 5 + 2);
+
+        // ranges too large for a bucket are taken lowest-address first
+        let mut ranges: RangeSet = RangeSet::new().with_small_sizes(2);
+        let pointers: Vec<_> = [3, 1, 3, 1, 2, 1].iter()
+            .map(|size| (ranges.mark_first(*size).0, *size))
+            .collect();
+        for index in [2, 0, 4] {
+            let (pointer, size) = pointers[index];
+            ranges.free(pointer, size);
+        }
+        let (pointer, size) = ranges.largest_free().unwrap();
+        assert_eq!((pointer.idx(), size), (0, 3));
+        ranges.free(pointers[1].0, 1);
+        assert_eq!(ranges.largest_free().map(|(p, s)| (p.idx(), s)), Some((0, 7)));
+    }
+
+    #[test]
+    fn free_merges_with_following_range() {
+        let mut ranges: RangeSet = RangeSet::new();
+        let pointers: Vec<_> = [2, 5, 1].iter().map(|size| ranges.mark_first(*size).0).collect();
+        ranges.free(pointers[1], 5);
+        ranges.free(pointers[0], 2);
+
+        let free: Vec<_> = ranges.iter_free().map(|(p, s)| (p.idx(), s)).collect();
+        assert_eq!(free, vec![(0, 7)]);
+    }
+
+    #[test]
+    fn small_index_stress() {
+        let mut ranges = RangeSet::<u32>::new();
+        let mut pointers = Vec::new();
+        let mut rng = attorand::Rng::new_with_seed(22);
+
+        for _ in 0..200 {
+            let size = rng.next_u64_max(16) as usize + 1;
+            pointers.push((ranges.mark_first(size).0, size));
+
+            if rng.next_bool() {
+                let index = rng.next_u64_max((pointers.len() - 1) as u64) as usize;
+                let (pointer, size) = pointers.swap_remove(index);
+                ranges.free(pointer, size);
+            }
+        }
+
+        let live: usize = pointers.iter().map(|(_, size)| size).sum();
+        assert_eq!(live + ranges.total_free(), ranges.capacity);
+        for (pointer, size) in pointers {
+            assert_eq!(ranges.size_of(pointer), Some(size));
+        }
+    }
+
+    /// Runs a stress workload of tiny allocations, checking the range set afterwards.
+    fn tiny_stress(ranges: &mut RangeSet) {
+        let mut pointers = Vec::new();
+        let mut rng = attorand::Rng::new_with_seed(23);
+
+        for _ in 0..20_000 {
+            let size = rng.next_u64_max(8) as usize + 1;
+            pointers.push((ranges.mark_first(size).0, size));
+
+            if rng.next_bool() {
+                let index = rng.next_u64_max((pointers.len() - 1) as u64) as usize;
+                let (pointer, size) = pointers.swap_remove(index);
+                ranges.free(pointer, size);
+            }
+        }
+
+        assert!(ranges.is_consistent());
+        let live: usize = pointers.iter().map(|(_, size)| size).sum();
+        assert_eq!(live + ranges.total_free(), ranges.capacity);
+        for (pointer, size) in pointers {
+            assert_eq!(ranges.size_of(pointer), Some(size));
+        }
+    }
+
+    #[test]
+    fn tiny_stress_keeps_each_range_in_one_place() {
+        let mut without: RangeSet = RangeSet::new().with_small_sizes(0);
+        let mut with: RangeSet = RangeSet::new();
+        tiny_stress(&mut without);
+        tiny_stress(&mut with);
+
+        // every free range is in exactly one place
+        assert!(without.small.is_empty());
+        let in_buckets: usize = with.small.iter().map(|bucket| bucket.len()).sum();
+        let in_map: usize = with.free.values().map(|pointers| pointers.len()).sum();
+        assert_eq!(in_buckets + in_map, with.ranges.len());
+        assert!(with.free.keys().all(|size| *size > SMALL_SIZES));
+    }
+
+    #[test]
+    fn small_ranges_are_taken_from_the_back() {
+        let mut ranges: RangeSet = RangeSet::new();
+        let pointers: Vec<_> = (0..8).map(|_| ranges.mark_first(2).0).collect();
+        for pointer in pointers.iter().step_by(2) {
+            ranges.free(*pointer, 2);
+        }
+        let bucket = |ranges: &RangeSet| ranges.small[1].iter().map(|p| p.to_usize()).collect::<Vec<_>>();
+        assert_eq!(bucket(&ranges), vec![0, 4, 8, 12]);
+
+        // the last freed is placed first, leaving the rest of the bucket in order
+        assert_eq!(ranges.mark_first(2).0.idx(), 12);
+        assert_eq!(bucket(&ranges), vec![0, 4, 8]);
+        // and merging takes ranges out from anywhere in it
+        ranges.free(pointers[1], 2);
+        assert_eq!(bucket(&ranges), vec![8]);
+        assert_eq!(ranges.small[5], vec![PointerIdx::new(0)]);
+        ranges.audit().unwrap();
+    }
+
+    #[test]
+    fn coalescing_promotes_small_ranges() {
+        let mut ranges: RangeSet = RangeSet::new().with_small_sizes(4);
+        let pointers: Vec<_> = [3, 3, 1].iter().map(|size| ranges.mark_first(*size).0).collect();
+        ranges.free(pointers[0], 3);
+        assert_eq!(ranges.small[2].len(), 1);
+
+        // merging two small ranges makes one too big for a bucket
+        ranges.free(pointers[1], 3);
+        assert!(ranges.small.iter().all(|bucket| bucket.is_empty()));
+        assert_eq!(ranges.largest_free().map(|(p, s)| (p.idx(), s)), Some((0, 6)));
+
+        // and splitting it puts the rest back in a bucket
+        let (pointer, _) = ranges.mark_first(4);
+        assert_eq!(pointer.idx(), 0);
+        assert_eq!(ranges.small[1], vec![PointerIdx::new(4)]);
+        assert!(ranges.free.is_empty());
+    }
+
+    #[test]
+    fn consistency_checks() {
+        let mut ranges: RangeSet = RangeSet::new();
+        let pointers: Vec<_> = [2, 12, 3, 1].iter().map(|size| (ranges.mark_first(*size).0, *size)).collect();
+        ranges.free(pointers[0].0, pointers[0].1);
+        ranges.free(pointers[1].0, pointers[1].1);
+        assert!(ranges.is_consistent());
+
+        // a size class with nothing in it
+        let mut empty_class: RangeSet = RangeSet::new();
+        empty_class.free.insert(20, BTreeSet::new());
+        assert!(!empty_class.is_consistent());
+        assert_eq!(empty_class.audit(), Err(AuditError::EmptySizeClass { size: 20 }));
+
+        // a free range that overlaps a live allocation
+        let mut overlapping: RangeSet = RangeSet::new_with_free_capacity(10);
+        overlapping.live.insert(PointerIdx::new(4), 2);
+        assert!(!overlapping.is_consistent());
+        assert!(matches!(overlapping.audit(), Err(AuditError::Overlapping { .. })));
+
+        // two free ranges that should have been merged
+        let mut unmerged: RangeSet = RangeSet::new_with_free_capacity(2);
+        unmerged.ranges.insert(PointerIdx::new(2), 2);
+        unmerged.small[1].push(PointerIdx::new(2));
+        unmerged.capacity = 4;
+        assert!(!unmerged.is_consistent());
+        assert_eq!(unmerged.audit(), Err(AuditError::Unmerged { first: 0, second: 2 }));
+
+        // a free range missing from its bucket
+        let mut unclassified: RangeSet = RangeSet::new_with_free_capacity(3);
+        unclassified.small[2].clear();
+        assert!(matches!(unclassified.audit(), Err(AuditError::Unclassified { size: 3, .. })));
+
+        // ranges too far out for a pointer are still reported
+        let mut far: RangeSet = RangeSet::new_with_free_capacity(4);
+        far.live.insert(PointerIdx::new(1 << 50), 2);
+        assert_eq!(far.audit(), Err(AuditError::PastCapacity { start: 1 << 50, size: 2 }));
+        far.live.insert(PointerIdx::new(2), 1 << 50);
+        assert_eq!(far.audit(), Err(AuditError::Overlapping { first: 0, second: 2 }));
+    }
+
+    #[test]
+    fn to_dot_lists_ranges() {
+        let mut ranges: RangeSet = RangeSet::new();
+        let pointers: Vec<_> = [2, 1, 2, 1].iter().map(|size| ranges.mark_first(*size).0).collect();
+        ranges.free(pointers[0], 2);
+        ranges.free(pointers[2], 2);
+
+        let dot = ranges.to_dot();
+        assert!(dot.starts_with("digraph"));
+        assert!(dot.trim_end().ends_with('}'));
+        assert!(dot.contains("r0 [label=\"0 | 2 slots\"];"));
+        assert!(dot.contains("r0 -> r3;"));
+        assert!(dot.contains("s2 -> r0 [style=dashed];"));
+        assert!(dot.contains("s2 -> r3 [style=dashed];"));
+        assert_eq!(dot.matches("shape=ellipse").count(), 1);
+    }
+
+    #[test]
+    fn size_histogram_counts() {
+        let mut ranges: RangeSet = RangeSet::new();
+        let sizes = [1, 9, 1, 9, 1, 12, 1, 1, 1, 9, 1];
+        let pointers: Vec<_> = sizes.iter().map(|size| ranges.mark_first(*size).0).collect();
+        for index in [1, 3, 5, 7, 9] {
+            ranges.free(pointers[index], sizes[index]);
+        }
+
+        let histogram: Vec<_> = ranges.size_histogram().into_iter().collect();
+        assert_eq!(histogram, vec![(1, 1), (9, 3), (12, 1)]);
+    }
+
+    #[test]
+    fn audit_after_every_op() {
+        let mut ranges: RangeSet = RangeSet::new();
+        let mut pointers = Vec::new();
+        let mut rng = attorand::Rng::new_with_seed(36);
+
+        for i in 0..500 {
+            let size = rng.next_u64_max(24) as usize + 1;
+            pointers.push((ranges.mark_first(size).0, size));
+            ranges.audit().unwrap_or_else(|error| panic!("after mark {}: {}", i, error));
+
+            if rng.next_bool() {
+                let index = rng.next_u64_max((pointers.len() - 1) as u64) as usize;
+                let (pointer, size) = pointers.swap_remove(index);
+                if rng.next_bool() {
+                    ranges.free(pointer, size);
+                } else {
+                    ranges.free_within(pointer, size);
+                }
+                ranges.audit().unwrap_or_else(|error| panic!("after free {}: {}", i, error));
+            }
+            if i % 50 == 0 {
+                ranges.trim_tail();
+                ranges.audit().unwrap_or_else(|error| panic!("after trim {}: {}", i, error));
+            }
+        }
+    }
+
+    #[test]
+    fn free_ranges_at_least_size() {
+        let mut ranges: RangeSet = RangeSet::new();
+        let sizes = [3, 1, 12, 1, 5, 1, 3, 1, 9, 1];
+        let pointers: Vec<_> = sizes.iter().map(|size| ranges.mark_first(*size).0).collect();
+        for index in [0, 2, 4, 6, 8] {
+            ranges.free(pointers[index], sizes[index]);
+        }
+
+        let at_least: Vec<_> = ranges.free_ranges_at_least(4).map(|(p, s)| (p.idx(), s)).collect();
+        assert_eq!(at_least, vec![(17, 5), (27, 9), (4, 12)]);
+        assert_eq!(ranges.free_ranges_at_least(1).count(), 5);
+        assert_eq!(ranges.free_ranges_at_least(3).next().map(|(p, s)| (p.idx(), s)), Some((0, 3)));
+        assert_eq!(ranges.free_ranges_at_least(13).count(), 0);
+
+        // the same as filtering every range by size
+        for size in 0..14 {
+            let filtered: Vec<_> = ranges.iter_free_by_size()
+                .filter(|(range_size, _pointer)| *range_size >= size)
+                .map(|(range_size, pointer)| (pointer, range_size))
+                .collect();
+            assert_eq!(ranges.free_ranges_at_least(size).collect::<Vec<_>>(), filtered);
+        }
+    }
+
+    #[test]
+    fn free_merged_spans_neighbors() {
+        let mut ranges: RangeSet = RangeSet::new();
+        let pointers: Vec<_> = [2, 3, 4, 1].iter().map(|size| ranges.mark_first(*size).0).collect();
+        ranges.free(pointers[0], 2);
+        ranges.free(pointers[2], 4);
+
+        let result = ranges.free_merged(pointers[1], 3);
+        assert_eq!((result.range.0.idx(), result.range.1), (0, 9));
+        assert_eq!(result.reclaimed_tail, 0);
+
+        // freeing the last allocation merges everything into the tail
+        let result = ranges.free_merged(pointers[3], 1);
+        assert_eq!((result.range.0.idx(), result.range.1), (0, 10));
+        assert_eq!(result.reclaimed_tail, 10);
+        assert_eq!(ranges.capacity, 0);
+    }
+
+    #[test]
+    fn no_wrapping_near_index_max() {
+        let max = u32::MAX as usize + 1;
+        let mut ranges = RangeSet::<u32>::new_with_free_capacity(max - 10);
+        assert_eq!(ranges.max_capacity(), max);
+        let (a, _) = ranges.mark_first(max - 20);
+
+        // the 10 free slots at the tail can only grow by 10 more
+        let error = ranges.try_mark_first(25).unwrap_err();
+        assert_eq!(error, AllocError::OutOfMemory { requested: 25, available: 10 });
+        assert_eq!(ranges.capacity, max - 10);
+        assert_eq!(ranges.audit(), Ok(()));
+
+        // an allocation right up to the last index
+        let (b, grown) = ranges.try_mark_first(20).unwrap();
+        assert_eq!((b.idx(), grown), (max as u64 - 20, 10));
+        assert_eq!(ranges.capacity, max);
+        assert!(ranges.try_mark_first(1).is_err());
+        assert!(!ranges.is_free(b, usize::MAX));
+
+        // freeing the last range must not wrap around to index 0 when merging
+        ranges.free_within(a, max - 20);
+        ranges.free_within(b, 20);
+        let free: Vec<_> = ranges.iter_free().map(|(p, s)| (p.idx(), s)).collect();
+        assert_eq!(free, vec![(0, max)]);
+        assert!(!ranges.is_free(Pointer::tagged(5, true), usize::MAX - 1));
+        assert_eq!(ranges.audit(), Ok(()));
+        assert_eq!(ranges.trim_tail(), max);
+    }
+
+    #[test]
+    fn defragmentation_plan_predicts_compaction() {
+        let mut ranges: RangeSet = RangeSet::new_with_free_capacity(16);
+        let pointers: Vec<_> = [2, 3, 1, 4, 2].iter().map(|size| ranges.mark_first(*size).0).collect();
+        assert_eq!(ranges.defragmentation_plan(), DefragPlan { moves: 0, copied_slots: 0, free_tail: 4 });
+
+        // |..|###|.|####|##|....| leaves the first hole at 0
+        ranges.free(pointers[0], 2);
+        ranges.free(pointers[2], 1);
+        let plan = ranges.defragmentation_plan();
+        assert_eq!(plan, DefragPlan { moves: 3, copied_slots: 9, free_tail: 7 });
+        assert_eq!(plan.free_tail, ranges.total_free());
+
+        // nothing moves before the first hole
+        let mut ranges: RangeSet = RangeSet::new_with_free_capacity(10);
+        let pointers: Vec<_> = [3, 2, 5].iter().map(|size| ranges.mark_first(*size).0).collect();
+        ranges.free(pointers[1], 2);
+        assert_eq!(ranges.defragmentation_plan(), DefragPlan { moves: 1, copied_slots: 5, free_tail: 2 });
+        assert!(ranges.is_consistent());
+    }
+
+    #[test]
+    fn coalesce_all_repairs_unmerged_ranges() {
+        // free ranges at 0..2, 2..4 and 4..9, then a live range, then 10..11 and 11..14
+        let mut ranges: RangeSet = RangeSet::new();
+        for (start, size) in [(0, 2), (2, 2), (4, 5), (10, 1), (11, 3)] {
+            ranges.insert_free(PointerIdx::new(start), size);
+        }
+        ranges.live.insert(PointerIdx::new(9), 1);
+        ranges.live.insert(PointerIdx::new(14), 2);
+        ranges.capacity = 16;
+        assert!(matches!(ranges.audit(), Err(AuditError::Unmerged { .. })));
+
+        assert_eq!(ranges.coalesce_all(), 3);
+        ranges.audit().unwrap();
+        let free: Vec<_> = ranges.iter_free().map(|(p, s)| (p.idx(), s)).collect();
+        assert_eq!(free, vec![(0, 9), (10, 4)]);
+        assert_eq!(ranges.coalesce_all(), 0);
+    }
+
+    #[test]
+    fn is_tail_at_the_boundary() {
+        let mut ranges: RangeSet = RangeSet::new();
+        let (a, _) = ranges.mark_first(3);
+        let (b, _) = ranges.mark_first(4);
+        assert!(ranges.is_tail(b, 4));
+        assert!(ranges.is_tail(a, 7));
+        // before the boundary
+        assert!(!ranges.is_tail(a, 3));
+        assert!(!ranges.is_tail(b, 3));
+        // past the boundary
+        assert!(!ranges.is_tail(b, 5));
+        assert!(!ranges.is_tail(Pointer::tagged(u64::MAX >> 16, true), usize::MAX));
+        // an empty range is a tail only when it starts at the end
+        assert!(ranges.is_tail(Pointer::tagged(7, true), 0));
+
+        // freeing the tail moves the boundary down
+        assert_eq!(ranges.free(b, 4), 4);
+        assert!(ranges.is_tail(a, 3));
+    }
+
+    #[test]
+    fn mark_fit_never_grows() {
+        // 10 slots, with free gaps of 2 and 3 slots between live ranges
+        let mut ranges: RangeSet = RangeSet::new();
+        let pointers: Vec<_> = [2, 1, 3, 4].iter().map(|size| (ranges.mark_first(*size).0, *size)).collect();
+        ranges.free(pointers[0].0, pointers[0].1);
+        ranges.free(pointers[2].0, pointers[2].1);
+        assert_eq!(ranges.capacity(), 10);
+
+        // fragmented, so 4 slots only fit by growing
+        assert_eq!(ranges.mark_fit(4), None);
+        assert_eq!(ranges.capacity(), 10);
+        ranges.audit().unwrap();
+
+        let pointer = ranges.mark_fit(3).unwrap();
+        assert_eq!(pointer.idx(), 3);
+        assert_eq!(ranges.size_of(pointer), Some(3));
+        assert_eq!(ranges.capacity(), 10);
+        ranges.audit().unwrap();
+
+        let (pointer, grown) = ranges.mark_first(4);
+        assert_eq!((pointer.idx(), grown), (10, 4));
+        assert_eq!(ranges.capacity(), 14);
+    }
+
+    #[test]
+    fn from_live_fills_in_the_gaps() {
+        let at = |idx: u64| Pointer::tagged(idx, true);
+        // out of order, with gaps of 2 at the start, 3 between, and 4 at the tail
+        let live = [(at(7), 1), (at(2), 2), (at(4), 0), (at(4), 0)];
+        let ranges: RangeSet = RangeSet::from_live(12, &live[..3]).unwrap();
+        let free: Vec<_> = ranges.iter_free().map(|(pointer, size)| (pointer.idx(), size)).collect();
+        assert_eq!(free, vec![(0, 2), (4, 3), (8, 4)]);
+        assert_eq!(ranges.size_of(at(2)), Some(2));
+        assert_eq!(ranges.size_of(at(4)), Some(0));
+        assert_eq!(ranges.capacity(), 12);
+        ranges.audit().unwrap();
+
+        // an empty layout is all free, and a full one has no free ranges
+        let ranges: RangeSet = RangeSet::from_live(5, &[]).unwrap();
+        assert_eq!(ranges.iter_free().map(|(_pointer, size)| size).collect::<Vec<_>>(), vec![5]);
+        let ranges: RangeSet = RangeSet::from_live(3, &[(at(0), 3)]).unwrap();
+        assert_eq!(ranges.iter_free().count(), 0);
+
+        assert_eq!(
+            RangeSet::<u64>::from_live(12, &[(at(2), 3), (at(4), 1)]).unwrap_err(),
+            ImportError::Overlapping { first: at(2), second: at(4) },
+        );
+        assert_eq!(
+            RangeSet::<u64>::from_live(12, &live[2..]).unwrap_err(),
+            ImportError::Overlapping { first: at(4), second: at(4) },
+        );
+        assert_eq!(
+            RangeSet::<u64>::from_live(7, &live[..3]).unwrap_err(),
+            ImportError::PastCapacity { start: at(7), size: 1 },
+        );
+        assert_eq!(
+            RangeSet::<u16>::from_live(1 << 20, &[]).unwrap_err(),
+            ImportError::CapacityTooLarge { capacity: 1 << 20 },
+        );
+
+        // the largest capacity is one past the largest index
+        let ranges = RangeSet::<u16>::from_live(1 << 16, &[(at(0xffff), 1)]).unwrap();
+        assert_eq!(ranges.capacity(), ranges.max_capacity());
+        assert_eq!(ranges.size_of(at(0xffff)), Some(1));
+        ranges.audit().unwrap();
+        assert!(RangeSet::<u16>::from_live(1 << 16, &[]).is_ok());
+        assert_eq!(
+            RangeSet::<u16>::from_live((1 << 16) + 1, &[]).unwrap_err(),
+            ImportError::CapacityTooLarge { capacity: (1 << 16) + 1 },
+        );
+        assert_eq!(
+            RangeSet::<u16>::from_live(1 << 16, &[(at(1 << 16), 0)]).unwrap_err(),
+            ImportError::PastCapacity { start: at(1 << 16), size: 0 },
+        );
     }
 }
@@ -0,0 +1,236 @@
+//! `serde` support for [`Heap`], [`RangeSet`], and [`Pointer`], behind the `serde` feature.
+//!
+//! Unlike a snapshot, see [`Heap::snapshot`], a serialized heap keeps every slot,
+//! free or live, and the range set exactly as it was, down to the order of each bucket.
+//! Like a snapshot, configuration is not saved.
+//! Everything is checked on load, so a damaged or hand-edited heap is an error, not a panic.
+
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error;
+
+use crate::Slot;
+use super::{Heap, RangeSet, Pointer, Index, FitPolicy, AllocCounters, Backing, MAX_SLOTS};
+use super::pointer::PointerIdx;
+
+impl Serialize for Pointer {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // SAFETY: the bits are written out as they are, ownership and all
+        serializer.serialize_u64(unsafe { self.to_bits() })
+    }
+}
+
+impl<'de> Deserialize<'de> for Pointer {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Pointer, D::Error> {
+        let bits = u64::deserialize(deserializer)?;
+        // SAFETY: the bits were written out by serializing a pointer
+        Ok(unsafe { Pointer::from_bits(bits) })
+    }
+}
+
+/// A [`RangeSet`] as it is serialized, with every index as a plain number.
+#[derive(Serialize, Deserialize)]
+struct RangeSetData {
+    capacity: usize,
+    ranges:   Vec<(usize, usize)>,
+    free:     BTreeMap<usize, Vec<usize>>,
+    small:    Vec<Vec<usize>>,
+    live:     Vec<(usize, usize)>,
+    policy:   FitPolicy,
+    counters: AllocCounters,
+}
+
+/// Converts a loaded index, which may not fit in the index type.
+fn index<I: Index, E: Error>(idx: usize) -> Result<PointerIdx<I>, E> {
+    if idx > I::MAX {
+        return Err(E::custom(format!("index {} does not fit in the index type", idx)));
+    }
+    Ok(PointerIdx::new(idx))
+}
+
+/// Converts loaded indices, for a size class or bucket.
+fn starts<I: Index, C: FromIterator<PointerIdx<I>>, E: Error>(starts: Vec<usize>) -> Result<C, E> {
+    starts.into_iter().map(index).collect()
+}
+
+impl<I: Index> Serialize for RangeSet<I> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let spans = |map: &BTreeMap<PointerIdx<I>, usize>| map.iter()
+            .map(|(start, slots)| (start.to_usize(), *slots))
+            .collect();
+        RangeSetData {
+            capacity: self.capacity,
+            ranges:   spans(&self.ranges),
+            free:     self.free.iter()
+                .map(|(size, starts)| (*size, starts.iter().map(|start| start.to_usize()).collect()))
+                .collect(),
+            small:    self.small.iter()
+                .map(|bucket| bucket.iter().map(|start| start.to_usize()).collect())
+                .collect(),
+            live:     spans(&self.live),
+            policy:   self.policy,
+            counters: self.counters,
+        }.serialize(serializer)
+    }
+}
+
+impl<'de, I: Index> Deserialize<'de> for RangeSet<I> {
+    /// Fails unless the range set passes [`RangeSet::audit`].
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<RangeSet<I>, D::Error> {
+        let data = RangeSetData::deserialize(deserializer)?;
+        let spans = |spans: Vec<(usize, usize)>| spans.into_iter()
+            .map(|(start, slots)| Ok((index(start)?, slots)))
+            .collect::<Result<_, D::Error>>();
+        let ranges = RangeSet {
+            capacity: data.capacity,
+            ranges:   spans(data.ranges)?,
+            free:     data.free.into_iter()
+                .map(|(size, pointers)| Ok((size, starts(pointers)?)))
+                .collect::<Result<_, D::Error>>()?,
+            small:    data.small.into_iter()
+                .map(starts)
+                .collect::<Result<_, D::Error>>()?,
+            live:     spans(data.live)?,
+            policy:   data.policy,
+            counters: data.counters,
+        };
+        ranges.audit().map_err(D::Error::custom)?;
+        Ok(ranges)
+    }
+}
+
+/// A [`Heap`] as it is serialized, each slot as its raw bits.
+#[derive(Serialize, Deserialize)]
+struct HeapData<R> {
+    data: Vec<u64>,
+    free: R,
+    refs: Vec<(usize, usize)>,
+}
+
+impl<B: Backing> Serialize for Heap<B> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        HeapData {
+            // SAFETY: the bits are only copied, never interpreted
+            data: self.data.iter().map(|slot| unsafe { slot.to_u64() }).collect(),
+            free: &self.free,
+            refs: self.refs.iter().map(|(start, count)| (start.to_usize(), *count)).collect(),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Heap {
+    /// Fails unless the range set passes [`RangeSet::audit`], covers exactly the slots of the heap,
+    /// and only shared allocations have reference counts.
+    /// The heap is configured as by [`Heap::new`], see [`Heap::restore`].
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Heap, D::Error> {
+        let HeapData { data, free, refs } = HeapData::<RangeSet>::deserialize(deserializer)?;
+        if free.capacity != data.len() || free.capacity > MAX_SLOTS {
+            return Err(D::Error::custom(format!(
+                "range set of {} slots does not match heap of {} slots",
+                free.capacity, data.len(),
+            )));
+        }
+        // the audit has checked no two ranges overlap, so neither sum overflows
+        let covered: usize = free.ranges.values().chain(free.live.values()).sum();
+        if covered != free.capacity {
+            return Err(D::Error::custom("range set does not cover every slot of the heap"));
+        }
+
+        let mut counts = BTreeMap::new();
+        for (start, count) in refs {
+            let start = index(start)?;
+            if count < 2 || !free.live.contains_key(&start) {
+                return Err(D::Error::custom(format!(
+                    "reference count of {} for {} is not of a shared allocation",
+                    count, start.to_usize(),
+                )));
+            }
+            counts.insert(start, count);
+        }
+
+        // SAFETY: the bits were written from a slot by serializing a heap
+        let data = data.into_iter().map(|bits| unsafe { Slot::from_bits(bits) }).collect();
+        Ok(Heap { data, free, refs: counts, ..Heap::new() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::tests::{slots, read_u64s};
+
+    fn fragmented_heap() -> Heap {
+        let mut heap = Heap::new();
+        let pointers: Vec<_> = [3, 1, 4, 1, 5, 9, 2, 6].iter()
+            .map(|size| (heap.calloc(*size), *size))
+            .collect();
+        for (index, (pointer, size)) in pointers.iter().enumerate() {
+            let values: Vec<_> = (0..*size as u64).map(|v| v + 10 * index as u64).collect();
+            heap.write(*pointer, &slots(&values));
+        }
+        heap.share(pointers[3].0);
+        for (pointer, size) in pointers.iter().step_by(2) {
+            heap.free(*pointer, *size);
+        }
+        heap
+    }
+
+    #[test]
+    fn round_trip() {
+        let heap = fragmented_heap();
+        let json = serde_json::to_string(&heap).unwrap();
+        let restored: Heap = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(restored.stats(), heap.stats());
+        assert_eq!(restored.free.capacity, heap.free.capacity);
+        assert_eq!(restored.free.ranges, heap.free.ranges);
+        assert_eq!(restored.free.free, heap.free.free);
+        assert_eq!(restored.free.small, heap.free.small);
+        assert_eq!(restored.free.live, heap.free.live);
+        assert_eq!(restored.refs, heap.refs);
+        let pointer = Pointer::tagged(0, true);
+        assert_eq!(read_u64s(&restored, pointer, heap.capacity()), read_u64s(&heap, pointer, heap.capacity()));
+        assert_eq!(serde_json::to_string(&restored).unwrap(), json);
+    }
+
+    #[test]
+    fn pointer_round_trip() {
+        let pointers = [Pointer::tagged(0, true), Pointer::tagged(12345, false).with_generation(3)];
+        let json = serde_json::to_string(&pointers).unwrap();
+        assert_eq!(serde_json::from_str::<[Pointer; 2]>(&json).unwrap(), pointers);
+    }
+
+    #[test]
+    fn inconsistent_heaps_are_rejected() {
+        let json = serde_json::to_value(fragmented_heap()).unwrap();
+        let load = |edit: &dyn Fn(&mut serde_json::Value)| {
+            let mut json = json.clone();
+            edit(&mut json);
+            serde_json::from_value::<Heap>(json)
+        };
+        assert!(load(&|_json| {}).is_ok());
+
+        // an empty size class
+        assert!(load(&|json| { json["free"]["free"]["100"] = serde_json::json!([]); }).is_err());
+        // a free range missing from its bucket
+        assert!(load(&|json| { json["free"]["small"][2] = serde_json::json!([]); }).is_err());
+        // a capacity that does not match the data
+        assert!(load(&|json| { json["data"].as_array_mut().unwrap().pop(); }).is_err());
+        // slots that are neither free nor live
+        assert!(load(&|json| { json["free"]["live"].as_array_mut().unwrap().pop(); }).is_err());
+        // a reference count for a free range
+        assert!(load(&|json| { json["refs"] = serde_json::json!([[0, 2]]); }).is_err());
+        // a range too far out for any index
+        assert!(load(&|json| { json["free"]["live"][0][0] = serde_json::json!(u64::MAX); }).is_err());
+    }
+
+    #[test]
+    fn small_indices_are_checked() {
+        let mut ranges: RangeSet = RangeSet::new_with_free_capacity(1 << 20);
+        let _pointer = ranges.mark_first(1 << 17);
+        let json = serde_json::to_string(&ranges).unwrap();
+        assert!(serde_json::from_str::<RangeSet>(&json).unwrap().is_consistent());
+        assert!(serde_json::from_str::<RangeSet<u16>>(&json).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+//! A compact binary format for saving and restoring a [`Heap`].
+//!
+//! A snapshot is laid out as follows, where every number is an
+//! unsigned LEB128 varint unless said otherwise:
+//!
+//! - the magic bytes `flex`, then the format version as a single byte.
+//! - the capacity of the heap, in slots.
+//! - the number of live allocations, then for each allocation in address order:
+//!   the gap since the end of the previous allocation, its size, its reference count,
+//!   and the raw bits of each of its slots as little-endian `u64`s.
+//! - the number of free ranges, then for each range in address order:
+//!   the gap since the end of the previous range, and its size.
+//!
+//! Because ranges are stored as gaps and lengths,
+//! a heap that is mostly one big free range snapshots in a handful of bytes.
+
+use std::collections::BTreeMap;
+
+use crate::Slot;
+use super::{Heap, RangeSet, Backing, MAX_SLOTS};
+use super::pointer::PointerIdx;
+
+const MAGIC: &[u8; 4] = b"flex";
+/// The version of the snapshot format written by [`Heap::snapshot`].
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+/// Returned when a snapshot can not be restored, see [`Heap::restore`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The snapshot was written by a different version of the format.
+    VersionMismatch { found: u8, expected: u8 },
+    /// The snapshot ended before it was complete.
+    Truncated,
+    /// The snapshot is not a snapshot, or does not describe a valid heap.
+    Corrupt,
+    /// The snapshot describes a valid heap, but there is not enough memory to restore it.
+    TooLarge { capacity: usize },
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::VersionMismatch { found, expected } => write!(
+                f, "snapshot is version {}, expected version {}",
+                found, expected,
+            ),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Corrupt   => write!(f, "snapshot is corrupt"),
+            SnapshotError::TooLarge { capacity } => write!(
+                f, "snapshot is of a heap of {} slots, which does not fit in memory",
+                capacity,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Reads a snapshot front to back.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, SnapshotError> {
+        let (byte, rest) = self.bytes.split_first().ok_or(SnapshotError::Truncated)?;
+        self.bytes = rest;
+        Ok(*byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, SnapshotError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as u64;
+            // the last byte may only hold the top bit of the value
+            if bits << shift >> shift != bits { return Err(SnapshotError::Corrupt); }
+            value |= bits << shift;
+            if byte & 0x80 == 0 { return Ok(value); }
+        }
+        Err(SnapshotError::Corrupt)
+    }
+
+    fn usize(&mut self) -> Result<usize, SnapshotError> {
+        usize::try_from(self.varint()?).map_err(|_| SnapshotError::Corrupt)
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        if self.bytes.len() < 8 { return Err(SnapshotError::Truncated); }
+        let (bits, rest) = self.bytes.split_at(8);
+        self.bytes = rest;
+        Ok(u64::from_le_bytes(bits.try_into().unwrap()))
+    }
+
+    /// Reads a gap and a size, returning the start and end of the range they describe,
+    /// which must lie within the most slots a heap can hold.
+    fn range(&mut self, end: usize) -> Result<(usize, usize), SnapshotError> {
+        let start = end.checked_add(self.usize()?).ok_or(SnapshotError::Corrupt)?;
+        let end = start.checked_add(self.usize()?).ok_or(SnapshotError::Corrupt)?;
+        if end > MAX_SLOTS { return Err(SnapshotError::Corrupt); }
+        Ok((start, end))
+    }
+}
+
+impl<B: Backing> Heap<B> {
+    /// Writes the heap to a compact binary snapshot, see [`Heap::restore`].
+    /// Only the data of live allocations is saved, free slots are not.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(SNAPSHOT_VERSION);
+        write_varint(&mut bytes, self.data.len() as u64);
+
+        write_varint(&mut bytes, self.free.live.len() as u64);
+        let mut end = 0;
+        for (start, slots) in self.free.live.iter() {
+            write_varint(&mut bytes, (start.to_usize() - end) as u64);
+            write_varint(&mut bytes, *slots as u64);
+            write_varint(&mut bytes, self.refs.get(start).copied().unwrap_or(1) as u64);
+            for slot in self.data[start.to_usize()..(start.to_usize() + slots)].iter() {
+                // SAFETY: the bits are only copied, never interpreted
+                bytes.extend_from_slice(&unsafe { slot.to_u64() }.to_le_bytes());
+            }
+            end = start.to_usize() + slots;
+        }
+
+        write_varint(&mut bytes, self.free.ranges.len() as u64);
+        let mut end = 0;
+        for (start, slots) in self.free.ranges.iter() {
+            write_varint(&mut bytes, (start.to_usize() - end) as u64);
+            write_varint(&mut bytes, *slots as u64);
+            end = start.to_usize() + slots;
+        }
+
+        return bytes;
+    }
+}
+
+impl Heap {
+    /// Restores a heap from a snapshot made by [`Heap::snapshot`].
+    /// Free slots are restored zeroed.
+    /// Configuration is not part of a snapshot,
+    /// so the restored heap is configured as by [`Heap::new`];
+    /// the builder methods can be used to change this afterwards.
+    pub fn restore(bytes: &[u8]) -> Result<Heap, SnapshotError> {
+        let mut reader = Reader { bytes };
+        for expected in MAGIC.iter() {
+            if reader.byte()? != *expected { return Err(SnapshotError::Corrupt); }
+        }
+        let version = reader.byte()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::VersionMismatch { found: version, expected: SNAPSHOT_VERSION });
+        }
+        let capacity = reader.usize()?;
+        if capacity > MAX_SLOTS { return Err(SnapshotError::Corrupt); }
+
+        let mut live = vec![];
+        let mut refs = BTreeMap::new();
+        let mut contents = vec![];
+        let mut end = 0;
+        for _ in 0..reader.usize()? {
+            let (start, range_end) = reader.range(end)?;
+            let count = reader.usize()?;
+            if count == 0 { return Err(SnapshotError::Corrupt); }
+            if count > 1 { refs.insert(PointerIdx::new(start), count); }
+            for _ in start..range_end {
+                contents.push(reader.u64()?);
+            }
+            live.push((start, range_end - start));
+            end = range_end;
+        }
+
+        let mut free = vec![];
+        let mut end = 0;
+        for _ in 0..reader.usize()? {
+            let (start, range_end) = reader.range(end)?;
+            free.push((start, range_end - start));
+            end = range_end;
+        }
+        if !reader.bytes.is_empty() { return Err(SnapshotError::Corrupt); }
+
+        // every slot of a heap is either live or free, so a capacity that is not covered
+        // by the ranges is made up; each list of ranges is in order, so neither sum overflows
+        let covered: usize = live.iter().chain(free.iter()).map(|(_start, slots)| slots).sum();
+        if covered != capacity { return Err(SnapshotError::Corrupt); }
+
+        // checks that every range lies within the capacity before touching the data
+        let free = RangeSet::from_ranges(capacity, &free, &live).ok_or(SnapshotError::Corrupt)?;
+
+        let mut data: Vec<Slot> = vec![];
+        data.try_reserve_exact(capacity).map_err(|_| SnapshotError::TooLarge { capacity })?;
+        // SAFETY: free slots are zeroed, live slots are overwritten below
+        data.extend((0..capacity).map(|_| unsafe { Slot::zero() }));
+        let mut contents = contents.into_iter();
+        for (start, slots) in live {
+            for slot in data[start..(start + slots)].iter_mut() {
+                // SAFETY: the bits were written from a slot by `snapshot`
+                *slot = unsafe { Slot::from_bits(contents.next().unwrap()) };
+            }
+        }
+
+        Ok(Heap { data, free, refs, ..Heap::new() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::tests::{slots, read_u64s};
+
+    fn fragmented_heap() -> Heap {
+        let mut heap = Heap::new();
+        let pointers: Vec<_> = [3, 1, 4, 1, 5].iter()
+            .map(|size| (heap.calloc(*size), *size))
+            .collect();
+        for (index, (pointer, size)) in pointers.iter().enumerate() {
+            let values: Vec<_> = (0..*size as u64).map(|v| v + 10 * index as u64).collect();
+            heap.write(*pointer, &slots(&values));
+        }
+        heap.share(pointers[3].0);
+        heap.free(pointers[0].0, 3);
+        heap.free(pointers[2].0, 4);
+        heap
+    }
+
+    #[test]
+    fn round_trip() {
+        let heap = fragmented_heap();
+        let restored = Heap::restore(&heap.snapshot()).unwrap();
+
+        assert_eq!(restored.stats(), heap.stats());
+        assert_eq!(restored.free.ranges, heap.free.ranges);
+        assert_eq!(restored.free.live, heap.free.live);
+        assert_eq!(restored.refs, heap.refs);
+        for (start, slots) in heap.free.live.iter() {
+            let pointer = crate::Pointer::tagged(start.to_u64(), true);
+            assert_eq!(read_u64s(&restored, pointer, *slots), read_u64s(&heap, pointer, *slots));
+        }
+        assert_eq!(restored.snapshot(), heap.snapshot());
+    }
+
+    #[test]
+    fn free_tail_is_tiny() {
+        let mut heap = Heap::new();
+        heap.reserve(1 << 20);
+        let snapshot = heap.snapshot();
+        assert!(snapshot.len() < 16, "snapshot is {} bytes", snapshot.len());
+        assert_eq!(Heap::restore(&snapshot).unwrap().stats(), heap.stats());
+    }
+
+    #[test]
+    fn every_prefix_is_rejected() {
+        let snapshot = fragmented_heap().snapshot();
+        for end in 0..snapshot.len() {
+            assert!(Heap::restore(&snapshot[..end]).is_err());
+        }
+        assert_eq!(Heap::restore(&snapshot[..3]).unwrap_err(), SnapshotError::Truncated);
+    }
+
+    #[test]
+    fn bad_snapshots() {
+        let mut snapshot = fragmented_heap().snapshot();
+        snapshot.push(0);
+        assert_eq!(Heap::restore(&snapshot).unwrap_err(), SnapshotError::Corrupt);
+
+        snapshot.pop();
+        snapshot[4] = SNAPSHOT_VERSION + 1;
+        assert_eq!(
+            Heap::restore(&snapshot).unwrap_err(),
+            SnapshotError::VersionMismatch { found: SNAPSHOT_VERSION + 1, expected: SNAPSHOT_VERSION },
+        );
+
+        assert_eq!(Heap::restore(b"nope!").unwrap_err(), SnapshotError::Corrupt);
+    }
+
+    /// Builds a snapshot with no live allocations, and the given capacity and free ranges.
+    fn free_only(capacity: u64, free: &[(u64, u64)]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(SNAPSHOT_VERSION);
+        write_varint(&mut bytes, capacity);
+        write_varint(&mut bytes, 0);
+        write_varint(&mut bytes, free.len() as u64);
+        for (gap, size) in free.iter() {
+            write_varint(&mut bytes, *gap);
+            write_varint(&mut bytes, *size);
+        }
+        bytes
+    }
+
+    #[test]
+    fn corrupt_fields_are_rejected() {
+        assert_eq!(Heap::restore(&free_only(4, &[(0, 4)])).unwrap().stats().total_slots, 4);
+
+        // past the slots a pointer can index
+        assert_eq!(Heap::restore(&free_only(1 << 59, &[(0, 1 << 59)])).unwrap_err(), SnapshotError::Corrupt);
+        assert_eq!(Heap::restore(&free_only(4, &[(1 << 50, 1)])).unwrap_err(), SnapshotError::Corrupt);
+        assert_eq!(Heap::restore(&free_only(u64::MAX, &[])).unwrap_err(), SnapshotError::Corrupt);
+        // a capacity the ranges don't account for
+        assert_eq!(Heap::restore(&free_only(1 << 40, &[])).unwrap_err(), SnapshotError::Corrupt);
+        assert_eq!(Heap::restore(&free_only(4, &[(0, 3)])).unwrap_err(), SnapshotError::Corrupt);
+        assert_eq!(Heap::restore(&free_only(4, &[(1, 3)])).unwrap_err(), SnapshotError::Corrupt);
+        assert_eq!(Heap::restore(&free_only(4, &[(0, 2), (0, 2)])).unwrap_err(), SnapshotError::Corrupt);
+
+        // flipping any bit of a real snapshot never panics
+        let snapshot = fragmented_heap().snapshot();
+        for index in 0..snapshot.len() {
+            for bit in 0..8 {
+                let mut corrupted = snapshot.clone();
+                corrupted[index] ^= 1 << bit;
+                let _ = Heap::restore(&corrupted);
+            }
+        }
+    }
+}
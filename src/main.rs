@@ -1,77 +1,32 @@
-use std::collections::{BTreeMap, BTreeSet};
+// Much of the VM is still sketched out and not yet wired into `main`.
+#![allow(dead_code)]
+// Explicit early returns are preferred for readability.
+#![allow(clippy::needless_return)]
 
 mod heap;
-pub use heap::{Pointer, Heap};
+pub use heap::{Pointer, Heap, HeapStats, AllocEvent, PressureResponse, PressureFn, AllocError, GrowError, HeapError, FitPolicy, SnapshotError, HeapDiff, ArenaId, AuditError, ImportError, GrowthPolicy, SizeClassPolicy, FreeResult, DefragPlan, AllocCounters, CompactState, WeakPointer, ConcurrentHeap, Backing};
+#[cfg(feature = "fixed_backing")]
+pub use heap::FixedBacking;
 
 mod stack;
-// mod fiber;
+mod fiber;
 mod code;
 mod slot;
+mod vm;
+mod assembler;
+mod constant;
+mod worker;
+mod module;
 
-pub use slot::Slot;
-
-// pub struct Code {
-//     bytes: Vec<u8>,
-// }
-//
-// pub struct Worker {
-//     code_pool:     BTreeMap<CodeId, Code>,
-//     constant_pool: BTreeMap<ConstantId, Constant>,
-//     process_pool:  BTreeMap<FiberId, Fiber>,
-// }
-//
-// macro_rules! op {
-//     {
-//         fn $name:ident(
-//             $ip:ident,
-//             $next_op:ident,
-//             $stack:ident,
-//             $heap:ident,
-//             $code:ident,
-//         ) $body:expr
-//     } => {
-//         pub fn name(
-//             ip: &mut usize,
-//             next_op: &mut OpCode,
-//             stack: &mut Stack,
-//             heap: &mut Heap,
-//             code: &Code,
-//         ) -> std::option::Option<HandlerId> {
-//             body
-//         }
-//     };
-// }
-//
-// op! {
-//     fn add_u64(
-//         ip,
-//         next_op,
-//         stack,
-//         heap,
-//         code,
-//     ) {
-//         *next_op = code.prefetch(ip);
-//         let a = stack.pop();
-//         let b = stack.pop();
-//         stack.push(a + b);
-//         None
-//     }
-//
-// }
-//
-// pub fn add_u64(
-//     ip: &mut usize,
-//     next_op: &mut OpCode,
-//     stack: &mut Stack,
-//     heap: &mut Heap,
-//     code: &Code,
-// ) -> Option<HandlerId> {
-//     *next_op = code.prefetch(ip);
-//     let a = stack.pop();
-//     let b = stack.pop();
-//     stack.push(a + b);
-//     None
-// }
+pub use slot::{Slot, FromSlots, ToSlots};
+pub use stack::{Stack, Frame, StackError};
+pub use code::{Code, OpCode, VerifyError};
+pub use constant::{Constant, ConstantId};
+pub use vm::{step, step_with, StepResult, VmConfig, Heaps};
+pub use assembler::{Assembler, AssembleError};
+pub use fiber::{Fiber, FiberId, FiberSnapshot, Handler, HandlerId};
+pub use worker::{Worker, CodeId, Exit, RunSummary, Stepped, TraceHook, TrapAction, TrapHandler, SchedulePolicy};
+pub use module::{Module, ModuleId, LoadError};
 
 pub fn main() {
     todo!();
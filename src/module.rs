@@ -0,0 +1,208 @@
+//! A container format for shipping code and constants together, see [`Module::parse`].
+//!
+//! A module is laid out as follows, with every number little-endian:
+//!
+//! ```text
+//! magic     b"FLEX"
+//! version   u32, currently 1
+//! constants u32 count, then each constant as a u8 tag and its value:
+//!           0 for a natural, followed by a u64,
+//!           1 for bytes, followed by a u32 length and that many bytes
+//! code      u32 count of at least 1, then each section as a u32 length and that many bytes
+//! ```
+//!
+//! Nothing may follow the last code section.
+
+use crate::Constant;
+
+/// The bytes every module starts with.
+pub const MAGIC: &[u8; 4] = b"FLEX";
+/// The version of the format parsed by [`Module::parse`].
+pub const VERSION: u32 = 1;
+
+const TAG_U64:   u8 = 0;
+const TAG_BYTES: u8 = 1;
+
+/// Identifies a module loaded on a worker, see [`crate::Worker::load_module`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ModuleId(pub usize);
+
+/// Returned when a module can not be parsed, see [`Module::parse`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The module does not start with [`MAGIC`].
+    BadMagic,
+    /// The module is of a version that can not be parsed.
+    UnsupportedVersion(u32),
+    /// The module ends in the middle of a section.
+    Truncated,
+    /// A constant has a tag that is not a kind of constant.
+    BadConstantTag(u8),
+    /// The module has no code sections.
+    NoCode,
+    /// Some bytes follow the last code section.
+    TrailingBytes,
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::BadMagic                    => write!(f, "not a module, the magic header is wrong"),
+            LoadError::UnsupportedVersion(version) => write!(f, "module version {} is not supported", version),
+            LoadError::Truncated                   => write!(f, "module ends in the middle of a section"),
+            LoadError::BadConstantTag(tag)         => write!(f, "constant tag {} is not a kind of constant", tag),
+            LoadError::NoCode                      => write!(f, "module has no code"),
+            LoadError::TrailingBytes               => write!(f, "module has bytes past its last section"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// The constants and code sections of a parsed module.
+/// Every code section shares the same constant pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    /// The constant pool, shared by every code section.
+    pub constants: Vec<Constant>,
+    /// The code sections, in order; the first is the entry point.
+    pub code: Vec<Vec<u8>>,
+}
+
+/// Reads numbers and byte strings off the front of a module.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        if len > self.bytes.len() { return Err(LoadError::Truncated); }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        return Ok(taken);
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a u32 length, then that many bytes.
+    fn section(&mut self) -> Result<&'a [u8], LoadError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+impl Module {
+    /// Parses a module, see the [module docs](self) for the format.
+    /// Returns an error instead of panicking on any malformed input.
+    pub fn parse(bytes: &[u8]) -> Result<Module, LoadError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len()).map_err(|_| LoadError::BadMagic)? != MAGIC {
+            return Err(LoadError::BadMagic);
+        }
+        let version = reader.u32()?;
+        if version != VERSION { return Err(LoadError::UnsupportedVersion(version)); }
+
+        // counts come from the module, so are not trusted to size allocations
+        let mut constants = vec![];
+        for _ in 0..reader.u32()? {
+            let constant = match reader.u8()? {
+                TAG_U64   => Constant::U64(reader.u64()?),
+                TAG_BYTES => Constant::Bytes(reader.section()?.to_vec()),
+                tag       => return Err(LoadError::BadConstantTag(tag)),
+            };
+            constants.push(constant);
+        }
+
+        let mut code = vec![];
+        for _ in 0..reader.u32()? {
+            code.push(reader.section()?.to_vec());
+        }
+        if code.is_empty() { return Err(LoadError::NoCode); }
+        if !reader.bytes.is_empty() { return Err(LoadError::TrailingBytes); }
+
+        return Ok(Module { constants, code });
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Hand-builds a module with some constants and code sections.
+    pub fn build(constants: &[Constant], code: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(constants.len() as u32).to_le_bytes());
+        for constant in constants {
+            match constant {
+                Constant::U64(value) => {
+                    bytes.push(TAG_U64);
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                },
+                Constant::Bytes(value) => {
+                    bytes.push(TAG_BYTES);
+                    bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                    bytes.extend_from_slice(value);
+                },
+            }
+        }
+        bytes.extend_from_slice(&(code.len() as u32).to_le_bytes());
+        for section in code {
+            bytes.extend_from_slice(&(section.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(section);
+        }
+        return bytes;
+    }
+
+    #[test]
+    fn parse_module() {
+        let constants = vec![Constant::U64(7), Constant::Bytes(b"abc".to_vec())];
+        let bytes = build(&constants, &[&[1, 2, 3], &[]]);
+        let module = Module::parse(&bytes).unwrap();
+        assert_eq!(module.constants, constants);
+        assert_eq!(module.code, vec![vec![1, 2, 3], vec![]]);
+    }
+
+    #[test]
+    fn malformed_modules_error() {
+        let bytes = build(&[Constant::Bytes(b"abc".to_vec())], &[&[1, 2, 3]]);
+        // every cut short prefix errors, instead of panicking
+        for len in 0..bytes.len() {
+            let error = Module::parse(&bytes[..len]).unwrap_err();
+            let expected = if len < MAGIC.len() { LoadError::BadMagic } else { LoadError::Truncated };
+            assert_eq!(error, expected, "prefix of {} bytes", len);
+        }
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(Module::parse(&bad_magic), Err(LoadError::BadMagic));
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 2;
+        assert_eq!(Module::parse(&bad_version), Err(LoadError::UnsupportedVersion(2)));
+
+        let mut bad_tag = bytes.clone();
+        bad_tag[12] = 9;
+        assert_eq!(Module::parse(&bad_tag), Err(LoadError::BadConstantTag(9)));
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(Module::parse(&trailing), Err(LoadError::TrailingBytes));
+
+        assert_eq!(Module::parse(&build(&[], &[])), Err(LoadError::NoCode));
+        // a huge count does not allocate up front
+        let mut huge = build(&[], &[]);
+        huge[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Module::parse(&huge), Err(LoadError::Truncated));
+    }
+}
@@ -1,36 +1,68 @@
-use std::mem::transmute;
 use crate::Pointer;
 
 #[derive(Debug)]
+#[repr(transparent)]
 pub struct Slot(u64);
 
 impl Slot {
+    /// Returns a zeroed slot.
+    ///
+    /// # Safety
+    /// A zeroed slot is not a valid owned pointer.
     pub unsafe fn zero() -> Slot {
         Slot(0)
     }
 
+    /// Reinterpret raw bits as a slot.
+    ///
+    /// # Safety
+    /// Caller must ensure the bits are a valid representation
+    /// of whatever the slot will later be read as.
     pub unsafe fn from_bits(bits: u64) -> Slot {
         Slot(bits)
     }
 
+    /// Interpreting the slot as a pointer,
+    /// returns a borrowed copy of that pointer.
+    ///
+    /// # Safety
+    /// Caller must ensure that the slot in question is a pointer.
     pub unsafe fn to_borrowed_pointer(&self) -> Pointer {
         Pointer::from_bits(self.0).borrow()
     }
 
+    /// Interpreting the slot as a pointer,
+    /// returns the pointer, ownership included.
+    ///
+    /// # Safety
+    /// Caller must ensure that the slot in question is a pointer,
+    /// and that an owned pointer is not duplicated.
     pub unsafe fn to_pointer(&self) -> Pointer {
         Pointer::from_bits(self.0)
     }
 
+    /// Returns the raw bits of the slot.
+    ///
+    /// # Safety
+    /// Caller must ensure that the slot in question is a natural number.
     pub unsafe fn to_u64(&self) -> u64 {
         self.0
     }
 
+    /// Interpreting the slot as a signed integer.
+    ///
+    /// # Safety
+    /// Caller must ensure that the slot in question is an integer.
     pub unsafe fn to_i64(&self) -> i64 {
-        transmute(self.0)
+        self.0 as i64
     }
 
+    /// Interpreting the slot as a floating point number.
+    ///
+    /// # Safety
+    /// Caller must ensure that the slot in question is a float.
     pub unsafe fn to_f64(&self) -> f64 {
-        transmute(self.0)
+        f64::from_bits(self.0)
     }
 
     /// Interpreting the slot as a bitfield,
@@ -59,3 +91,88 @@ impl Slot {
         (shifted & 0xFF) as u8
     }
 }
+
+/// A value read from a fixed number of slots, see [`crate::Heap::read_as`].
+pub trait FromSlots: Sized {
+    /// The number of slots a value takes up.
+    const SLOTS: usize;
+
+    /// Reads a value from exactly [`FromSlots::SLOTS`] slots.
+    ///
+    /// # Safety
+    /// Caller must ensure the slots hold a value of this type.
+    unsafe fn from_slots(slots: &[Slot]) -> Self;
+}
+
+/// A value written to a fixed number of slots, see [`crate::Heap::write_as`].
+pub trait ToSlots {
+    /// The number of slots a value takes up.
+    const SLOTS: usize;
+
+    /// Appends exactly [`ToSlots::SLOTS`] slots holding the value.
+    fn to_slots(&self, slots: &mut Vec<Slot>);
+}
+
+impl FromSlots for u64 {
+    const SLOTS: usize = 1;
+
+    unsafe fn from_slots(slots: &[Slot]) -> u64 {
+        slots[0].to_u64()
+    }
+}
+
+impl ToSlots for u64 {
+    const SLOTS: usize = 1;
+
+    fn to_slots(&self, slots: &mut Vec<Slot>) {
+        slots.push(Slot(*self));
+    }
+}
+
+impl FromSlots for i64 {
+    const SLOTS: usize = 1;
+
+    unsafe fn from_slots(slots: &[Slot]) -> i64 {
+        slots[0].to_i64()
+    }
+}
+
+impl ToSlots for i64 {
+    const SLOTS: usize = 1;
+
+    fn to_slots(&self, slots: &mut Vec<Slot>) {
+        slots.push(Slot(*self as u64));
+    }
+}
+
+impl FromSlots for f64 {
+    const SLOTS: usize = 1;
+
+    unsafe fn from_slots(slots: &[Slot]) -> f64 {
+        slots[0].to_f64()
+    }
+}
+
+impl ToSlots for f64 {
+    const SLOTS: usize = 1;
+
+    fn to_slots(&self, slots: &mut Vec<Slot>) {
+        slots.push(Slot(self.to_bits()));
+    }
+}
+
+impl<const N: usize> FromSlots for [u64; N] {
+    const SLOTS: usize = N;
+
+    unsafe fn from_slots(slots: &[Slot]) -> [u64; N] {
+        std::array::from_fn(|index| slots[index].to_u64())
+    }
+}
+
+impl<const N: usize> ToSlots for [u64; N] {
+    const SLOTS: usize = N;
+
+    fn to_slots(&self, slots: &mut Vec<Slot>) {
+        slots.extend(self.iter().map(|value| Slot(*value)));
+    }
+}
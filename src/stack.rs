@@ -1,8 +1,262 @@
+/// A call frame on the stack.
+/// The values of a frame start at its base, its arguments and locals first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Frame {
-    
+    return_ip: usize,
+    base: usize,
 }
 
+impl Frame {
+    /// Returns where to continue running once the frame returns.
+    pub fn return_ip(&self) -> usize {
+        self.return_ip
+    }
+
+    /// Returns where the values of the frame start on the stack.
+    pub fn base(&self) -> usize {
+        self.base
+    }
+}
+
+use crate::VmConfig;
+
+/// Returned when the stack is misused, see [`Stack::pop`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    /// A value was popped or read from a frame with no values left.
+    Underflow,
+    /// A frame was popped when there were none.
+    NoFrame,
+    /// A local was accessed past the values of the current frame.
+    LocalOutOfRange { index: usize, locals: usize },
+    /// A value or frame was pushed past the limits of the stack, see [`VmConfig`].
+    Overflow,
+}
+
+impl std::fmt::Display for StackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StackError::Underflow => write!(f, "stack underflow"),
+            StackError::NoFrame   => write!(f, "no frame to pop"),
+            StackError::Overflow  => write!(f, "stack overflow"),
+            StackError::LocalOutOfRange { index, locals } => write!(
+                f, "local {} is out of range, the frame has {} values",
+                index, locals,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StackError {}
+
+/// The values an instruction works on, split into frames, see [`crate::vm::step`].
+/// Values below the current frame can not be popped or read,
+/// so frames never see each other's values.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct Stack {
     data: Vec<u64>,
     frames: Vec<Frame>,
+    config: VmConfig,
+}
+
+impl Stack {
+    /// Constructs a new empty stack, with the default limits.
+    pub fn new() -> Stack {
+        Stack::with_config(VmConfig::default())
+    }
+
+    /// Constructs a new empty stack, with the limits of some configuration.
+    pub fn with_config(config: VmConfig) -> Stack {
+        Stack { data: vec![], frames: vec![], config }
+    }
+
+    /// Returns where the values of the current frame start.
+    /// If there are no frames, the whole stack is the frame.
+    fn base(&self) -> usize {
+        self.frames.last().map_or(0, |frame| frame.base)
+    }
+
+    /// Pushes a value onto the top of the stack.
+    /// Returns an error if the stack already holds as many values as it may.
+    pub fn push(&mut self, value: u64) -> Result<(), StackError> {
+        if self.data.len() >= self.config.max_stack_slots() { return Err(StackError::Overflow); }
+        self.data.push(value);
+        return Ok(());
+    }
+
+    /// Pops the value on top of the stack.
+    /// Returns an error if the current frame has no values.
+    pub fn pop(&mut self) -> Result<u64, StackError> {
+        if self.data.len() == self.base() { return Err(StackError::Underflow); }
+        return Ok(self.data.pop().unwrap());
+    }
+
+    /// Returns the number of values in the current frame.
+    pub fn depth_in_frame(&self) -> usize {
+        self.data.len() - self.base()
+    }
+
+    /// Returns the value on top of the stack, without popping it.
+    /// Returns an error if the current frame has no values.
+    pub fn peek(&self) -> Result<u64, StackError> {
+        if self.data.len() == self.base() { return Err(StackError::Underflow); }
+        return Ok(*self.data.last().unwrap());
+    }
+
+    /// Starts a new frame on top of the current one, that returns to `return_ip`.
+    /// The top `args` values of the current frame become the first locals of the new frame,
+    /// and values pushed from now on are its locals too.
+    /// Returns an error if the current frame has fewer than `args` values,
+    /// or there are already as many frames as there may be.
+    pub fn push_frame(&mut self, return_ip: usize, args: usize) -> Result<(), StackError> {
+        let values = self.data.len() - self.base();
+        if args > values { return Err(StackError::Underflow); }
+        if self.frames.len() >= self.config.max_frames() { return Err(StackError::Overflow); }
+        self.frames.push(Frame { return_ip, base: self.data.len() - args });
+        return Ok(());
+    }
+
+    /// Ends the current frame, dropping its values.
+    /// Returns an error if there are no frames.
+    pub fn pop_frame(&mut self) -> Result<Frame, StackError> {
+        let frame = self.frames.pop().ok_or(StackError::NoFrame)?;
+        self.data.truncate(frame.base);
+        return Ok(frame);
+    }
+
+    /// Ends the current frame like [`Stack::pop_frame`],
+    /// but keeps its top `results` values, which are moved down to where the frame started.
+    /// Returns an error if there are no frames, or the frame has fewer than `results` values,
+    /// in which case the stack is left as it was.
+    pub fn return_frame(&mut self, results: usize) -> Result<Frame, StackError> {
+        let frame = *self.frames.last().ok_or(StackError::NoFrame)?;
+        let values = self.data.len() - frame.base;
+        if results > values { return Err(StackError::Underflow); }
+
+        self.frames.pop();
+        self.data.drain(frame.base..(self.data.len() - results));
+        return Ok(frame);
+    }
+
+    /// Returns the number of frames.
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns a local of the current frame,
+    /// which is the value at some index from the base of the frame.
+    pub fn get_local(&self, index: usize) -> Result<u64, StackError> {
+        let base = self.base();
+        match self.data.get(base + index) {
+            Some(value) => Ok(*value),
+            None => Err(StackError::LocalOutOfRange { index, locals: self.data.len() - base }),
+        }
+    }
+
+    /// Overwrites a local of the current frame, see [`Stack::get_local`].
+    /// The local must already have been pushed.
+    pub fn set_local(&mut self, index: usize, value: u64) -> Result<(), StackError> {
+        let base = self.base();
+        let locals = self.data.len() - base;
+        match self.data.get_mut(base + index) {
+            Some(slot) => { *slot = value; Ok(()) },
+            None => Err(StackError::LocalOutOfRange { index, locals }),
+        }
+    }
+
+    /// Returns the values on the stack, the top last.
+    pub fn as_slice(&self) -> &[u64] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_pop_peek() {
+        let mut stack = Stack::new();
+        assert_eq!(stack.pop(), Err(StackError::Underflow));
+        assert_eq!(stack.peek(), Err(StackError::Underflow));
+        stack.push(1).unwrap();
+        stack.push(2).unwrap();
+        assert_eq!(stack.peek(), Ok(2));
+        assert_eq!(stack.pop(), Ok(2));
+        assert_eq!(stack.pop(), Ok(1));
+        assert_eq!(stack.pop(), Err(StackError::Underflow));
+        assert_eq!(stack.pop_frame(), Err(StackError::NoFrame));
+    }
+
+    #[test]
+    fn nested_frames_do_not_alias() {
+        let mut stack = Stack::new();
+        stack.push(10).unwrap();
+        stack.push(11).unwrap();
+
+        stack.push_frame(0, 0).unwrap();
+        stack.push(20).unwrap();
+        stack.push(21).unwrap();
+        stack.set_local(1, 22).unwrap();
+        assert_eq!(stack.get_local(0), Ok(20));
+
+        stack.push_frame(0, 0).unwrap();
+        assert_eq!(stack.depth(), 2);
+        // the new frame starts empty, and can't see the frame below
+        assert_eq!(stack.get_local(0), Err(StackError::LocalOutOfRange { index: 0, locals: 0 }));
+        assert_eq!(stack.pop(), Err(StackError::Underflow));
+        stack.push(30).unwrap();
+        stack.set_local(0, 31).unwrap();
+        assert_eq!(stack.set_local(1, 0), Err(StackError::LocalOutOfRange { index: 1, locals: 1 }));
+        assert_eq!(stack.as_slice(), &[10, 11, 20, 22, 31]);
+
+        // popping a frame drops its values, and the frame below is untouched
+        stack.pop_frame().unwrap();
+        assert_eq!(stack.get_local(0), Ok(20));
+        assert_eq!(stack.get_local(1), Ok(22));
+        stack.pop_frame().unwrap();
+        assert_eq!(stack.get_local(1), Ok(11));
+        assert_eq!(stack.as_slice(), &[10, 11]);
+        assert_eq!(stack.depth(), 0);
+    }
+
+    #[test]
+    fn frames_take_arguments_and_return_results() {
+        let mut stack = Stack::new();
+        stack.push(1).unwrap();
+        stack.push(2).unwrap();
+        stack.push(3).unwrap();
+        assert_eq!(stack.push_frame(7, 4), Err(StackError::Underflow));
+
+        // the top two values are the arguments
+        stack.push_frame(7, 2).unwrap();
+        assert_eq!(stack.get_local(0), Ok(2));
+        assert_eq!(stack.get_local(1), Ok(3));
+        stack.push(4).unwrap();
+        stack.push(5).unwrap();
+        stack.push(6).unwrap();
+        assert_eq!(stack.return_frame(6), Err(StackError::Underflow));
+        assert_eq!(stack.depth(), 1);
+
+        // the results are moved down over the arguments
+        let frame = stack.return_frame(2).unwrap();
+        assert_eq!(frame.return_ip(), 7);
+        assert_eq!(frame.base(), 1);
+        assert_eq!(stack.as_slice(), &[1, 5, 6]);
+        assert_eq!(stack.return_frame(0), Err(StackError::NoFrame));
+    }
+
+    #[test]
+    fn limits_overflow() {
+        let config = VmConfig::new().with_max_stack_slots(3).with_max_frames(1);
+        let mut stack = Stack::with_config(config);
+        stack.push(1).unwrap();
+        stack.push(2).unwrap();
+        stack.push_frame(0, 1).unwrap();
+        assert_eq!(stack.push_frame(0, 0), Err(StackError::Overflow));
+        stack.push(3).unwrap();
+        assert_eq!(stack.push(4), Err(StackError::Overflow));
+        assert_eq!(stack.as_slice(), &[1, 2, 3]);
+        assert_eq!(stack.depth(), 1);
+    }
 }
@@ -0,0 +1,1285 @@
+//! Decoding and running instructions, one at a time.
+
+use crate::{Code, OpCode, Stack, StackError, Heap, Pointer, Slot, Constant, ConstantId, CodeId, FiberId};
+
+/// What happened when running an instruction, see [`step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    /// The instruction ran, and the instruction pointer is at the next one.
+    Continue,
+    /// A `Halt` was run, or the code ended.
+    /// A [`crate::Fiber`] only stops like this if its stack is empty, see [`StepResult::Halted`].
+    Halt,
+    /// A fiber halted, with the value on top of its stack as its result.
+    /// Only a [`crate::Fiber`] gives a result, the VM halts with [`StepResult::Halt`].
+    Halted(u64),
+    /// The byte at the instruction pointer is not an opcode.
+    /// The instruction pointer is left pointing at it.
+    IllegalInstruction(u8),
+    /// An instruction needed more values than were on the stack.
+    StackUnderflow,
+    /// A checked arithmetic instruction overflowed.
+    /// Its operands were popped, and nothing was pushed.
+    Overflow,
+    /// A jump or call would have left the code.
+    /// Jumping to the very end is allowed, and halts.
+    JumpOutOfBounds,
+    /// A `Return` was run outside of any subroutine.
+    NoFrame,
+    /// A value or frame was pushed past the limits of the stack, see [`VmConfig`].
+    StackOverflow,
+    /// A `LoadConst` named a constant that is not in the pool.
+    MissingConstant(ConstantId),
+    /// An `Alloc` did not fit in the heap.
+    OutOfMemory,
+    /// A heap instruction was given a pointer that is not the start of a live allocation,
+    /// or, for a `Free`, the wrong size for the allocation.
+    BadPointer,
+    /// A `Load` or `Store` was given a slot past the end of the allocation.
+    OutOfBounds,
+    /// An `AllocShared` was run where there is no shared heap.
+    NoSharedHeap,
+    /// A `Spawn` asks for a fiber running some code to be spawned,
+    /// and its id pushed, which only a [`crate::Worker`] can do.
+    /// The instruction pointer is already past the `Spawn`.
+    Spawn(CodeId),
+    /// A `Spawn` named code that is not loaded on the worker.
+    UnknownCode(CodeId),
+    /// An `InstallHandler` asks for a handler to be installed, which only a [`crate::Fiber`] can do.
+    /// The instruction pointer is already past it, and its operands are still on the stack.
+    InstallHandler { effect: u64, target: usize },
+    /// A `Perform` asks for an effect to be performed, see [`StepResult::InstallHandler`].
+    Perform(u64),
+    /// A `Resume` asks for the running handler to resume, see [`StepResult::InstallHandler`].
+    Resume,
+    /// A `Perform` found no handler for its effect.
+    UnhandledEffect(u64),
+    /// A `Resume` was run outside of a handler.
+    NoResumption,
+    /// A `Send` asks for a value to be sent to a fiber, which only a [`crate::Worker`] can do.
+    /// The instruction pointer is already past it, and its operands were popped.
+    Send { to: FiberId, value: u64 },
+    /// A `Receive` asks for a message, which only a [`crate::Fiber`] can give.
+    /// The instruction pointer is already past it.
+    Receive,
+    /// The fiber has no messages to receive, and is waiting for one.
+    /// The instruction pointer is left at the `Receive`, so running it again retries.
+    Parked,
+    /// A `Send` named a fiber that is not running.
+    UnknownFiber(FiberId),
+    /// The fiber has used up its gas, so the instruction was not run.
+    /// Running it again after refueling continues where it left off, see [`VmConfig::with_gas`].
+    OutOfGas,
+}
+
+impl StepResult {
+    /// Returns whether the code halted, with a result or not.
+    pub fn is_halt(&self) -> bool {
+        matches!(self, StepResult::Halt | StepResult::Halted(_))
+    }
+}
+
+/// Limits on the resources code may use while running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmConfig {
+    max_stack_slots: usize,
+    max_frames: usize,
+    gas: u64,
+}
+
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+        VmConfig::new()
+    }
+}
+
+impl VmConfig {
+    /// Constructs the default configuration,
+    /// which allows a million values on the stack and sixty-odd thousand frames,
+    /// and as much gas as there can be.
+    pub fn new() -> VmConfig {
+        VmConfig { max_stack_slots: 1 << 20, max_frames: 1 << 16, gas: u64::MAX }
+    }
+
+    /// Sets the most values the stack may hold, across every frame.
+    pub fn with_max_stack_slots(mut self, max_stack_slots: usize) -> VmConfig {
+        self.max_stack_slots = max_stack_slots;
+        self
+    }
+
+    /// Sets the most frames there may be, which limits how deep calls can recurse.
+    pub fn with_max_frames(mut self, max_frames: usize) -> VmConfig {
+        self.max_frames = max_frames;
+        self
+    }
+
+    /// Sets the gas a fiber starts with, which is the number of instructions it may run
+    /// before it must be refueled, see [`crate::Worker::refuel`].
+    pub fn with_gas(mut self, gas: u64) -> VmConfig {
+        self.gas = gas;
+        self
+    }
+
+    /// Returns the gas a fiber starts with.
+    pub fn gas(&self) -> u64 {
+        self.gas
+    }
+
+    /// Returns the most values the stack may hold.
+    pub fn max_stack_slots(&self) -> usize {
+        self.max_stack_slots
+    }
+
+    /// Returns the most frames there may be.
+    pub fn max_frames(&self) -> usize {
+        self.max_frames
+    }
+}
+
+/// The heaps an instruction can reach, see [`step_with`]:
+/// the fiber's own, and the heap shared by every fiber on a worker, if there is one.
+/// Which heap a pointer is into is tagged on the pointer, see [`Pointer::is_shared`].
+#[derive(Debug)]
+pub struct Heaps<'a> {
+    pub local: &'a mut Heap,
+    pub shared: Option<&'a mut Heap>,
+}
+
+impl Heaps<'_> {
+    /// Returns the heap a pointer is into,
+    /// or `None` if it is into a shared heap and there is none.
+    pub fn of(&mut self, pointer: Pointer) -> Option<&mut Heap> {
+        match pointer.is_shared() {
+            true  => self.shared.as_deref_mut(),
+            false => Some(&mut *self.local),
+        }
+    }
+}
+
+/// Runs an instruction, given the instruction pointer, the stack, the heaps, and the code.
+/// Every handler has this signature, see [`HANDLERS`].
+type Handler = fn(&mut usize, &mut Stack, &mut Heaps, &Code) -> StepResult;
+
+/// Defines a handler for an instruction.
+/// The instruction pointer is already past the opcode when the handler runs.
+macro_rules! op {
+    {
+        fn $name:ident(
+            $ip:ident,
+            $stack:ident,
+            $heap:ident,
+            $code:ident $(,)?
+        ) $body:block
+    } => {
+        #[allow(unused_variables)]
+        fn $name(
+            $ip: &mut usize,
+            $stack: &mut Stack,
+            $heap: &mut Heaps,
+            $code: &Code,
+        ) -> StepResult $body
+    };
+}
+
+/// Pops a value, returning from the handler if the frame is empty.
+macro_rules! pop {
+    ($stack:ident) => {
+        match $stack.pop() {
+            Ok(value) => value,
+            Err(_) => return StepResult::StackUnderflow,
+        }
+    };
+}
+
+/// Returns from the handler if the frame holds fewer than some number of values,
+/// checked before any are popped, so an instruction that underflows leaves the stack as it was.
+macro_rules! operands {
+    ($stack:ident, $count:expr) => {
+        if $stack.depth_in_frame() < $count {
+            return StepResult::StackUnderflow;
+        }
+    };
+}
+
+/// Pushes a value, returning from the handler if the stack is full.
+macro_rules! push {
+    ($stack:ident, $value:expr) => {
+        if $stack.push($value).is_err() {
+            return StepResult::StackOverflow;
+        }
+    };
+}
+
+/// Reads an immediate natural, halting like the end of the code if it is cut short.
+macro_rules! immediate {
+    ($ip:ident, $code:ident) => {
+        match $code.read_u64($ip) {
+            Some(immediate) => immediate,
+            None => return StepResult::Halt,
+        }
+    };
+}
+
+/// Handlers for each instruction, indexed by opcode.
+const HANDLERS: [Handler; 41] = [
+    add_u64,
+    sub_u64,
+    mul_u64,
+    push,
+    pop,
+    dup,
+    halt,
+    jump,
+    add_u64_checked,
+    sub_u64_checked,
+    mul_u64_checked,
+    eq,
+    lt,
+    gt,
+    jump_if_zero,
+    jump_if_non_zero,
+    call,
+    ret,
+    load_const,
+    alloc,
+    free,
+    load,
+    store,
+    spawn,
+    install_handler,
+    perform,
+    resume,
+    send,
+    receive,
+    swap,
+    over,
+    rot,
+    alloc_shared,
+    share,
+    add_f64,
+    sub_f64,
+    mul_f64,
+    div_f64,
+    i2f,
+    f2i,
+    realloc,
+];
+
+/// Decodes and runs the instruction at the instruction pointer,
+/// moving the instruction pointer past it.
+/// Running off the end of the code halts.
+/// There is no shared heap, see [`step_with`].
+pub fn step(ip: &mut usize, stack: &mut Stack, heap: &mut Heap, code: &Code) -> StepResult {
+    step_with(ip, stack, &mut Heaps { local: heap, shared: None }, code)
+}
+
+/// Like [`step`], but with a shared heap as well as a local one.
+pub fn step_with(ip: &mut usize, stack: &mut Stack, heaps: &mut Heaps, code: &Code) -> StepResult {
+    // prefetching would quietly halt on a byte that is not an opcode
+    if let Some(byte) = code.bytes().get(*ip) {
+        if OpCode::from_u8(*byte).is_none() {
+            return StepResult::IllegalInstruction(*byte);
+        }
+    }
+
+    let op = code.prefetch(ip);
+    return HANDLERS[op as usize](ip, stack, heaps, code);
+}
+
+op! {
+    fn add_u64(ip, stack, heap, code) {
+        operands!(stack, 2);
+        let b = pop!(stack);
+        let a = pop!(stack);
+        push!(stack, a.wrapping_add(b));
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn sub_u64(ip, stack, heap, code) {
+        operands!(stack, 2);
+        let b = pop!(stack);
+        let a = pop!(stack);
+        push!(stack, a.wrapping_sub(b));
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn mul_u64(ip, stack, heap, code) {
+        operands!(stack, 2);
+        let b = pop!(stack);
+        let a = pop!(stack);
+        push!(stack, a.wrapping_mul(b));
+        StepResult::Continue
+    }
+}
+
+/// Pops two naturals and pushes the result of a checked operation on them,
+/// returning from the handler if it overflowed.
+macro_rules! checked {
+    ($stack:ident, $op:ident) => {{
+        operands!($stack, 2);
+        let b = pop!($stack);
+        let a = pop!($stack);
+        match a.$op(b) {
+            Some(result) => push!($stack, result),
+            None => return StepResult::Overflow,
+        }
+        StepResult::Continue
+    }};
+}
+
+op! {
+    fn add_u64_checked(ip, stack, heap, code) {
+        checked!(stack, checked_add)
+    }
+}
+
+op! {
+    fn sub_u64_checked(ip, stack, heap, code) {
+        checked!(stack, checked_sub)
+    }
+}
+
+op! {
+    fn mul_u64_checked(ip, stack, heap, code) {
+        checked!(stack, checked_mul)
+    }
+}
+
+op! {
+    fn push(ip, stack, heap, code) {
+        let immediate = immediate!(ip, code);
+        push!(stack, immediate);
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn pop(ip, stack, heap, code) {
+        pop!(stack);
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn dup(ip, stack, heap, code) {
+        let a = pop!(stack);
+        push!(stack, a);
+        push!(stack, a);
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn swap(ip, stack, heap, code) {
+        operands!(stack, 2);
+        let b = pop!(stack);
+        let a = pop!(stack);
+        push!(stack, b);
+        push!(stack, a);
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn over(ip, stack, heap, code) {
+        operands!(stack, 2);
+        let b = pop!(stack);
+        let a = pop!(stack);
+        push!(stack, a);
+        push!(stack, b);
+        push!(stack, a);
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn rot(ip, stack, heap, code) {
+        operands!(stack, 3);
+        let c = pop!(stack);
+        let b = pop!(stack);
+        let a = pop!(stack);
+        push!(stack, b);
+        push!(stack, c);
+        push!(stack, a);
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn halt(ip, stack, heap, code) {
+        StepResult::Halt
+    }
+}
+
+/// Moves the instruction pointer by a signed offset, if `taken`.
+/// The offset is read either way.
+fn jump_by(ip: &mut usize, code: &Code, taken: bool) -> StepResult {
+    let offset = match code.read_i64(ip) {
+        Some(offset) => offset,
+        None => return StepResult::Halt,
+    };
+    if !taken { return StepResult::Continue; }
+
+    match ip.checked_add_signed(offset as isize) {
+        Some(target) if target <= code.bytes().len() => {
+            *ip = target;
+            StepResult::Continue
+        },
+        _ => StepResult::JumpOutOfBounds,
+    }
+}
+
+op! {
+    fn jump(ip, stack, heap, code) {
+        jump_by(ip, code, true)
+    }
+}
+
+op! {
+    fn jump_if_zero(ip, stack, heap, code) {
+        let condition = pop!(stack);
+        jump_by(ip, code, condition == 0)
+    }
+}
+
+op! {
+    fn jump_if_non_zero(ip, stack, heap, code) {
+        let condition = pop!(stack);
+        jump_by(ip, code, condition != 0)
+    }
+}
+
+op! {
+    fn call(ip, stack, heap, code) {
+        let target = immediate!(ip, code);
+        let args = immediate!(ip, code);
+        if target > code.bytes().len() as u64 { return StepResult::JumpOutOfBounds; }
+        if args > usize::MAX as u64 { return StepResult::StackUnderflow; }
+
+        match stack.push_frame(*ip, args as usize) {
+            Ok(()) => (),
+            Err(StackError::Overflow) => return StepResult::StackOverflow,
+            Err(_) => return StepResult::StackUnderflow,
+        }
+        *ip = target as usize;
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn ret(ip, stack, heap, code) {
+        let results = immediate!(ip, code);
+        if results > usize::MAX as u64 { return StepResult::StackUnderflow; }
+
+        match stack.return_frame(results as usize) {
+            Ok(frame) => {
+                *ip = frame.return_ip();
+                StepResult::Continue
+            },
+            Err(StackError::NoFrame) => StepResult::NoFrame,
+            Err(_) => StepResult::StackUnderflow,
+        }
+    }
+}
+
+op! {
+    fn load_const(ip, stack, heap, code) {
+        let id = ConstantId(immediate!(ip, code) as usize);
+        let constant = match code.constant(id) {
+            Some(constant) => constant,
+            None => return StepResult::MissingConstant(id),
+        };
+
+        match constant {
+            Constant::U64(value) => push!(stack, *value),
+            Constant::Bytes(_) => {
+                let slots = constant.to_slots().unwrap();
+                let pointer = match heap.local.try_calloc(slots.len()) {
+                    Ok(pointer) => heap.local.write(pointer, &slots),
+                    Err(_) => return StepResult::OutOfMemory,
+                };
+                // SAFETY: the pointer is moved onto the stack, not copied
+                push!(stack, unsafe { pointer.to_bits() });
+            },
+        }
+        StepResult::Continue
+    }
+}
+
+/// Pops a size, and pushes a pointer to a fresh zeroed allocation of that size in a heap,
+/// tagged as shared or not.
+fn alloc_in(stack: &mut Stack, heap: &mut Heap, shared: bool) -> StepResult {
+    let slots = pop!(stack) as usize;
+    match heap.try_calloc(slots) {
+        // SAFETY: the pointer is moved onto the stack, not copied
+        Ok(pointer) => push!(stack, unsafe { pointer.with_shared(shared).to_bits() }),
+        Err(_) => return StepResult::OutOfMemory,
+    }
+    StepResult::Continue
+}
+
+op! {
+    fn alloc(ip, stack, heap, code) {
+        alloc_in(stack, heap.local, false)
+    }
+}
+
+op! {
+    fn alloc_shared(ip, stack, heap, code) {
+        match heap.shared.as_deref_mut() {
+            Some(shared) => alloc_in(stack, shared, true),
+            None => StepResult::NoSharedHeap,
+        }
+    }
+}
+
+/// Pops a pointer, returning from the handler if it is not the start of a live allocation.
+/// Returns the pointer, the size of the allocation, and the heap it is in.
+macro_rules! pop_pointer {
+    ($stack:ident, $heaps:ident) => {{
+        // SAFETY: the stack is untyped, so the bits are trusted to be a pointer,
+        // but it is checked against the heap before it is used.
+        let pointer = unsafe { Pointer::from_bits(pop!($stack)) };
+        let heap = match $heaps.of(pointer) {
+            Some(heap) => heap,
+            None => return StepResult::BadPointer,
+        };
+        match heap.size_of(pointer) {
+            Some(slots) if heap.try_read(pointer, slots).is_ok() => (pointer, slots, heap),
+            _ => return StepResult::BadPointer,
+        }
+    }};
+}
+
+op! {
+    fn free(ip, stack, heap, code) {
+        operands!(stack, 2);
+        let slots = pop!(stack) as usize;
+        let (pointer, size, heap) = pop_pointer!(stack, heap);
+        if slots != size || !pointer.is_owned() { return StepResult::BadPointer; }
+
+        // another owner is left, so only this share is given up
+        if heap.ref_count(pointer) > 1 {
+            heap.release(pointer, slots);
+        } else if heap.try_free(pointer, slots).is_err() {
+            return StepResult::BadPointer;
+        }
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn realloc(ip, stack, heap, code) {
+        operands!(stack, 3);
+        let new = pop!(stack) as usize;
+        let old = pop!(stack) as usize;
+        let (pointer, size, heap) = pop_pointer!(stack, heap);
+        // other owners would be left pointing at the old allocation
+        if old != size || !heap.is_unique(pointer) { return StepResult::BadPointer; }
+
+        // SAFETY: the slots past the old size are zeroed before the pointer is pushed
+        let new_pointer = match unsafe { heap.try_realloc(pointer, old, new) } {
+            Ok(new_pointer) => new_pointer.with_shared(pointer.is_shared()),
+            Err(_) => return StepResult::OutOfMemory,
+        };
+        for slot in old..new {
+            // SAFETY: zero is a natural
+            heap.write_slot(new_pointer, slot, unsafe { Slot::zero() });
+        }
+        // SAFETY: the old pointer was popped, so the new one is moved onto the stack
+        push!(stack, unsafe { new_pointer.to_bits() });
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn share(ip, stack, heap, code) {
+        let (pointer, _size, heap) = pop_pointer!(stack, heap);
+        if !pointer.is_owned() { return StepResult::BadPointer; }
+        // SAFETY: the new owner is counted, so the pointer can be copied
+        let bits = unsafe { heap.share(pointer).to_bits() };
+        push!(stack, bits);
+        push!(stack, bits);
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn load(ip, stack, heap, code) {
+        operands!(stack, 2);
+        let slot = pop!(stack) as usize;
+        let (pointer, size, heap) = pop_pointer!(stack, heap);
+        if slot >= size { return StepResult::OutOfBounds; }
+        // SAFETY: the bits are copied verbatim
+        push!(stack, unsafe { heap.read_slot(pointer, slot).to_u64() });
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn store(ip, stack, heap, code) {
+        operands!(stack, 3);
+        let value = pop!(stack);
+        let slot = pop!(stack) as usize;
+        let (pointer, size, heap) = pop_pointer!(stack, heap);
+        if slot >= size { return StepResult::OutOfBounds; }
+        // SAFETY: the bits are copied verbatim
+        heap.write_slot(pointer, slot, unsafe { Slot::from_bits(value) });
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn spawn(ip, stack, heap, code) {
+        let code = immediate!(ip, code);
+        StepResult::Spawn(CodeId(code as usize))
+    }
+}
+
+op! {
+    fn install_handler(ip, stack, heap, code) {
+        let effect = immediate!(ip, code);
+        let target = immediate!(ip, code);
+        if target > code.bytes().len() as u64 { return StepResult::JumpOutOfBounds; }
+        StepResult::InstallHandler { effect, target: target as usize }
+    }
+}
+
+op! {
+    fn perform(ip, stack, heap, code) {
+        StepResult::Perform(immediate!(ip, code))
+    }
+}
+
+op! {
+    fn resume(ip, stack, heap, code) {
+        StepResult::Resume
+    }
+}
+
+op! {
+    fn send(ip, stack, heap, code) {
+        operands!(stack, 2);
+        let value = pop!(stack);
+        let to = FiberId(pop!(stack) as usize);
+        StepResult::Send { to, value }
+    }
+}
+
+op! {
+    fn receive(ip, stack, heap, code) {
+        StepResult::Receive
+    }
+}
+
+/// Pops two naturals and pushes whether they compare some way, as `1` or `0`.
+macro_rules! compare {
+    ($stack:ident, $op:tt) => {{
+        operands!($stack, 2);
+        let b = pop!($stack);
+        let a = pop!($stack);
+        push!($stack, (a $op b) as u64);
+        StepResult::Continue
+    }};
+}
+
+op! {
+    fn eq(ip, stack, heap, code) {
+        compare!(stack, ==)
+    }
+}
+
+op! {
+    fn lt(ip, stack, heap, code) {
+        compare!(stack, <)
+    }
+}
+
+op! {
+    fn gt(ip, stack, heap, code) {
+        compare!(stack, >)
+    }
+}
+
+/// Pops two floats, and pushes the result of an arithmetic operator on them.
+macro_rules! float {
+    ($stack:ident, $op:tt) => {{
+        operands!($stack, 2);
+        let b = f64::from_bits(pop!($stack));
+        let a = f64::from_bits(pop!($stack));
+        push!($stack, (a $op b).to_bits());
+        StepResult::Continue
+    }};
+}
+
+op! {
+    fn add_f64(ip, stack, heap, code) {
+        float!(stack, +)
+    }
+}
+
+op! {
+    fn sub_f64(ip, stack, heap, code) {
+        float!(stack, -)
+    }
+}
+
+op! {
+    fn mul_f64(ip, stack, heap, code) {
+        float!(stack, *)
+    }
+}
+
+op! {
+    fn div_f64(ip, stack, heap, code) {
+        float!(stack, /)
+    }
+}
+
+op! {
+    fn i2f(ip, stack, heap, code) {
+        let natural = pop!(stack);
+        push!(stack, (natural as f64).to_bits());
+        StepResult::Continue
+    }
+}
+
+op! {
+    fn f2i(ip, stack, heap, code) {
+        let float = f64::from_bits(pop!(stack));
+        push!(stack, float as u64);
+        StepResult::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Assembler, Pointer};
+
+    /// Steps through some code until it stops, returning the stack, how it stopped, and where.
+    fn run(code: Code) -> (Vec<u64>, StepResult, usize) {
+        run_with(code, VmConfig::default())
+    }
+
+    /// Like [`run`], with a stack limited by some configuration.
+    fn run_with(code: Code, config: VmConfig) -> (Vec<u64>, StepResult, usize) {
+        let mut stack = Stack::with_config(config);
+        let mut heap = Heap::new();
+        let mut ip = 0;
+        loop {
+            match step(&mut ip, &mut stack, &mut heap, &code) {
+                StepResult::Continue => continue,
+                result => return (stack.as_slice().to_vec(), result, ip),
+            }
+        }
+    }
+
+    #[test]
+    fn handlers_match_opcodes() {
+        // the handler for each opcode is found at its byte
+        let code = Code::new(vec![OpCode::Halt as u8]);
+        let mut heaps = Heaps { local: &mut Heap::new(), shared: None };
+        let result = HANDLERS[OpCode::Halt as usize](&mut 1, &mut Stack::new(), &mut heaps, &code);
+        assert_eq!(result, StepResult::Halt);
+        assert_eq!(OpCode::from_u8(HANDLERS.len() as u8), None);
+        assert!(OpCode::from_u8(HANDLERS.len() as u8 - 1).is_some());
+    }
+
+    #[test]
+    fn arithmetic() {
+        // (7 - 2) * (7 - 2) + 1
+        let code = Assembler::new()
+            .push(7).push(2).sub_u64()
+            .dup().mul_u64()
+            .push(1).add_u64()
+            .halt()
+            .finish().unwrap();
+        let (stack, result, _ip) = run(code);
+        assert_eq!(result, StepResult::Halt);
+        assert_eq!(stack, vec![26]);
+    }
+
+    #[test]
+    fn pop_and_end_of_code() {
+        let code = Assembler::new().push(1).push(2).pop().finish().unwrap();
+        let length = code.bytes().len();
+        let (stack, result, ip) = run(code);
+        assert_eq!(result, StepResult::Halt);
+        assert_eq!(stack, vec![1]);
+        assert_eq!(ip, length);
+
+        // an immediate cut short halts too
+        let (stack, result, _ip) = run(Code::new(vec![OpCode::Push as u8, 1, 2]));
+        assert_eq!(result, StepResult::Halt);
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn jump_skips_code() {
+        let code = Assembler::new()
+            .push(1)
+            .jump("end")
+            .push(2)
+            .label("end")
+            .push(3)
+            .finish().unwrap();
+        let (stack, result, _ip) = run(code);
+        assert_eq!(result, StepResult::Halt);
+        assert_eq!(stack, vec![1, 3]);
+    }
+
+    #[test]
+    fn illegal_instruction() {
+        let mut bytes = Assembler::new().push(3).finish().unwrap().bytes().to_vec();
+        bytes.extend_from_slice(&[0xff, OpCode::Halt as u8]);
+        let (stack, result, ip) = run(Code::new(bytes));
+        assert_eq!(result, StepResult::IllegalInstruction(0xff));
+        assert_eq!(stack, vec![3]);
+        assert_eq!(ip, 9);
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let (_stack, result, _ip) = run(Assembler::new().push(3).add_u64().finish().unwrap());
+        assert_eq!(result, StepResult::StackUnderflow);
+        let (_stack, result, _ip) = run(Assembler::new().dup().finish().unwrap());
+        assert_eq!(result, StepResult::StackUnderflow);
+    }
+
+    #[test]
+    fn wrapping_arithmetic_wraps() {
+        let code = Assembler::new()
+            .push(u64::MAX).push(1).add_u64()
+            .push(0).push(1).sub_u64()
+            .push(u64::MAX).push(2).mul_u64()
+            .finish().unwrap();
+        let (stack, result, _ip) = run(code);
+        assert_eq!(result, StepResult::Halt);
+        assert_eq!(stack, vec![0, u64::MAX, u64::MAX - 1]);
+    }
+
+    #[test]
+    fn checked_arithmetic_traps() {
+        let (stack, result, _ip) = run(Assembler::new().push(u64::MAX - 1).push(1).add_u64_checked().finish().unwrap());
+        assert_eq!(result, StepResult::Halt);
+        assert_eq!(stack, vec![u64::MAX]);
+
+        let overflows = [
+            Assembler::new().push(u64::MAX).push(1).add_u64_checked(),
+            Assembler::new().push(0).push(1).sub_u64_checked(),
+            Assembler::new().push(u64::MAX / 2 + 1).push(2).mul_u64_checked(),
+        ];
+        for code in overflows {
+            let (stack, result, _ip) = run(code.push(5).finish().unwrap());
+            assert_eq!(result, StepResult::Overflow);
+            assert!(stack.is_empty());
+        }
+    }
+
+    #[test]
+    fn float_arithmetic() {
+        // (3 + 0.5) * 2 / 7 - 0.25
+        let code = Assembler::new()
+            .push(3).i2f()
+            .push_f64(0.5).add_f64()
+            .push_f64(2.0).mul_f64()
+            .push_f64(7.0).div_f64()
+            .push_f64(0.25).sub_f64()
+            .finish().unwrap();
+        let (stack, _result, _ip) = run(code);
+        assert_eq!(stack, vec![0x3fe8000000000000]);
+        assert_eq!(f64::from_bits(stack[0]), 0.75);
+
+        // dividing by zero does not trap
+        let code = Assembler::new()
+            .push_f64(1.0).push_f64(0.0).div_f64()
+            .push_f64(0.0).push_f64(0.0).div_f64()
+            .finish().unwrap();
+        let (stack, result, _ip) = run(code);
+        assert_eq!(result, StepResult::Halt);
+        assert_eq!(stack[0], f64::INFINITY.to_bits());
+        assert!(f64::from_bits(stack[1]).is_nan());
+
+        let code = Assembler::new()
+            .push_f64(7.9).f2i()
+            .push_f64(-1.5).f2i()
+            .push_f64(1e30).f2i()
+            .push_f64(f64::NAN).f2i()
+            .finish().unwrap();
+        let (stack, _result, _ip) = run(code);
+        assert_eq!(stack, vec![7, 0, u64::MAX, 0]);
+    }
+
+    #[test]
+    fn comparisons() {
+        let code = Assembler::new()
+            .push(3).push(3).eq()
+            .push(3).push(4).eq()
+            .push(3).push(4).lt()
+            .push(4).push(3).lt()
+            .push(4).push(3).gt()
+            .push(3).push(3).gt()
+            .finish().unwrap();
+        let (stack, _result, _ip) = run(code);
+        assert_eq!(stack, vec![1, 0, 1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn countdown_loop() {
+        let code = Assembler::new()
+            .push(5)
+            .label("loop")
+            .push(1).sub_u64()
+            .dup().jump_if_non_zero("loop")
+            .halt()
+            .finish().unwrap();
+        let (stack, result, _ip) = run(code);
+        assert_eq!(result, StepResult::Halt);
+        assert_eq!(stack, vec![0]);
+
+        // counts up while less than 10, jumping out forwards
+        let code = Assembler::new()
+            .push(0)
+            .label("loop")
+            .push(1).add_u64()
+            .dup().push(10).lt().jump_if_zero("done")
+            .jump("loop")
+            .label("done")
+            .finish().unwrap();
+        let (stack, result, ip) = run(code.clone());
+        assert_eq!(result, StepResult::Halt);
+        assert_eq!(stack, vec![10]);
+        assert_eq!(ip, code.bytes().len());
+    }
+
+    #[test]
+    fn jumps_out_of_bounds_trap() {
+        let mut forward = vec![OpCode::Jump as u8];
+        forward.extend_from_slice(&1_i64.to_le_bytes());
+        let (_stack, result, _ip) = run(Code::new(forward));
+        assert_eq!(result, StepResult::JumpOutOfBounds);
+
+        let mut backward = vec![OpCode::Jump as u8];
+        backward.extend_from_slice(&(-10_i64).to_le_bytes());
+        let (_stack, result, _ip) = run(Code::new(backward));
+        assert_eq!(result, StepResult::JumpOutOfBounds);
+
+        // a jump that is not taken never traps
+        let mut untaken = Assembler::new().push(1).finish().unwrap().bytes().to_vec();
+        untaken.push(OpCode::JumpIfZero as u8);
+        untaken.extend_from_slice(&i64::MIN.to_le_bytes());
+        let (stack, result, _ip) = run(Code::new(untaken));
+        assert_eq!(result, StepResult::Halt);
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn call_and_return() {
+        let code = Assembler::new()
+            .push(100)
+            .push(2)
+            .push(3)
+            .call("add", 2)
+            .push(4)
+            .halt()
+            .label("add")
+            .push(7) // a local, dropped on return
+            .pop()
+            .add_u64()
+            .ret(1)
+            .finish().unwrap();
+
+        let mut stack = Stack::new();
+        let mut heap = Heap::new();
+        let mut ip = 0;
+        while step(&mut ip, &mut stack, &mut heap, &code) == StepResult::Continue {}
+        assert_eq!(stack.as_slice(), &[100, 5, 4]);
+        assert_eq!(stack.depth(), 0);
+    }
+
+    #[test]
+    fn bad_calls_trap() {
+        let (_stack, result, ip) = run(Assembler::new().ret(0).finish().unwrap());
+        assert_eq!(result, StepResult::NoFrame);
+        assert_eq!(ip, 9);
+
+        let mut outside = vec![OpCode::Call as u8];
+        outside.extend_from_slice(&100_u64.to_le_bytes());
+        outside.extend_from_slice(&0_u64.to_le_bytes());
+        let (_stack, result, _ip) = run(Code::new(outside));
+        assert_eq!(result, StepResult::JumpOutOfBounds);
+
+        // more arguments than values
+        let code = Assembler::new().push(1).label("f").call("f", 2).finish().unwrap();
+        let (stack, result, _ip) = run(code);
+        assert_eq!(result, StepResult::StackUnderflow);
+        assert_eq!(stack, vec![1]);
+
+        // more results than values
+        let code = Assembler::new().push(1).call("f", 1).label("f").ret(2).finish().unwrap();
+        assert_eq!(run(code).1, StepResult::StackUnderflow);
+    }
+
+    #[test]
+    fn infinite_recursion_overflows() {
+        let config = VmConfig::new().with_max_frames(10);
+        let code = Assembler::new().label("f").push(1).call("f", 0).finish().unwrap();
+        let (stack, result, ip) = run_with(code, config);
+        assert_eq!(result, StepResult::StackOverflow);
+        // a value was pushed in every frame, and then once more before the call that failed
+        assert_eq!(stack.len(), 11);
+        assert_eq!(ip, 26);
+
+        // pushing forever overflows too
+        let config = VmConfig::new().with_max_stack_slots(100);
+        let code = Assembler::new().label("loop").push(1).jump("loop").finish().unwrap();
+        let (stack, result, _ip) = run_with(code, config);
+        assert_eq!(result, StepResult::StackOverflow);
+        assert_eq!(stack.len(), 100);
+    }
+
+    #[test]
+    fn load_constants() {
+        let code = Assembler::new()
+            .constant(Constant::U64(42))
+            .constant(Constant::Bytes(b"hello, world".to_vec()))
+            .load_const(ConstantId(0))
+            .load_const(ConstantId(1))
+            .halt()
+            .finish().unwrap();
+
+        let mut stack = Stack::new();
+        let mut heap = Heap::new();
+        let mut ip = 0;
+        while step(&mut ip, &mut stack, &mut heap, &code) == StepResult::Continue {}
+        assert_eq!(stack.as_slice()[0], 42);
+
+        // SAFETY: the constant pushed an owned pointer to bytes
+        let pointer = unsafe { Pointer::from_bits(stack.as_slice()[1]) };
+        assert!(pointer.is_owned());
+        assert_eq!(heap.size_of(pointer), Some(3));
+        let bytes = unsafe { crate::constant::bytes_from_slots(heap.read(pointer, 3)) };
+        assert_eq!(bytes, b"hello, world");
+
+        let code = Assembler::new().load_const(ConstantId(2)).finish().unwrap();
+        assert_eq!(run(code).1, StepResult::MissingConstant(ConstantId(2)));
+    }
+
+    #[test]
+    fn alloc_store_load_free() {
+        let code = Assembler::new()
+            .push(4).alloc()
+            .dup().dup().push(2).push(99).store()
+            .push(2).load()
+            .push(99).eq().jump_if_zero("wrong")
+            .push(4).free()
+            .halt()
+            .label("wrong")
+            .push(0xbad)
+            .finish().unwrap();
+
+        let mut stack = Stack::new();
+        let mut heap = Heap::new();
+        let mut ip = 0;
+        let mut result = StepResult::Continue;
+        while result == StepResult::Continue {
+            result = step(&mut ip, &mut stack, &mut heap, &code);
+            if ip == 10 { assert_eq!(heap.used(), 4); }
+        }
+        assert_eq!(result, StepResult::Halt);
+        assert!(stack.as_slice().is_empty());
+        assert_eq!(heap.used(), 0);
+    }
+
+    #[test]
+    fn bad_heap_access_traps() {
+        let out_of_bounds = Assembler::new().push(2).alloc().push(2).load().finish().unwrap();
+        assert_eq!(run(out_of_bounds).1, StepResult::OutOfBounds);
+
+        let out_of_bounds = Assembler::new().push(2).alloc().push(5).push(1).store().finish().unwrap();
+        assert_eq!(run(out_of_bounds).1, StepResult::OutOfBounds);
+
+        let wrong_size = Assembler::new().push(2).alloc().push(3).free().finish().unwrap();
+        assert_eq!(run(wrong_size).1, StepResult::BadPointer);
+
+        let double_free = Assembler::new()
+            .push(2).alloc().dup()
+            .push(2).free()
+            .push(2).free()
+            .finish().unwrap();
+        assert_eq!(run(double_free).1, StepResult::BadPointer);
+
+        let not_a_pointer = Assembler::new().push(12345).push(0).load().finish().unwrap();
+        assert_eq!(run(not_a_pointer).1, StepResult::BadPointer);
+    }
+
+    #[test]
+    fn forged_pointers_trap() {
+        // past the end of the heap, or into no allocation at all
+        let past_the_heap = Assembler::new().push(2).alloc().pop().push(1 << 40).push(0).load();
+        let into_free_space = Assembler::new().push(2).alloc().dup().push(2).free().push(0).load();
+        // into the middle of a live allocation
+        let interior = Assembler::new().push(4).alloc().push(1).add_u64().push(0).push(7).store();
+        // into a shared heap there is not, at the same index as a live allocation
+        // SAFETY: the pointer is checked by the heap, and never read through
+        let shared = unsafe { Pointer::tagged(0, true).with_shared(true).to_bits() };
+        let shared = Assembler::new().push(2).alloc().pop().push(shared).push(0).load();
+        for code in [past_the_heap, into_free_space, interior, shared] {
+            let (stack, result, _ip) = run(code.finish().unwrap());
+            assert_eq!(result, StepResult::BadPointer);
+            assert!(stack.is_empty());
+        }
+
+        // a live pointer can't be used to reach past its allocation into the next
+        let next = Assembler::new()
+            .push(2).alloc()
+            .push(2).alloc().pop()
+            .push(2).push(9).store()
+            .finish().unwrap();
+        assert_eq!(run(next).1, StepResult::OutOfBounds);
+    }
+
+    #[test]
+    fn realloc_moves_data() {
+        let grow = Assembler::new()
+            .push(2).alloc()
+            .dup().push(0).push(11).store()
+            .dup().push(1).push(12).store()
+            // an allocation right after, so growing has to move
+            .push(1).alloc().pop()
+            .dup().push(2).push(5).realloc()
+            .halt()
+            .finish().unwrap();
+        let mut stack = Stack::new();
+        let mut heap = Heap::new().with_generation_checks(true);
+        let mut ip = 0;
+        while step(&mut ip, &mut stack, &mut heap, &grow) == StepResult::Continue {}
+
+        // SAFETY: both are pointers pushed by the code
+        let (old, new) = unsafe { (Pointer::from_bits(stack.as_slice()[0]), Pointer::from_bits(stack.as_slice()[1])) };
+        assert_ne!(old.idx(), new.idx());
+        let slots: Vec<_> = heap.read(new, 5).iter().map(|slot| unsafe { slot.to_u64() }).collect();
+        assert_eq!(slots, vec![11, 12, 0, 0, 0]);
+
+        // the stale copy traps, even once its slots are reused
+        let stale = Assembler::new().pop().push(2).alloc().pop().push(0).load().finish().unwrap();
+        let mut ip = 0;
+        loop {
+            match step(&mut ip, &mut stack, &mut heap, &stale) {
+                StepResult::Continue => continue,
+                result => { assert_eq!(result, StepResult::BadPointer); break; },
+            }
+        }
+
+        // the size must be right, and shrinking keeps the pointer
+        let wrong_size = Assembler::new().push(3).alloc().push(2).push(5).realloc().finish().unwrap();
+        assert_eq!(run(wrong_size).1, StepResult::BadPointer);
+        let shrink = Assembler::new().push(3).alloc().dup().push(3).push(1).realloc().halt().finish().unwrap();
+        let (stack, result, _ip) = run(shrink);
+        assert_eq!(result, StepResult::Halt);
+        assert_eq!(stack[0], stack[1]);
+    }
+
+    #[test]
+    fn alloc_out_of_memory_traps() {
+        let code = Assembler::new().push(100).alloc().finish().unwrap();
+        let mut stack = Stack::new();
+        let mut heap = Heap::new().with_max_capacity(10);
+        let mut ip = 0;
+        assert_eq!(step(&mut ip, &mut stack, &mut heap, &code), StepResult::Continue);
+        assert_eq!(step(&mut ip, &mut stack, &mut heap, &code), StepResult::OutOfMemory);
+        assert!(stack.as_slice().is_empty());
+
+        // sizes no heap could hold trap too, even without a maximum capacity
+        for slots in [1 << 62, (1 << 48) + 5, u64::MAX] {
+            let code = Assembler::new().push(slots).alloc().finish().unwrap();
+            let (stack, result, _ip) = run(code);
+            assert_eq!(result, StepResult::OutOfMemory);
+            assert!(stack.is_empty());
+        }
+        let code = Assembler::new().push(2).alloc().push(2).push(1 << 62).realloc().finish().unwrap();
+        let (stack, result, _ip) = run(code);
+        assert_eq!(result, StepResult::OutOfMemory);
+        assert!(stack.is_empty());
+
+        // as do constants too large for the heap
+        let code = Assembler::new()
+            .constant(Constant::Bytes(vec![0; 100]))
+            .load_const(ConstantId(0))
+            .finish().unwrap();
+        let mut stack = Stack::new();
+        let mut heap = Heap::new().with_max_capacity(10);
+        let mut ip = 0;
+        assert_eq!(step(&mut ip, &mut stack, &mut heap, &code), StepResult::OutOfMemory);
+        assert!(stack.as_slice().is_empty());
+    }
+
+    #[test]
+    fn stack_shuffling() {
+        let shuffles: [(OpCode, &[u64]); 4] = [
+            (OpCode::Dup,  &[1, 2, 3, 3]),
+            (OpCode::Swap, &[1, 3, 2]),
+            (OpCode::Over, &[1, 2, 3, 2]),
+            (OpCode::Rot,  &[2, 3, 1]),
+        ];
+        for (shuffle, expected) in shuffles {
+            let code = Assembler::new().push(1).push(2).push(3).op(shuffle).finish().unwrap();
+            let (stack, result, _ip) = run(code);
+            assert_eq!(result, StepResult::Halt);
+            assert_eq!(stack, expected);
+        }
+
+        // too few operands trap, however many there are
+        let needs = [(OpCode::Dup, 1), (OpCode::Swap, 2), (OpCode::Over, 2), (OpCode::Rot, 3)];
+        for (shuffle, operands) in needs {
+            for given in 0..operands {
+                let mut assembler = Assembler::new();
+                for value in 0..given {
+                    assembler = assembler.push(value);
+                }
+                let (stack, result, _ip) = run(assembler.op(shuffle).finish().unwrap());
+                assert_eq!(result, StepResult::StackUnderflow);
+                assert_eq!(stack, (0..given).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn underflow_leaves_the_stack_alone() {
+        let needs = [
+            (OpCode::AddU64, 2), (OpCode::SubU64, 2), (OpCode::MulU64, 2),
+            (OpCode::AddU64Checked, 2), (OpCode::SubU64Checked, 2), (OpCode::MulU64Checked, 2),
+            (OpCode::Eq, 2), (OpCode::Lt, 2), (OpCode::Gt, 2),
+            (OpCode::AddF64, 2), (OpCode::SubF64, 2), (OpCode::MulF64, 2), (OpCode::DivF64, 2),
+            (OpCode::Free, 2), (OpCode::Load, 2), (OpCode::Store, 3), (OpCode::Realloc, 3),
+            (OpCode::Send, 2),
+        ];
+        for (op, operands) in needs {
+            for given in 0..operands {
+                let mut assembler = Assembler::new();
+                for value in 0..given {
+                    assembler = assembler.push(value + 1);
+                }
+                let (stack, result, _ip) = run(assembler.op(op).finish().unwrap());
+                assert_eq!(result, StepResult::StackUnderflow, "{:?} with {} operands", op, given);
+                assert_eq!(stack, (1..=given).collect::<Vec<_>>(), "{:?} with {} operands", op, given);
+            }
+        }
+
+        // nor is anything popped from below the current frame
+        let code = Assembler::new()
+            .push(1).push(2).call("callee", 0)
+            .halt()
+            .label("callee")
+            .push(3).op(OpCode::Swap)
+            .finish().unwrap();
+        let (stack, result, _ip) = run(code);
+        assert_eq!(result, StepResult::StackUnderflow);
+        assert_eq!(stack, vec![1, 2, 3]);
+    }
+}
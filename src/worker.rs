@@ -0,0 +1,1083 @@
+//! Running many fibers on one thread, taking turns.
+
+use std::collections::BTreeMap;
+
+use crate::{Code, OpCode, Fiber, FiberId, Heap, StepResult, VmConfig, Module, ModuleId, LoadError};
+use crate::code::OPCODES;
+
+/// Identifies some code loaded on a worker, see [`Worker::add_code`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CodeId(pub usize);
+
+/// A fiber that stopped running, see [`Worker::run`].
+#[derive(Debug)]
+pub struct Exit {
+    /// The id the fiber had while it was running.
+    pub id: FiberId,
+    /// How the fiber stopped, which is [`StepResult::Halted`] or [`StepResult::Halt`]
+    /// unless it trapped.
+    pub result: StepResult,
+    /// The fiber as it was when it stopped, stack and heap included.
+    pub fiber: Fiber,
+}
+
+/// What running a worker until no fiber can run did, see [`Worker::run_until_idle`].
+#[derive(Debug)]
+pub struct RunSummary {
+    /// The fibers that stopped, in the order they stopped.
+    pub exits: Vec<Exit>,
+    /// Number of fibers that halted.
+    pub halted: usize,
+    /// Number of fibers that trapped.
+    pub trapped: usize,
+    /// Number of fibers left waiting for a message.
+    pub parked: usize,
+    /// Number of fibers left waiting to be refueled.
+    pub out_of_gas: usize,
+    /// The results of the fibers that halted with one, see [`Worker::results`].
+    pub results: BTreeMap<FiberId, u64>,
+    /// Whether fibers are left, and every one of them is waiting for a message,
+    /// so nothing will ever run again without a message from outside.
+    pub deadlocked: bool,
+}
+
+/// A single instruction run by a debugger, see [`Worker::step_fiber`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stepped {
+    /// The instruction that ran,
+    /// or `None` if there was no instruction at the instruction pointer.
+    pub op: Option<OpCode>,
+    /// What happened, which is [`StepResult::Continue`] unless the fiber stopped or parked.
+    pub result: StepResult,
+}
+
+/// Called before each instruction a worker runs, see [`Worker::set_trace`].
+pub type TraceHook = Box<dyn FnMut(FiberId, usize, OpCode)>;
+
+/// How many times each instruction was run, see [`Worker::with_profiling`].
+#[derive(Debug, Clone)]
+struct Profile {
+    // opcode -> times run.
+    ops: [u64; OPCODES.len()],
+    // code -> offset -> times the instruction there was run.
+    offsets: BTreeMap<CodeId, Vec<u64>>,
+}
+
+impl Profile {
+    fn new() -> Profile {
+        Profile { ops: [0; OPCODES.len()], offsets: BTreeMap::new() }
+    }
+
+    /// Counts an instruction in some code, which is `len` bytes long, being run.
+    fn count(&mut self, code: CodeId, len: usize, ip: usize, op: OpCode) {
+        self.ops[op as usize] += 1;
+        self.offsets.entry(code).or_insert_with(|| vec![0; len])[ip] += 1;
+    }
+}
+
+/// What a worker does with a fiber that trapped, see [`Worker::set_trap_handler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrapAction {
+    /// The fiber stops, and is removed from the pool, as if there were no handler.
+    #[default]
+    Kill,
+    /// The fiber starts its code over, with a fresh stack, heap, mailbox, and gas,
+    /// as if it had just been spawned, but keeping its id and parent.
+    Restart,
+    /// The value is pushed, and the fiber carries on from where the trap left it.
+    /// Most traps leave the instruction pointer past the instruction that trapped,
+    /// but some leave it at the instruction, which then runs again, see [`StepResult`].
+    Resume(u64),
+}
+
+/// Called when a fiber traps, with the fiber and how it trapped, see [`Worker::set_trap_handler`].
+pub type TrapHandler = Box<dyn FnMut(FiberId, StepResult) -> TrapAction>;
+
+/// How a worker picks which fiber gets the next turn, see [`Worker::with_schedule`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchedulePolicy {
+    /// Every fiber that can run takes a turn, in order of id, round after round.
+    #[default]
+    RoundRobin,
+    /// Each turn goes to a fiber that can run, picked at random from a seed,
+    /// so the same seed always interleaves fibers the same way.
+    Seeded(u64),
+}
+
+/// Runs fibers cooperatively, round-robin unless scheduled otherwise.
+/// Each fiber runs for a quantum of instructions before the next one gets a turn.
+pub struct Worker {
+    code_pool:    BTreeMap<CodeId, Code>,
+    // module -> the code loaded from it, the entry point first.
+    modules:      BTreeMap<ModuleId, Vec<CodeId>>,
+    process_pool: BTreeMap<FiberId, Fiber>,
+    next_fiber:   usize,
+    quantum:      usize,
+    config:       VmConfig,
+    trace:        Option<TraceHook>,
+    // decides what happens to a fiber that trapped, if set.
+    trap_handler: Option<TrapHandler>,
+    // counts the instructions run, only present if profiling.
+    profile:      Option<Profile>,
+    // the heap every fiber can reach, see `OpCode::AllocShared`.
+    shared:       Option<Heap>,
+    schedule:     SchedulePolicy,
+    // picks the next fiber to run, if the schedule is seeded.
+    rng:          Option<attorand::Rng>,
+    // fiber -> the result it halted with.
+    results:      BTreeMap<FiberId, u64>,
+}
+
+impl std::fmt::Debug for Worker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Worker")
+            .field("code_pool", &self.code_pool)
+            .field("modules", &self.modules)
+            .field("process_pool", &self.process_pool)
+            .field("next_fiber", &self.next_fiber)
+            .field("quantum", &self.quantum)
+            .field("config", &self.config)
+            .field("trace", &self.trace.is_some())
+            .field("trap_handler", &self.trap_handler.is_some())
+            .field("profile", &self.profile)
+            .field("shared", &self.shared)
+            .field("schedule", &self.schedule)
+            .field("results", &self.results)
+            .finish()
+    }
+}
+
+impl Default for Worker {
+    fn default() -> Worker {
+        Worker::new()
+    }
+}
+
+impl Worker {
+    /// Constructs a worker with no code or fibers,
+    /// that runs each fiber for 1000 instructions at a time.
+    pub fn new() -> Worker {
+        Worker {
+            code_pool: BTreeMap::new(),
+            modules: BTreeMap::new(),
+            process_pool: BTreeMap::new(),
+            next_fiber: 0,
+            quantum: 1000,
+            config: VmConfig::default(),
+            trace: None,
+            trap_handler: None,
+            profile: None,
+            shared: None,
+            schedule: SchedulePolicy::RoundRobin,
+            rng: None,
+            results: BTreeMap::new(),
+        }
+    }
+
+    /// Sets how the worker picks which fiber gets the next turn.
+    pub fn with_schedule(mut self, schedule: SchedulePolicy) -> Worker {
+        self.schedule = schedule;
+        self.rng = match schedule {
+            SchedulePolicy::RoundRobin => None,
+            SchedulePolicy::Seeded(seed) => Some(attorand::Rng::new_with_seed(seed)),
+        };
+        self
+    }
+
+    /// Returns how the worker picks which fiber gets the next turn.
+    pub fn schedule(&self) -> SchedulePolicy {
+        self.schedule
+    }
+
+    /// Gives the worker a heap shared by every fiber on it,
+    /// so pointers can be sent between fibers without copying, see [`OpCode::AllocShared`].
+    pub fn with_shared_heap(mut self, heap: Heap) -> Worker {
+        self.shared = Some(heap);
+        self
+    }
+
+    /// Returns the heap shared by every fiber, if there is one.
+    pub fn shared_heap(&self) -> Option<&Heap> {
+        self.shared.as_ref()
+    }
+
+    /// Sets a hook called before each instruction is run,
+    /// with the fiber running it, where the instruction is, and what it is.
+    /// Only instructions that decode are traced.
+    pub fn set_trace(&mut self, hook: TraceHook) {
+        self.trace = Some(hook);
+    }
+
+    /// Removes the hook set by [`Worker::set_trace`], if any.
+    pub fn clear_trace(&mut self) {
+        self.trace = None;
+    }
+
+    /// Sets a handler called whenever a fiber traps, which decides what happens to it,
+    /// so traps can be caught like exceptions. Without one, fibers that trap are killed.
+    /// A fiber that halts, parks, or runs out of gas has not trapped.
+    ///
+    /// A handler that restarts or resumes a fiber that always traps keeps it running forever,
+    /// unless the fiber runs out of gas, see [`VmConfig::with_gas`].
+    pub fn set_trap_handler(&mut self, handler: TrapHandler) {
+        self.trap_handler = Some(handler);
+    }
+
+    /// Removes the handler set by [`Worker::set_trap_handler`], if any.
+    pub fn clear_trap_handler(&mut self) {
+        self.trap_handler = None;
+    }
+
+    /// Sets whether to count how many times each instruction is run,
+    /// see [`Worker::opcode_profile`] and [`Worker::offset_profile`].
+    /// Off by default, as counting slows down every instruction.
+    /// Turning it off drops the counts so far.
+    pub fn with_profiling(mut self, profiling: bool) -> Worker {
+        self.profile = profiling.then(Profile::new);
+        self
+    }
+
+    /// Returns how many times each opcode was run, for opcodes that were run at all.
+    /// Only instructions that decode are counted, and none are unless profiling,
+    /// see [`Worker::with_profiling`].
+    pub fn opcode_profile(&self) -> BTreeMap<OpCode, u64> {
+        let Some(profile) = &self.profile else { return BTreeMap::new(); };
+        profile.ops.iter().zip(OPCODES.iter())
+            .filter(|(count, _op)| **count > 0)
+            .map(|(count, op)| (*op, *count))
+            .collect()
+    }
+
+    /// Returns how many times the instruction at each offset of some code was run,
+    /// for instructions that were run at all, so hot loops stand out,
+    /// see [`Worker::opcode_profile`].
+    pub fn offset_profile(&self, code: CodeId) -> BTreeMap<usize, u64> {
+        let Some(offsets) = self.profile.as_ref().and_then(|profile| profile.offsets.get(&code)) else {
+            return BTreeMap::new();
+        };
+        offsets.iter().enumerate()
+            .filter(|(_ip, count)| **count > 0)
+            .map(|(ip, count)| (ip, *count))
+            .collect()
+    }
+
+    /// Sets how many instructions a fiber runs before yielding to the next.
+    ///
+    /// # Panics
+    /// If the quantum is zero, as no fiber would ever run.
+    pub fn with_quantum(mut self, quantum: usize) -> Worker {
+        assert!(quantum > 0, "a worker must run fibers for at least one instruction at a time");
+        self.quantum = quantum;
+        self
+    }
+
+    /// Sets the limits of the stacks of fibers spawned from now on.
+    pub fn with_config(mut self, config: VmConfig) -> Worker {
+        self.config = config;
+        self
+    }
+
+    /// Adds some code to the pool, returning its id.
+    /// Ids count up from zero, in the order code is added.
+    pub fn add_code(&mut self, code: Code) -> CodeId {
+        let id = CodeId(self.code_pool.len());
+        self.code_pool.insert(id, code);
+        return id;
+    }
+
+    /// Adds some encoded instructions to the pool, with no constants, returning its id.
+    pub fn load_code(&mut self, bytes: Vec<u8>) -> CodeId {
+        self.add_code(Code::new(bytes))
+    }
+
+    /// Parses a module, see [`Module::parse`], and adds each of its code sections to the pool,
+    /// each with the constants of the module. Returns the id of the module,
+    /// or an error leaving the pool as it was if the module is malformed.
+    pub fn load_module(&mut self, bytes: &[u8]) -> Result<ModuleId, LoadError> {
+        let module = Module::parse(bytes)?;
+        let mut codes = vec![];
+        for bytes in module.code {
+            let mut code = Code::new(bytes);
+            for constant in module.constants.iter() {
+                code.add_constant(constant.clone());
+            }
+            codes.push(self.add_code(code));
+        }
+
+        let id = ModuleId(self.modules.len());
+        self.modules.insert(id, codes);
+        return Ok(id);
+    }
+
+    /// Returns the code loaded from a module, the entry point first,
+    /// or `None` if there is no module with that id.
+    pub fn module(&self, id: ModuleId) -> Option<&[CodeId]> {
+        self.modules.get(&id).map(|codes| &codes[..])
+    }
+
+    /// Returns some code in the pool, or `None` if there is no code with that id.
+    pub fn code(&self, id: CodeId) -> Option<&Code> {
+        self.code_pool.get(&id)
+    }
+
+    /// Starts a new fiber running some code from the beginning, returning its id.
+    /// The fiber first runs during the next round of [`Worker::run`].
+    ///
+    /// # Panics
+    /// If there is no code with that id.
+    pub fn spawn(&mut self, code: CodeId) -> FiberId {
+        assert!(self.code_pool.contains_key(&code), "spawn of unknown code {:?}", code);
+        return self.insert_fiber(code, None);
+    }
+
+    /// Adds a new fiber to the pool, giving it the next id.
+    fn insert_fiber(&mut self, code: CodeId, parent: Option<FiberId>) -> FiberId {
+        let id = FiberId(self.next_fiber);
+        self.next_fiber += 1;
+        self.process_pool.insert(id, Fiber::new(code, parent, self.config));
+        return id;
+    }
+
+    /// Starts a copy of a running fiber as its child, returning the id of the copy,
+    /// see [`Fiber::fork`]. The copy has its own stack, and shares the heap copy-on-write,
+    /// so the two can go on to explore different paths without seeing each other's writes.
+    ///
+    /// # Panics
+    /// If the fiber is not in the pool.
+    pub fn fork_fiber(&mut self, fiber: FiberId) -> FiberId {
+        let child = match self.process_pool.get(&fiber) {
+            Some(parent) => parent.fork(fiber),
+            None => panic!("fork of unknown fiber {:?}", fiber),
+        };
+        let id = FiberId(self.next_fiber);
+        self.next_fiber += 1;
+        self.process_pool.insert(id, child);
+        return id;
+    }
+
+    /// Spawns a child of a fiber that ran a `Spawn`, pushing the id of the child.
+    /// Returns how the parent traps, or `None` if the child was spawned.
+    fn spawn_child(&mut self, parent: FiberId, code: CodeId) -> Option<StepResult> {
+        if !self.code_pool.contains_key(&code) {
+            return Some(StepResult::UnknownCode(code));
+        }
+        // the id is pushed first, so a full stack does not leave an orphan
+        let child = FiberId(self.next_fiber);
+        let stack = self.process_pool.get_mut(&parent).unwrap().stack_mut();
+        if stack.push(child.0 as u64).is_err() {
+            return Some(StepResult::StackOverflow);
+        }
+        self.insert_fiber(code, Some(parent));
+        return None;
+    }
+
+    /// Returns a fiber that is still running, or `None` if it already stopped.
+    pub fn fiber(&self, id: FiberId) -> Option<&Fiber> {
+        self.process_pool.get(&id)
+    }
+
+    /// Returns the number of fibers still running.
+    pub fn len(&self) -> usize {
+        self.process_pool.len()
+    }
+
+    /// Returns whether every fiber has stopped.
+    pub fn is_empty(&self) -> bool {
+        self.process_pool.is_empty()
+    }
+
+    /// Runs every fiber until it stops, taking turns as scheduled, see [`SchedulePolicy`].
+    /// A fiber that halts or traps is removed from the pool,
+    /// and returned along with how it stopped, in the order they stopped.
+    /// When round-robin, fibers spawned while running first run during the next round.
+    ///
+    /// Parked fibers are skipped until a message is sent to them,
+    /// and fibers out of gas are skipped until they are refueled, see [`Worker::refuel`].
+    /// If no fiber left can run, they are left in the pool and this returns.
+    pub fn run(&mut self) -> Vec<Exit> {
+        let mut exits = vec![];
+        loop {
+            let ids: Vec<FiberId> = self.process_pool.iter()
+                .filter(|(_id, fiber)| fiber.is_runnable())
+                .map(|(id, _fiber)| *id)
+                .collect();
+            if ids.is_empty() { break; }
+
+            if let Some(rng) = self.rng.as_mut() {
+                let id = ids[rng.next_u64_max(ids.len() as u64 - 1) as usize];
+                self.take_turn(id, &mut exits);
+                continue;
+            }
+            for id in ids {
+                if !self.process_pool[&id].is_runnable() { continue; }
+                self.take_turn(id, &mut exits);
+            }
+        }
+        return exits;
+    }
+
+    /// Runs every fiber like [`Worker::run`], until no fiber can run,
+    /// and sums up how each one stopped or why it is waiting.
+    /// Never spins on a deadlock: once every fiber is parked, this returns,
+    /// reporting it as [`RunSummary::deadlocked`].
+    pub fn run_until_idle(&mut self) -> RunSummary {
+        let exits = self.run();
+        let halted = exits.iter().filter(|exit| exit.result.is_halt()).count();
+        let results = exits.iter()
+            .filter_map(|exit| match exit.result {
+                StepResult::Halted(value) => Some((exit.id, value)),
+                _ => None,
+            })
+            .collect();
+        let parked = self.process_pool.values().filter(|fiber| fiber.is_parked()).count();
+        let out_of_gas = self.process_pool.values().filter(|fiber| fiber.gas() == 0).count();
+        return RunSummary {
+            halted,
+            trapped: exits.len() - halted,
+            parked,
+            out_of_gas,
+            results,
+            deadlocked: parked > 0 && parked == self.process_pool.len(),
+            exits,
+        };
+    }
+
+    /// Returns the result of every fiber that halted with one, see [`StepResult::Halted`].
+    /// A fiber that halted with an empty stack has no result, nor does one that trapped.
+    pub fn results(&self) -> &BTreeMap<FiberId, u64> {
+        &self.results
+    }
+
+    /// Runs a fiber for a quantum, removing it from the pool if it stopped.
+    fn take_turn(&mut self, id: FiberId, exits: &mut Vec<Exit>) {
+        if let Some(result) = self.run_quantum(id) {
+            if let StepResult::Halted(value) = result {
+                self.results.insert(id, value);
+            }
+            let fiber = self.process_pool.remove(&id).unwrap();
+            exits.push(Exit { id, result, fiber });
+        }
+    }
+
+    /// Runs a fiber for up to a quantum of instructions, or until it parks or runs out of gas.
+    /// Returns how it stopped, or `None` if it is still running.
+    fn run_quantum(&mut self, id: FiberId) -> Option<StepResult> {
+        for _ in 0..self.quantum {
+            match self.step_one(id) {
+                StepResult::Continue => continue,
+                StepResult::Parked | StepResult::OutOfGas => return None,
+                result => return Some(result),
+            }
+        }
+        return None;
+    }
+
+    /// Runs one instruction of a fiber, doing what it asks of the worker.
+    /// Returns [`StepResult::Continue`] unless the fiber stopped or parked.
+    fn step_one(&mut self, id: FiberId) -> StepResult {
+        // an instruction that is not run is not traced
+        if self.process_pool[&id].gas() == 0 { return StepResult::OutOfGas; }
+
+        // only decodes the instruction twice when tracing or profiling
+        if self.trace.is_some() || self.profile.is_some() {
+            let next = self.next_op(id).map(|op| (self.process_pool[&id].ip(), op));
+            if let (Some(trace), Some((ip, op))) = (self.trace.as_mut(), next) {
+                trace(id, ip, op);
+            }
+            if let (Some(profile), Some((ip, op))) = (self.profile.as_mut(), next) {
+                let code = self.process_pool[&id].code();
+                profile.count(code, self.code_pool[&code].bytes().len(), ip, op);
+            }
+        }
+
+        let fiber = self.process_pool.get_mut(&id).unwrap();
+        let code = &self.code_pool[&fiber.code()];
+        let result = match fiber.step(code, self.shared.as_mut()) {
+            StepResult::Spawn(code) => self.spawn_child(id, code).unwrap_or(StepResult::Continue),
+            StepResult::Send { to, value } => match self.process_pool.get_mut(&to) {
+                Some(fiber) => {
+                    fiber.deliver(value);
+                    StepResult::Continue
+                },
+                None => StepResult::UnknownFiber(to),
+            },
+            result => result,
+        };
+        return self.handle_trap(id, result);
+    }
+
+    /// Asks the trap handler what to do with a fiber, if it trapped and there is a handler.
+    /// Returns [`StepResult::Continue`] if the fiber carries on, or how it stopped if not.
+    fn handle_trap(&mut self, id: FiberId, result: StepResult) -> StepResult {
+        use StepResult::{Continue, Halt, Halted, Parked, OutOfGas};
+        if matches!(result, Continue | Halt | Halted(_) | Parked | OutOfGas) { return result; }
+        let handler = match self.trap_handler.as_mut() {
+            Some(handler) => handler,
+            None => return result,
+        };
+
+        match handler(id, result) {
+            TrapAction::Kill => result,
+            TrapAction::Restart => {
+                let fiber = &self.process_pool[&id];
+                let restarted = Fiber::new(fiber.code(), fiber.parent(), self.config);
+                self.process_pool.insert(id, restarted);
+                StepResult::Continue
+            },
+            // a full stack is not handled again, as resuming would only fill it again
+            TrapAction::Resume(value) => match self.process_pool.get_mut(&id).unwrap().stack_mut().push(value) {
+                Ok(()) => StepResult::Continue,
+                Err(_) => StepResult::StackOverflow,
+            },
+        }
+    }
+
+    /// Runs exactly one instruction of a fiber, for debugging,
+    /// or returns `None` if the fiber is not in the pool.
+    /// Unlike [`Worker::run`], a fiber that stops is left in the pool, so it can be inspected.
+    pub fn step_fiber(&mut self, id: FiberId) -> Option<Stepped> {
+        if !self.process_pool.contains_key(&id) { return None; }
+        let op = self.next_op(id);
+        let result = self.step_one(id);
+        return Some(Stepped { op, result });
+    }
+
+    /// Decodes the next instruction a fiber in the pool will run,
+    /// or returns `None` if there is no instruction at its instruction pointer.
+    fn next_op(&self, id: FiberId) -> Option<OpCode> {
+        let fiber = &self.process_pool[&id];
+        let byte = self.code_pool[&fiber.code()].bytes().get(fiber.ip())?;
+        OpCode::from_u8(*byte)
+    }
+
+    /// Adds to the gas of a fiber, so it can run again if it ran out.
+    /// Returns whether the fiber is in the pool.
+    pub fn refuel(&mut self, id: FiberId, gas: u64) -> bool {
+        match self.process_pool.get_mut(&id) {
+            Some(fiber) => {
+                fiber.refuel(gas);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Returns a copy of the stack of a fiber, the top last,
+    /// or `None` if the fiber is not in the pool.
+    pub fn stack_snapshot(&self, id: FiberId) -> Option<Vec<u64>> {
+        self.process_pool.get(&id).map(|fiber| fiber.stack().as_slice().to_vec())
+    }
+
+    /// Returns the offset of the next instruction a fiber will run,
+    /// or `None` if the fiber is not in the pool.
+    pub fn ip(&self, id: FiberId) -> Option<usize> {
+        self.process_pool.get(&id).map(|fiber| fiber.ip())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Assembler, OpCode, Constant, ConstantId};
+
+    #[test]
+    fn fibers_take_turns() {
+        let mut worker = Worker::new().with_quantum(3);
+        // counts down from 10, which takes many turns
+        let countdown = worker.add_code(Assembler::new()
+            .push(10)
+            .label("loop")
+            .push(1).sub_u64()
+            .dup().jump_if_non_zero("loop")
+            .finish().unwrap());
+        let sum = worker.add_code(Assembler::new()
+            .push(2).push(3).add_u64()
+            .halt()
+            .finish().unwrap());
+
+        let a = worker.spawn(countdown);
+        let b = worker.spawn(sum);
+        assert_eq!(worker.len(), 2);
+        assert_eq!(worker.fiber(a).unwrap().parent(), None);
+
+        let exits = worker.run();
+        assert!(worker.is_empty());
+        // the short fiber finishes first, even though it was spawned last
+        let ids: Vec<FiberId> = exits.iter().map(|exit| exit.id).collect();
+        assert_eq!(ids, vec![b, a]);
+        assert_eq!(exits[0].result, StepResult::Halted(5));
+        assert_eq!(exits[0].fiber.stack().as_slice(), &[5]);
+        assert_eq!(exits[1].result, StepResult::Halted(0));
+        assert_eq!(exits[1].fiber.stack().as_slice(), &[0]);
+    }
+
+    #[test]
+    fn halted_fibers_give_results() {
+        let mut worker = Worker::new();
+        // 6 * 7, leaving a value below the result
+        let compute = worker.add_code(Assembler::new()
+            .push(1)
+            .push(6).push(7).mul_u64()
+            .halt()
+            .finish().unwrap());
+        let empty = worker.add_code(Assembler::new().push(1).pop().halt().finish().unwrap());
+        let trap = worker.add_code(Assembler::new().pop().finish().unwrap());
+        let compute = worker.spawn(compute);
+        let empty = worker.spawn(empty);
+        let trap = worker.spawn(trap);
+
+        let exits = worker.run();
+        assert_eq!(exits[0].result, StepResult::Halted(42));
+        assert_eq!(exits[1].result, StepResult::Halt);
+        assert_eq!(exits[2].result, StepResult::StackUnderflow);
+        // only the fiber that halted with a value has a result
+        assert_eq!(worker.results().get(&compute), Some(&42));
+        assert_eq!(worker.results().get(&empty), None);
+        assert_eq!(worker.results().get(&trap), None);
+        assert_eq!(worker.results().len(), 1);
+    }
+
+    #[test]
+    fn forked_fibers_do_not_share_writes() {
+        let mut worker = Worker::new();
+        // writes to a fresh block, then overwrites it with a message, and reads it back
+        let code = worker.add_code(Assembler::new()
+            .push(1).alloc()
+            .dup().push(0).push(10).store()
+            .dup().push(0).receive().store()
+            .push(0).load()
+            .finish().unwrap());
+        let parent = worker.spawn(code);
+        assert!(worker.run().is_empty());
+
+        let child = worker.fork_fiber(parent);
+        assert_eq!(worker.fiber(child).unwrap().parent(), Some(parent));
+        assert_eq!(worker.stack_snapshot(child), worker.stack_snapshot(parent));
+        // forking does not copy the heap
+        assert!(worker.fiber(child).unwrap().shares_heap_with(worker.fiber(parent).unwrap()));
+        let sender = worker.add_code(Assembler::new().push(child.0 as u64).push(20).send().finish().unwrap());
+        worker.spawn(sender);
+
+        // the child overwrites its copy of the block, the parent still waits
+        let exits = worker.run();
+        assert_eq!(exits.len(), 2);
+        assert_eq!(exits[1].id, child);
+        assert_eq!(exits[1].result, StepResult::Halted(20));
+        let parent = worker.fiber(parent).unwrap();
+        // SAFETY: the parent allocated the block, and only ever wrote naturals to it
+        let pointer = unsafe { crate::Pointer::from_bits(parent.stack().as_slice()[0]) };
+        assert_eq!(unsafe { parent.heap().read_slot(pointer, 0).to_u64() }, 10);
+    }
+
+    #[test]
+    fn trapping_fibers_are_removed() {
+        let mut worker = Worker::new();
+        let underflow = worker.add_code(Assembler::new().add_u64().finish().unwrap());
+        let fine = worker.add_code(Assembler::new().push(1).finish().unwrap());
+        worker.spawn(underflow);
+        worker.spawn(fine);
+        let results: Vec<StepResult> = worker.run().into_iter().map(|exit| exit.result).collect();
+        assert_eq!(results, vec![StepResult::StackUnderflow, StepResult::Halted(1)]);
+    }
+
+    #[test]
+    fn spawned_children_run_to_completion() {
+        let mut worker = Worker::new();
+        let child = worker.add_code(Assembler::new().push(7).push(8).mul_u64().finish().unwrap());
+        let parent = worker.add_code(Assembler::new().spawn(child).push(1).finish().unwrap());
+        let id = worker.spawn(parent);
+
+        let exits = worker.run();
+        assert_eq!(exits.len(), 2);
+        assert_eq!(exits[0].id, id);
+        assert_eq!(exits[0].result, StepResult::Halted(1));
+
+        // the parent got the id of its child, which started empty
+        let child_id = FiberId(exits[0].fiber.stack().as_slice()[0] as usize);
+        assert_eq!(exits[0].fiber.stack().as_slice(), &[child_id.0 as u64, 1]);
+        assert_eq!(exits[1].id, child_id);
+        assert_eq!(exits[1].result, StepResult::Halted(56));
+        assert_eq!(exits[1].fiber.parent(), Some(id));
+        assert_eq!(exits[1].fiber.code(), child);
+        assert_eq!(exits[1].fiber.stack().as_slice(), &[56]);
+    }
+
+    #[test]
+    fn spawning_unknown_code_traps() {
+        let mut worker = Worker::new();
+        let parent = worker.add_code(Assembler::new().spawn(CodeId(9)).finish().unwrap());
+        worker.spawn(parent);
+        let exits = worker.run();
+        assert_eq!(exits.len(), 1);
+        assert_eq!(exits[0].result, StepResult::UnknownCode(CodeId(9)));
+    }
+
+    #[test]
+    fn fibers_send_and_receive_messages() {
+        let mut worker = Worker::new();
+        let receiver = worker.add_code(Assembler::new()
+            .receive()
+            .receive().add_u64()
+            .receive().add_u64()
+            .finish().unwrap());
+        let receiver = worker.spawn(receiver);
+        let sender = worker.add_code(Assembler::new()
+            .push(receiver.0 as u64).push(10).send()
+            .push(receiver.0 as u64).push(20).send()
+            .push(receiver.0 as u64).push(12).send()
+            .finish().unwrap());
+        let sender = worker.spawn(sender);
+
+        // the receiver parks before anything is sent, and wakes once there is
+        let exits = worker.run();
+        let ids: Vec<FiberId> = exits.iter().map(|exit| exit.id).collect();
+        assert_eq!(ids, vec![sender, receiver]);
+        assert_eq!(exits[1].result, StepResult::Halted(42));
+        assert_eq!(exits[1].fiber.stack().as_slice(), &[42]);
+    }
+
+    #[test]
+    fn shared_pointers_are_sent_without_copying() {
+        let mut worker = Worker::new().with_shared_heap(Heap::new());
+        let receiver = worker.add_code(Assembler::new()
+            .receive()
+            .dup().push(0).load()
+            .swap().push(2).free()
+            .finish().unwrap());
+        let receiver = worker.spawn(receiver);
+        let sender = worker.add_code(Assembler::new()
+            .push(2).alloc_shared()
+            .dup().push(0).push(77).store()
+            // one share is sent, the other is kept
+            .share()
+            .push(receiver.0 as u64).swap().send()
+            .dup().push(0).load()
+            .swap().push(2).free()
+            .finish().unwrap());
+        worker.spawn(sender);
+
+        // both sides read the same allocation, which is freed once both give it up
+        let exits = worker.run();
+        assert_eq!(exits.len(), 2);
+        for exit in exits.iter() {
+            assert_eq!(exit.result, StepResult::Halted(77));
+            assert_eq!(exit.fiber.stack().as_slice(), &[77]);
+            assert_eq!(exit.fiber.heap().used(), 0);
+        }
+        assert_eq!(worker.shared_heap().unwrap().used(), 0);
+
+        // without a shared heap, there is nothing to allocate in
+        let mut worker = Worker::new();
+        let code = worker.add_code(Assembler::new().push(2).alloc_shared().finish().unwrap());
+        worker.spawn(code);
+        assert_eq!(worker.run()[0].result, StepResult::NoSharedHeap);
+    }
+
+    #[test]
+    fn seeded_schedules_are_reproducible() {
+        use std::{cell::RefCell, rc::Rc};
+
+        // three fibers send to a fourth, which adds up what it receives
+        let interleave = |schedule| {
+            let mut worker = Worker::new().with_quantum(1).with_schedule(schedule);
+            let adder = worker.add_code(Assembler::new()
+                .receive()
+                .receive().add_u64()
+                .receive().add_u64()
+                .finish().unwrap());
+            let adder = worker.spawn(adder);
+            for value in [10, 20, 30] {
+                let sender = worker.add_code(Assembler::new()
+                    .push(adder.0 as u64).push(value).send()
+                    .finish().unwrap());
+                worker.spawn(sender);
+            }
+
+            let turns = Rc::new(RefCell::new(vec![]));
+            let seen = turns.clone();
+            worker.set_trace(Box::new(move |fiber, _ip, _op| seen.borrow_mut().push(fiber)));
+            let exits = worker.run();
+            assert_eq!(exits.len(), 4);
+            assert!(exits.iter().all(|exit| exit.result.is_halt()));
+            let adder = exits.iter().find(|exit| exit.id == adder).unwrap();
+            assert_eq!(adder.fiber.stack().as_slice(), &[60]);
+            return turns.take();
+        };
+
+        assert_eq!(interleave(SchedulePolicy::Seeded(1)), interleave(SchedulePolicy::Seeded(1)));
+        assert_ne!(interleave(SchedulePolicy::Seeded(1)), interleave(SchedulePolicy::Seeded(2)));
+        assert_ne!(interleave(SchedulePolicy::Seeded(1)), interleave(SchedulePolicy::RoundRobin));
+    }
+
+    #[test]
+    fn mutual_receives_deadlock() {
+        let mut worker = Worker::new();
+        // each waits for the other to send first
+        let first = worker.add_code(Assembler::new().receive().push(1).push(7).send().halt().finish().unwrap());
+        let second = worker.add_code(Assembler::new().receive().push(0).push(8).send().halt().finish().unwrap());
+        let answer = worker.add_code(Assembler::new().push(42).halt().finish().unwrap());
+        let trap = worker.add_code(Assembler::new().pop().finish().unwrap());
+        worker.spawn(first);
+        worker.spawn(second);
+        let answer = worker.spawn(answer);
+        worker.spawn(trap);
+
+        let summary = worker.run_until_idle();
+        assert!(summary.deadlocked);
+        assert_eq!((summary.halted, summary.trapped, summary.parked, summary.out_of_gas), (1, 1, 2, 0));
+        assert_eq!(summary.results.into_iter().collect::<Vec<_>>(), vec![(answer, 42)]);
+        assert_eq!(summary.exits.len(), 2);
+        assert_eq!(worker.len(), 2);
+
+        // a fiber that can be refueled means it is not stuck for good
+        let mut worker = Worker::new().with_config(VmConfig::new().with_gas(3));
+        let wait = worker.add_code(Assembler::new().receive().finish().unwrap());
+        let spin = worker.add_code(Assembler::new().label("loop").jump("loop").finish().unwrap());
+        worker.spawn(wait);
+        let spin = worker.spawn(spin);
+        let summary = worker.run_until_idle();
+        assert!(!summary.deadlocked);
+        assert_eq!((summary.parked, summary.out_of_gas), (1, 1));
+        assert!(worker.refuel(spin, 0));
+        assert!(worker.run_until_idle().exits.is_empty());
+    }
+
+    #[test]
+    fn parked_fibers_are_left_waiting() {
+        let mut worker = Worker::new();
+        let code = worker.add_code(Assembler::new().receive().finish().unwrap());
+        let id = worker.spawn(code);
+        assert!(worker.run().is_empty());
+        assert!(worker.fiber(id).unwrap().is_parked());
+        assert_eq!(worker.fiber(id).unwrap().ip(), 0);
+
+        let unknown = worker.add_code(Assembler::new().push(99).push(1).send().finish().unwrap());
+        worker.spawn(unknown);
+        let exits = worker.run();
+        assert_eq!(exits.len(), 1);
+        assert_eq!(exits[0].result, StepResult::UnknownFiber(FiberId(99)));
+        assert_eq!(worker.len(), 1);
+    }
+
+    #[test]
+    fn load_and_run_a_module() {
+        let mut worker = Worker::new();
+        let before = worker.load_code(vec![OpCode::Halt as u8]);
+
+        let helper = Assembler::new().load_const(ConstantId(1)).finish().unwrap();
+        let entry = Assembler::new()
+            .load_const(ConstantId(0)).push(2).add_u64()
+            // the helper is loaded right after the entry point
+            .spawn(CodeId(before.0 + 2))
+            .finish().unwrap();
+        let constants = [Constant::U64(40), Constant::Bytes(b"hi".to_vec())];
+        let bytes = crate::module::tests::build(&constants, &[entry.bytes(), helper.bytes()]);
+
+        let module = worker.load_module(&bytes).unwrap();
+        let codes = worker.module(module).unwrap().to_vec();
+        assert_eq!(codes, vec![CodeId(1), CodeId(2)]);
+        assert_eq!(worker.code(codes[1]).unwrap().constant(ConstantId(1)), Some(&constants[1]));
+
+        worker.spawn(codes[0]);
+        let exits = worker.run();
+        assert_eq!(exits.len(), 2);
+        assert_eq!(exits[0].result, StepResult::Halted(1));
+        assert_eq!(exits[0].fiber.stack().as_slice()[0], 42);
+        assert_eq!(exits[1].result, StepResult::Halted(exits[1].fiber.stack().as_slice()[0]));
+        assert_eq!(exits[1].fiber.heap().used(), 2);
+
+        // a malformed module loads nothing
+        assert_eq!(worker.load_module(&bytes[..10]), Err(LoadError::Truncated));
+        assert_eq!(worker.code(CodeId(3)), None);
+    }
+
+    #[test]
+    fn single_stepping() {
+        let mut worker = Worker::new();
+        let code = worker.add_code(Assembler::new()
+            .push(2).push(3).add_u64()
+            .pop().pop()
+            .finish().unwrap());
+        let id = worker.spawn(code);
+
+        let expected: [(OpCode, StepResult, usize, &[u64]); 5] = [
+            (OpCode::Push,   StepResult::Continue,       9,  &[2]),
+            (OpCode::Push,   StepResult::Continue,       18, &[2, 3]),
+            (OpCode::AddU64, StepResult::Continue,       19, &[5]),
+            (OpCode::Pop,    StepResult::Continue,       20, &[]),
+            (OpCode::Pop,    StepResult::StackUnderflow, 21, &[]),
+        ];
+        assert_eq!(worker.ip(id), Some(0));
+        for (op, result, ip, stack) in expected {
+            assert_eq!(worker.step_fiber(id), Some(Stepped { op: Some(op), result }));
+            assert_eq!(worker.ip(id), Some(ip));
+            assert_eq!(worker.stack_snapshot(id).unwrap(), stack);
+        }
+
+        // the fiber that trapped is still there to inspect, until it runs off the end
+        assert_eq!(worker.step_fiber(id), Some(Stepped { op: None, result: StepResult::Halt }));
+        assert_eq!(worker.run().len(), 1);
+        assert_eq!(worker.step_fiber(id), None);
+        assert_eq!(worker.ip(id), None);
+    }
+
+    #[test]
+    fn tracing_sees_every_instruction() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let mut worker = Worker::new();
+        let code = worker.add_code(Assembler::new()
+            .push(2)
+            .label("loop")
+            .push(1).sub_u64()
+            .dup().jump_if_non_zero("loop")
+            .halt()
+            .finish().unwrap());
+        let id = worker.spawn(code);
+
+        let trace = Rc::new(RefCell::new(vec![]));
+        let seen = trace.clone();
+        worker.set_trace(Box::new(move |fiber, ip, op| seen.borrow_mut().push((fiber, ip, op))));
+        worker.run();
+
+        let body = [(9, OpCode::Push), (18, OpCode::SubU64), (19, OpCode::Dup), (20, OpCode::JumpIfNonZero)];
+        let mut expected = vec![(0, OpCode::Push)];
+        expected.extend_from_slice(&body);
+        expected.extend_from_slice(&body);
+        expected.push((29, OpCode::Halt));
+        let expected: Vec<_> = expected.into_iter().map(|(ip, op)| (id, ip, op)).collect();
+        assert_eq!(*trace.borrow(), expected);
+
+        // nothing is traced once the hook is cleared
+        worker.clear_trace();
+        worker.spawn(code);
+        worker.run();
+        assert_eq!(trace.borrow().len(), expected.len());
+    }
+
+    #[test]
+    fn fibers_run_out_of_gas() {
+        let mut worker = Worker::new().with_quantum(7).with_config(VmConfig::new().with_gas(25));
+        let code = worker.add_code(Assembler::new()
+            .push(0)
+            .label("loop")
+            .push(1).add_u64()
+            .jump("loop")
+            .finish().unwrap());
+        let id = worker.spawn(code);
+
+        // one push, then eight times around the loop
+        assert!(worker.run().is_empty());
+        assert_eq!(worker.fiber(id).unwrap().gas(), 0);
+        assert_eq!(worker.stack_snapshot(id).unwrap(), &[8]);
+        assert_eq!(worker.step_fiber(id).unwrap().result, StepResult::OutOfGas);
+
+        // refueling picks up where it left off
+        assert!(worker.refuel(id, 30));
+        assert!(worker.run().is_empty());
+        assert_eq!(worker.stack_snapshot(id).unwrap(), &[18]);
+        assert!(!worker.refuel(FiberId(9), 1));
+    }
+
+    #[test]
+    fn effects_cost_one_gas() {
+        let code = Assembler::new()
+            .push(5).install_handler(1, "handler")
+            .push(0).push(0).perform(1)
+            .halt()
+            .label("handler")
+            .pop().pop().dup().resume()
+            .finish().unwrap();
+
+        // ten instructions run, with the perform counted once
+        for (gas, result) in [(10, StepResult::Halted(5)), (9, StepResult::OutOfGas)] {
+            let mut worker = Worker::new().with_config(VmConfig::new().with_gas(gas));
+            let code = worker.add_code(code.clone());
+            let id = worker.spawn(code);
+            let mut last = StepResult::Continue;
+            while last == StepResult::Continue {
+                last = worker.step_fiber(id).unwrap().result;
+            }
+            assert_eq!(last, result);
+            assert_eq!(worker.fiber(id).unwrap().gas(), 0);
+        }
+    }
+
+    #[test]
+    fn trap_handlers_catch_traps() {
+        let mut worker = Worker::new();
+        // adds one to an overflowing sum, which is caught and replaced
+        let overflow = worker.add_code(Assembler::new()
+            .push(u64::MAX).push(1).add_u64_checked()
+            .push(1).add_u64()
+            .halt()
+            .finish().unwrap());
+        let underflow = worker.add_code(Assembler::new().pop().finish().unwrap());
+        let caught = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let seen = caught.clone();
+        worker.set_trap_handler(Box::new(move |fiber, trap| {
+            seen.borrow_mut().push((fiber, trap));
+            match trap {
+                StepResult::Overflow => TrapAction::Resume(99),
+                _ => TrapAction::Kill,
+            }
+        }));
+        let resumed = worker.spawn(overflow);
+        let killed = worker.spawn(underflow);
+
+        let exits = worker.run();
+        assert_eq!(exits.len(), 2);
+        assert_eq!((exits[0].id, exits[0].result), (resumed, StepResult::Halted(100)));
+        assert_eq!((exits[1].id, exits[1].result), (killed, StepResult::StackUnderflow));
+        assert_eq!(*caught.borrow(), vec![(resumed, StepResult::Overflow), (killed, StepResult::StackUnderflow)]);
+
+        // restarting runs the fiber again from the top, until the handler gives up
+        let restarts = std::rc::Rc::new(std::cell::Cell::new(0));
+        let count = restarts.clone();
+        worker.set_trap_handler(Box::new(move |_fiber, _trap| {
+            count.set(count.get() + 1);
+            if count.get() < 3 { TrapAction::Restart } else { TrapAction::Kill }
+        }));
+        let restarted = worker.spawn(underflow);
+        let exits = worker.run();
+        assert_eq!((exits[0].id, exits[0].result), (restarted, StepResult::StackUnderflow));
+        assert_eq!(restarts.get(), 3);
+
+        // without a handler, traps kill
+        worker.clear_trap_handler();
+        worker.spawn(overflow);
+        assert_eq!(worker.run()[0].result, StepResult::Overflow);
+    }
+
+    #[test]
+    fn profiles_show_hot_loops() {
+        let countdown = Assembler::new()
+            .push(10)
+            .label("loop")
+            .push(1).sub_u64()
+            .dup().jump_if_non_zero("loop")
+            .finish().unwrap();
+
+        // nothing is counted unless profiling
+        let mut worker = Worker::new();
+        let code = worker.add_code(countdown.clone());
+        worker.spawn(code);
+        worker.run();
+        assert!(worker.opcode_profile().is_empty());
+        assert!(worker.offset_profile(code).is_empty());
+
+        let mut worker = Worker::new().with_profiling(true);
+        let code = worker.add_code(countdown);
+        worker.spawn(code);
+        worker.run();
+        let ops = worker.opcode_profile();
+        assert_eq!(ops.into_iter().collect::<Vec<_>>(), vec![
+            (OpCode::SubU64, 10), (OpCode::Push, 11),
+            (OpCode::Dup, 10), (OpCode::JumpIfNonZero, 10),
+        ]);
+        // the prologue runs once, the loop body every time round
+        let offsets = worker.offset_profile(code);
+        assert_eq!(offsets.into_iter().collect::<Vec<_>>(), vec![(0, 1), (9, 10), (18, 10), (19, 10), (20, 10)]);
+        assert!(worker.offset_profile(CodeId(7)).is_empty());
+    }
+}